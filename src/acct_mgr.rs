@@ -1,6 +1,33 @@
+extern crate crypto;
 extern crate std;
+extern crate treexml;
+extern crate treexml_util;
 
 use common;
+use coproc;
+use errors;
+use file_names;
+use hostinfo;
+use http;
+use projects;
+use util;
+
+use std::io::Write;
+use std::path::Path;
+
+use common::ProjAm;
+
+use self::crypto::digest::Digest;
+use self::crypto::md5::Md5;
+use self::treexml_util::{make_text_element, make_tree_element, Unmarshaller};
+
+/// Seconds between syncs unless the account manager asks otherwise
+pub const DEFAULT_REPEAT_SEC: f64 = 86400.0;
+
+/// Error number account managers return for a wrong login or password
+pub const ERR_BAD_PASSWD: i64 = -206;
+/// Error number for replies that fail signature checks
+pub const ERR_RSA_FAILED: i64 = -137;
 
 pub enum CookieStatus {
     None,
@@ -22,6 +49,12 @@ pub struct AcctMgrInfo {
     pub password_hash: String,
     pub cookie_status: CookieStatus,
     pub password_error: bool,
+    /// public key the account manager signs project URLs with
+    pub signing_key: String,
+    /// data the account manager wants echoed back in the next request
+    pub opaque: Option<treexml::Element>,
+    /// when to sync again
+    pub next_rpc_time: Option<common::Time>,
 }
 
 impl common::ProjAm for AcctMgrInfo {
@@ -33,3 +66,497 @@ impl common::ProjAm for AcctMgrInfo {
         Some(self.project_name.clone())
     }
 }
+
+impl AcctMgrInfo {
+    pub fn is_set(&self) -> bool {
+        !self.master_url.is_empty()
+    }
+
+    /// Whether the periodic sync is due. Syncs stop after the account manager rejected the password.
+    pub fn rpc_due(&self, now: &common::Time) -> bool {
+        self.is_set() && !self.password_error && self.next_rpc_time.map(|t| t <= *now).unwrap_or(false)
+    }
+
+    /// Contents of acct_mgr_url.xml
+    pub fn url_element(&self) -> treexml::Element {
+        let mut children = vec![
+            make_text_element("name", &self.project_name),
+            make_text_element("url", &self.master_url),
+        ];
+        if !self.signing_key.is_empty() {
+            children.push(make_text_element("signing_key", &self.signing_key));
+        }
+        if let CookieStatus::Required(ref failure_url) = self.cookie_status {
+            children.push(treexml::Element::new("cookie_required"));
+            children.push(make_text_element("cookie_failure_url", failure_url));
+        }
+        make_tree_element("acct_mgr", children)
+    }
+
+    /// Contents of acct_mgr_login.xml
+    pub fn login_element(&self) -> treexml::Element {
+        let mut children = vec![
+            make_text_element("login", self.login_name.clone().unwrap_or_default()),
+            make_text_element("password_hash", &self.password_hash),
+            make_text_element("user_name", &self.user_name),
+            make_text_element("password_error", self.password_error as u8),
+        ];
+        if let Some(t) = self.next_rpc_time {
+            children.push(make_text_element("next_rpc_time", t.timestamp()));
+        }
+        if let Some(ref opaque) = self.opaque {
+            children.push(opaque.clone());
+        }
+        make_tree_element("acct_mgr_login", children)
+    }
+
+    pub fn parse_url(&mut self, root: &treexml::Element) -> errors::Result<()> {
+        let mut cookie_required = false;
+        let mut cookie_failure_url = String::new();
+        for node in &root.children {
+            match &*node.name {
+                "name" => {
+                    let _ = self.project_name.unmarshal(&node);
+                }
+                "url" => {
+                    let _ = self.master_url.unmarshal(&node);
+                }
+                "signing_key" => {
+                    let _ = self.signing_key.unmarshal(&node);
+                    self.signing_key = self.signing_key.trim().to_string();
+                }
+                "cookie_required" => {
+                    cookie_required = util::parse_flag(node);
+                }
+                "cookie_failure_url" => {
+                    let _ = cookie_failure_url.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+
+        if self.master_url.is_empty() {
+            bail!(errors::ErrorKind::XMLError("account manager without URL".into()));
+        }
+        self.cookie_status = if cookie_required {
+            CookieStatus::Required(cookie_failure_url)
+        } else {
+            CookieStatus::None
+        };
+        Ok(())
+    }
+
+    pub fn parse_login(&mut self, root: &treexml::Element) -> errors::Result<()> {
+        for node in &root.children {
+            match &*node.name {
+                "login" => {
+                    let mut s = String::new();
+                    if s.unmarshal(&node).is_ok() && !s.is_empty() {
+                        self.login_name = Some(s);
+                    }
+                }
+                "password_hash" => {
+                    let _ = self.password_hash.unmarshal(&node);
+                }
+                "user_name" => {
+                    let _ = self.user_name.unmarshal(&node);
+                }
+                "password_error" => {
+                    self.password_error = util::parse_flag(node);
+                }
+                "next_rpc_time" => {
+                    let mut n = 0i64;
+                    if n.unmarshal(&node).is_ok() {
+                        self.next_rpc_time = Some(
+                            common::Time::from(std::time::SystemTime::UNIX_EPOCH) + common::Duration::seconds(n),
+                        );
+                    }
+                }
+                "opaque" => {
+                    self.opaque = Some(node.clone());
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads acct_mgr_url.xml and acct_mgr_login.xml from the directory.
+    /// Yields the default, unset info if there is no account manager.
+    pub fn read_files(dir: &Path) -> errors::Result<AcctMgrInfo> {
+        let mut v = AcctMgrInfo::default();
+        let url_path = dir.join(file_names::ACCT_MGR_URL_FILE_NAME);
+        if !url_path.exists() {
+            return Ok(v);
+        }
+        if let Some(root) = treexml::Document::parse(std::fs::File::open(url_path)?)?.root {
+            v.parse_url(&root)?;
+        }
+
+        let login_path = dir.join(file_names::ACCT_MGR_LOGIN_FILE_NAME);
+        if login_path.exists() {
+            if let Some(root) = treexml::Document::parse(std::fs::File::open(login_path)?)?.root {
+                v.parse_login(&root)?;
+            }
+        }
+        Ok(v)
+    }
+
+    pub fn write_files(&self, dir: &Path) -> errors::Result<()> {
+        std::fs::File::create(dir.join(file_names::ACCT_MGR_URL_FILE_NAME))?
+            .write_fmt(format_args!("{}", self.url_element()))?;
+        std::fs::File::create(dir.join(file_names::ACCT_MGR_LOGIN_FILE_NAME))?
+            .write_fmt(format_args!("{}", self.login_element()))?;
+        Ok(())
+    }
+
+    pub fn remove_files(dir: &Path) -> errors::Result<()> {
+        for name in &[
+            file_names::ACCT_MGR_URL_FILE_NAME,
+            file_names::ACCT_MGR_LOGIN_FILE_NAME,
+        ] {
+            let path = dir.join(name);
+            if path.exists() {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Progress of the account manager RPC started through the GUI RPC
+#[derive(Debug, Default)]
+pub struct AcctMgrOp {
+    pub in_progress: bool,
+    pub error: Option<errors::Error>,
+    pub messages: Vec<String>,
+}
+
+/// Password hash sent instead of the password: MD5 of the password and the lowercased login.
+pub fn password_hash(password: &str, login: &str) -> String {
+    let mut hasher = Md5::new();
+    hasher.input_str(&format!("{}{}", password, login.to_lowercase()));
+    hasher.result_str()
+}
+
+/// One `<account>` of the reply, describing a project the host should be attached to
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AcctMgrAccount {
+    pub url: String,
+    pub url_signature: String,
+    pub authenticator: String,
+    pub detach: bool,
+    pub update: bool,
+    pub suspend: Option<bool>,
+    pub dont_request_more_work: Option<bool>,
+    pub detach_when_done: bool,
+    pub resource_share: Option<f64>,
+    /// resources the project must not fetch work for
+    pub no_rsc: [bool; coproc::MAX_RSC],
+}
+
+impl AcctMgrAccount {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<AcctMgrAccount> {
+        let mut v = AcctMgrAccount::default();
+        for node in &root.children {
+            match &*node.name {
+                "url" => {
+                    let _ = v.url.unmarshal(&node);
+                }
+                "url_signature" => {
+                    let _ = v.url_signature.unmarshal(&node);
+                }
+                "authenticator" => {
+                    let _ = v.authenticator.unmarshal(&node);
+                }
+                "detach" => {
+                    v.detach = util::parse_flag(node);
+                }
+                "update" => {
+                    v.update = util::parse_flag(node);
+                }
+                "suspend" => {
+                    v.suspend = Some(util::parse_flag(node));
+                }
+                "dont_request_more_work" => {
+                    v.dont_request_more_work = Some(util::parse_flag(node));
+                }
+                "detach_when_done" => {
+                    v.detach_when_done = util::parse_flag(node);
+                }
+                "resource_share" => {
+                    let mut n = 0.0f64;
+                    if n.unmarshal(&node).is_ok() && n >= 0.0 {
+                        v.resource_share = Some(n);
+                    }
+                }
+                "no_rsc" => {
+                    if let Some(rsc) = node.text
+                        .as_ref()
+                        .and_then(|s| coproc::ProcType::from_rsc_name(s.trim()))
+                    {
+                        v.no_rsc[rsc.rsc_index()] = true;
+                    }
+                }
+                "no_cpu" => {
+                    v.no_rsc[coproc::ProcType::CPU.rsc_index()] = util::parse_flag(node);
+                }
+                "no_cuda" => {
+                    v.no_rsc[coproc::ProcType::NVIDIAGraphics.rsc_index()] = util::parse_flag(node);
+                }
+                "no_ati" => {
+                    v.no_rsc[coproc::ProcType::AMDGraphics.rsc_index()] = util::parse_flag(node);
+                }
+                _ => {}
+            }
+        }
+
+        if v.url.is_empty() {
+            bail!(errors::ErrorKind::XMLError("account without URL".into()));
+        }
+        Ok(v)
+    }
+}
+
+/// Parsed `<acct_mgr_reply>`
+#[derive(Clone, Debug, Default)]
+pub struct AcctMgrReply {
+    pub name: String,
+    pub error_num: i64,
+    pub error_msg: String,
+    pub messages: Vec<String>,
+    pub signing_key: String,
+    pub repeat_sec: f64,
+    pub opaque: Option<treexml::Element>,
+    pub global_preferences: Option<treexml::Element>,
+    pub accounts: Vec<AcctMgrAccount>,
+}
+
+impl AcctMgrReply {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<AcctMgrReply> {
+        let mut v = AcctMgrReply {
+            repeat_sec: DEFAULT_REPEAT_SEC,
+            ..Default::default()
+        };
+        for node in &root.children {
+            match &*node.name {
+                "name" => {
+                    let _ = v.name.unmarshal(&node);
+                }
+                "error_num" => {
+                    let _ = v.error_num.unmarshal(&node);
+                }
+                "error" | "error_msg" => {
+                    let _ = v.error_msg.unmarshal(&node);
+                }
+                "message" => {
+                    if let Some(ref s) = node.text {
+                        v.messages.push(s.trim().to_string());
+                    }
+                }
+                "signing_key" => {
+                    let _ = v.signing_key.unmarshal(&node);
+                    v.signing_key = v.signing_key.trim().to_string();
+                }
+                "repeat_sec" => {
+                    let _ = v.repeat_sec.unmarshal(&node);
+                }
+                "opaque" => {
+                    v.opaque = Some(node.clone());
+                }
+                "global_preferences" => {
+                    v.global_preferences = Some(node.clone());
+                }
+                "account" => {
+                    v.accounts.push(AcctMgrAccount::try_from(node)?);
+                }
+                _ => {}
+            }
+        }
+
+        if v.error_num == 0 && !v.error_msg.is_empty() {
+            v.error_num = -1;
+        }
+        Ok(v)
+    }
+
+    /// The error reported by the account manager, if any
+    pub fn error(&self) -> Option<errors::Error> {
+        if self.error_num == 0 {
+            return None;
+        }
+        Some(errors::ErrorKind::AcctMgrError(self.error_num, self.error_msg.clone()).into())
+    }
+}
+
+/// Builds the `<acct_mgr_request>` listing the host and the projects it is attached to.
+pub fn make_request(
+    info: &AcctMgrInfo,
+    host_info: &hostinfo::HostInfo,
+    projects: &projects::Projects,
+    client_version: &str,
+) -> treexml::Element {
+    let mut children = vec![
+        make_text_element("name", info.login_name.clone().unwrap_or_default()),
+        make_text_element("password_hash", &info.password_hash),
+        make_text_element("host_cpid", &host_info.host_cpid),
+        make_text_element("domain_name", &host_info.domain_name),
+        make_text_element("client_version", client_version),
+    ];
+
+    let mut urls = projects.data.iter().map(|p| p.master_url()).collect::<Vec<_>>();
+    urls.sort();
+    for url in urls {
+        let project = projects.find_by_url(&url).unwrap();
+        let data = project.data.lock().unwrap();
+        children.push(make_tree_element(
+            "project",
+            vec![
+                make_text_element("url", &url),
+                make_text_element("project_name", data.project_name.clone().unwrap_or_default()),
+                make_text_element("suspended_via_gui", data.suspended_via_gui as u8),
+                make_text_element("account_key", &data.authenticator),
+                make_text_element("hostid", data.hostid),
+                make_text_element("attached_via_acct_mgr", data.attached_via_acct_mgr as u8),
+                make_text_element("dont_request_more_work", data.dont_request_more_work as u8),
+                make_text_element("cpu_ec", data.cpu_ec),
+                make_text_element("cpu_time", data.cpu_time),
+                make_text_element("gpu_ec", data.gpu_ec),
+                make_text_element("gpu_time", data.gpu_time),
+            ],
+        ));
+    }
+
+    if let Some(ref opaque) = info.opaque {
+        children.push(opaque.clone());
+    }
+
+    make_tree_element("acct_mgr_request", children)
+}
+
+/// Sends the request to the account manager at the URL and parses its reply.
+pub fn rpc(master_url: &str, request: &treexml::Element) -> errors::Result<AcctMgrReply> {
+    let url = if master_url.ends_with('/') {
+        format!("{}rpc.php", master_url)
+    } else {
+        format!("{}/rpc.php", master_url)
+    };
+    AcctMgrReply::try_from(&http::post_xml(&url, request)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    #[test]
+    fn test_password_hash() {
+        assert_eq!(password_hash("secret", "User"), password_hash("secret", "user"));
+        assert_ne!(password_hash("secret", "user"), password_hash("other", "user"));
+    }
+
+    #[test]
+    fn test_rpc() {
+        let (url, server) = http::serve_once(
+            200,
+            "<acct_mgr_reply>\n\
+             <name>Test AM</name>\n\
+             <signing_key>KEY</signing_key>\n\
+             <repeat_sec>3600</repeat_sec>\n\
+             <message>Hello</message>\n\
+             <opaque><id>5</id></opaque>\n\
+             <account>\n\
+             <url>http://a/</url>\n\
+             <authenticator>abc</authenticator>\n\
+             <resource_share>50</resource_share>\n\
+             <suspend>1</suspend>\n\
+             <no_rsc>NVIDIA</no_rsc>\n\
+             </account>\n\
+             <account><url>http://b/</url><detach/></account>\n\
+             </acct_mgr_reply>",
+        );
+
+        let mut info = AcctMgrInfo::default();
+        info.login_name = Some("user".into());
+        info.password_hash = password_hash("secret", "user");
+        let mut projects = projects::Projects::new(std::sync::Arc::new(::messages::DummyLogger::default()));
+        projects.data.insert(projects::Project::new("http://b/".into()));
+
+        let request = make_request(&info, &hostinfo::HostInfo::default(), &projects, "1.0");
+        let reply = rpc(&url, &request).unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /rpc.php HTTP/1.1");
+        assert!(body.contains(&format!("<password_hash>{}</password_hash>", info.password_hash)));
+        assert!(body.contains("<url>http://b/</url>"));
+
+        assert_eq!(reply.name, "Test AM");
+        assert!(reply.error().is_none());
+        assert_eq!(reply.repeat_sec, 3600.0);
+        assert_eq!(reply.messages, vec!["Hello".to_string()]);
+        assert!(reply.opaque.is_some());
+        assert_eq!(reply.accounts.len(), 2);
+
+        let a = &reply.accounts[0];
+        assert_eq!(a.authenticator, "abc");
+        assert_eq!(a.resource_share, Some(50.0));
+        assert_eq!(a.suspend, Some(true));
+        assert!(a.no_rsc[coproc::ProcType::NVIDIAGraphics.rsc_index()]);
+        assert!(!a.no_rsc[coproc::ProcType::CPU.rsc_index()]);
+        assert!(reply.accounts[1].detach);
+    }
+
+    #[test]
+    fn test_files() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("acct-mgr-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert!(!AcctMgrInfo::read_files(&dir).unwrap().is_set());
+
+        let mut info = AcctMgrInfo::default();
+        info.master_url = "https://am.example.com/".into();
+        info.project_name = "Example AM".into();
+        info.login_name = Some("user".into());
+        info.password_hash = password_hash("secret", "user");
+        info.signing_key = "KEY".into();
+        info.cookie_status = CookieStatus::Required("https://am.example.com/cookie".into());
+        info.password_error = true;
+        let epoch = common::Time::from(std::time::SystemTime::UNIX_EPOCH);
+        info.next_rpc_time = Some(epoch + common::Duration::seconds(1000));
+        info.write_files(&dir).unwrap();
+
+        let v = AcctMgrInfo::read_files(&dir).unwrap();
+        assert_eq!(v.master_url, info.master_url);
+        assert_eq!(v.project_name, info.project_name);
+        assert_eq!(v.login_name, info.login_name);
+        assert_eq!(v.password_hash, info.password_hash);
+        assert_eq!(v.signing_key, "KEY");
+        assert!(v.password_error);
+        assert_eq!(v.next_rpc_time, info.next_rpc_time);
+        match v.cookie_status {
+            CookieStatus::Required(ref url) => assert_eq!(url, "https://am.example.com/cookie"),
+            CookieStatus::None => panic!("cookie status not persisted"),
+        }
+        // No syncs with a rejected password
+        assert!(!v.rpc_due(&(epoch + common::Duration::seconds(2000))));
+
+        AcctMgrInfo::remove_files(&dir).unwrap();
+        assert!(!AcctMgrInfo::read_files(&dir).unwrap().is_set());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_error_reply() {
+        let reply = AcctMgrReply::try_from(
+            &treexml::Document::parse(
+                "<acct_mgr_reply><error_num>-206</error_num><error_msg>Bad password</error_msg></acct_mgr_reply>"
+                    .as_bytes(),
+            ).unwrap()
+                .root
+                .unwrap(),
+        ).unwrap();
+        assert_eq!(i64::from(&reply.error().unwrap()), ERR_BAD_PASSWD);
+    }
+}
@@ -1,9 +1,419 @@
 extern crate std;
+extern crate treexml;
+extern crate treexml_util;
 
 use errors;
+use http;
+use util;
 
+use self::treexml_util::{make_text_element, make_tree_element, Unmarshaller};
+
+/// The master page could not be fetched
+pub const ERR_ATTACH_FAIL_DOWNLOAD: i64 = -198;
+/// The master page does not name a scheduler
+pub const ERR_ATTACH_FAIL_PARSE: i64 = -199;
+/// The scheduler did not accept the account key
+pub const ERR_ATTACH_FAIL_BAD_KEY: i64 = -200;
+/// The scheduler could not be reached
+pub const ERR_ATTACH_FAIL_SERVER_ERROR: i64 = -202;
+
+/// Progress of the attach started through the GUI RPC
 #[derive(Debug, Default)]
 pub struct ProjectAttach {
-    pub error: std::sync::Mutex<Option<errors::Error>>,
+    pub in_progress: bool,
+    /// the account comes from project_init.xml, which is removed once attached
+    pub from_config_file: bool,
+    pub error: Option<errors::Error>,
     pub messages: Vec<String>,
 }
+
+/// Web RPCs a project offers for finding or making accounts
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccountRpc {
+    Lookup,
+    Create,
+}
+
+impl AccountRpc {
+    fn script(&self) -> &'static str {
+        match *self {
+            AccountRpc::Lookup => "lookup_account.php",
+            AccountRpc::Create => "create_account.php",
+        }
+    }
+}
+
+/// Account details sent to the project
+#[derive(Clone, Debug, Default)]
+pub struct AccountIn {
+    pub url: String,
+    pub email_addr: String,
+    /// MD5 of the password and the lowercased email address
+    pub passwd_hash: String,
+    pub user_name: String,
+    pub team_name: String,
+}
+
+impl AccountIn {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<AccountIn> {
+        let mut v = AccountIn::default();
+        for node in &root.children {
+            match &*node.name {
+                "url" => {
+                    let _ = v.url.unmarshal(&node);
+                }
+                "email_addr" => {
+                    let _ = v.email_addr.unmarshal(&node);
+                }
+                "passwd_hash" => {
+                    let _ = v.passwd_hash.unmarshal(&node);
+                }
+                "user_name" => {
+                    let _ = v.user_name.unmarshal(&node);
+                }
+                "team_name" => {
+                    let _ = v.team_name.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+        Ok(v)
+    }
+
+    /// Address of the web RPC with the account details in the query
+    pub fn rpc_url(&self, rpc: AccountRpc) -> String {
+        let mut query = vec![
+            ("email_addr", self.email_addr.as_str()),
+            ("passwd_hash", self.passwd_hash.as_str()),
+        ];
+        match rpc {
+            AccountRpc::Lookup => {
+                query.push(("get_opaque_auth", "1"));
+            }
+            AccountRpc::Create => {
+                query.push(("user_name", self.user_name.as_str()));
+                if !self.team_name.is_empty() {
+                    query.push(("team_name", self.team_name.as_str()));
+                }
+            }
+        }
+
+        format!(
+            "{}{}{}?{}",
+            self.url,
+            if self.url.ends_with('/') { "" } else { "/" },
+            rpc.script(),
+            query
+                .into_iter()
+                .map(|(k, v)| format!("{}={}", k, util::url_encode(v)))
+                .collect::<Vec<_>>()
+                .join("&")
+        )
+    }
+}
+
+/// Outcome of an account web RPC
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccountOut {
+    pub error_num: i64,
+    pub error_msg: String,
+    pub authenticator: String,
+}
+
+impl AccountOut {
+    /// Parses `<account_out>` or the `<error>` a project replies with on failure.
+    pub fn try_from(root: &treexml::Element) -> errors::Result<AccountOut> {
+        let mut v = AccountOut::default();
+        for node in &root.children {
+            match &*node.name {
+                "error_num" => {
+                    let _ = v.error_num.unmarshal(&node);
+                }
+                "error_msg" => {
+                    let _ = v.error_msg.unmarshal(&node);
+                }
+                "authenticator" => {
+                    let _ = v.authenticator.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+
+        if root.name == "error" && v.error_num == 0 {
+            v.error_num = -1;
+        }
+        if v.error_num == 0 && v.authenticator.is_empty() {
+            bail!(errors::ErrorKind::XMLError("reply without authenticator".into()));
+        }
+        Ok(v)
+    }
+
+    pub fn from_error(e: &errors::Error) -> AccountOut {
+        AccountOut {
+            error_num: i64::from(e),
+            error_msg: e.to_string(),
+            authenticator: String::new(),
+        }
+    }
+}
+
+/// Progress of an account web RPC started through the GUI RPC
+#[derive(Clone, Debug, Default)]
+pub struct AccountOp {
+    pub in_progress: bool,
+    pub reply: Option<AccountOut>,
+}
+
+/// Calls the project's web RPC. Failures to reach the project are reported like project errors.
+pub fn call(rpc: AccountRpc, v: &AccountIn) -> AccountOut {
+    match http::get_xml(&v.rpc_url(rpc)).and_then(|root| AccountOut::try_from(&root)) {
+        Ok(v) => v,
+        Err(e) => AccountOut::from_error(&e),
+    }
+}
+
+/// What a project tells about itself in get_project_config.php
+#[derive(Clone, Debug, Default)]
+pub struct ProjectConfig {
+    pub error_num: i64,
+    pub error_msg: String,
+    pub name: String,
+    pub master_url: String,
+    pub web_rpc_url_base: String,
+    pub min_passwd_length: i64,
+    pub uses_username: bool,
+    pub account_creation_disabled: bool,
+    pub client_account_creation_disabled: bool,
+    pub terms_of_use: String,
+    /// names of the platforms the project has applications for
+    pub platforms: Vec<String>,
+}
+
+impl<'a> From<&'a ProjectConfig> for treexml::Element {
+    fn from(v: &ProjectConfig) -> treexml::Element {
+        let mut children = vec![make_text_element("error_num", v.error_num)];
+        if v.error_num != 0 {
+            children.push(make_text_element("error_msg", &v.error_msg));
+            return make_tree_element("project_config", children);
+        }
+
+        children.append(&mut vec![
+            make_text_element("name", &v.name),
+            make_text_element("master_url", &v.master_url),
+            make_text_element("web_rpc_url_base", &v.web_rpc_url_base),
+            make_text_element("min_passwd_length", v.min_passwd_length),
+        ]);
+        if v.uses_username {
+            children.push(treexml::Element::new("uses_username"));
+        }
+        if v.account_creation_disabled {
+            children.push(treexml::Element::new("account_creation_disabled"));
+        }
+        if v.client_account_creation_disabled {
+            children.push(treexml::Element::new("client_account_creation_disabled"));
+        }
+        if !v.terms_of_use.is_empty() {
+            children.push(make_text_element("terms_of_use", &v.terms_of_use));
+        }
+        children.push(make_tree_element(
+            "platforms",
+            v.platforms
+                .iter()
+                .map(|p| make_tree_element("platform", vec![make_text_element("platform_name", p)]))
+                .collect(),
+        ));
+        make_tree_element("project_config", children)
+    }
+}
+
+impl ProjectConfig {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<ProjectConfig> {
+        let mut v = ProjectConfig::default();
+        for node in &root.children {
+            match &*node.name {
+                "error_num" => {
+                    let _ = v.error_num.unmarshal(&node);
+                }
+                "error_msg" => {
+                    let _ = v.error_msg.unmarshal(&node);
+                }
+                "name" => {
+                    let _ = v.name.unmarshal(&node);
+                }
+                "master_url" => {
+                    let _ = v.master_url.unmarshal(&node);
+                }
+                "web_rpc_url_base" => {
+                    let _ = v.web_rpc_url_base.unmarshal(&node);
+                }
+                "min_passwd_length" => {
+                    let _ = v.min_passwd_length.unmarshal(&node);
+                }
+                "uses_username" => {
+                    v.uses_username = util::parse_flag(node);
+                }
+                "account_creation_disabled" => {
+                    v.account_creation_disabled = util::parse_flag(node);
+                }
+                "client_account_creation_disabled" => {
+                    v.client_account_creation_disabled = util::parse_flag(node);
+                }
+                "terms_of_use" => {
+                    let _ = v.terms_of_use.unmarshal(&node);
+                    v.terms_of_use = v.terms_of_use.trim().to_string();
+                }
+                "platforms" => for platform in &node.children {
+                    if let Some(name) = platform
+                        .find_child(|e| e.name == "platform_name")
+                        .and_then(|e| e.text.clone())
+                    {
+                        let name = name.trim().to_string();
+                        if !v.platforms.contains(&name) {
+                            v.platforms.push(name);
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+        Ok(v)
+    }
+
+    pub fn from_error(e: &errors::Error) -> ProjectConfig {
+        ProjectConfig {
+            error_num: i64::from(e),
+            error_msg: e.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether the project has applications for one of the platforms.
+    /// Projects that don't list their platforms are given the benefit of the doubt.
+    pub fn supports_platform(&self, platforms: &[&str]) -> bool {
+        self.platforms.is_empty() || self.platforms.iter().any(|p| platforms.contains(&p.as_str()))
+    }
+
+    pub fn account_creation_allowed(&self) -> bool {
+        !(self.account_creation_disabled || self.client_account_creation_disabled)
+    }
+}
+
+/// Progress of the get_project_config GUI RPC
+#[derive(Clone, Debug, Default)]
+pub struct ProjectConfigOp {
+    pub in_progress: bool,
+    pub url: String,
+    pub config: Option<ProjectConfig>,
+}
+
+/// Fetches the project's configuration. Failures to reach the project are reported like project errors.
+pub fn get_project_config(url: &str) -> ProjectConfig {
+    let url = format!(
+        "{}{}get_project_config.php",
+        url,
+        if url.ends_with('/') { "" } else { "/" }
+    );
+    match http::get_xml(&url).and_then(|root| ProjectConfig::try_from(&root)) {
+        Ok(v) => v,
+        Err(e) => ProjectConfig::from_error(&e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use acct_mgr;
+
+    fn account_in(url: &str) -> AccountIn {
+        AccountIn {
+            url: url.into(),
+            email_addr: "user+1@example.com".into(),
+            passwd_hash: acct_mgr::password_hash("secret", "user+1@example.com"),
+            user_name: "User One".into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_lookup_account() {
+        let (url, server) = http::serve_once(200, "<account_out><authenticator>abc123</authenticator></account_out>");
+        let v = account_in(&url);
+        assert_eq!(
+            call(AccountRpc::Lookup, &v),
+            AccountOut {
+                authenticator: "abc123".into(),
+                ..Default::default()
+            }
+        );
+
+        let (request_line, _) = server.join().unwrap();
+        assert_eq!(
+            request_line,
+            format!(
+                "GET /lookup_account.php?email_addr=user%2B1%40example.com&passwd_hash={}&get_opaque_auth=1 HTTP/1.1",
+                v.passwd_hash
+            )
+        );
+    }
+
+    #[test]
+    fn test_create_account_error() {
+        let (url, server) = http::serve_once(
+            200,
+            "<error><error_num>-137</error_num><error_msg>email address is already in use</error_msg></error>",
+        );
+        let v = call(AccountRpc::Create, &account_in(&url));
+        assert_eq!(v.error_num, -137);
+        assert_eq!(v.error_msg, "email address is already in use");
+
+        let (request_line, _) = server.join().unwrap();
+        assert!(request_line.starts_with("GET /create_account.php?"));
+        assert!(request_line.contains("&user_name=User%20One"));
+    }
+
+    #[test]
+    fn test_unreachable_project() {
+        let (url, server) = http::serve_once(500, "");
+        let v = call(AccountRpc::Lookup, &account_in(&url));
+        assert_eq!(v.error_num, -184);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_get_project_config() {
+        let (url, server) = http::serve_once(
+            200,
+            "<project_config>\n\
+             <name>Test Project</name>\n\
+             <master_url>http://test/</master_url>\n\
+             <min_passwd_length>6</min_passwd_length>\n\
+             <client_account_creation_disabled/>\n\
+             <terms_of_use>\n  Be nice\n</terms_of_use>\n\
+             <platforms>\n\
+             <platform><platform_name>windows_x86_64</platform_name></platform>\n\
+             <platform><platform_name>x86_64-pc-linux-gnu</platform_name><plan_class>cuda</plan_class></platform>\n\
+             <platform><platform_name>x86_64-pc-linux-gnu</platform_name></platform>\n\
+             </platforms>\n\
+             </project_config>",
+        );
+        let v = get_project_config(&url);
+        let (request_line, _) = server.join().unwrap();
+        assert_eq!(request_line, "GET /get_project_config.php HTTP/1.1");
+
+        assert_eq!(v.error_num, 0);
+        assert_eq!(v.name, "Test Project");
+        assert_eq!(v.min_passwd_length, 6);
+        assert_eq!(v.terms_of_use, "Be nice");
+        assert_eq!(
+            v.platforms,
+            vec!["windows_x86_64".to_string(), "x86_64-pc-linux-gnu".to_string()]
+        );
+        assert!(v.supports_platform(&["x86_64-pc-linux-gnu"]));
+        assert!(!v.supports_platform(&["i686-pc-linux-gnu"]));
+        assert!(!v.account_creation_allowed());
+
+        let e = treexml::Element::from(&v);
+        assert!(e.find_child(|e| e.name == "client_account_creation_disabled").is_some());
+    }
+}
@@ -1,11 +1,18 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
 extern crate uuid;
 
+use coproc;
+use errors;
+use file_info;
 use workunit;
 
 use std::collections::{HashMap, HashSet};
+use self::treexml_util::Unmarshaller;
 use self::uuid::Uuid;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct AppVersion {
     pub file_name: String,
     pub app_name: String,
@@ -15,6 +22,85 @@ pub struct AppVersion {
     pub api_version: String,
     pub avg_ncpus: f64,
     pub max_ncpus: f64,
+    /// speed the app version is expected to run at, zero if unknown
+    pub flops: f64,
+    /// all files of the app version, including the main program
+    pub files: Vec<file_info::FileRef>,
+    /// coprocessor type and the instances of it the app version uses
+    pub coproc: Option<(coproc::ProcType, f64)>,
+}
+
+impl AppVersion {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<AppVersion> {
+        if root.name != "app_version" {
+            bail!(errors::ErrorKind::XMLError(format!("unexpected app version root: {}", root.name).into()));
+        }
+
+        let mut v = AppVersion::default();
+        for node in &root.children {
+            match &*node.name {
+                "app_name" => {
+                    let _ = v.app_name.unmarshal(&node);
+                }
+                "version_num" => {
+                    let mut n = 0i64;
+                    if n.unmarshal(&node).is_ok() {
+                        v.version_num = n as i32;
+                    }
+                }
+                "platform" => {
+                    let _ = v.platform.unmarshal(&node);
+                }
+                "plan_class" => {
+                    let _ = v.plan_class.unmarshal(&node);
+                }
+                "api_version" => {
+                    let _ = v.api_version.unmarshal(&node);
+                }
+                "avg_ncpus" => {
+                    let _ = v.avg_ncpus.unmarshal(&node);
+                }
+                "max_ncpus" => {
+                    let _ = v.max_ncpus.unmarshal(&node);
+                }
+                "flops" => {
+                    let _ = v.flops.unmarshal(&node);
+                }
+                "coproc" => {
+                    let mut name = String::new();
+                    let mut count = 0.0f64;
+                    for child in &node.children {
+                        match &*child.name {
+                            "type" => {
+                                let _ = name.unmarshal(&child);
+                            }
+                            "count" => {
+                                let _ = count.unmarshal(&child);
+                            }
+                            _ => {}
+                        }
+                    }
+                    if let Some(rsc) = coproc::ProcType::from_rsc_name(name.trim()) {
+                        if count > 0.0 {
+                            v.coproc = Some((rsc, count));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        v.files = file_info::file_refs(root);
+        v.file_name = v.files
+            .iter()
+            .find(|f| f.main_program)
+            .or_else(|| v.files.first())
+            .map(|f| f.file_name.clone())
+            .unwrap_or_default();
+        if v.app_name.is_empty() || v.file_name.is_empty() {
+            bail!(errors::ErrorKind::XMLError("app version has no app or program".into()));
+        }
+        Ok(v)
+    }
 }
 
 #[derive(Debug)]
@@ -25,3 +111,61 @@ pub struct App {
     pub versions: HashMap<Uuid, AppVersion>,
     pub active_tasks: HashSet<Uuid>,
 }
+
+impl App {
+    pub fn new(name: String, user_friendly_name: String) -> Self {
+        Self {
+            name: name,
+            user_friendly_name: user_friendly_name,
+            work_units: HashMap::new(),
+            versions: HashMap::new(),
+            active_tasks: HashSet::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_app_version() {
+        let root = treexml::Document::parse(std::io::Cursor::new(
+            "<app_version>\n\
+             <app_name>app</app_name>\n\
+             <version_num>712</version_num>\n\
+             <platform>x86_64-pc-linux-gnu</platform>\n\
+             <avg_ncpus>1</avg_ncpus>\n\
+             <file_ref><file_name>lib.so</file_name></file_ref>\n\
+             <file_ref><file_name>app_7.12</file_name><main_program/></file_ref>\n\
+             </app_version>",
+        )).unwrap()
+            .root
+            .unwrap();
+        let v = AppVersion::try_from(&root).unwrap();
+        assert_eq!(v.app_name, "app");
+        assert_eq!(v.version_num, 712);
+        assert_eq!(v.file_name, "app_7.12");
+        assert_eq!(
+            v.files.iter().map(|f| f.file_name.as_str()).collect::<Vec<_>>(),
+            vec!["lib.so", "app_7.12"]
+        );
+
+        assert_eq!(v.coproc, None);
+
+        let root = treexml::Document::parse(std::io::Cursor::new(
+            "<app_version>\n\
+             <app_name>app</app_name>\n\
+             <plan_class>cuda</plan_class>\n\
+             <coproc><type>NVIDIA</type><count>0.5</count></coproc>\n\
+             <file_ref><file_name>app_cuda</file_name></file_ref>\n\
+             </app_version>",
+        )).unwrap()
+            .root
+            .unwrap();
+        let v = AppVersion::try_from(&root).unwrap();
+        assert_eq!(v.coproc, Some((coproc::ProcType::NVIDIAGraphics, 0.5)));
+
+        assert!(AppVersion::try_from(&treexml::Element::new("app_version")).is_err());
+    }
+}
@@ -1,6 +1,79 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+
 use coproc;
+use errors;
+use file_names;
+use util;
 
 use std::collections::HashMap;
+use std::path::Path;
+
+use self::treexml_util::Unmarshaller;
+
+/// Keeps a project, or one of its apps, off some GPUs
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExcludeGpu {
+    pub url: String,
+    /// all devices if not set
+    pub device_num: Option<i64>,
+    /// all GPU types if not set
+    pub rsc_type: Option<coproc::ProcType>,
+    /// all of the project's apps if not set
+    pub appname: Option<String>,
+}
+
+impl ExcludeGpu {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<ExcludeGpu> {
+        let mut v = ExcludeGpu::default();
+        for node in &root.children {
+            match &*node.name {
+                "url" => {
+                    let _ = v.url.unmarshal(&node);
+                }
+                "device_num" => {
+                    let mut n = 0i64;
+                    if n.unmarshal(&node).is_ok() {
+                        v.device_num = Some(n);
+                    }
+                }
+                "type" => {
+                    v.rsc_type = node.text
+                        .as_ref()
+                        .and_then(|s| coproc::ProcType::from_rsc_name(s.trim()));
+                    if v.rsc_type.is_none() {
+                        bail!(errors::ErrorKind::XMLError(format!(
+                            "Unknown GPU type in exclude_gpu: {}",
+                            node.text.clone().unwrap_or_default()
+                        ).into()));
+                    }
+                }
+                "app" => {
+                    v.appname = node.text.clone();
+                }
+                _ => {}
+            }
+        }
+
+        if v.url.is_empty() {
+            bail!(errors::ErrorKind::XMLError("exclude_gpu is missing url".into()));
+        }
+
+        Ok(v)
+    }
+
+    /// Whether the exclusion covers the device when used by the project's app
+    pub fn matches(&self, url: &str, appname: Option<&str>, rsc: &coproc::ProcType, device: i64) -> bool {
+        util::urls_match(&self.url, url) && self.rsc_type.map(|v| v == *rsc).unwrap_or(true)
+            && self.device_num.map(|v| v == device).unwrap_or(true)
+            && match (self.appname.as_ref(), appname) {
+                (None, _) => true,
+                (Some(a), Some(b)) => a == b,
+                (Some(_), None) => false,
+            }
+    }
+}
 
 #[derive(Clone, Debug, Default)]
 pub struct CCConfig {
@@ -17,7 +90,7 @@ pub struct CCConfig {
     pub dont_contact_ref_site: bool,
     pub dont_suspend_nci: bool,
     pub dont_use_vbox: bool,
-    //    pub exclude_gpus: Vec<EXCLUDE_GPU>,
+    pub exclude_gpus: Vec<ExcludeGpu>,
     pub exclusive_apps: Vec<String>,
     pub exclusive_gpu_apps: Vec<String>,
     pub exit_after_finish: bool,
@@ -61,3 +134,292 @@ pub struct CCConfig {
     pub use_certs: bool,
     pub use_certs_only: bool,
 }
+
+impl CCConfig {
+    /// Parses the `<cc_config>` element of cc_config.xml. Unknown options are ignored.
+    pub fn try_from(root: &treexml::Element) -> errors::Result<CCConfig> {
+        let mut v = CCConfig::default();
+        for section in &root.children {
+            if section.name != "options" {
+                continue;
+            }
+            for node in &section.children {
+                match &*node.name {
+                    "abort_jobs_on_exit" => {
+                        let _ = v.abort_jobs_on_exit.unmarshal(&node);
+                    }
+                    "allow_multiple_clients" => {
+                        let _ = v.allow_multiple_clients.unmarshal(&node);
+                    }
+                    "allow_remote_gui_rpc" => {
+                        let _ = v.allow_remote_gui_rpc.unmarshal(&node);
+                    }
+                    "client_download_url" => {
+                        let _ = v.client_download_url.unmarshal(&node);
+                    }
+                    "client_new_version_text" => {
+                        let _ = v.client_new_version_text.unmarshal(&node);
+                    }
+                    "client_version_check_url" => {
+                        let _ = v.client_version_check_url.unmarshal(&node);
+                    }
+                    "disallow_attach" => {
+                        let _ = v.disallow_attach.unmarshal(&node);
+                    }
+                    "dont_check_file_sizes" => {
+                        let _ = v.dont_check_file_sizes.unmarshal(&node);
+                    }
+                    "dont_contact_ref_site" => {
+                        let _ = v.dont_contact_ref_site.unmarshal(&node);
+                    }
+                    "dont_suspend_nci" => {
+                        let _ = v.dont_suspend_nci.unmarshal(&node);
+                    }
+                    "dont_use_vbox" => {
+                        let _ = v.dont_use_vbox.unmarshal(&node);
+                    }
+                    "exit_after_finish" => {
+                        let _ = v.exit_after_finish.unmarshal(&node);
+                    }
+                    "exit_before_start" => {
+                        let _ = v.exit_before_start.unmarshal(&node);
+                    }
+                    "exit_when_idle" => {
+                        let _ = v.exit_when_idle.unmarshal(&node);
+                    }
+                    "fetch_minimal_work" => {
+                        let _ = v.fetch_minimal_work.unmarshal(&node);
+                    }
+                    "fetch_on_update" => {
+                        let _ = v.fetch_on_update.unmarshal(&node);
+                    }
+                    "force_auth" => {
+                        let _ = v.force_auth.unmarshal(&node);
+                    }
+                    "http_1_0" => {
+                        let _ = v.http_1_0.unmarshal(&node);
+                    }
+                    "http_transfer_timeout_bps" => {
+                        let _ = v.http_transfer_timeout_bps.unmarshal(&node);
+                    }
+                    "http_transfer_timeout" => {
+                        let _ = v.http_transfer_timeout.unmarshal(&node);
+                    }
+                    "lower_client_priority" => {
+                        let _ = v.lower_client_priority.unmarshal(&node);
+                    }
+                    "max_event_log_lines" => {
+                        let _ = v.max_event_log_lines.unmarshal(&node);
+                    }
+                    "max_file_xfers" => {
+                        let _ = v.max_file_xfers.unmarshal(&node);
+                    }
+                    "max_file_xfers_per_project" => {
+                        let _ = v.max_file_xfers_per_project.unmarshal(&node);
+                    }
+                    "max_stderr_file_size" => {
+                        let _ = v.max_stderr_file_size.unmarshal(&node);
+                    }
+                    "max_stdout_file_size" => {
+                        let _ = v.max_stdout_file_size.unmarshal(&node);
+                    }
+                    "max_tasks_reported" => {
+                        let _ = v.max_tasks_reported.unmarshal(&node);
+                    }
+                    "ncpus" => {
+                        let _ = v.ncpus.unmarshal(&node);
+                    }
+                    "network_test_url" => {
+                        let _ = v.network_test_url.unmarshal(&node);
+                    }
+                    "no_alt_platform" => {
+                        let _ = v.no_alt_platform.unmarshal(&node);
+                    }
+                    "no_gpus" => {
+                        let _ = v.no_gpus.unmarshal(&node);
+                    }
+                    "no_info_fetch" => {
+                        let _ = v.no_info_fetch.unmarshal(&node);
+                    }
+                    "no_opencl" => {
+                        let _ = v.no_opencl.unmarshal(&node);
+                    }
+                    "no_priority_change" => {
+                        let _ = v.no_priority_change.unmarshal(&node);
+                    }
+                    "os_random_only" => {
+                        let _ = v.os_random_only.unmarshal(&node);
+                    }
+                    "process_priority" => {
+                        let _ = v.process_priority.unmarshal(&node);
+                    }
+                    "process_priority_special" => {
+                        let _ = v.process_priority_special.unmarshal(&node);
+                    }
+                    "rec_half_life" => {
+                        let _ = v.rec_half_life.unmarshal(&node);
+                    }
+                    "report_results_immediately" => {
+                        let _ = v.report_results_immediately.unmarshal(&node);
+                    }
+                    "run_apps_manually" => {
+                        let _ = v.run_apps_manually.unmarshal(&node);
+                    }
+                    "save_stats_days" => {
+                        let _ = v.save_stats_days.unmarshal(&node);
+                    }
+                    "skip_cpu_benchmarks" => {
+                        let _ = v.skip_cpu_benchmarks.unmarshal(&node);
+                    }
+                    "simple_gui_only" => {
+                        let _ = v.simple_gui_only.unmarshal(&node);
+                    }
+                    "start_delay" => {
+                        let _ = v.start_delay.unmarshal(&node);
+                    }
+                    "stderr_head" => {
+                        let _ = v.stderr_head.unmarshal(&node);
+                    }
+                    "suppress_net_info" => {
+                        let _ = v.suppress_net_info.unmarshal(&node);
+                    }
+                    "unsigned_apps_ok" => {
+                        let _ = v.unsigned_apps_ok.unmarshal(&node);
+                    }
+                    "use_all_gpus" => {
+                        let _ = v.use_all_gpus.unmarshal(&node);
+                    }
+                    "use_certs" => {
+                        let _ = v.use_certs.unmarshal(&node);
+                    }
+                    "use_certs_only" => {
+                        let _ = v.use_certs_only.unmarshal(&node);
+                    }
+                    "alt_platform" => {
+                        if let Some(ref s) = node.text {
+                            v.alt_platforms.push(s.clone());
+                        }
+                    }
+                    "exclusive_app" => {
+                        if let Some(ref s) = node.text {
+                            v.exclusive_apps.push(s.clone());
+                        }
+                    }
+                    "exclusive_gpu_app" => {
+                        if let Some(ref s) = node.text {
+                            v.exclusive_gpu_apps.push(s.clone());
+                        }
+                    }
+                    "exclude_gpu" => {
+                        v.exclude_gpus.push(ExcludeGpu::try_from(node)?);
+                    }
+                    "ignore_nvidia_dev" | "ignore_ati_dev" | "ignore_intel_gpu_dev" => {
+                        let rsc = match &*node.name {
+                            "ignore_nvidia_dev" => coproc::ProcType::NVIDIAGraphics,
+                            "ignore_ati_dev" => coproc::ProcType::AMDGraphics,
+                            _ => coproc::ProcType::IntelGraphics,
+                        };
+                        let mut n = 0i64;
+                        if n.unmarshal(&node).is_ok() {
+                            v.ignore_gpu_instance
+                                .entry(rsc)
+                                .or_insert_with(Vec::new)
+                                .push(n);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(v)
+    }
+
+    /// Reads cc_config.xml from the directory. Without the file, all options keep their defaults.
+    pub fn read(dir: &Path) -> errors::Result<CCConfig> {
+        let path = dir.join(file_names::CONFIG_FILE_NAME);
+        if !path.exists() {
+            return Ok(CCConfig::default());
+        }
+        match treexml::Document::parse(std::fs::File::open(path)?)?.root {
+            Some(root) => CCConfig::try_from(&root),
+            None => Ok(CCConfig::default()),
+        }
+    }
+
+    /// Devices of the given type the project's app may not use
+    pub fn excluded_devices(&self, url: &str, appname: Option<&str>, rsc: &coproc::ProcType, devices: &[i64]) -> Vec<i64> {
+        devices
+            .iter()
+            .cloned()
+            .filter(|n| self.exclude_gpus.iter().any(|v| v.matches(url, appname, rsc, *n)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_exclude_gpu() {
+        let doc = treexml::Document::parse(std::io::Cursor::new(
+            r#"<cc_config>
+                <options>
+                    <max_tasks_reported>10</max_tasks_reported>
+                    <exclude_gpu>
+                        <url>http://a/</url>
+                        <device_num>1</device_num>
+                        <type>NVIDIA</type>
+                    </exclude_gpu>
+                    <exclude_gpu>
+                        <url>http://b/</url>
+                        <app>app1</app>
+                    </exclude_gpu>
+                    <ignore_ati_dev>0</ignore_ati_dev>
+                </options>
+            </cc_config>"#,
+        )).unwrap();
+        let v = CCConfig::try_from(&doc.root.unwrap()).unwrap();
+
+        assert_eq!(v.max_tasks_reported, 10);
+        assert_eq!(
+            v.exclude_gpus[0],
+            ExcludeGpu {
+                url: "http://a/".into(),
+                device_num: Some(1),
+                rsc_type: Some(coproc::ProcType::NVIDIAGraphics),
+                appname: None,
+            }
+        );
+        assert_eq!(v.ignore_gpu_instance[&coproc::ProcType::AMDGraphics], vec![0]);
+
+        let nvidia = coproc::ProcType::NVIDIAGraphics;
+        assert_eq!(v.excluded_devices("http://a/", None, &nvidia, &[0, 1]), vec![1]);
+        assert_eq!(v.excluded_devices("https://a", None, &nvidia, &[0, 1]), vec![1]);
+        assert!(v.excluded_devices("http://a/", None, &coproc::ProcType::AMDGraphics, &[0, 1]).is_empty());
+        assert!(v.excluded_devices("http://b/", None, &nvidia, &[0, 1]).is_empty());
+        assert_eq!(v.excluded_devices("http://b/", Some("app1"), &nvidia, &[0, 1]), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("cc-config-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(CCConfig::read(&dir).unwrap().max_tasks_reported, 0);
+
+        std::fs::File::create(dir.join(file_names::CONFIG_FILE_NAME))
+            .unwrap()
+            .write_all(b"<cc_config><options><max_tasks_reported>5</max_tasks_reported></options></cc_config>")
+            .unwrap();
+        assert_eq!(CCConfig::read(&dir).unwrap().max_tasks_reported, 5);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,11 +1,11 @@
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CertSig {
     pub signature: String,
     pub subject: String,
     pub hash: String,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct CertSigs {
     pub signatures: Vec<CertSig>,
 }
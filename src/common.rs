@@ -80,6 +80,52 @@ impl NetStats {
     }
 }
 
+/// Why computation is suspended
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SuspendReason {
+    Batteries,
+    UserActive,
+    UserRequest,
+    TimeOfDay,
+    Benchmarks,
+    DiskSize,
+    CpuThrottle,
+    NoRecentInput,
+    InitialDelay,
+    ExclusiveAppRunning,
+    CpuUsage,
+    NetworkQuotaExceeded,
+    OS,
+    WifiState,
+    BatteryCharging,
+    BatteryOverheated,
+    NoGuiKeepalive,
+}
+
+impl From<SuspendReason> for i32 {
+    fn from(v: SuspendReason) -> i32 {
+        match v {
+            SuspendReason::Batteries => 1,
+            SuspendReason::UserActive => 2,
+            SuspendReason::UserRequest => 4,
+            SuspendReason::TimeOfDay => 8,
+            SuspendReason::Benchmarks => 16,
+            SuspendReason::DiskSize => 32,
+            SuspendReason::CpuThrottle => 64,
+            SuspendReason::NoRecentInput => 128,
+            SuspendReason::InitialDelay => 256,
+            SuspendReason::ExclusiveAppRunning => 512,
+            SuspendReason::CpuUsage => 1024,
+            SuspendReason::NetworkQuotaExceeded => 2048,
+            SuspendReason::OS => 4096,
+            SuspendReason::WifiState => 4097,
+            SuspendReason::BatteryCharging => 4098,
+            SuspendReason::BatteryOverheated => 4099,
+            SuspendReason::NoGuiKeepalive => 4100,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RpcReason {
     UserRequest,
@@ -1,4 +1,8 @@
 pub const ENV_RPC_ADDR: &str = "RPC_ADDR";
 pub const ENV_RPC_PASSWORD: &str = "RPC_PASSWORD";
 pub const STATE_FILE_NAME: &str = "client_state.xml";
+/// Written first and then renamed to `STATE_FILE_NAME`, so a crash never leaves a partial state file
+pub const STATE_FILE_NEXT_NAME: &str = "client_state_next.xml";
 pub const ALL_PROJECTS_LIST_FILENAME: &str = "all_projects_list.xml";
+/// Platform this client runs applications for
+pub const PRIMARY_PLATFORM: &str = "x86_64-pc-linux-gnu";
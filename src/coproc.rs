@@ -1,3 +1,7 @@
+extern crate std;
+
+use std::collections::HashMap;
+
 pub const MAX_RSC: usize = 8;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -9,5 +13,114 @@ pub enum ProcType {
     MinerASIC,
 }
 
-#[derive(Clone, Debug, Default)]
-pub struct Coprocs {}
+/// Devices of one coprocessor type
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coproc {
+    /// numbers of the usable device instances
+    pub devices: Vec<i64>,
+    pub model: String,
+    /// bytes of memory on each device
+    pub memory: f64,
+    pub driver_version: String,
+}
+
+impl Coproc {
+    pub fn count(&self) -> usize {
+        self.devices.len()
+    }
+}
+
+/// Coprocessors present on the host
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coprocs {
+    pub data: HashMap<ProcType, Coproc>,
+}
+
+impl Coprocs {
+    pub fn get(&self, rsc: &ProcType) -> Option<&Coproc> {
+        self.data.get(rsc)
+    }
+
+    /// Number of usable instances of each coprocessor type
+    pub fn counts(&self) -> HashMap<ProcType, f64> {
+        self.data
+            .iter()
+            .filter(|&(_, v)| v.count() > 0)
+            .map(|(k, v)| (*k, v.count() as f64))
+            .collect()
+    }
+
+    /// Applies the user's configuration to the detected devices.
+    ///
+    /// Coprocessors declared in `configured` are added unless already detected.
+    /// Instances listed in `ignore` are dropped, and `no_gpus` removes every GPU.
+    pub fn apply_config(&mut self, configured: &Coprocs, ignore: &HashMap<ProcType, Vec<i64>>, no_gpus: bool) {
+        for (rsc, v) in &configured.data {
+            self.data.entry(*rsc).or_insert_with(|| v.clone());
+        }
+
+        for (rsc, devices) in ignore {
+            if let Some(v) = self.data.get_mut(rsc) {
+                v.devices.retain(|n| !devices.contains(n));
+            }
+        }
+
+        if no_gpus {
+            self.data.retain(|rsc, _| !rsc.is_gpu());
+        }
+        self.data.retain(|_, v| v.count() > 0);
+    }
+}
+
+impl ProcType {
+    /// Position of the resource in per-resource arrays such as `no_rsc_pref`
+    pub fn rsc_index(&self) -> usize {
+        match *self {
+            ProcType::CPU => 0,
+            ProcType::NVIDIAGraphics => 1,
+            ProcType::AMDGraphics => 2,
+            ProcType::IntelGraphics => 3,
+            ProcType::MinerASIC => 4,
+        }
+    }
+
+    pub fn is_gpu(&self) -> bool {
+        match *self {
+            ProcType::NVIDIAGraphics | ProcType::AMDGraphics | ProcType::IntelGraphics => true,
+            _ => false,
+        }
+    }
+
+    /// GPU vendor owning the PCI vendor ID
+    pub fn from_pci_vendor(id: u32) -> Option<ProcType> {
+        match id {
+            0x10de => Some(ProcType::NVIDIAGraphics),
+            0x1002 => Some(ProcType::AMDGraphics),
+            0x8086 => Some(ProcType::IntelGraphics),
+            _ => None,
+        }
+    }
+
+    /// Reverse of `rsc_name`, also accepting the vendor names used in cc_config.xml
+    pub fn from_rsc_name(name: &str) -> Option<ProcType> {
+        match name {
+            "CPU" => Some(ProcType::CPU),
+            "NVIDIA" | "nvidia" => Some(ProcType::NVIDIAGraphics),
+            "ATI" | "ati" | "AMD" | "amd" => Some(ProcType::AMDGraphics),
+            "intel_gpu" | "Intel" | "intel" => Some(ProcType::IntelGraphics),
+            "miner_asic" => Some(ProcType::MinerASIC),
+            _ => None,
+        }
+    }
+
+    /// Name used for the resource in scheduler requests
+    pub fn rsc_name(&self) -> &'static str {
+        match *self {
+            ProcType::CPU => "CPU",
+            ProcType::NVIDIAGraphics => "NVIDIA",
+            ProcType::AMDGraphics => "ATI",
+            ProcType::IntelGraphics => "intel_gpu",
+            ProcType::MinerASIC => "miner_asic",
+        }
+    }
+}
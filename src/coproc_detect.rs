@@ -0,0 +1,538 @@
+extern crate libc;
+extern crate std;
+
+use coproc;
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use coproc::ProcType;
+
+/// Finds the host's GPUs through sysfs, procfs and, if installed, the OpenCL ICD loader.
+///
+/// Roots are configurable so that detection can run against a fake tree.
+#[derive(Clone, Debug)]
+pub struct Detector {
+    pub sysfs_root: PathBuf,
+    pub procfs_root: PathBuf,
+    pub use_opencl: bool,
+}
+
+impl Default for Detector {
+    fn default() -> Self {
+        Self {
+            sysfs_root: "/sys".into(),
+            procfs_root: "/proc".into(),
+            use_opencl: true,
+        }
+    }
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    let mut s = String::new();
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .ok()
+        .map(|_| s.trim().to_string())
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s.trim_left_matches("0x"), 16).ok()
+}
+
+/// A GPU found in DRM
+struct DrmCard {
+    rsc: ProcType,
+    model: String,
+    memory: f64,
+    /// version of the driver module, empty if it has none
+    driver: String,
+}
+
+impl Detector {
+    pub fn detect(&self) -> coproc::Coprocs {
+        let mut v = coproc::Coprocs::default();
+
+        for card in self.drm_cards() {
+            let entry = v.data.entry(card.rsc).or_insert_with(Default::default);
+            let n = entry.devices.len() as i64;
+            entry.devices.push(n);
+            if entry.model.is_empty() {
+                entry.model = card.model;
+            }
+            if entry.memory == 0.0 {
+                entry.memory = card.memory;
+            }
+            if entry.driver_version.is_empty() {
+                entry.driver_version = card.driver;
+            }
+        }
+
+        self.apply_nvidia_proc(&mut v);
+
+        if self.use_opencl {
+            apply_opencl(&mut v, &opencl::devices());
+        }
+
+        v
+    }
+
+    /// GPUs listed under class/drm, ordered by card number
+    fn drm_cards(&self) -> Vec<DrmCard> {
+        let entries = match std::fs::read_dir(self.sysfs_root.join("class/drm")) {
+            Ok(v) => v,
+            Err(_) => {
+                return Vec::new();
+            }
+        };
+
+        let mut cards = entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| {
+                let name = e.file_name().to_string_lossy().into_owned();
+                let n = name.trim_left_matches("card").parse::<u32>().ok();
+                if name.starts_with("card") {
+                    n.map(|n| (n, e.path()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        cards.sort_by_key(|&(n, _)| n);
+
+        cards
+            .into_iter()
+            .filter_map(|(_, path)| {
+                let device = path.join("device");
+                let rsc = match read_string(&device.join("vendor"))
+                    .and_then(|s| parse_hex(&s))
+                    .and_then(ProcType::from_pci_vendor)
+                {
+                    Some(v) => v,
+                    None => {
+                        return None;
+                    }
+                };
+                let model = read_string(&device.join("product_name"))
+                    .or_else(|| read_string(&device.join("device")).map(|id| format!("PCI device {}", id)))
+                    .unwrap_or_default();
+                let memory = read_string(&device.join("mem_info_vram_total"))
+                    .and_then(|s| s.parse::<f64>().ok())
+                    .unwrap_or(0.0);
+                // Only out-of-tree driver modules declare a version
+                let driver = read_string(&device.join("driver/module/version")).unwrap_or_default();
+                Some(DrmCard {
+                    rsc: rsc,
+                    model: model,
+                    memory: memory,
+                    driver: driver,
+                })
+            })
+            .collect()
+    }
+
+    /// Fills in what the proprietary NVIDIA driver reports about its GPUs.
+    fn apply_nvidia_proc(&self, v: &mut coproc::Coprocs) {
+        let root = self.procfs_root.join("driver/nvidia");
+        let version = read_string(&root.join("version")).and_then(|s| {
+            s.lines()
+                .next()
+                .and_then(|line| line.split("Kernel Module").nth(1))
+                .and_then(|rest| rest.split_whitespace().next())
+                .map(|v| v.to_string())
+        });
+
+        let mut gpus = std::fs::read_dir(root.join("gpus"))
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        gpus.sort();
+        let models = gpus.iter()
+            .filter_map(|p| read_string(&p.join("information")))
+            .filter_map(|s| {
+                s.lines()
+                    .find(|line| line.starts_with("Model:"))
+                    .map(|line| line["Model:".len()..].trim().to_string())
+            })
+            .collect::<Vec<_>>();
+
+        if version.is_none() && models.is_empty() {
+            return;
+        }
+
+        let entry = v.data
+            .entry(ProcType::NVIDIAGraphics)
+            .or_insert_with(Default::default);
+        while entry.devices.len() < models.len() {
+            let n = entry.devices.len() as i64;
+            entry.devices.push(n);
+        }
+        if let Some(model) = models.into_iter().next() {
+            entry.model = model;
+        }
+        if let Some(version) = version {
+            entry.driver_version = version;
+        }
+    }
+}
+
+/// A GPU reported by an OpenCL platform
+#[derive(Clone, Debug, Default)]
+pub struct OpenClDevice {
+    pub vendor_id: u32,
+    pub name: String,
+    pub memory: f64,
+    pub driver_version: String,
+}
+
+/// Uses OpenCL to add devices sysfs did not show and to complete the description of those it did.
+fn apply_opencl(v: &mut coproc::Coprocs, devices: &[OpenClDevice]) {
+    let mut by_rsc: HashMap<ProcType, Vec<&OpenClDevice>> = HashMap::new();
+    for dev in devices {
+        if let Some(rsc) = ProcType::from_pci_vendor(dev.vendor_id) {
+            by_rsc.entry(rsc).or_insert_with(Vec::new).push(dev);
+        }
+    }
+
+    for (rsc, devs) in by_rsc {
+        let entry = v.data.entry(rsc).or_insert_with(Default::default);
+        while entry.devices.len() < devs.len() {
+            let n = entry.devices.len() as i64;
+            entry.devices.push(n);
+        }
+        let first = devs[0];
+        if !first.name.is_empty() {
+            entry.model = first.name.clone();
+        }
+        if first.memory > 0.0 {
+            entry.memory = first.memory;
+        }
+        if entry.driver_version.is_empty() {
+            entry.driver_version = first.driver_version.clone();
+        }
+    }
+}
+
+/// Minimal binding to the OpenCL ICD loader, resolved at runtime so that it is not a build dependency.
+mod opencl {
+    use super::libc;
+    use super::std;
+
+    use super::OpenClDevice;
+
+    use std::ffi::CString;
+
+    type ClInt = i32;
+    type ClUint = u32;
+    type ClPlatformId = *mut libc::c_void;
+    type ClDeviceId = *mut libc::c_void;
+
+    const CL_SUCCESS: ClInt = 0;
+    const CL_DEVICE_TYPE_GPU: u64 = 1 << 2;
+    const CL_DEVICE_VENDOR_ID: ClUint = 0x1001;
+    const CL_DEVICE_GLOBAL_MEM_SIZE: ClUint = 0x101F;
+    const CL_DEVICE_NAME: ClUint = 0x102B;
+    const CL_DRIVER_VERSION: ClUint = 0x102D;
+
+    const LIBRARY_NAMES: [&str; 2] = ["libOpenCL.so.1", "libOpenCL.so"];
+
+    type GetPlatformIDs = unsafe extern "C" fn(ClUint, *mut ClPlatformId, *mut ClUint) -> ClInt;
+    type GetDeviceIDs = unsafe extern "C" fn(ClPlatformId, u64, ClUint, *mut ClDeviceId, *mut ClUint) -> ClInt;
+    type GetDeviceInfo = unsafe extern "C" fn(ClDeviceId, ClUint, libc::size_t, *mut libc::c_void, *mut libc::size_t) -> ClInt;
+
+    struct Library(*mut libc::c_void);
+
+    impl Drop for Library {
+        fn drop(&mut self) {
+            unsafe {
+                libc::dlclose(self.0);
+            }
+        }
+    }
+
+    impl Library {
+        fn open() -> Option<Library> {
+            LIBRARY_NAMES.iter().filter_map(|name| {
+                let name = CString::new(*name).unwrap();
+                let handle = unsafe { libc::dlopen(name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+                if handle.is_null() {
+                    None
+                } else {
+                    Some(Library(handle))
+                }
+            }).next()
+        }
+
+        fn symbol(&self, name: &str) -> Option<*mut libc::c_void> {
+            let name = CString::new(name).unwrap();
+            let v = unsafe { libc::dlsym(self.0, name.as_ptr()) };
+            if v.is_null() {
+                None
+            } else {
+                Some(v)
+            }
+        }
+    }
+
+    unsafe fn info_bytes(f: GetDeviceInfo, dev: ClDeviceId, param: ClUint) -> Option<Vec<u8>> {
+        let mut size: libc::size_t = 0;
+        if f(dev, param, 0, std::ptr::null_mut(), &mut size) != CL_SUCCESS {
+            return None;
+        }
+        let mut buf = vec![0u8; size];
+        if f(dev, param, size, buf.as_mut_ptr() as *mut libc::c_void, std::ptr::null_mut()) != CL_SUCCESS {
+            return None;
+        }
+        Some(buf)
+    }
+
+    unsafe fn info_string(f: GetDeviceInfo, dev: ClDeviceId, param: ClUint) -> String {
+        info_bytes(f, dev, param)
+            .map(|v| {
+                String::from_utf8_lossy(&v)
+                    .trim_right_matches('\0')
+                    .trim()
+                    .to_string()
+            })
+            .unwrap_or_default()
+    }
+
+    unsafe fn info_u64(f: GetDeviceInfo, dev: ClDeviceId, param: ClUint) -> u64 {
+        info_bytes(f, dev, param)
+            .map(|v| {
+                v.iter()
+                    .take(8)
+                    .enumerate()
+                    .fold(0u64, |acc, (i, b)| acc | (u64::from(*b) << (8 * i)))
+            })
+            .unwrap_or(0)
+    }
+
+    /// GPUs of all OpenCL platforms. Empty if no ICD loader is installed.
+    pub fn devices() -> Vec<OpenClDevice> {
+        let lib = match Library::open() {
+            Some(v) => v,
+            None => {
+                return Vec::new();
+            }
+        };
+
+        let symbols = (
+            lib.symbol("clGetPlatformIDs"),
+            lib.symbol("clGetDeviceIDs"),
+            lib.symbol("clGetDeviceInfo"),
+        );
+        let (get_platforms, get_devices, get_info) = match symbols {
+            (Some(a), Some(b), Some(c)) => unsafe {
+                (
+                    std::mem::transmute::<_, GetPlatformIDs>(a),
+                    std::mem::transmute::<_, GetDeviceIDs>(b),
+                    std::mem::transmute::<_, GetDeviceInfo>(c),
+                )
+            },
+            _ => {
+                return Vec::new();
+            }
+        };
+
+        let mut out = Vec::new();
+        unsafe {
+            let mut nplatforms: ClUint = 0;
+            if get_platforms(0, std::ptr::null_mut(), &mut nplatforms) != CL_SUCCESS || nplatforms == 0 {
+                return out;
+            }
+            let mut platforms = vec![std::ptr::null_mut(); nplatforms as usize];
+            if get_platforms(nplatforms, platforms.as_mut_ptr(), std::ptr::null_mut()) != CL_SUCCESS {
+                return out;
+            }
+
+            for platform in platforms {
+                let mut ndevices: ClUint = 0;
+                if get_devices(platform, CL_DEVICE_TYPE_GPU, 0, std::ptr::null_mut(), &mut ndevices) != CL_SUCCESS
+                    || ndevices == 0
+                {
+                    continue;
+                }
+                let mut devices = vec![std::ptr::null_mut(); ndevices as usize];
+                if get_devices(
+                    platform,
+                    CL_DEVICE_TYPE_GPU,
+                    ndevices,
+                    devices.as_mut_ptr(),
+                    std::ptr::null_mut(),
+                ) != CL_SUCCESS
+                {
+                    continue;
+                }
+
+                for dev in devices {
+                    out.push(OpenClDevice {
+                        vendor_id: info_u64(get_info, dev, CL_DEVICE_VENDOR_ID) as u32,
+                        name: info_string(get_info, dev, CL_DEVICE_NAME),
+                        memory: info_u64(get_info, dev, CL_DEVICE_GLOBAL_MEM_SIZE) as f64,
+                        driver_version: info_string(get_info, dev, CL_DRIVER_VERSION),
+                    });
+                }
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use std::io::Write;
+
+    struct FakeRoot(PathBuf);
+
+    impl Drop for FakeRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl FakeRoot {
+        fn new() -> Self {
+            let mut v = std::env::temp_dir();
+            v.push(format!("coproc-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+            std::fs::create_dir_all(&v).unwrap();
+            FakeRoot(v)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::File::create(path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap();
+        }
+
+        fn detector(&self) -> Detector {
+            Detector {
+                sysfs_root: self.0.join("sys"),
+                procfs_root: self.0.join("proc"),
+                use_opencl: false,
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_gpu() {
+        let root = FakeRoot::new();
+        root.write("sys/class/drm/version", "drm 1.1.0");
+        assert_eq!(root.detector().detect(), coproc::Coprocs::default());
+    }
+
+    #[test]
+    fn test_sysfs_and_nvidia_proc() {
+        let root = FakeRoot::new();
+        root.write("sys/class/drm/card0/device/vendor", "0x8086\n");
+        root.write("sys/class/drm/card0/device/device", "0x5917\n");
+        root.write("sys/class/drm/card0-eDP-1/status", "connected\n");
+        root.write("sys/class/drm/card1/device/vendor", "0x1002\n");
+        root.write("sys/class/drm/card1/device/mem_info_vram_total", "8589934592\n");
+        root.write("sys/class/drm/card1/device/driver/module/version", "5.11.5.21.20\n");
+        root.write("sys/class/drm/card2/device/vendor", "0x1002\n");
+        root.write(
+            "proc/driver/nvidia/version",
+            "NVRM version: NVIDIA UNIX x86_64 Kernel Module  470.57.02  Tue Jul 13 16:14:05 UTC 2021\n",
+        );
+        root.write(
+            "proc/driver/nvidia/gpus/0000:01:00.0/information",
+            "Model: \t\t GeForce GTX 1080\nIRQ:   \t\t 130\n",
+        );
+
+        let v = root.detector().detect();
+        let intel = v.get(&ProcType::IntelGraphics).unwrap();
+        assert_eq!(intel.count(), 1);
+        assert_eq!(intel.model, "PCI device 0x5917");
+        assert_eq!(intel.driver_version, "");
+
+        let amd = v.get(&ProcType::AMDGraphics).unwrap();
+        assert_eq!(amd.devices, vec![0, 1]);
+        assert_eq!(amd.memory, 8589934592.0);
+        assert_eq!(amd.driver_version, "5.11.5.21.20");
+
+        let nvidia = v.get(&ProcType::NVIDIAGraphics).unwrap();
+        assert_eq!(nvidia.count(), 1);
+        assert_eq!(nvidia.model, "GeForce GTX 1080");
+        assert_eq!(nvidia.driver_version, "470.57.02");
+    }
+
+    #[test]
+    fn test_opencl_fills_gaps() {
+        let mut v = coproc::Coprocs::default();
+        v.data.insert(
+            ProcType::AMDGraphics,
+            coproc::Coproc {
+                devices: vec![0],
+                driver_version: "5.11.5.21.20".into(),
+                ..Default::default()
+            },
+        );
+        apply_opencl(
+            &mut v,
+            &[
+                OpenClDevice {
+                    vendor_id: 0x1002,
+                    name: "Ellesmere".into(),
+                    memory: 4e9,
+                    driver_version: "3180.7".into(),
+                },
+                OpenClDevice {
+                    vendor_id: 0x10de,
+                    name: "GeForce GTX 1080".into(),
+                    memory: 8e9,
+                    driver_version: "470.57.02".into(),
+                },
+            ],
+        );
+
+        let amd = v.get(&ProcType::AMDGraphics).unwrap();
+        assert_eq!(amd.model, "Ellesmere");
+        assert_eq!(amd.memory, 4e9);
+        assert_eq!(amd.driver_version, "5.11.5.21.20");
+        assert_eq!(v.get(&ProcType::NVIDIAGraphics).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_apply_config() {
+        let mut v = coproc::Coprocs::default();
+        v.data.insert(
+            ProcType::AMDGraphics,
+            coproc::Coproc {
+                devices: vec![0, 1],
+                ..Default::default()
+            },
+        );
+        let mut configured = coproc::Coprocs::default();
+        configured.data.insert(
+            ProcType::MinerASIC,
+            coproc::Coproc {
+                devices: vec![0],
+                ..Default::default()
+            },
+        );
+        let mut ignore = HashMap::new();
+        ignore.insert(ProcType::AMDGraphics, vec![0]);
+
+        let mut a = v.clone();
+        a.apply_config(&configured, &ignore, false);
+        assert_eq!(a.get(&ProcType::AMDGraphics).unwrap().devices, vec![1]);
+        assert_eq!(a.get(&ProcType::MinerASIC).unwrap().count(), 1);
+
+        v.apply_config(&configured, &ignore, true);
+        assert!(v.get(&ProcType::AMDGraphics).is_none());
+        assert!(v.get(&ProcType::MinerASIC).is_some());
+    }
+}
@@ -0,0 +1,733 @@
+extern crate futures;
+extern crate std;
+extern crate uuid;
+
+use cc_config;
+use common;
+use coproc;
+use errors;
+use projects;
+use rr_sim;
+use tasks;
+use throttle;
+
+use self::futures::Future;
+use self::uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use common::{ClockInitializable, ClockSource, ProjAm};
+
+/// Half-life of recent estimated credit used when cc_config.xml does not set one, in days
+pub const DEFAULT_REC_HALF_LIFE_DAYS: f64 = 10.0;
+
+/// A job the scheduler may run
+#[derive(Clone, Debug)]
+pub struct RunnableJob {
+    pub task: Uuid,
+    /// name of the result the task computes
+    pub name: String,
+    pub project_url: String,
+    pub app_name: String,
+    pub report_deadline: common::Time,
+    pub avg_ncpus: f64,
+    /// coprocessor type and number of instances used, if any
+    pub coproc: Option<(coproc::ProcType, f64)>,
+    /// seconds of work left at full speed
+    pub runtime_remaining: f64,
+}
+
+/// Processing capacity available to the scheduler
+#[derive(Clone, Debug, Default)]
+pub struct Resources {
+    pub ncpus: f64,
+    pub coprocs: HashMap<coproc::ProcType, f64>,
+    /// peak FLOPS of a single instance of each resource, CPU included
+    pub flops: HashMap<coproc::ProcType, f64>,
+    /// device numbers of each coprocessor, `0..count` if not listed
+    pub devices: HashMap<coproc::ProcType, Vec<i64>>,
+    pub exclude_gpus: Vec<cc_config::ExcludeGpu>,
+}
+
+impl Resources {
+    pub fn devices_of(&self, rsc: &coproc::ProcType) -> Vec<i64> {
+        match self.devices.get(rsc) {
+            Some(v) => v.clone(),
+            None => (0..self.coprocs.get(rsc).cloned().unwrap_or(0.0) as i64).collect(),
+        }
+    }
+
+    /// Devices of the job's coprocessor type that its project and app may use
+    pub fn usable_devices(&self, job: &RunnableJob, rsc: &coproc::ProcType) -> Vec<i64> {
+        self.devices_of(rsc)
+            .into_iter()
+            .filter(|n| {
+                !self.exclude_gpus
+                    .iter()
+                    .any(|v| v.matches(&job.project_url, Some(&job.app_name), rsc, *n))
+            })
+            .collect()
+    }
+}
+
+/// Jobs chosen to run in the next scheduling period
+#[derive(Clone, Debug, Default)]
+pub struct Schedule {
+    pub run: Vec<Uuid>,
+    /// jobs run ahead of their turn because they would otherwise miss their deadline
+    pub edf: HashSet<Uuid>,
+    /// coprocessor type and devices assigned to each job
+    pub devices: HashMap<Uuid, (coproc::ProcType, Vec<i64>)>,
+}
+
+impl Schedule {
+    /// Device the job's task is told to use, the first of those assigned
+    pub fn gpu_of(&self, id: &Uuid) -> Option<(coproc::ProcType, i64)> {
+        self.devices
+            .get(id)
+            .and_then(|&(rsc, ref v)| v.first().map(|n| (rsc, *n)))
+    }
+}
+
+/// Converts `rec_half_life` from cc_config.xml into seconds.
+pub fn rec_half_life(days: f64) -> f64 {
+    (if days > 0.0 {
+        days
+    } else {
+        DEFAULT_REC_HALF_LIFE_DAYS
+    }) * 86400.0
+}
+
+/// Scheduling priority of each project that may run jobs.
+///
+/// Projects which have recently received less than their resource share of processing get higher priority.
+pub fn project_priorities(projects: &projects::Projects) -> HashMap<String, f64> {
+    let data = projects
+        .data
+        .iter()
+        .filter_map(|p| {
+            let d = p.data.lock().unwrap();
+            if d.suspended_via_gui {
+                None
+            } else {
+                Some((p.master_url(), d.resource_share, d.cpu_ec + d.gpu_ec))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let rs_sum = data.iter().map(|&(_, rs, _)| rs).sum::<f64>();
+    let rec_sum = data.iter().map(|&(_, _, rec)| rec).sum::<f64>();
+
+    data.into_iter()
+        .map(|(url, rs, rec)| {
+            let priority = if rs <= 0.0 || rs_sum <= 0.0 {
+                std::f64::MIN
+            } else if rec_sum <= 0.0 {
+                0.0
+            } else {
+                -(rec / rec_sum) / (rs / rs_sum)
+            };
+            (url, priority)
+        })
+        .collect()
+}
+
+/// Resource shares of projects that may run jobs
+pub fn resource_shares(projects: &projects::Projects) -> HashMap<String, f64> {
+    projects
+        .data
+        .iter()
+        .filter_map(|p| {
+            let d = p.data.lock().unwrap();
+            if d.suspended_via_gui {
+                None
+            } else {
+                Some((p.master_url(), d.resource_share))
+            }
+        })
+        .collect()
+}
+
+/// Decides which tasks run on the host's processors.
+pub struct CpuScheduler {
+    clock_source: Arc<ClockSource>,
+    /// time between rescheduling
+    pub period: common::Duration,
+    /// half-life of recent estimated credit in seconds
+    pub rec_half_life: f64,
+    last_run: Option<common::Time>,
+    last_rec_update: Option<common::Time>,
+    /// jobs scheduled by the last run, used to account for the processing they got since then
+    running: Vec<RunnableJob>,
+    /// jobs passed to the last run; rescheduling happens right away when they change
+    last_jobs: HashSet<Uuid>,
+    /// tasks suspended by the CPU throttler, which count as running
+    pub throttled: throttle::Throttled,
+}
+
+impl ClockInitializable for CpuScheduler {
+    fn new_with_clock(clock_source: Arc<ClockSource>) -> Self {
+        Self {
+            clock_source: clock_source,
+            period: common::Duration::minutes(60),
+            rec_half_life: rec_half_life(0.0),
+            last_run: None,
+            last_rec_update: None,
+            running: Vec::new(),
+            last_jobs: HashSet::new(),
+            throttled: Default::default(),
+        }
+    }
+}
+
+impl CpuScheduler {
+    pub fn is_due(&self) -> bool {
+        match self.last_run {
+            Some(v) => self.clock_source.now() - v >= self.period,
+            None => true,
+        }
+    }
+
+    /// Credits each project with the peak FLOPs its running jobs have used since the last update.
+    pub fn update_rec(&mut self, projects: &projects::Projects, resources: &Resources) {
+        let now = self.clock_source.now();
+        let dt = match self.last_rec_update {
+            Some(v) => (now - v).num_milliseconds() as f64 / 1000.0,
+            None => 0.0,
+        };
+        self.last_rec_update = Some(now);
+        if dt <= 0.0 {
+            return;
+        }
+
+        let flops = |rsc: &coproc::ProcType| resources.flops.get(rsc).cloned().unwrap_or(0.0);
+        for proj in &projects.data {
+            let url = proj.master_url();
+            let (mut cpu_flops, mut gpu_flops) = (0.0, 0.0);
+            for job in self.running.iter().filter(|job| job.project_url == url) {
+                match job.coproc {
+                    Some((ref rsc, n)) => {
+                        gpu_flops += n * flops(rsc);
+                    }
+                    None => {
+                        cpu_flops += job.avg_ncpus * flops(&coproc::ProcType::CPU);
+                    }
+                }
+            }
+
+            proj.data
+                .lock()
+                .unwrap()
+                .update_rec(dt, cpu_flops, gpu_flops, self.rec_half_life);
+        }
+    }
+
+    /// Forces rescheduling on the next call to `run`.
+    pub fn request_reschedule(&mut self) {
+        self.last_run = None;
+    }
+
+    /// Picks jobs to run. Jobs that the round-robin simulation predicts to miss their deadline go first,
+    /// earliest deadline first. The remaining capacity goes to the jobs of projects with the highest priority.
+    pub fn make_schedule(
+        &self,
+        jobs: &[RunnableJob],
+        priorities: &HashMap<String, f64>,
+        misses: &HashSet<Uuid>,
+        resources: &Resources,
+    ) -> Schedule {
+        let jobs = jobs.iter()
+            .filter(|job| priorities.contains_key(&job.project_url))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        let mut edf = jobs.iter()
+            .filter(|job| misses.contains(&job.task))
+            .collect::<Vec<_>>();
+        edf.sort_by(|a, b| {
+            a.report_deadline
+                .cmp(&b.report_deadline)
+                .then(a.task.cmp(&b.task))
+        });
+
+        let mut rest = jobs.iter()
+            .filter(|job| !misses.contains(&job.task))
+            .collect::<Vec<_>>();
+        rest.sort_by(|a, b| {
+            a.report_deadline
+                .cmp(&b.report_deadline)
+                .then(a.task.cmp(&b.task))
+        });
+
+        let mut schedule = Schedule::default();
+        let mut cpus_used = 0.0;
+        // Share of each device taken by scheduled jobs
+        let mut devices_used: HashMap<(coproc::ProcType, i64), f64> = HashMap::new();
+        let mut assigned: HashMap<Uuid, (coproc::ProcType, Vec<i64>)> = HashMap::new();
+
+        {
+            let mut fits = |job: &RunnableJob| -> bool {
+                match job.coproc {
+                    Some((rsc, n)) => {
+                        let free = |used: &HashMap<(coproc::ProcType, i64), f64>, d: &i64, need: f64| {
+                            used.get(&(rsc, *d)).cloned().unwrap_or(0.0) + need <= 1.0 + 1e-9
+                        };
+                        let usable = resources.usable_devices(job, &rsc);
+                        let picked = if n < 1.0 {
+                            // Fractional jobs share a device
+                            usable
+                                .into_iter()
+                                .find(|d| free(&devices_used, d, n))
+                                .map(|d| vec![d])
+                                .unwrap_or_default()
+                        } else {
+                            usable
+                                .into_iter()
+                                .filter(|d| free(&devices_used, d, 1.0))
+                                .take(n.ceil() as usize)
+                                .collect()
+                        };
+                        if picked.is_empty() || (n >= 1.0 && picked.len() < n.ceil() as usize) {
+                            return false;
+                        }
+                        for d in &picked {
+                            *devices_used.entry((rsc, *d)).or_insert(0.0) += n.min(1.0);
+                        }
+                        assigned.insert(job.task, (rsc, picked));
+                    }
+                    None => {
+                        // CPU used by coprocessor jobs is not counted, it would otherwise starve CPU jobs
+                        if cpus_used + job.avg_ncpus > resources.ncpus {
+                            return false;
+                        }
+                        cpus_used += job.avg_ncpus;
+                    }
+                }
+                true
+            };
+
+            // Coprocessor jobs go first so that idle coprocessors are not left waiting behind CPU jobs
+            for pass_coproc in &[true, false] {
+                for job in edf.iter().filter(|job| job.coproc.is_some() == *pass_coproc) {
+                    if fits(job) {
+                        schedule.run.push(job.task);
+                        schedule.edf.insert(job.task);
+                    }
+                }
+
+                let mut candidates = rest.iter()
+                    .filter(|job| job.coproc.is_some() == *pass_coproc)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                let mut priorities = priorities.clone();
+                while !candidates.is_empty() {
+                    // Highest priority wins; among equals the earlier deadline. Sorting is stable so ties keep deadline order.
+                    let i = (0..candidates.len())
+                        .fold(0, |best, i| {
+                            if priorities[&candidates[i].project_url]
+                                > priorities[&candidates[best].project_url]
+                            {
+                                i
+                            } else {
+                                best
+                            }
+                        });
+                    let job = candidates.remove(i);
+                    if fits(job) {
+                        schedule.run.push(job.task);
+                        // Scheduling a job lowers the project's priority so that others get their turn
+                        if resources.ncpus > 0.0 {
+                            *priorities.get_mut(&job.project_url).unwrap() -= job.avg_ncpus / resources.ncpus;
+                        }
+                    }
+                }
+            }
+        }
+
+        schedule.devices = assigned;
+        schedule
+    }
+
+    /// Starts scheduled tasks and preempts running ones that did not make it into the schedule.
+    ///
+    /// Tasks suspended by the throttler are running as far as scheduling goes: they are left
+    /// for the throttler to resume if scheduled, and preempted properly otherwise.
+    pub fn enforce(
+        &self,
+        schedule: &Schedule,
+        tasks: &tasks::TaskServer,
+        leave_apps_in_memory: bool,
+    ) -> errors::Result<()> {
+        // Holding the lock keeps the throttler from switching tasks in the meantime
+        let mut throttled = self.throttled.lock().unwrap();
+        let current = tasks.tasks().wait()?;
+        let run = schedule.run.iter().collect::<HashSet<_>>();
+
+        for (id, status) in &current {
+            let running = status.status == tasks::RunStatus::Running || throttled.contains(id);
+            if running && !run.contains(id) {
+                throttled.remove(id);
+                tasks.preempt_task(id, leave_apps_in_memory).wait()?;
+            }
+        }
+
+        for id in &schedule.run {
+            match current.get(id).map(|v| v.status) {
+                Some(tasks::RunStatus::Running) => {}
+                Some(tasks::RunStatus::Suspended) if throttled.contains(id) => {}
+                Some(_) => {
+                    tasks.assign_gpu(id, schedule.gpu_of(id)).wait()?;
+                    tasks.start_task(id).wait()?;
+                }
+                None => {
+                    bail!(errors::ErrorKind::NoSuchTaskError(*id));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reschedules if the scheduling period has elapsed or jobs have come or gone. Returns the schedule if one was made.
+    pub fn run(
+        &mut self,
+        jobs: &[RunnableJob],
+        projects: &projects::Projects,
+        sim: &rr_sim::RRSimOutput,
+        resources: &Resources,
+        tasks: &tasks::TaskServer,
+        leave_apps_in_memory: bool,
+    ) -> errors::Result<Option<Schedule>> {
+        let ids = jobs.iter().map(|job| job.task).collect::<HashSet<_>>();
+        if !self.is_due() && ids == self.last_jobs {
+            return Ok(None);
+        }
+
+        self.update_rec(projects, resources);
+
+        let priorities = project_priorities(projects);
+        let schedule = self.make_schedule(jobs, &priorities, &sim.deadline_at_risk(), resources);
+        self.enforce(&schedule, tasks, leave_apps_in_memory)?;
+        self.last_run = Some(self.clock_source.now());
+        self.last_jobs = ids;
+        self.running = jobs.iter()
+            .filter(|job| schedule.run.contains(&job.task))
+            .cloned()
+            .collect();
+
+        Ok(Some(schedule))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use app;
+    use test_util::TestClock;
+    use workunit;
+
+    fn clock() -> Arc<TestClock> {
+        Arc::new(TestClock::epoch())
+    }
+
+    fn job(project_url: &str, deadline_secs: i64, runtime_remaining: f64) -> RunnableJob {
+        RunnableJob {
+            task: uuid::Uuid::new(uuid::UuidVersion::Random).unwrap(),
+            name: "job".into(),
+            project_url: project_url.into(),
+            app_name: "app".into(),
+            report_deadline: common::Time::from(std::time::SystemTime::UNIX_EPOCH)
+                + common::Duration::seconds(deadline_secs),
+            avg_ncpus: 1.0,
+            coproc: None,
+            runtime_remaining: runtime_remaining,
+        }
+    }
+
+    fn map(v: &[(&str, f64)]) -> HashMap<String, f64> {
+        v.iter().map(|&(k, v)| (k.to_string(), v)).collect()
+    }
+
+    fn misses(clock: &TestClock, jobs: &[RunnableJob], shares: &HashMap<String, f64>, resources: &Resources) -> HashSet<Uuid> {
+        rr_sim::simulate(&clock.now(), jobs, shares, resources, 0.0).deadline_at_risk()
+    }
+
+    #[test]
+    fn test_priority_order() {
+        let clock = clock();
+        let sched = CpuScheduler::new_with_clock(clock.clone());
+        let a = job("http://a/", 100000, 100.0);
+        let b = job("http://b/", 100000, 100.0);
+        let jobs = [a.clone(), b.clone()];
+        let resources = Resources {
+            ncpus: 1.0,
+            ..Default::default()
+        };
+        let shares = map(&[("http://a/", 100.0), ("http://b/", 100.0)]);
+
+        let v = sched.make_schedule(
+            &jobs,
+            &map(&[("http://a/", -2.0), ("http://b/", -0.5)]),
+            &misses(&clock, &jobs, &shares, &resources),
+            &resources,
+        );
+        assert_eq!(v.run, vec![b.task]);
+        assert!(v.edf.is_empty());
+    }
+
+    #[test]
+    fn test_edf_on_deadline_miss() {
+        let clock = clock();
+        let sched = CpuScheduler::new_with_clock(clock.clone());
+        let a = job("http://a/", 1000, 950.0);
+        let b = job("http://b/", 100000, 100.0);
+        let jobs = [a.clone(), b.clone()];
+        let resources = Resources {
+            ncpus: 1.0,
+            ..Default::default()
+        };
+        let shares = map(&[("http://a/", 100.0), ("http://b/", 100.0)]);
+
+        let v = sched.make_schedule(
+            &jobs,
+            &map(&[("http://a/", -2.0), ("http://b/", -0.5)]),
+            &misses(&clock, &jobs, &shares, &resources),
+            &resources,
+        );
+        assert_eq!(v.run, vec![a.task]);
+        assert!(v.edf.contains(&a.task));
+    }
+
+    #[test]
+    fn test_coprocs_and_ncpus() {
+        let clock = clock();
+        let sched = CpuScheduler::new_with_clock(clock.clone());
+        let mut gpu1 = job("http://a/", 100000, 100.0);
+        gpu1.coproc = Some((coproc::ProcType::NVIDIAGraphics, 1.0));
+        gpu1.avg_ncpus = 0.1;
+        let mut gpu2 = gpu1.clone();
+        gpu2.task = uuid::Uuid::new(uuid::UuidVersion::Random).unwrap();
+        let cpu1 = job("http://a/", 100000, 100.0);
+        let cpu2 = job("http://a/", 100000, 100.0);
+        let resources = Resources {
+            ncpus: 1.0,
+            coprocs: vec![(coproc::ProcType::NVIDIAGraphics, 1.0)]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        };
+
+        let jobs = [cpu1.clone(), cpu2.clone(), gpu1.clone(), gpu2.clone()];
+
+        let v = sched.make_schedule(
+            &jobs,
+            &map(&[("http://a/", 0.0)]),
+            &misses(&clock, &jobs, &map(&[("http://a/", 100.0)]), &resources),
+            &resources,
+        );
+        assert_eq!(v.run.len(), 2);
+        assert!(v.run[0] == gpu1.task || v.run[0] == gpu2.task);
+        assert!(v.run[1] == cpu1.task || v.run[1] == cpu2.task);
+    }
+
+    #[test]
+    fn test_gpu_exclusions() {
+        let clock = clock();
+        let sched = CpuScheduler::new_with_clock(clock.clone());
+        let mut a = job("http://a/", 100000, 100.0);
+        a.coproc = Some((coproc::ProcType::AMDGraphics, 1.0));
+        let mut b = job("http://b/", 100000, 100.0);
+        b.coproc = Some((coproc::ProcType::AMDGraphics, 1.0));
+        let mut b2 = b.clone();
+        b2.task = uuid::Uuid::new(uuid::UuidVersion::Random).unwrap();
+        let resources = Resources {
+            coprocs: vec![(coproc::ProcType::AMDGraphics, 2.0)]
+                .into_iter()
+                .collect(),
+            exclude_gpus: vec![
+                cc_config::ExcludeGpu {
+                    url: "http://a/".into(),
+                    device_num: Some(0),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let jobs = [a.clone(), b.clone(), b2.clone()];
+        let priorities = map(&[("http://a/", 0.0), ("http://b/", -1.0)]);
+        let v = sched.make_schedule(&jobs, &priorities, &HashSet::new(), &resources);
+
+        // Project A may only use device 1, so B gets device 0 and its second job waits
+        assert_eq!(v.run.len(), 2);
+        assert_eq!(v.devices[&a.task].1, vec![1]);
+        assert_eq!(v.devices[&v.run[1]].1, vec![0]);
+        assert!(v.run[1] == b.task || v.run[1] == b2.task);
+    }
+
+    #[test]
+    fn test_enforce_assigns_devices() {
+        let clock = clock();
+        let sched = CpuScheduler::new_with_clock(clock.clone());
+        let server = tasks::MockTaskServer::default();
+        let mut a = job("http://a/", 100000, 100.0);
+        a.coproc = Some((coproc::ProcType::AMDGraphics, 1.0));
+        a.task = server
+            .create_task(
+                &app::AppVersion::default(),
+                &workunit::Workunit::default(),
+                &tasks::InitData::default(),
+                &tasks::TaskFiles::default(),
+            )
+            .wait()
+            .unwrap();
+        let resources = Resources {
+            coprocs: vec![(coproc::ProcType::AMDGraphics, 2.0)]
+                .into_iter()
+                .collect(),
+            exclude_gpus: vec![
+                cc_config::ExcludeGpu {
+                    url: "http://a/".into(),
+                    device_num: Some(0),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        let v = sched.make_schedule(&[a.clone()], &map(&[("http://a/", 0.0)]), &HashSet::new(), &resources);
+        sched.enforce(&v, &server, false).unwrap();
+
+        // The excluded device is never handed to the task
+        assert_eq!(server.gpu(&a.task), Some((coproc::ProcType::AMDGraphics, 1)));
+        assert_eq!(server.tasks().wait().unwrap()[&a.task].status, tasks::RunStatus::Running);
+    }
+
+    #[test]
+    fn test_enforce_with_period() {
+        let clock = clock();
+        let mut sched = CpuScheduler::new_with_clock(clock.clone());
+        let server = tasks::MockTaskServer::new_with_clock(clock.clone());
+        let ids = (0..2)
+            .map(|_| {
+                server
+                    .create_task(
+                        &app::AppVersion::default(),
+                        &workunit::Workunit::default(),
+                        &tasks::InitData::default(),
+                        &tasks::TaskFiles::default(),
+                    )
+                    .wait()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut jobs = ids.iter()
+            .map(|id| {
+                let mut v = job("http://a/", 100000, 100.0);
+                v.task = *id;
+                v
+            })
+            .collect::<Vec<_>>();
+        let resources = Resources {
+            ncpus: 1.0,
+            flops: vec![(coproc::ProcType::CPU, 1e9)].into_iter().collect(),
+            ..Default::default()
+        };
+        let mut projects = projects::Projects::new(Arc::new(::messages::DummyLogger::default()));
+        let proj = projects::Project::new("http://a/".into());
+        proj.data.lock().unwrap().resource_share = 100.0;
+        projects.data.insert(proj);
+        let shares = resource_shares(&projects);
+        let sim = |jobs: &[RunnableJob]| rr_sim::simulate(&clock.now(), jobs, &shares, &resources, 0.0);
+
+        {
+            let v = sched
+                .run(&jobs, &projects, &sim(&jobs), &resources, &server, true)
+                .unwrap()
+                .unwrap();
+            assert_eq!(v.run.len(), 1);
+            let running = v.run[0];
+            assert_eq!(
+                server.tasks().wait().unwrap()[&running].status,
+                tasks::RunStatus::Running
+            );
+
+            // Not due yet
+            assert!(
+                sched
+                    .run(&jobs, &projects, &sim(&jobs), &resources, &server, true)
+                    .unwrap()
+                    .is_none()
+            );
+
+            // No time has passed, so no recent credit has been earned yet
+            assert_eq!(projects.data.iter().next().unwrap().data.lock().unwrap().cpu_ec, 0.0);
+
+            // The other job becomes urgent
+            clock.advance(common::Duration::minutes(61));
+            let other = *ids.iter().find(|id| **id != running).unwrap();
+            for v in jobs.iter_mut() {
+                if v.task == other {
+                    v.report_deadline = clock.now() + common::Duration::seconds(50);
+                }
+            }
+            let v = sched
+                .run(&jobs, &projects, &sim(&jobs), &resources, &server, true)
+                .unwrap()
+                .unwrap();
+            // Running a job for the period earns recent credit
+            assert!(projects.data.iter().next().unwrap().data.lock().unwrap().cpu_ec > 0.0);
+            assert_eq!(v.run, vec![other]);
+            let statuses = server.tasks().wait().unwrap();
+            assert_eq!(statuses[&other].status, tasks::RunStatus::Running);
+            assert_eq!(statuses[&running].status, tasks::RunStatus::Suspended);
+
+            // A job going away reschedules before the period is over
+            jobs.retain(|v| v.task != other);
+            let v = sched
+                .run(&jobs, &projects, &sim(&jobs), &resources, &server, true)
+                .unwrap()
+                .unwrap();
+            assert_eq!(v.run, vec![running]);
+        }
+    }
+
+    #[test]
+    fn test_enforce_throttled() {
+        let sched = CpuScheduler::new_with_clock(clock());
+        let server = tasks::MockTaskServer::default();
+        let ids = (0..2)
+            .map(|_| {
+                let id = server
+                    .create_task(
+                        &app::AppVersion::default(),
+                        &workunit::Workunit::default(),
+                        &tasks::InitData::default(),
+                        &tasks::TaskFiles::default(),
+                    )
+                    .wait()
+                    .unwrap();
+                server.suspend_task(&id).wait().unwrap();
+                id
+            })
+            .collect::<Vec<_>>();
+        sched.throttled.lock().unwrap().extend(ids.iter().cloned());
+
+        let schedule = Schedule {
+            run: vec![ids[0]],
+            ..Default::default()
+        };
+        sched.enforce(&schedule, &server, false).unwrap();
+
+        // The scheduled task is left for the throttler to resume, the other one is
+        // preempted and no longer the throttler's to resume
+        let statuses = server.tasks().wait().unwrap();
+        assert_eq!(statuses[&ids[0]].status, tasks::RunStatus::Suspended);
+        assert_eq!(statuses[&ids[1]].status, tasks::RunStatus::Suspended);
+        assert_eq!(
+            *sched.throttled.lock().unwrap(),
+            vec![ids[0]].into_iter().collect::<HashSet<_>>()
+        );
+    }
+}
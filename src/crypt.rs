@@ -0,0 +1,127 @@
+extern crate crypto;
+extern crate num_bigint;
+extern crate std;
+
+use errors;
+
+use self::crypto::digest::Digest;
+use self::crypto::md5::Md5;
+use self::num_bigint::BigUint;
+
+/// RSA public key as printed by the reference client: the bit count on the first line,
+/// then the hex of the modulus and exponent, each padded to the same length, ended by a `.` line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PublicKey {
+    pub modulus: BigUint,
+    pub exponent: BigUint,
+}
+
+/// Reads hex data up to the terminating `.`, skipping whitespace.
+fn scan_hex(text: &str) -> Option<Vec<u8>> {
+    let digits = text.chars()
+        .take_while(|c| *c != '.')
+        .filter(|c| !c.is_whitespace())
+        .collect::<Vec<_>>();
+    if digits.len() % 2 != 0 {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let hi = pair[0].to_digit(16);
+            let lo = pair[1].to_digit(16);
+            match (hi, lo) {
+                (Some(hi), Some(lo)) => Some((hi * 16 + lo) as u8),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+impl PublicKey {
+    pub fn parse(text: &str) -> errors::Result<PublicKey> {
+        let text = text.trim();
+        let body = match text.find('\n') {
+            Some(pos) if text[..pos].trim().parse::<u32>().is_ok() => &text[pos + 1..],
+            _ => bail!(errors::ErrorKind::InternalError("key has no bit count".into())),
+        };
+        let data = match scan_hex(body) {
+            Some(ref v) if !v.is_empty() && v.len() % 2 == 0 => v.clone(),
+            _ => bail!(errors::ErrorKind::InternalError("key has invalid data".into())),
+        };
+        let (modulus, exponent) = data.split_at(data.len() / 2);
+        Ok(PublicKey {
+            modulus: BigUint::from_bytes_be(modulus),
+            exponent: BigUint::from_bytes_be(exponent),
+        })
+    }
+
+    /// Undoes the PKCS #1 signature padding, returning the signed data.
+    fn decrypt(&self, signature: &[u8]) -> Option<Vec<u8>> {
+        let len = ((self.modulus.bits() + 7) / 8) as usize;
+        let m = BigUint::from_bytes_be(signature).modpow(&self.exponent, &self.modulus);
+        let mut block = m.to_bytes_be();
+        if block.len() > len {
+            return None;
+        }
+        let mut padded = vec![0u8; len - block.len()];
+        padded.append(&mut block);
+
+        if padded.len() < 11 || padded[0] != 0 || padded[1] != 1 {
+            return None;
+        }
+        let end = match padded[2..].iter().position(|b| *b != 0xff) {
+            Some(v) => 2 + v,
+            None => {
+                return None;
+            }
+        };
+        if end < 10 || padded[end] != 0 {
+            return None;
+        }
+        Some(padded[end + 1..].to_vec())
+    }
+}
+
+/// Whether the hex signature is the key owner's signature of the text's MD5 hash.
+pub fn verify_string(text: &str, signature: &str, key: &PublicKey) -> bool {
+    let mut hasher = Md5::new();
+    hasher.input_str(text);
+    let md5 = hasher.result_str();
+
+    match scan_hex(signature).and_then(|v| key.decrypt(&v)) {
+        Some(v) => v == md5.as_bytes(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "512\n\
+        0000000000000000000000000000000000000000000000000000000000000000\n\
+        0000000000000000000000000000000000000000000000000000000000000000\n\
+        7d2be5742569abe235b6d2bdab82b610f5862282b9a1a75aac22f672cbf97c33\n\
+        9a4af34718beb80c25953e352fe1e2db9283de56df4a1a7290c7f4e82761d45b\n\
+        0000000000000000000000000000000000000000000000000000000000000000\n\
+        0000000000000000000000000000000000000000000000000000000000000000\n\
+        0000000000000000000000000000000000000000000000000000000000000000\n\
+        0000000000000000000000000000000000000000000000000000000000010001\n\
+        .\n";
+
+    const SIGNATURE: &str = "41e82f4c696fd68c85cbfab609122cb7d52a8504a2cd2e81478c51e010537025\n\
+        4aca2e232dedacb6bee16998c05283aa3d59254391534348bf3ca8eed19df7a7\n\
+        .\n";
+
+    #[test]
+    fn test_verify_string() {
+        let key = PublicKey::parse(KEY).unwrap();
+        assert_eq!(key.exponent, BigUint::from(65537u32));
+
+        assert!(verify_string("http://example.com/proj/", SIGNATURE, &key));
+        assert!(!verify_string("http://evil.example.com/", SIGNATURE, &key));
+        assert!(!verify_string("http://example.com/proj/", "00\n.\n", &key));
+        assert!(PublicKey::parse("KEY").is_err());
+    }
+}
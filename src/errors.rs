@@ -48,10 +48,34 @@ error_chain! {
             description("action is not allowed by user"),
             display("action is not allowed by user: {}", &t),
         }
+        ResourceLimitExceededError(t: String) {
+            description("resource limit exceeded"),
+            display("task exceeded its {} limit", &t),
+        }
         InternalError(t: String) {
             description("internal error"),
             display("internal error has occurred: {}", &t),
         }
+        HttpTransientError(t: String) {
+            description("transient HTTP error"),
+            display("transient HTTP error: {}", &t),
+        }
+        HttpPermanentError(t: String) {
+            description("permanent HTTP error"),
+            display("permanent HTTP error: {}", &t),
+        }
+        AttachError(code: i64, t: String) {
+            description("attach failed"),
+            display("{}", &t),
+        }
+        AcctMgrError(code: i64, t: String) {
+            description("account manager error"),
+            display("account manager error {}: {}", code, &t),
+        }
+        FileXferError(code: i64, t: String) {
+            description("file transfer failed"),
+            display("{}", &t),
+        }
     }
 }
 
@@ -60,12 +84,21 @@ impl<'a> From<&'a Error> for i64 {
         match v.kind() {
             &ErrorKind::AlreadyAttachedError(_) => -130,
             &ErrorKind::AuthError(_) => -155,
+            &ErrorKind::HttpPermanentError(_) => -183,
+            &ErrorKind::HttpTransientError(_) => -184,
             &ErrorKind::InvalidURLError(_) => -189,
             &ErrorKind::UserPermissionError(_) => -201,
+            &ErrorKind::ResourceLimitExceededError(_) => -221,
+            &ErrorKind::AttachError(code, _) => code,
+            &ErrorKind::AcctMgrError(code, _) => code,
+            &ErrorKind::FileXferError(code, _) => code,
             _ => -1,
         }
     }
 }
 
+/// Reported by poll RPCs while the operation they poll is still running
+pub const ERR_IN_PROGRESS: i64 = -204;
+
 pub type FResult<T> = Box<futures::future::Future<Item = T, Error = Error>>;
 pub type TResult<T> = std::thread::JoinHandle<self::Result<T>>;
@@ -0,0 +1,125 @@
+use app;
+use workunit;
+
+/// Bounds of the duration correction factor
+pub const MIN_DCF: f64 = 0.01;
+pub const MAX_DCF: f64 = 100.0;
+
+/// Correction factors outside this band around the actual ratio trigger an adjustment
+const DCF_TOLERANCE: f64 = 0.1;
+/// Weight of a new sample when the correction factor is brought down
+const DCF_DECAY_WEIGHT: f64 = 0.1;
+
+fn dcf_or_default(dcf: f64) -> f64 {
+    if dcf > 0.0 {
+        dcf
+    } else {
+        1.0
+    }
+}
+
+/// FLOPS the app version achieves, falling back to the host's speed times the CPUs it uses.
+pub fn app_version_flops(avp: &app::AppVersion, p_fpops: f64) -> f64 {
+    if avp.flops > 0.0 {
+        avp.flops
+    } else {
+        p_fpops * avp.avg_ncpus.max(1.0)
+    }
+}
+
+/// Elapsed time the whole job is expected to take, before any progress is known.
+pub fn estimated_runtime(wu: &workunit::Workunit, flops: f64, dcf: f64) -> f64 {
+    if flops > 0.0 {
+        wu.rsc_fpops_est / flops * dcf_or_default(dcf)
+    } else {
+        0.0
+    }
+}
+
+/// Elapsed time the job still needs.
+///
+/// The static estimate derived from the workunit is blended with the one extrapolated from progress so far.
+/// The latter is trusted more as the fraction done grows.
+pub fn estimated_runtime_remaining(
+    wu: &workunit::Workunit,
+    flops: f64,
+    dcf: f64,
+    fraction_done: f64,
+    elapsed_time: f64,
+) -> f64 {
+    let fraction_done = fraction_done.max(0.0).min(1.0);
+    let total = estimated_runtime(wu, flops, dcf);
+    if fraction_done <= 0.0 || elapsed_time <= 0.0 {
+        return (total - elapsed_time).max(0.0);
+    }
+    if fraction_done >= 1.0 {
+        return 0.0;
+    }
+
+    let static_remaining = total * (1.0 - fraction_done);
+    let dynamic_remaining = elapsed_time / fraction_done - elapsed_time;
+    (fraction_done * dynamic_remaining + (1.0 - fraction_done) * static_remaining).max(0.0)
+}
+
+/// Adjusts the project's duration correction factor after a job ran for `elapsed_time`
+/// against an uncorrected estimate of `raw_estimate`.
+///
+/// Underestimates raise the factor right away while overestimates bring it down gradually.
+pub fn update_dcf(dcf: f64, raw_estimate: f64, elapsed_time: f64) -> f64 {
+    let dcf = dcf_or_default(dcf);
+    if raw_estimate <= 0.0 || elapsed_time <= 0.0 {
+        return dcf;
+    }
+
+    let ratio = elapsed_time / raw_estimate;
+    let v = if ratio > dcf * (1.0 + DCF_TOLERANCE) {
+        ratio
+    } else if ratio < dcf * (1.0 - DCF_TOLERANCE) {
+        dcf * (1.0 - DCF_DECAY_WEIGHT) + ratio * DCF_DECAY_WEIGHT
+    } else {
+        dcf
+    };
+    v.max(MIN_DCF).min(MAX_DCF)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wu() -> workunit::Workunit {
+        workunit::Workunit {
+            rsc_fpops_est: 1e12,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_static_estimate() {
+        assert_eq!(estimated_runtime(&wu(), 1e9, 1.0), 1000.0);
+        assert_eq!(estimated_runtime(&wu(), 1e9, 2.0), 2000.0);
+        assert_eq!(estimated_runtime_remaining(&wu(), 1e9, 1.0, 0.0, 100.0), 900.0);
+    }
+
+    #[test]
+    fn test_blending() {
+        // Halfway after 200s while the static estimate says 1000s in total
+        let v = estimated_runtime_remaining(&wu(), 1e9, 1.0, 0.5, 200.0);
+        assert_eq!(v, 0.5 * 200.0 + 0.5 * 500.0);
+
+        // Near the end progress dominates
+        let v = estimated_runtime_remaining(&wu(), 1e9, 1.0, 0.9, 900.0);
+        assert!((v - (0.9 * 100.0 + 0.1 * 100.0)).abs() < 1e-6);
+        assert_eq!(estimated_runtime_remaining(&wu(), 1e9, 1.0, 1.0, 900.0), 0.0);
+    }
+
+    #[test]
+    fn test_dcf() {
+        // Jobs taking twice as long raise the factor at once
+        assert_eq!(update_dcf(1.0, 1000.0, 2000.0), 2.0);
+        // Faster jobs lower it slowly
+        assert!((update_dcf(2.0, 1000.0, 1000.0) - 1.9).abs() < 1e-9);
+        // Close enough estimates leave it alone
+        assert_eq!(update_dcf(1.0, 1000.0, 1050.0), 1.0);
+        assert_eq!(update_dcf(1.0, 1.0, 1e6), MAX_DCF);
+    }
+}
@@ -0,0 +1,187 @@
+extern crate std;
+
+use cc_config;
+use common;
+
+use std::collections::HashSet;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use common::{ClockInitializable, ClockSource, SuspendReason};
+
+/// How often running programs are scanned, in seconds
+pub const POLL_PERIOD: i64 = 5;
+/// Computation resumes this long after the last exclusive app has exited, in seconds
+pub const EXCLUSIVE_APP_WAIT: i64 = 30;
+
+/// Linux truncates process names in `comm` to this many bytes
+const COMM_LEN: usize = 15;
+
+fn read_file(path: &Path) -> Option<Vec<u8>> {
+    let mut v = Vec::new();
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut v))
+        .ok()
+        .map(|_| v)
+}
+
+/// Names of the processes found under the procfs root: their `comm` and the file name of their executable.
+pub fn running_programs(proc_root: &Path) -> HashSet<String> {
+    let mut out = HashSet::new();
+    let entries = match std::fs::read_dir(proc_root) {
+        Ok(v) => v,
+        Err(_) => {
+            return out;
+        }
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let is_pid = entry
+            .file_name()
+            .to_str()
+            .map(|v| !v.is_empty() && v.chars().all(|c| c.is_ascii_digit()))
+            .unwrap_or(false);
+        if !is_pid {
+            continue;
+        }
+
+        let dir = entry.path();
+        if let Some(v) = read_file(&dir.join("comm")) {
+            let s = String::from_utf8_lossy(&v).trim().to_string();
+            if !s.is_empty() {
+                out.insert(s);
+            }
+        }
+        if let Some(v) = read_file(&dir.join("cmdline")) {
+            let argv0 = v.split(|b| *b == 0).next().unwrap_or(&[]);
+            let argv0 = String::from_utf8_lossy(argv0);
+            if let Some(name) = Path::new(&*argv0).file_name() {
+                out.insert(name.to_string_lossy().into_owned());
+            }
+        }
+    }
+    out
+}
+
+/// Whether any of the listed programs is among the running ones.
+pub fn any_running(apps: &[String], running: &HashSet<String>) -> bool {
+    apps.iter().any(|app| {
+        running.contains(app)
+            || (app.len() > COMM_LEN && app.get(..COMM_LEN).map(|v| running.contains(v)).unwrap_or(false))
+    })
+}
+
+/// Suspends computation while programs listed in cc_config.xml run.
+pub struct ExclusiveAppMonitor {
+    clock_source: Arc<ClockSource>,
+    pub proc_root: PathBuf,
+    last_poll: Option<common::Time>,
+    /// last time an app from `exclusive_apps` was seen running
+    last_seen: Option<common::Time>,
+    /// same for `exclusive_gpu_apps`
+    last_seen_gpu: Option<common::Time>,
+}
+
+impl ClockInitializable for ExclusiveAppMonitor {
+    fn new_with_clock(clock_source: Arc<ClockSource>) -> Self {
+        Self {
+            clock_source: clock_source,
+            proc_root: "/proc".into(),
+            last_poll: None,
+            last_seen: None,
+            last_seen_gpu: None,
+        }
+    }
+}
+
+impl ExclusiveAppMonitor {
+    fn reason(&self, last_seen: &Option<common::Time>, now: &common::Time) -> Option<SuspendReason> {
+        match *last_seen {
+            Some(t) if *now - t < common::Duration::seconds(EXCLUSIVE_APP_WAIT) => {
+                Some(SuspendReason::ExclusiveAppRunning)
+            }
+            _ => None,
+        }
+    }
+
+    /// Scans running programs if the poll period has elapsed.
+    ///
+    /// Returns the suspend reasons for all tasks and for GPU tasks. They stay set for
+    /// `EXCLUSIVE_APP_WAIT` seconds after the program was last seen.
+    pub fn poll(&mut self, cc_config: &cc_config::CCConfig) -> (Option<SuspendReason>, Option<SuspendReason>) {
+        let now = self.clock_source.now();
+        let due = match self.last_poll {
+            Some(t) => now - t >= common::Duration::seconds(POLL_PERIOD),
+            None => true,
+        };
+
+        if due && !(cc_config.exclusive_apps.is_empty() && cc_config.exclusive_gpu_apps.is_empty()) {
+            self.last_poll = Some(now);
+            let running = running_programs(&self.proc_root);
+            if any_running(&cc_config.exclusive_apps, &running) {
+                self.last_seen = Some(now);
+            }
+            if any_running(&cc_config.exclusive_gpu_apps, &running) {
+                self.last_seen_gpu = Some(now);
+            }
+        }
+
+        (
+            self.reason(&self.last_seen, &now),
+            self.reason(&self.last_seen_gpu, &now),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use std::io::Write;
+
+    use test_util::TestClock;
+
+    fn write(path: &Path, contents: &[u8]) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::File::create(path)
+            .unwrap()
+            .write_all(contents)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_exclusive_apps() {
+        let mut root = std::env::temp_dir();
+        root.push(format!("exclusive-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        write(&root.join("100/comm"), b"game\n");
+        write(&root.join("200/comm"), b"averylongprogra\n");
+        write(&root.join("200/cmdline"), b"/usr/bin/averylongprogramname\0--flag\0");
+        write(&root.join("self/comm"), b"ignored\n");
+
+        let running = running_programs(&root);
+        assert!(running.contains("game"));
+        assert!(running.contains("averylongprogramname"));
+        assert!(!running.contains("ignored"));
+        assert!(any_running(&["averylongprogramname_x".to_string()], &running));
+
+        let clock = Arc::new(TestClock::epoch());
+        let mut monitor = ExclusiveAppMonitor::new_with_clock(clock.clone());
+        monitor.proc_root = root.clone();
+        let mut cc_config = cc_config::CCConfig::default();
+        cc_config.exclusive_gpu_apps.push("game".into());
+
+        assert_eq!(monitor.poll(&cc_config), (None, Some(SuspendReason::ExclusiveAppRunning)));
+
+        std::fs::remove_dir_all(root.join("100")).unwrap();
+        clock.advance(common::Duration::seconds(POLL_PERIOD));
+        assert_eq!(monitor.poll(&cc_config), (None, Some(SuspendReason::ExclusiveAppRunning)));
+
+        clock.advance(common::Duration::seconds(EXCLUSIVE_APP_WAIT));
+        assert_eq!(monitor.poll(&cc_config), (None, None));
+
+        let _ = std::fs::remove_dir_all(&root);
+    }
+}
@@ -1,12 +1,20 @@
 extern crate std;
 
 extern crate treexml;
+extern crate treexml_util;
 extern crate uuid;
 
 use cert_sig;
 use common;
+use util;
 
-#[derive(Debug, Default)]
+use self::treexml_util::Unmarshaller;
+
+/// Values of `FileInfo::status`. Negative values are error codes.
+pub const FILE_NOT_PRESENT: i64 = 0;
+pub const FILE_PRESENT: i64 = 1;
+
+#[derive(Clone, Debug, Default)]
 pub struct FileInfo {
     pub name: String,
     pub md5_cksum: String,
@@ -28,10 +36,14 @@ pub struct FileInfo {
     pub pers_file_xfer: Option<uuid::Uuid>,
     pub result: Option<uuid::Uuid>,
     pub project: Option<uuid::Uuid>,
+    /// master URL of the project the file belongs to
+    pub project_url: String,
     pub download_urls: Vec<String>,
     pub upload_urls: Vec<String>,
     pub download_gzipped: bool,
     pub xml_signature: Option<String>,
+    /// description of the file exactly as the project signed it, sent back unchanged with uploads
+    pub signed_xml: Option<String>,
     pub file_signature: String,
     pub error_msg: Option<String>,
     pub cert_sigs: Option<cert_sig::CertSigs>,
@@ -40,15 +52,209 @@ pub struct FileInfo {
 impl<'a> From<&'a treexml::Element> for FileInfo {
     fn from(v: &treexml::Element) -> FileInfo {
         let mut obj = FileInfo::default();
+        let mut urls = Vec::new();
+        let mut generated_locally = false;
         for node in &v.children {
             match &*node.name {
+                "name" => {
+                    let _ = obj.name.unmarshal(&node);
+                }
+                "url" => {
+                    let mut url = String::new();
+                    if url.unmarshal(&node).is_ok() {
+                        urls.push(url.trim().to_string());
+                    }
+                }
+                "md5_cksum" => {
+                    let _ = obj.md5_cksum.unmarshal(&node);
+                    obj.md5_cksum = obj.md5_cksum.trim().to_string();
+                }
+                "nbytes" => {
+                    let _ = obj.nbytes.unmarshal(&node);
+                }
+                "max_nbytes" => {
+                    let _ = obj.max_nbytes.unmarshal(&node);
+                }
+                "executable" => {
+                    obj.executable = util::parse_flag(&node);
+                }
+                "sticky" => {
+                    obj.sticky = util::parse_flag(&node);
+                }
+                "generated_locally" | "upload_when_present" => {
+                    generated_locally = util::parse_flag(&node);
+                }
                 "xml_signature" => {
                     obj.xml_signature = node.text.clone();
                 }
                 _ => {}
             }
         }
+        // Output files name the upload handler, input files where to download from
+        if generated_locally {
+            obj.upload_urls = urls;
+        } else {
+            obj.download_urls = urls;
+        }
 
         obj
     }
 }
+
+/// Contents of each `<file_info>` element of the document up to its `<xml_signature>`, as the project signed them.
+pub fn signed_texts(doc: &str) -> Vec<String> {
+    let mut v = Vec::new();
+    let mut rest = doc;
+    while let Some(start) = rest.find("<file_info>") {
+        rest = &rest[start + "<file_info>".len()..];
+        let end = rest.find("</file_info>").unwrap_or(rest.len());
+        let body = &rest[..end];
+        let signed = match body.find("<xml_signature>") {
+            Some(i) => &body[..i],
+            None => body,
+        };
+        let signed = if signed.starts_with("\r\n") {
+            &signed[2..]
+        } else if signed.starts_with('\n') {
+            &signed[1..]
+        } else {
+            signed
+        };
+        v.push(signed.to_string());
+        rest = &rest[end..];
+    }
+    v
+}
+
+/// Reference of a workunit, result or app version to one of its files
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FileRef {
+    pub file_name: String,
+    /// name the application opens the file by, the file name unless given
+    pub open_name: String,
+    pub main_program: bool,
+    /// the file is copied into the slot directory rather than linked
+    pub copy_file: bool,
+}
+
+/// The `<file_ref>` elements of a workunit, result or app version
+pub fn file_refs(root: &treexml::Element) -> Vec<FileRef> {
+    root.children
+        .iter()
+        .filter(|node| node.name == "file_ref")
+        .filter_map(|node| {
+            let mut v = FileRef::default();
+            for child in &node.children {
+                match &*child.name {
+                    "file_name" => {
+                        let _ = v.file_name.unmarshal(&child);
+                    }
+                    "open_name" => {
+                        let _ = v.open_name.unmarshal(&child);
+                    }
+                    "main_program" => {
+                        v.main_program = util::parse_flag(&child);
+                    }
+                    "copy_file" => {
+                        v.copy_file = util::parse_flag(&child);
+                    }
+                    _ => {}
+                }
+            }
+            v.file_name = v.file_name.trim().to_string();
+            v.open_name = v.open_name.trim().to_string();
+            if v.file_name.is_empty() {
+                return None;
+            }
+            if v.open_name.is_empty() {
+                v.open_name = v.file_name.clone();
+            }
+            Some(v)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> treexml::Element {
+        treexml::Document::parse(std::io::Cursor::new(s)).unwrap().root.unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        let v = FileInfo::from(&parse(
+            "<file_info>\n\
+             <name>in_1</name>\n\
+             <url>http://a/download/in_1</url>\n\
+             <url>http://b/download/in_1</url>\n\
+             <md5_cksum>abc</md5_cksum>\n\
+             <nbytes>1024</nbytes>\n\
+             <executable/>\n\
+             </file_info>",
+        ));
+        assert_eq!(v.name, "in_1");
+        assert_eq!(v.download_urls, vec!["http://a/download/in_1", "http://b/download/in_1"]);
+        assert!(v.upload_urls.is_empty());
+        assert_eq!(v.md5_cksum, "abc");
+        assert_eq!(v.nbytes, 1024.0);
+        assert!(v.executable);
+
+        let v = FileInfo::from(&parse(
+            "<file_info>\n\
+             <name>out_1</name>\n\
+             <generated_locally/>\n\
+             <upload_when_present/>\n\
+             <max_nbytes>10000</max_nbytes>\n\
+             <url>http://a/upload</url>\n\
+             <xml_signature>sig</xml_signature>\n\
+             </file_info>",
+        ));
+        assert!(v.download_urls.is_empty());
+        assert_eq!(v.upload_urls, vec!["http://a/upload"]);
+        assert_eq!(v.max_nbytes, 10000.0);
+        assert_eq!(v.xml_signature, Some("sig".into()));
+    }
+
+    #[test]
+    fn test_signed_texts() {
+        let doc = "<scheduler_reply>\n\
+                   <file_info>\n<name>out_1</name>\n<url>http://a/upload</url>\n\
+                   <xml_signature>\nsig\n</xml_signature>\n</file_info>\n\
+                   <file_info><name>in_1</name></file_info>\n\
+                   </scheduler_reply>";
+        assert_eq!(
+            signed_texts(doc),
+            vec!["<name>out_1</name>\n<url>http://a/upload</url>\n", "<name>in_1</name>"]
+        );
+    }
+
+    #[test]
+    fn test_file_refs() {
+        let v = parse(
+            "<workunit>\n\
+             <name>wu</name>\n\
+             <file_ref><file_name>in_1</file_name><open_name>in</open_name></file_ref>\n\
+             <file_ref><file_name> in_2 </file_name><copy_file/></file_ref>\n\
+             <file_ref><open_name>nameless</open_name></file_ref>\n\
+             </workunit>",
+        );
+        assert_eq!(
+            file_refs(&v),
+            vec![
+                FileRef {
+                    file_name: "in_1".into(),
+                    open_name: "in".into(),
+                    ..Default::default()
+                },
+                FileRef {
+                    file_name: "in_2".into(),
+                    open_name: "in_2".into(),
+                    copy_file: true,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+}
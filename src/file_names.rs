@@ -1,8 +1,23 @@
+use util;
+
+pub const ACCT_MGR_LOGIN_FILE_NAME: &str = "acct_mgr_login.xml";
+pub const ACCT_MGR_URL_FILE_NAME: &str = "acct_mgr_url.xml";
 pub const APP_INFO_FILE_NAME: &str = "app_info.xml";
+pub const CONFIG_FILE_NAME: &str = "cc_config.xml";
+pub const GLOBAL_PREFS_FILE_NAME: &str = "global_prefs.xml";
+pub const GLOBAL_PREFS_OVERRIDE_FILE_NAME: &str = "global_prefs_override.xml";
+pub const INIT_DATA_FILE_NAME: &str = "init_data.xml";
+pub const MMAPPED_FILE_NAME: &str = "boinc_mmap_file";
 pub const PROJECT_INIT_FILE_NAME: &str = "project_init.xml";
+pub const STDERR_FILE_NAME: &str = "stderr.txt";
+pub const STDOUT_FILE_NAME: &str = "stdout.txt";
 
 pub const PROJECTS_DIR: &str = "projects";
 
-pub fn account_filename(canonical_master_url: &str) -> String {
-    format!("account_{}", canonical_master_url)
+pub fn account_filename(master_url: &str) -> String {
+    format!("account_{}.xml", util::escape_project_url(master_url))
+}
+
+pub fn is_account_filename(name: &str) -> bool {
+    name.starts_with("account_") && name.ends_with(".xml")
 }
@@ -0,0 +1,259 @@
+extern crate crypto;
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+
+use errors;
+use file_info;
+use http;
+use scheduler;
+
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use self::crypto::digest::Digest;
+use self::crypto::md5::Md5;
+use self::treexml_util::{make_text_element, Unmarshaller};
+
+/// Downloaded data does not match the checksum
+pub const ERR_MD5_FAILED: i64 = -119;
+/// Output file is larger than the project allows
+pub const ERR_FILE_TOO_BIG: i64 = -141;
+/// Output file to upload does not exist
+pub const ERR_NOT_FOUND: i64 = -161;
+
+fn xfer_error(code: i64, msg: String) -> errors::Error {
+    errors::ErrorKind::FileXferError(code, msg).into()
+}
+
+fn md5_hex(data: &[u8]) -> String {
+    let mut hasher = Md5::new();
+    hasher.input(data);
+    hasher.result_str()
+}
+
+/// Transfer of one file between the project's data servers and the project directory
+#[derive(Debug)]
+pub struct FileXfer {
+    pub upload: bool,
+    pub info: file_info::FileInfo,
+    pub path: PathBuf,
+}
+
+impl FileXfer {
+    /// Runs the transfer, trying each of the file's URLs in turn. Transient errors are worth retrying later.
+    pub fn run(&self) -> errors::Result<()> {
+        let urls = if self.upload {
+            &self.info.upload_urls
+        } else {
+            &self.info.download_urls
+        };
+        let mut v = Err(xfer_error(
+            ERR_NOT_FOUND,
+            format!("No URL to transfer {}", self.info.name),
+        ));
+        for url in urls {
+            v = if self.upload {
+                upload(url, &self.info, &self.path)
+            } else {
+                download(url, &self.info, &self.path)
+            };
+            let transient = match v {
+                Err(ref e) => match *e.kind() {
+                    errors::ErrorKind::HttpTransientError(_) => true,
+                    _ => false,
+                },
+                Ok(_) => false,
+            };
+            if !transient {
+                break;
+            }
+        }
+        v
+    }
+}
+
+/// Fetches the file into the path, checking its checksum.
+pub fn download(url: &str, info: &file_info::FileInfo, path: &Path) -> errors::Result<()> {
+    let data = http::get(url)?;
+    if !info.md5_cksum.is_empty() && md5_hex(&data) != info.md5_cksum {
+        bail!(errors::ErrorKind::FileXferError(
+            ERR_MD5_FAILED,
+            format!("MD5 check failed for {}", info.name),
+        ));
+    }
+
+    std::fs::File::create(path)?.write_all(&data)?;
+    if info.executable {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+/// Sends the file to the project's upload handler along with its signed description.
+pub fn upload(url: &str, info: &file_info::FileInfo, path: &Path) -> errors::Result<()> {
+    let mut data = Vec::new();
+    if std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut data))
+        .is_err()
+    {
+        bail!(errors::ErrorKind::FileXferError(
+            ERR_NOT_FOUND,
+            format!("Output file {} is missing", info.name),
+        ));
+    }
+    if info.max_nbytes > 0.0 && data.len() as f64 > info.max_nbytes {
+        bail!(errors::ErrorKind::FileXferError(
+            ERR_FILE_TOO_BIG,
+            format!("{} has {} bytes, at most {} allowed", info.name, data.len(), info.max_nbytes),
+        ));
+    }
+
+    // The upload handler checks the signature against the description exactly as the project sent it
+    let signed_xml = match info.signed_xml {
+        Some(ref v) => v.clone(),
+        None => {
+            let mut v = vec![
+                make_text_element("name", &info.name),
+                make_text_element("max_nbytes", info.max_nbytes),
+            ];
+            for url in &info.upload_urls {
+                v.push(make_text_element("url", url));
+            }
+            v.iter().map(|e| format!("{}\n", e)).collect::<String>()
+        }
+    };
+    let signature = match info.xml_signature {
+        Some(ref sig) => format!("<xml_signature>{}</xml_signature>\n", sig),
+        None => String::new(),
+    };
+
+    // The data follows the request document's <data> tag unescaped
+    let mut body = format!(
+        "<data_server_request>\n\
+         {}\n{}\n{}\n\
+         <file_upload>\n\
+         <file_info>\n{}{}</file_info>\n\
+         {}\n{}\n{}\n\
+         <data>\n",
+        make_text_element("core_client_major_version", scheduler::CORE_CLIENT_MAJOR_VERSION),
+        make_text_element("core_client_minor_version", scheduler::CORE_CLIENT_MINOR_VERSION),
+        make_text_element("core_client_release", scheduler::CORE_CLIENT_RELEASE),
+        signed_xml,
+        signature,
+        make_text_element("nbytes", data.len()),
+        make_text_element("md5_cksum", md5_hex(&data)),
+        make_text_element("offset", 0),
+    ).into_bytes();
+    body.extend_from_slice(&data);
+
+    let reply = http::parse_xml(&http::post(url, &body)?)?;
+    let mut status = 0i64;
+    let mut message = String::new();
+    for node in &reply.children {
+        match &*node.name {
+            "status" => {
+                let _ = status.unmarshal(&node);
+            }
+            "message" => {
+                let _ = message.unmarshal(&node);
+            }
+            _ => {}
+        }
+    }
+    match status {
+        0 => Ok(()),
+        // Positive status asks to try again later
+        n if n > 0 => bail!(errors::ErrorKind::HttpTransientError(format!(
+            "Upload of {} deferred: {}",
+            info.name, message
+        ))),
+        n => bail!(errors::ErrorKind::FileXferError(
+            n,
+            format!("Upload of {} failed: {}", info.name, message),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    fn temp_file(name: &str) -> PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("file-xfer-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn test_download() {
+        let path = temp_file("in_1");
+        let info = file_info::FileInfo {
+            name: "in_1".into(),
+            md5_cksum: md5_hex(b"hello"),
+            executable: true,
+            ..Default::default()
+        };
+
+        let (url, server) = http::serve_once(200, "hello");
+        download(&url, &info, &path).unwrap();
+        server.join().unwrap();
+        let mut data = Vec::new();
+        std::fs::File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello".to_vec());
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o755);
+
+        let (url, server) = http::serve_once(200, "hellO");
+        assert_eq!(i64::from(&download(&url, &info, &path).unwrap_err()), ERR_MD5_FAILED);
+        server.join().unwrap();
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_upload() {
+        let path = temp_file("out_1");
+        let mut info = file_info::FileInfo {
+            name: "out_1".into(),
+            max_nbytes: 100.0,
+            xml_signature: Some("sig".into()),
+            ..Default::default()
+        };
+        assert_eq!(i64::from(&upload("http://a/", &info, &path).unwrap_err()), ERR_NOT_FOUND);
+
+        std::fs::File::create(&path).unwrap().write_all(b"result data").unwrap();
+        let (url, server) = http::serve_once(200, "<data_server_reply><status>0</status></data_server_reply>");
+        info.upload_urls = vec![url.clone()];
+        upload(&url, &info, &path).unwrap();
+        let (_, body) = server.join().unwrap();
+        assert!(body.contains("<name>out_1</name>"));
+        assert!(body.contains("<xml_signature>sig</xml_signature>"));
+        assert!(body.contains("<nbytes>11</nbytes>"));
+        assert!(body.ends_with("<data>\nresult data"));
+
+        // A signed description is sent back unchanged
+        info.signed_xml = Some("<name>out_1</name>\n<max_nbytes>100</max_nbytes>\n".into());
+        let (url, server) = http::serve_once(200, "<data_server_reply><status>0</status></data_server_reply>");
+        upload(&url, &info, &path).unwrap();
+        let (_, body) = server.join().unwrap();
+        assert!(body.contains(
+            "<file_info>\n<name>out_1</name>\n<max_nbytes>100</max_nbytes>\n<xml_signature>sig</xml_signature>\n</file_info>"
+        ));
+
+        let (url, server) = http::serve_once(
+            200,
+            "<data_server_reply><status>-1</status><message>bad</message></data_server_reply>",
+        );
+        assert_eq!(i64::from(&upload(&url, &info, &path).unwrap_err()), -1);
+        server.join().unwrap();
+
+        info.max_nbytes = 5.0;
+        assert_eq!(i64::from(&upload("http://a/", &info, &path).unwrap_err()), ERR_FILE_TOO_BIG);
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -1,8 +1,12 @@
+extern crate libc;
+extern crate std;
 extern crate treexml;
 extern crate treexml_util;
 
 use errors;
 
+use std::os::unix::ffi::OsStrExt;
+
 use self::treexml_util::Unmarshaller;
 
 use self::treexml_util::{make_text_element, make_tree_element};
@@ -74,7 +78,20 @@ impl<'a> From<&'a HostInfo> for treexml::Element {
     }
 }
 
+/// Floating point speed of one CPU assumed until benchmarks are run, in FLOPS
+pub const DEFAULT_P_FPOPS: f64 = 1e9;
+
 impl HostInfo {
+    /// Describes the processors of this host. CPU speed is not measured yet, so it is taken to be `DEFAULT_P_FPOPS`.
+    pub fn detect() -> HostInfo {
+        let ncpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
+        HostInfo {
+            p_ncpus: if ncpus > 0 { ncpus as i64 } else { 1 },
+            p_fpops: DEFAULT_P_FPOPS,
+            ..Default::default()
+        }
+    }
+
     pub fn try_from(root: &treexml::Element) -> errors::Result<HostInfo> {
         let mut v = HostInfo::default();
         for node in &root.children {
@@ -155,3 +172,14 @@ impl HostInfo {
         Ok(v)
     }
 }
+
+/// Total and free bytes of the filesystem holding the path.
+pub fn disk_space(path: &std::path::Path) -> errors::Result<(f64, f64)> {
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).map_err(std::io::Error::from)?;
+    let mut v: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut v) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let block = v.f_frsize as f64;
+    Ok((v.f_blocks as f64 * block, v.f_bavail as f64 * block))
+}
@@ -0,0 +1,170 @@
+extern crate curl;
+extern crate std;
+extern crate treexml;
+
+use errors;
+
+use std::time::Duration;
+
+use self::curl::easy::{Easy, List};
+
+/// Give up on connecting after this many seconds
+const CONNECT_TIMEOUT: u64 = 30;
+/// Give up on transfers that take longer than this many seconds
+const TRANSFER_TIMEOUT: u64 = 300;
+
+const USER_AGENT: &str = concat!("volunode/", env!("CARGO_PKG_VERSION"));
+
+fn transient(e: curl::Error) -> errors::Error {
+    errors::ErrorKind::HttpTransientError(e.to_string()).into()
+}
+
+fn perform(url: &str, post_body: Option<&[u8]>) -> errors::Result<Vec<u8>> {
+    let mut easy = Easy::new();
+    easy.url(url).map_err(transient)?;
+    easy.useragent(USER_AGENT).map_err(transient)?;
+    easy.follow_location(true).map_err(transient)?;
+    easy.connect_timeout(Duration::from_secs(CONNECT_TIMEOUT))
+        .map_err(transient)?;
+    easy.timeout(Duration::from_secs(TRANSFER_TIMEOUT))
+        .map_err(transient)?;
+    if let Some(body) = post_body {
+        let mut headers = List::new();
+        headers.append("Content-Type: text/xml").map_err(transient)?;
+        // Servers may not handle 100-continue
+        headers.append("Expect:").map_err(transient)?;
+        easy.http_headers(headers).map_err(transient)?;
+        easy.post(true).map_err(transient)?;
+        easy.post_fields_copy(body).map_err(transient)?;
+    }
+
+    let mut data = Vec::new();
+    {
+        let mut transfer = easy.transfer();
+        transfer
+            .write_function(|buf| {
+                data.extend_from_slice(buf);
+                Ok(buf.len())
+            })
+            .map_err(transient)?;
+        transfer.perform().map_err(transient)?;
+    }
+
+    match easy.response_code().map_err(transient)? {
+        200...299 => Ok(data),
+        code @ 400...499 => bail!(errors::ErrorKind::HttpPermanentError(format!(
+            "{} returned HTTP status {}",
+            url, code
+        ))),
+        code => bail!(errors::ErrorKind::HttpTransientError(format!(
+            "{} returned HTTP status {}",
+            url, code
+        ))),
+    }
+}
+
+/// Parses the reply as an XML document.
+pub fn parse_xml(data: &[u8]) -> errors::Result<treexml::Element> {
+    match treexml::Document::parse(std::io::Cursor::new(data))?.root {
+        Some(v) => Ok(v),
+        None => bail!(errors::ErrorKind::XMLError("empty reply".into())),
+    }
+}
+
+/// Fetches the document at the URL.
+pub fn get(url: &str) -> errors::Result<Vec<u8>> {
+    perform(url, None)
+}
+
+/// Fetches and parses the XML document at the URL.
+pub fn get_xml(url: &str) -> errors::Result<treexml::Element> {
+    parse_xml(&get(url)?)
+}
+
+/// Posts the body to the URL, returning the reply.
+pub fn post(url: &str, body: &[u8]) -> errors::Result<Vec<u8>> {
+    perform(url, Some(body))
+}
+
+/// Posts the request document to the URL and parses the XML reply.
+pub fn post_xml(url: &str, request: &treexml::Element) -> errors::Result<treexml::Element> {
+    let body = format!("{}", request);
+    parse_xml(&post(url, body.as_bytes())?)
+}
+
+/// Local HTTP server answering a single request with the given status and body.
+/// Returns its base URL and a handle yielding the request line and body it received.
+#[cfg(test)]
+pub fn serve_once(status: u32, body: &str) -> (String, std::thread::JoinHandle<(String, String)>) {
+    use std::io::{BufRead, BufReader, Read, Write};
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    let response = format!(
+        "HTTP/1.0 {} Status\r\nContent-Type: text/xml\r\nContent-Length: {}\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let handle = std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim().to_string();
+            if line.is_empty() {
+                break;
+            }
+            let lower = line.to_lowercase();
+            if lower.starts_with("content-length:") {
+                content_length = lower["content-length:".len()..].trim().parse().unwrap();
+            }
+        }
+        let mut request_body = vec![0; content_length];
+        reader.read_exact(&mut request_body).unwrap();
+
+        reader.get_mut().write_all(response.as_bytes()).unwrap();
+        (
+            request_line.trim().to_string(),
+            String::from_utf8(request_body).unwrap(),
+        )
+    });
+    (url, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate treexml_util;
+
+    use self::treexml_util::make_text_element;
+
+    #[test]
+    fn test_post_xml() {
+        let (url, server) = serve_once(200, "<reply><value>42</value></reply>");
+        let v = post_xml(&format!("{}rpc.php", url), &make_text_element("request", "hello")).unwrap();
+        assert_eq!(v.name, "reply");
+        assert_eq!(v.find_child(|e| e.name == "value").unwrap().text, Some("42".into()));
+
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /rpc.php HTTP/1.1");
+        assert!(body.contains("<request>hello</request>"));
+    }
+
+    #[test]
+    fn test_status_errors() {
+        let (url, server) = serve_once(404, "");
+        assert_eq!(i64::from(&get(&url).unwrap_err()), -183);
+        server.join().unwrap();
+
+        let (url, server) = serve_once(503, "");
+        assert_eq!(i64::from(&get_xml(&url).unwrap_err()), -184);
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,272 @@
+extern crate std;
+
+use common;
+
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use common::{ClockInitializable, ClockSource};
+
+/// Source of the time of the user's last input
+pub trait IdleBackend {
+    fn last_input(&mut self, now: &common::Time) -> Option<common::Time>;
+}
+
+/// Watches keyboard and mouse interrupt counters in /proc/interrupts.
+/// Input is assumed to have happened when the counters change between polls.
+pub struct InterruptsBackend {
+    pub path: PathBuf,
+    last_count: Option<u64>,
+    last_change: Option<common::Time>,
+}
+
+impl Default for InterruptsBackend {
+    fn default() -> Self {
+        Self {
+            path: "/proc/interrupts".into(),
+            last_count: None,
+            last_change: None,
+        }
+    }
+}
+
+/// Total of the interrupt counters on lines belonging to input devices
+pub fn input_interrupts(s: &str) -> u64 {
+    s.lines()
+        .filter(|line| {
+            let line = line.to_lowercase();
+            line.contains("i8042") || line.contains("keyboard") || line.contains("mouse")
+        })
+        .map(|line| {
+            line.split_whitespace()
+                .skip(1)
+                .map(|v| v.parse::<u64>())
+                .take_while(|v| v.is_ok())
+                .map(|v| v.unwrap())
+                .sum::<u64>()
+        })
+        .sum()
+}
+
+impl IdleBackend for InterruptsBackend {
+    fn last_input(&mut self, now: &common::Time) -> Option<common::Time> {
+        let mut s = String::new();
+        if std::fs::File::open(&self.path)
+            .and_then(|mut f| f.read_to_string(&mut s))
+            .is_err()
+        {
+            return None;
+        }
+
+        let count = input_interrupts(&s);
+        if count == 0 {
+            return None;
+        }
+        if let Some(prev) = self.last_count {
+            if prev != count {
+                self.last_change = Some(*now);
+            }
+        }
+        self.last_count = Some(count);
+        self.last_change
+    }
+}
+
+/// Uses the access times of the device nodes under /dev/input.
+pub struct DevInputBackend {
+    pub dir: PathBuf,
+}
+
+impl Default for DevInputBackend {
+    fn default() -> Self {
+        Self {
+            dir: "/dev/input".into(),
+        }
+    }
+}
+
+impl IdleBackend for DevInputBackend {
+    fn last_input(&mut self, _: &common::Time) -> Option<common::Time> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(v) => v,
+            Err(_) => {
+                return None;
+            }
+        };
+
+        entries
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.metadata().ok())
+            .filter(|md| !md.is_dir())
+            .map(|md| md.atime())
+            .max()
+            .map(|secs| common::Time::from(std::time::UNIX_EPOCH + std::time::Duration::from_secs(secs.max(0) as u64)))
+    }
+}
+
+/// loginctl is asked at most once in this many seconds
+const LOGIND_QUERY_INTERVAL: i64 = 10;
+
+/// Asks logind through loginctl when the session went idle.
+/// Only the session of the environment the client runs in is queried.
+pub struct LogindBackend {
+    pub session: String,
+    /// when loginctl was last asked and its answer
+    last_query: Option<(common::Time, Option<common::Time>)>,
+}
+
+impl LogindBackend {
+    pub fn new(session: String) -> Self {
+        Self {
+            session: session,
+            last_query: None,
+        }
+    }
+
+    fn query(&self, now: &common::Time) -> Option<common::Time> {
+        let out = match std::process::Command::new("loginctl")
+            .args(&["show-session", &self.session, "-p", "IdleHint", "-p", "IdleSinceHint"])
+            .output()
+        {
+            Ok(v) => v,
+            Err(_) => {
+                return None;
+            }
+        };
+        let s = String::from_utf8_lossy(&out.stdout);
+
+        let mut idle = false;
+        let mut since = None;
+        for line in s.lines() {
+            let mut kv = line.splitn(2, '=');
+            match (kv.next(), kv.next()) {
+                (Some("IdleHint"), Some(v)) => {
+                    idle = v.trim() == "yes";
+                }
+                (Some("IdleSinceHint"), Some(v)) => {
+                    since = v.trim().parse::<u64>().ok().and_then(|v| if v > 0 { Some(v) } else { None });
+                }
+                _ => {}
+            }
+        }
+
+        if !idle {
+            return Some(*now);
+        }
+        since.map(|usecs| {
+            common::Time::from(
+                std::time::UNIX_EPOCH
+                    + std::time::Duration::new(usecs / 1_000_000, (usecs % 1_000_000) as u32 * 1000),
+            )
+        })
+    }
+}
+
+impl IdleBackend for LogindBackend {
+    fn last_input(&mut self, now: &common::Time) -> Option<common::Time> {
+        if let Some((t, v)) = self.last_query {
+            if t <= *now && *now - t < common::Duration::seconds(LOGIND_QUERY_INTERVAL) {
+                return v;
+            }
+        }
+        let v = self.query(now);
+        self.last_query = Some((*now, v));
+        v
+    }
+}
+
+/// Combines the backends into the time since the user last touched the machine.
+pub struct IdleDetector {
+    clock_source: Arc<ClockSource>,
+    pub backends: Vec<Box<IdleBackend + Send + Sync>>,
+    /// when the detector started, used as the last input if no backend knows better
+    started: common::Time,
+}
+
+impl ClockInitializable for IdleDetector {
+    fn new_with_clock(clock_source: Arc<ClockSource>) -> Self {
+        let mut backends: Vec<Box<IdleBackend + Send + Sync>> = vec![
+            Box::new(InterruptsBackend::default()),
+            Box::new(DevInputBackend::default()),
+        ];
+        if let Ok(session) = std::env::var("XDG_SESSION_ID") {
+            backends.push(Box::new(LogindBackend::new(session)));
+        }
+
+        Self {
+            started: clock_source.now(),
+            clock_source: clock_source,
+            backends: backends,
+        }
+    }
+}
+
+impl IdleDetector {
+    /// Seconds since the last input seen by any backend.
+    pub fn idle_time(&mut self) -> f64 {
+        let now = self.clock_source.now();
+        let last = self.backends
+            .iter_mut()
+            .filter_map(|v| v.last_input(&now))
+            .max()
+            .unwrap_or(self.started);
+        ((now - last).num_milliseconds() as f64 / 1000.0).max(0.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_util::TestClock;
+
+    struct FixedBackend(Option<common::Time>);
+
+    impl IdleBackend for FixedBackend {
+        fn last_input(&mut self, _: &common::Time) -> Option<common::Time> {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_input_interrupts() {
+        let s = "           CPU0       CPU1\n\
+                   0:         22          0   IO-APIC   2-edge      timer\n\
+                   1:        100         20   IO-APIC   1-edge      i8042\n\
+                  12:       3000          4   IO-APIC  12-edge      i8042\n\
+                  16:          5          0   IO-APIC  16-fasteoi   ehci_hcd:usb1\n";
+        assert_eq!(input_interrupts(s), 3124);
+    }
+
+    #[test]
+    fn test_latest_backend_wins() {
+        let epoch: common::Time = std::time::SystemTime::UNIX_EPOCH.into();
+        let clock = Arc::new(TestClock::new(epoch + common::Duration::seconds(1000)));
+        let mut detector = IdleDetector::new_with_clock(clock.clone());
+        detector.backends = vec![
+            Box::new(FixedBackend(Some(epoch + common::Duration::seconds(100)))) as Box<IdleBackend + Send + Sync>,
+            Box::new(FixedBackend(Some(epoch + common::Duration::seconds(400)))),
+            Box::new(FixedBackend(None)),
+        ];
+        assert_eq!(detector.idle_time(), 600.0);
+
+        detector.backends = vec![Box::new(FixedBackend(None)) as Box<IdleBackend + Send + Sync>];
+        clock.advance(common::Duration::seconds(30));
+        assert_eq!(detector.idle_time(), 30.0);
+    }
+
+    #[test]
+    fn test_logind_cached() {
+        let epoch: common::Time = std::time::SystemTime::UNIX_EPOCH.into();
+        let input = epoch + common::Duration::seconds(50);
+        let mut backend = LogindBackend::new(String::new());
+        backend.last_query = Some((epoch + common::Duration::seconds(100), Some(input)));
+
+        // loginctl is not asked again until the interval has passed
+        assert_eq!(backend.last_input(&(epoch + common::Duration::seconds(105))), Some(input));
+        backend.last_input(&(epoch + common::Duration::seconds(110)));
+        assert_eq!(backend.last_query.map(|v| v.0), Some(epoch + common::Duration::seconds(110)));
+    }
+}
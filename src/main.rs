@@ -19,19 +19,37 @@ mod common;
 mod constants;
 mod context;
 mod coproc;
+mod coproc_detect;
+mod cpu_sched;
+mod crypt;
 mod errors;
+mod estimate;
+mod exclusive_apps;
 mod file_info;
 mod file_names;
+mod file_xfer;
 mod hostinfo;
+mod http;
+mod idle;
 mod messages;
+mod power;
+mod prefs;
 mod process;
 mod project_init;
 mod projects;
+mod result;
 mod rpc;
 mod rpc_handlers;
+mod rr_sim;
+mod sandbox;
+mod scheduler;
 mod state;
 mod tasks;
+#[cfg(test)]
+mod test_util;
+mod throttle;
 mod util;
+mod work_fetch;
 mod workunit;
 
 use context::{Context, ContextFuture};
@@ -48,53 +66,86 @@ fn launch_service_threads(
         context
             .compose()
             .bind_rwlock(|r, _| loop {
-                match r.read().unwrap().as_ref() {
-                    Some(state) => state.messages.insert(
-                        None,
-                        common::MessagePriority::Info,
-                        std::time::SystemTime::now().into(),
-                        "Service 1 ping",
-                    ),
+                match r.write().unwrap().as_mut() {
+                    Some(state) => {
+                        state.check_exclusive_apps();
+                        state.check_user_activity();
+                        state.check_power();
+                    }
                     None => {
                         return;
                     }
                 };
-                std::thread::sleep(std::time::Duration::from_millis(2500));
+                std::thread::sleep(std::time::Duration::from_secs(1));
             })
             .run(),
         context
             .compose()
             .bind_rwlock(|r, _| loop {
-                std::thread::sleep(std::time::Duration::from_millis(500));
-                match r.read().unwrap().as_ref() {
-                    Some(state) => state.messages.insert(
-                        None,
-                        common::MessagePriority::Info,
-                        std::time::SystemTime::now().into(),
-                        "Service 2 ping",
-                    ),
+                match r.write().unwrap().as_mut() {
+                    Some(state) => state.poll_cpu_sched(),
                     None => {
                         return;
                     }
                 };
-                std::thread::sleep(std::time::Duration::from_millis(500));
+                std::thread::sleep(std::time::Duration::from_secs(1));
             })
             .run(),
         context
             .compose()
             .bind_rwlock(|r, _| loop {
-                match r.write().unwrap().as_mut() {
-                    Some(ref mut state) => state.messages.insert(
-                        None,
-                        common::MessagePriority::Info,
-                        std::time::SystemTime::now().into(),
-                        "Mutating service ping",
-                    ),
+                if r.read().unwrap().is_none() {
+                    return;
+                }
+                state::contact_scheduler(r);
+                std::thread::sleep(std::time::Duration::from_secs(10));
+            })
+            .run(),
+        context
+            .compose()
+            .bind_rwlock(|r, _| loop {
+                if r.read().unwrap().is_none() {
+                    return;
+                }
+                state::transfer_files(r);
+                std::thread::sleep(std::time::Duration::from_secs(10));
+            })
+            .run(),
+        context
+            .compose()
+            .bind_rwlock(|r, _| loop {
+                let due = match r.read().unwrap().as_ref() {
+                    Some(state) => state.acct_mgr_rpc_due(),
                     None => {
                         return;
                     }
                 };
-                std::thread::sleep(std::time::Duration::from_millis(2000));
+                if due {
+                    state::sync_acct_mgr(r);
+                }
+                std::thread::sleep(std::time::Duration::from_secs(60));
+            })
+            .run(),
+        context
+            .compose()
+            .bind_rwlock(|r, _| loop {
+                if r.read().unwrap().is_none() {
+                    return;
+                }
+                state::update_disk_usage(r);
+                std::thread::sleep(std::time::Duration::from_secs(60));
+            })
+            .run(),
+        context
+            .compose()
+            .bind_rwlock(|r, _| loop {
+                match r.read().unwrap().as_ref() {
+                    Some(state) => state.save_state(),
+                    None => {
+                        return;
+                    }
+                };
+                std::thread::sleep(std::time::Duration::from_secs(60));
             })
             .run(),
     ]
@@ -131,9 +182,31 @@ struct Daemon {
 
 impl Daemon {
     pub fn run(rpc_enable: RPCEnabled) -> Self {
-        let context = Arc::new(context::Context::new(state::ClientState::new(Arc::new(
-            messages::StandardLogger::default(),
-        ))));
+        let messages: messages::SafeLogger = Arc::new(messages::StandardLogger::default());
+        let cc_config = state::read_cc_config(&messages);
+        let host_info = hostinfo::HostInfo::detect();
+        let tasks = Arc::new(tasks::RealTaskServer::new(
+            std::path::PathBuf::from("."),
+            tasks::SpawnSettings::new(&cc_config, &host_info),
+        ));
+
+        let mut state = state::ClientState::new(messages, tasks);
+        state.cc_config = cc_config;
+        state.host_info = host_info;
+        state.load_global_prefs();
+        state.load_projects();
+        state.load_state();
+        state.detect_coprocs(&coproc_detect::Detector::default());
+        state.load_acct_mgr_info();
+        state.load_project_init();
+        state.update_disk_usage();
+        let context = Arc::new(context::Context::new(state));
+
+        let auto_attach = context
+            .compose()
+            .bind_rwlock(|lock, _| state::attach_from_project_init(lock))
+            .assemble();
+        std::thread::spawn(move || auto_attach());
 
         let srv = match rpc_enable {
             RPCEnabled::Yes(settings) => Some(rpc::RPCServer::run(
@@ -145,7 +218,7 @@ impl Daemon {
         };
 
         Self {
-            service_threads: Default::default(), //launch_service_threads(&*context),
+            service_threads: launch_service_threads(&*context),
             context: context,
             rpc_server: srv,
         }
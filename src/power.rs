@@ -0,0 +1,214 @@
+extern crate std;
+
+use prefs;
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use common::SuspendReason;
+
+/// What sysfs tells about the power supply
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PowerStatus {
+    /// unknown on machines without a battery
+    pub on_ac: Option<bool>,
+    /// lowest charge of the batteries in percent
+    pub charge_pct: Option<f64>,
+    /// temperature of the hottest battery in degrees Celsius, if the batteries report it
+    pub battery_temperature: Option<f64>,
+}
+
+impl PowerStatus {
+    /// Suspend reason for computation given the user's preferences
+    pub fn suspend_reason(&self, prefs: &prefs::GlobalPrefs) -> Option<SuspendReason> {
+        if let (Some(t), true) = (self.battery_temperature, prefs.battery_max_temperature > 0.0) {
+            if t > prefs.battery_max_temperature {
+                return Some(SuspendReason::BatteryOverheated);
+            }
+        }
+
+        match self.on_ac {
+            Some(false) if !prefs.run_on_batteries => Some(SuspendReason::Batteries),
+            Some(true) => match self.charge_pct {
+                Some(v) if v < prefs.battery_charge_min_pct => Some(SuspendReason::BatteryCharging),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+fn read_string(path: &Path) -> Option<String> {
+    let mut s = String::new();
+    std::fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .ok()
+        .map(|_| s.trim().to_string())
+}
+
+fn subdirs(path: &Path) -> Vec<PathBuf> {
+    let mut v = std::fs::read_dir(path)
+        .map(|entries| {
+            entries
+                .filter_map(|e| e.ok())
+                .map(|e| e.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    v.sort();
+    v
+}
+
+/// Reads the power supply state from sysfs.
+#[derive(Clone, Debug)]
+pub struct PowerMonitor {
+    pub sysfs_root: PathBuf,
+}
+
+impl Default for PowerMonitor {
+    fn default() -> Self {
+        Self {
+            sysfs_root: "/sys".into(),
+        }
+    }
+}
+
+impl PowerMonitor {
+    pub fn read(&self) -> PowerStatus {
+        let mut v = PowerStatus::default();
+
+        let mut mains_online = None;
+        let mut discharging = false;
+        for dir in subdirs(&self.sysfs_root.join("class/power_supply")) {
+            match read_string(&dir.join("type")).as_ref().map(|s| s.as_str()) {
+                Some("Mains") | Some("USB") => {
+                    let online = read_string(&dir.join("online")).map(|s| s == "1").unwrap_or(false);
+                    mains_online = Some(mains_online.unwrap_or(false) || online);
+                }
+                Some("Battery") => {
+                    if read_string(&dir.join("present")).map(|s| s == "0").unwrap_or(false) {
+                        continue;
+                    }
+                    if let Some(pct) = read_string(&dir.join("capacity")).and_then(|s| s.parse::<f64>().ok()) {
+                        v.charge_pct = Some(v.charge_pct.map(|v| v.min(pct)).unwrap_or(pct));
+                    }
+                    if read_string(&dir.join("status")).map(|s| s == "Discharging").unwrap_or(false) {
+                        discharging = true;
+                    }
+                    // Reported in tenths of a degree
+                    if let Some(t) = read_string(&dir.join("temp")).and_then(|s| s.parse::<f64>().ok()) {
+                        let t = t / 10.0;
+                        v.battery_temperature = Some(v.battery_temperature.map(|v| v.max(t)).unwrap_or(t));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        v.on_ac = match (mains_online, v.charge_pct.is_some()) {
+            (_, false) => None,
+            (Some(online), true) => Some(online && !discharging),
+            (None, true) => Some(!discharging),
+        };
+
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use std::io::Write;
+
+    struct FakeRoot(PathBuf);
+
+    impl Drop for FakeRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl FakeRoot {
+        fn new() -> Self {
+            let mut v = std::env::temp_dir();
+            v.push(format!("power-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+            std::fs::create_dir_all(&v).unwrap();
+            FakeRoot(v)
+        }
+
+        fn write(&self, path: &str, contents: &str) {
+            let path = self.0.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::File::create(path)
+                .unwrap()
+                .write_all(contents.as_bytes())
+                .unwrap();
+        }
+
+        fn monitor(&self) -> PowerMonitor {
+            PowerMonitor {
+                sysfs_root: self.0.clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn test_desktop() {
+        let root = FakeRoot::new();
+        root.write("class/power_supply/AC/type", "Mains\n");
+        root.write("class/power_supply/AC/online", "1\n");
+        root.write("class/thermal/thermal_zone0/temp", "45000\n");
+        root.write("class/thermal/thermal_zone1/temp", "61500\n");
+        root.write("class/thermal/cooling_device0/cur_state", "0\n");
+
+        let v = root.monitor().read();
+        assert_eq!(
+            v,
+            PowerStatus {
+                on_ac: None,
+                charge_pct: None,
+                battery_temperature: None,
+            }
+        );
+
+        // CPU thermal zones are not the battery
+        let mut prefs = prefs::GlobalPrefs::default();
+        prefs.battery_max_temperature = 40.0;
+        assert_eq!(v.suspend_reason(&prefs), None);
+    }
+
+    #[test]
+    fn test_laptop() {
+        let root = FakeRoot::new();
+        root.write("class/power_supply/AC/type", "Mains\n");
+        root.write("class/power_supply/AC/online", "0\n");
+        root.write("class/power_supply/BAT0/type", "Battery\n");
+        root.write("class/power_supply/BAT0/capacity", "80\n");
+        root.write("class/power_supply/BAT0/status", "Discharging\n");
+
+        let mut prefs = prefs::GlobalPrefs::default();
+        let v = root.monitor().read();
+        assert_eq!(v.on_ac, Some(false));
+        assert_eq!(v.charge_pct, Some(80.0));
+        assert_eq!(v.suspend_reason(&prefs), Some(SuspendReason::Batteries));
+
+        prefs.run_on_batteries = true;
+        assert_eq!(v.suspend_reason(&prefs), None);
+
+        root.write("class/power_supply/AC/online", "1\n");
+        root.write("class/power_supply/BAT0/status", "Charging\n");
+        prefs.battery_charge_min_pct = 90.0;
+        let v = root.monitor().read();
+        assert_eq!(v.on_ac, Some(true));
+        assert_eq!(v.suspend_reason(&prefs), Some(SuspendReason::BatteryCharging));
+
+        root.write("class/power_supply/BAT0/temp", "465\n");
+        prefs.battery_max_temperature = 45.0;
+        let v = root.monitor().read();
+        assert_eq!(v.battery_temperature, Some(46.5));
+        assert_eq!(v.suspend_reason(&prefs), Some(SuspendReason::BatteryOverheated));
+    }
+}
@@ -0,0 +1,251 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+
+use errors;
+use file_names;
+
+use std::path::Path;
+
+use common::SuspendReason;
+
+use self::treexml_util::Unmarshaller;
+
+/// Computing preferences shared by all projects, as found in global_prefs.xml
+#[derive(Clone, Debug)]
+pub struct GlobalPrefs {
+    /// keep suspended tasks in memory instead of quitting them
+    pub leave_apps_in_memory: bool,
+    /// how often to switch between tasks
+    pub cpu_scheduling_period_minutes: f64,
+    /// keep at least this much work on hand
+    pub work_buf_min_days: f64,
+    /// and up to this much more
+    pub work_buf_additional_days: f64,
+    /// compute while the user is at the machine
+    pub run_if_user_active: bool,
+    pub run_gpu_if_user_active: bool,
+    /// minutes without input after which the user is considered away
+    pub idle_time_to_run: f64,
+    /// suspend after this many minutes without input, zero to never suspend
+    pub suspend_if_no_recent_input: f64,
+    pub run_on_batteries: bool,
+    /// while on AC, wait for the battery to charge to this percentage
+    pub battery_charge_min_pct: f64,
+    /// suspend when the battery is hotter than this many degrees Celsius, zero for no limit
+    pub battery_max_temperature: f64,
+    /// percentage of time tasks may run, enforced by suspending them in between
+    pub cpu_usage_limit: f64,
+    /// use at most this many gigabytes of disk, zero for no limit
+    pub disk_max_used_gb: f64,
+    /// use at most this percentage of the disk, zero for no limit
+    pub disk_max_used_pct: f64,
+    /// leave at least this many gigabytes of disk free
+    pub disk_min_free_gb: f64,
+}
+
+impl Default for GlobalPrefs {
+    fn default() -> Self {
+        Self {
+            leave_apps_in_memory: false,
+            cpu_scheduling_period_minutes: 60.0,
+            work_buf_min_days: 0.1,
+            work_buf_additional_days: 0.5,
+            run_if_user_active: true,
+            run_gpu_if_user_active: false,
+            idle_time_to_run: 3.0,
+            suspend_if_no_recent_input: 0.0,
+            run_on_batteries: false,
+            battery_charge_min_pct: 0.0,
+            battery_max_temperature: 0.0,
+            cpu_usage_limit: 100.0,
+            disk_max_used_gb: 100.0,
+            disk_max_used_pct: 90.0,
+            disk_min_free_gb: 0.1,
+        }
+    }
+}
+
+impl GlobalPrefs {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<GlobalPrefs> {
+        let mut v = GlobalPrefs::default();
+        v.parse(root);
+        Ok(v)
+    }
+
+    /// Reads global_prefs.xml from the directory and applies global_prefs_override.xml on top.
+    /// Preferences found in neither file keep their defaults.
+    pub fn read(dir: &Path) -> errors::Result<GlobalPrefs> {
+        let mut v = GlobalPrefs::default();
+        for name in &[
+            file_names::GLOBAL_PREFS_FILE_NAME,
+            file_names::GLOBAL_PREFS_OVERRIDE_FILE_NAME,
+        ] {
+            let path = dir.join(name);
+            if !path.exists() {
+                continue;
+            }
+            if let Some(root) = treexml::Document::parse(std::fs::File::open(path)?)?.root {
+                v.parse(&root);
+            }
+        }
+        Ok(v)
+    }
+
+    /// Takes over the preferences present in the element, leaving the others alone.
+    fn parse(&mut self, root: &treexml::Element) {
+        for node in &root.children {
+            match &*node.name {
+                "leave_apps_in_memory" => {
+                    let _ = self.leave_apps_in_memory.unmarshal(&node);
+                }
+                "cpu_scheduling_period_minutes" => {
+                    let _ = self.cpu_scheduling_period_minutes.unmarshal(&node);
+                }
+                "work_buf_min_days" => {
+                    let _ = self.work_buf_min_days.unmarshal(&node);
+                }
+                "work_buf_additional_days" => {
+                    let _ = self.work_buf_additional_days.unmarshal(&node);
+                }
+                "run_if_user_active" => {
+                    let _ = self.run_if_user_active.unmarshal(&node);
+                }
+                "run_gpu_if_user_active" => {
+                    let _ = self.run_gpu_if_user_active.unmarshal(&node);
+                }
+                "idle_time_to_run" => {
+                    let _ = self.idle_time_to_run.unmarshal(&node);
+                }
+                "suspend_if_no_recent_input" => {
+                    let _ = self.suspend_if_no_recent_input.unmarshal(&node);
+                }
+                "run_on_batteries" => {
+                    let _ = self.run_on_batteries.unmarshal(&node);
+                }
+                "battery_charge_min_pct" => {
+                    let _ = self.battery_charge_min_pct.unmarshal(&node);
+                }
+                "battery_max_temperature" => {
+                    let _ = self.battery_max_temperature.unmarshal(&node);
+                }
+                "cpu_usage_limit" => {
+                    let _ = self.cpu_usage_limit.unmarshal(&node);
+                }
+                "disk_max_used_gb" => {
+                    let _ = self.disk_max_used_gb.unmarshal(&node);
+                }
+                "disk_max_used_pct" => {
+                    let _ = self.disk_max_used_pct.unmarshal(&node);
+                }
+                "disk_min_free_gb" => {
+                    let _ = self.disk_min_free_gb.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Suspend reasons for all tasks and for GPU tasks given the seconds since the last user input.
+    pub fn activity_suspend_reasons(&self, idle_time: f64) -> (Option<SuspendReason>, Option<SuspendReason>) {
+        let user_active = idle_time < self.idle_time_to_run * 60.0;
+        let all = if !self.run_if_user_active && user_active {
+            Some(SuspendReason::UserActive)
+        } else if self.suspend_if_no_recent_input > 0.0 && idle_time > self.suspend_if_no_recent_input * 60.0 {
+            Some(SuspendReason::NoRecentInput)
+        } else {
+            None
+        };
+        let gpu = if !self.run_gpu_if_user_active && user_active {
+            Some(SuspendReason::UserActive)
+        } else {
+            None
+        };
+        (all, gpu)
+    }
+
+    /// Bytes of disk the client may use given the disk's total and free bytes and the bytes it already uses.
+    pub fn allowed_disk_usage(&self, total: f64, free: f64, used: f64) -> f64 {
+        let mut v = used + free - self.disk_min_free_gb * 1e9;
+        if self.disk_max_used_gb > 0.0 {
+            v = v.min(self.disk_max_used_gb * 1e9);
+        }
+        if self.disk_max_used_pct > 0.0 {
+            v = v.min(total * self.disk_max_used_pct / 100.0);
+        }
+        v.max(0.0)
+    }
+
+    /// Length of the work buffer in seconds
+    pub fn work_buf_total(&self) -> f64 {
+        (self.work_buf_min_days + self.work_buf_additional_days) * 86400.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use std::io::Write;
+
+    #[test]
+    fn test_activity_suspend_reasons() {
+        let mut v = GlobalPrefs::default();
+        assert_eq!(v.activity_suspend_reasons(10.0), (None, Some(SuspendReason::UserActive)));
+        assert_eq!(v.activity_suspend_reasons(600.0), (None, None));
+
+        v.run_if_user_active = false;
+        v.suspend_if_no_recent_input = 60.0;
+        assert_eq!(
+            v.activity_suspend_reasons(10.0),
+            (Some(SuspendReason::UserActive), Some(SuspendReason::UserActive))
+        );
+        assert_eq!(v.activity_suspend_reasons(7200.0), (Some(SuspendReason::NoRecentInput), None));
+    }
+
+    #[test]
+    fn test_allowed_disk_usage() {
+        let mut v = GlobalPrefs::default();
+        v.disk_max_used_gb = 10.0;
+        v.disk_max_used_pct = 50.0;
+        v.disk_min_free_gb = 1.0;
+        // Limited by the gigabyte cap
+        assert_eq!(v.allowed_disk_usage(100e9, 80e9, 2e9), 10e9);
+        // Limited by the percentage
+        assert_eq!(v.allowed_disk_usage(10e9, 8e9, 1e9), 5e9);
+        // Limited by the free space to keep
+        assert_eq!(v.allowed_disk_usage(100e9, 2e9, 1e9), 2e9);
+        assert_eq!(v.allowed_disk_usage(100e9, 0.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_read() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("prefs-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert_eq!(GlobalPrefs::read(&dir).unwrap().cpu_usage_limit, 100.0);
+
+        std::fs::File::create(dir.join(file_names::GLOBAL_PREFS_FILE_NAME))
+            .unwrap()
+            .write_all(
+                b"<global_preferences>\n\
+                  <cpu_usage_limit>50</cpu_usage_limit>\n\
+                  <idle_time_to_run>10</idle_time_to_run>\n\
+                  </global_preferences>",
+            )
+            .unwrap();
+        std::fs::File::create(dir.join(file_names::GLOBAL_PREFS_OVERRIDE_FILE_NAME))
+            .unwrap()
+            .write_all(b"<global_preferences><cpu_usage_limit>75</cpu_usage_limit></global_preferences>")
+            .unwrap();
+
+        let v = GlobalPrefs::read(&dir).unwrap();
+        assert_eq!(v.cpu_usage_limit, 75.0);
+        assert_eq!(v.idle_time_to_run, 10.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,57 +1,153 @@
-extern crate chan;
+extern crate futures;
+extern crate libc;
 extern crate std;
 extern crate uuid;
 
 use self::std::io::prelude::*;
-use self::std::process::{Command, Stdio};
-use self::std::sync::{Arc, Mutex, RwLock};
+use self::std::os::unix::process::CommandExt;
+use self::std::process::{Command, ExitStatus, Stdio};
+use self::std::sync::{mpsc, Arc, Mutex};
 use self::std::sync::atomic::{AtomicBool, Ordering};
 use self::std::io::BufReader;
+use self::std::time::{Duration, Instant};
+use self::futures::Future;
+use self::futures::sync::oneshot;
 
-use app::*;
-use errors::*;
-use workunit::*;
+use errors;
 
 pub type ProcessOutputCB = Arc<Fn(String) + Send + Sync + 'static>;
 
+/// How often the supervisor checks on the child and on shutdown requests
+const POLL_PERIOD_MS: u64 = 50;
+/// Events kept for the reader. Further events are dropped until it catches up.
+const EVENTS_CAPACITY: usize = 64;
+
 pub trait Process {
-    fn push(&self, String);
+    fn push(&self, String) -> errors::Result<()>;
     fn set_output_cb(&self, Option<ProcessOutputCB>);
     fn get_output_cb(&self) -> Option<ProcessOutputCB>;
 }
 
+/// Delay between restarts. It doubles after each consecutive restart up to `max`.
+/// A run lasting at least `max` starts the sequence over.
+#[derive(Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Backoff {
+    fn delay(&self, attempt: u32) -> Duration {
+        match self.initial.checked_mul(2u32.pow(std::cmp::min(attempt, 31))) {
+            Some(v) => std::cmp::min(v, self.max),
+            None => self.max,
+        }
+    }
+
+    /// Attempt number for the next restart, given how long the last run lasted.
+    fn next_attempt(&self, attempt: u32, ran_for: Duration) -> u32 {
+        if ran_for >= self.max {
+            0
+        } else {
+            attempt.saturating_add(1)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure(Backoff),
+    Always(Backoff),
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::Never
+    }
+}
+
+impl RestartPolicy {
+    fn backoff(&self, status: &Option<ExitStatus>) -> Option<Backoff> {
+        match *self {
+            RestartPolicy::Never => None,
+            RestartPolicy::OnFailure(v) => match *status {
+                Some(ref s) if s.success() => None,
+                _ => Some(v),
+            },
+            RestartPolicy::Always(v) => Some(v),
+        }
+    }
+}
+
+/// Describes the program to run and how to supervise it
+#[derive(Clone, Debug)]
+pub struct ProcessSpec {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: Vec<(String, String)>,
+    pub restart: RestartPolicy,
+    /// time allowed between SIGTERM and SIGKILL on shutdown
+    pub term_timeout: Duration,
+}
+
+impl ProcessSpec {
+    pub fn new<S: ToString>(program: S) -> Self {
+        Self {
+            program: program.to_string(),
+            args: Default::default(),
+            env: Default::default(),
+            restart: Default::default(),
+            term_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Reported each time the child process ends
+#[derive(Clone, Debug)]
+pub enum ProcessEvent {
+    Exited(ExitStatus),
+    SpawnFailed(String),
+}
+
 pub struct SystemProcess {
-    dropping: Arc<AtomicBool>,
+    shutdown: Arc<AtomicBool>,
 
-    process_manager: Option<std::thread::JoinHandle<()>>,
+    supervisor: Option<std::thread::JoinHandle<()>>,
     output_cb: Arc<Mutex<Option<ProcessOutputCB>>>,
-    input: Arc<RwLock<Option<std::process::ChildStdin>>>,
+    input: Arc<Mutex<Option<std::process::ChildStdin>>>,
+    events: mpsc::Receiver<ProcessEvent>,
+    finished: Option<oneshot::Receiver<Option<ExitStatus>>>,
 }
 
 impl Drop for SystemProcess {
     fn drop(&mut self) {
-        self.dropping.store(true, Ordering::Relaxed);
-        self.process_manager.take().unwrap().join();
+        self.shutdown.store(true, Ordering::Relaxed);
+        if let Some(v) = self.supervisor.take() {
+            let _ = v.join();
+        }
     }
 }
 
 impl Process for SystemProcess {
-    fn push(&self, buf: String) {
-        let input = Arc::clone(&self.input);
-        let dropping = Arc::clone(&self.dropping);
-        std::thread::spawn(move || loop {
-            match input.write().unwrap().as_mut() {
-                Some(s) => {
-                    s.write_all(buf.as_bytes());
-                }
-                None => {
-                    if dropping.load(Ordering::Relaxed) {
-                        return;
-                    }
-                    std::thread::sleep(std::time::Duration::from_millis(300));
-                }
+    fn push(&self, buf: String) -> errors::Result<()> {
+        match self.input.lock().unwrap().as_mut() {
+            Some(s) => {
+                s.write_all(buf.as_bytes())?;
+                s.flush()?;
+                Ok(())
             }
-        });
+            None => Err(errors::ErrorKind::InternalError("Process is not running".into()).into()),
+        }
     }
 
     fn set_output_cb(&self, mut output_cb: Option<ProcessOutputCB>) {
@@ -64,78 +160,292 @@ impl Process for SystemProcess {
     }
 }
 
-impl SystemProcess {
-    pub fn new<S1, S2>(procname: S1, args: S2) -> Self
-    where
-        S1: ToString,
-        S2: ToString,
-    {
-        let dropping = Arc::new(AtomicBool::new(false));
-        let output_cb = Arc::new(Mutex::new(None));
-        let input = Arc::new(RwLock::new(None));
-        let process_manager =
-            std::thread::spawn({
-                let procname = procname.to_string();
-                let args = args.to_string();
-                let dropping = Arc::clone(&dropping);
-                let input = Arc::clone(&input);
-                let output_cb = Arc::clone(&output_cb);
-                move || {
-                    let mut process = None;
-                    let mut it: Option<
-                        std::io::Lines<BufReader<std::process::ChildStdout>>,
-                    > = None;
-
-                    loop {
-                        if dropping.load(Ordering::Relaxed) {
-                            break;
-                        }
-                        match process {
-                            None => {
-                                let mut p = Command::new(&procname)
-                                    .arg(&args)
-                                    .stdin(Stdio::piped())
-                                    .stdout(Stdio::inherit())
-                                    .spawn()
-                                    .unwrap();
-                                let in_s = p.stdin.take();
-                                it = Some(BufReader::new(p.stdout.take().unwrap()).lines());
-                                process = Some(p);
-                                *input.write().unwrap() = in_s;
-                            }
-                            Some(_) => for line in it.take().unwrap() {
-                                match line {
-                                    Ok(v) => {
-                                        {
-                                            let o = output_cb.lock().unwrap();
-                                            let fopt: Option<&ProcessOutputCB> = o.as_ref();
-                                            if let Some(f) = fopt {
-                                                f(v);
-                                            }
-                                        }
-                                        std::thread::sleep(std::time::Duration::from_millis(300));
-                                    }
-                                    Err(err) => {
-                                        if let std::io::ErrorKind::BrokenPipe = err.kind() {
-                                            break;
-                                        }
-                                    }
-                                }
-                            },
-                        }
-                        *input.write().unwrap() = None;
-                        process = None;
+fn forward_output(stdout: std::process::ChildStdout, output_cb: Arc<Mutex<Option<ProcessOutputCB>>>) {
+    for line in BufReader::new(stdout).lines() {
+        match line {
+            Ok(v) => {
+                let f = output_cb.lock().unwrap().clone();
+                if let Some(f) = f {
+                    f(v);
+                }
+            }
+            Err(_) => {
+                return;
+            }
+        }
+    }
+}
+
+/// Sleeps for the given time unless shutdown is requested first. Returns false if interrupted.
+fn sleep_unless(shutdown: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        if shutdown.load(Ordering::Relaxed) {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(POLL_PERIOD_MS));
+    }
+    true
+}
+
+/// Asks the child and whatever it started to terminate, killing them if the child does not exit in time.
+fn terminate(child: &mut std::process::Child, timeout: Duration) -> Option<ExitStatus> {
+    // The child leads its own process group
+    let pgid = child.id() as libc::pid_t;
+    unsafe {
+        libc::killpg(pgid, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        if let Ok(Some(v)) = child.try_wait() {
+            return Some(v);
+        }
+        std::thread::sleep(Duration::from_millis(POLL_PERIOD_MS));
+    }
+
+    unsafe {
+        libc::killpg(pgid, libc::SIGKILL);
+    }
+    child.wait().ok()
+}
+
+fn supervise(
+    spec: ProcessSpec,
+    shutdown: Arc<AtomicBool>,
+    input: Arc<Mutex<Option<std::process::ChildStdin>>>,
+    output_cb: Arc<Mutex<Option<ProcessOutputCB>>>,
+    events: mpsc::SyncSender<ProcessEvent>,
+) -> Option<ExitStatus> {
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let spawned = Command::new(&spec.program)
+            .args(&spec.args)
+            .envs(spec.env.iter().map(|&(ref k, ref v)| (k, v)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .before_exec(|| {
+                if unsafe { libc::setpgid(0, 0) } != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            })
+            .spawn();
+
+        let status = match spawned {
+            Err(e) => {
+                let _ = events.try_send(ProcessEvent::SpawnFailed(format!("{}", e)));
+                None
+            }
+            Ok(mut child) => {
+                *input.lock().unwrap() = child.stdin.take();
+                let reader = child.stdout.take().map(|stdout| {
+                    let output_cb = Arc::clone(&output_cb);
+                    std::thread::spawn(move || forward_output(stdout, output_cb))
+                });
+
+                let status = loop {
+                    if shutdown.load(Ordering::Relaxed) {
+                        break terminate(&mut child, spec.term_timeout);
+                    }
+                    match child.try_wait() {
+                        Ok(Some(v)) => break Some(v),
+                        Ok(None) => {}
+                        Err(_) => break None,
                     }
+                    std::thread::sleep(Duration::from_millis(POLL_PERIOD_MS));
+                };
 
-                    process.take().unwrap().kill();
+                *input.lock().unwrap() = None;
+                if let Some(v) = reader {
+                    let _ = v.join();
                 }
-            });
+                if let Some(v) = status {
+                    let _ = events.try_send(ProcessEvent::Exited(v));
+                }
+                status
+            }
+        };
+
+        if shutdown.load(Ordering::Relaxed) {
+            return status;
+        }
+
+        match spec.restart.backoff(&status) {
+            None => {
+                return status;
+            }
+            Some(backoff) => {
+                attempt = backoff.next_attempt(attempt, started.elapsed());
+                if !sleep_unless(&shutdown, backoff.delay(attempt)) {
+                    return status;
+                }
+            }
+        }
+    }
+}
+
+impl SystemProcess {
+    pub fn new(spec: ProcessSpec) -> Self {
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let output_cb = Arc::new(Mutex::new(None));
+        let input = Arc::new(Mutex::new(None));
+        let (events_tx, events_rx) = mpsc::sync_channel(EVENTS_CAPACITY);
+        let (finished_tx, finished_rx) = oneshot::channel();
+
+        let supervisor = std::thread::spawn({
+            let shutdown = Arc::clone(&shutdown);
+            let input = Arc::clone(&input);
+            let output_cb = Arc::clone(&output_cb);
+            move || {
+                let status = supervise(spec, shutdown, input, output_cb, events_tx);
+                let _ = finished_tx.send(status);
+            }
+        });
 
         Self {
-            dropping: dropping,
-            process_manager: Some(process_manager),
+            shutdown: shutdown,
+            supervisor: Some(supervisor),
             input: input,
             output_cb: output_cb,
+            events: events_rx,
+            finished: Some(finished_rx),
+        }
+    }
+
+    /// Every exit of the child, including those followed by a restart.
+    pub fn events(&self) -> &mpsc::Receiver<ProcessEvent> {
+        &self.events
+    }
+
+    /// Resolves with the last exit status once the supervisor has stopped restarting the process.
+    /// Can only be taken once.
+    pub fn finished(&mut self) -> errors::FResult<Option<ExitStatus>> {
+        match self.finished.take() {
+            Some(v) => Box::new(v.map_err(|_| {
+                errors::ErrorKind::InternalError("Supervisor has gone away".into()).into()
+            })),
+            None => Box::new(futures::future::err(
+                errors::ErrorKind::InternalError("Exit status has already been taken".into())
+                    .into(),
+            )),
+        }
+    }
+
+    /// Stops supervision: SIGTERM, then SIGKILL if the process outlives `term_timeout`.
+    pub fn shutdown(mut self) -> Option<ExitStatus> {
+        self.shutdown.store(true, Ordering::Relaxed);
+        let finished = self.finished.take();
+        if let Some(v) = self.supervisor.take() {
+            let _ = v.join();
+        }
+        finished.and_then(|v| v.wait().ok()).and_then(|v| v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::process::ExitStatusExt;
+
+    fn backoff() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+        }
+    }
+
+    fn sh(script: &str, restart: RestartPolicy) -> ProcessSpec {
+        ProcessSpec {
+            args: vec!["-c".into(), script.into()],
+            restart: restart,
+            ..ProcessSpec::new("sh")
+        }
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let ok = Some(ExitStatus::from_raw(0));
+        let failed = Some(ExitStatus::from_raw(1 << 8));
+
+        assert!(RestartPolicy::Never.backoff(&failed).is_none());
+        assert!(RestartPolicy::OnFailure(backoff()).backoff(&ok).is_none());
+        assert!(RestartPolicy::OnFailure(backoff()).backoff(&failed).is_some());
+        assert!(RestartPolicy::OnFailure(backoff()).backoff(&None).is_some());
+        assert!(RestartPolicy::Always(backoff()).backoff(&ok).is_some());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let v = backoff();
+        assert_eq!(v.delay(0), Duration::from_millis(10));
+        assert_eq!(v.delay(2), Duration::from_millis(40));
+        assert_eq!(v.delay(4), Duration::from_millis(100));
+        assert_eq!(v.delay(std::u32::MAX), Duration::from_millis(100));
+
+        let huge = Backoff {
+            initial: Duration::from_secs(std::u64::MAX / 2),
+            max: Duration::from_secs(std::u64::MAX),
+        };
+        assert_eq!(huge.delay(8), huge.max);
+
+        assert_eq!(v.next_attempt(3, Duration::from_millis(5)), 4);
+        assert_eq!(v.next_attempt(3, Duration::from_millis(100)), 0);
+        assert_eq!(v.next_attempt(std::u32::MAX, Duration::from_millis(5)), std::u32::MAX);
+    }
+
+    #[test]
+    fn test_exit_status() {
+        let mut process = SystemProcess::new(sh("exit 3", RestartPolicy::Never));
+        let status = process.finished().wait().unwrap().unwrap();
+        assert_eq!(status.code(), Some(3));
+        match process.events().recv().unwrap() {
+            ProcessEvent::Exited(v) => assert_eq!(v.code(), Some(3)),
+            other => panic!("unexpected event: {:?}", other),
         }
     }
+
+    #[test]
+    fn test_restart_on_failure() {
+        let mut process = SystemProcess::new(sh("exit 1", RestartPolicy::OnFailure(backoff())));
+        for _ in 0..3 {
+            match process.events().recv().unwrap() {
+                ProcessEvent::Exited(v) => assert_eq!(v.code(), Some(1)),
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+        let status = process.shutdown();
+        assert!(status.map(|v| !v.success()).unwrap_or(true));
+    }
+
+    #[test]
+    fn test_shutdown_kills_after_timeout() {
+        let spec = ProcessSpec {
+            term_timeout: Duration::from_millis(200),
+            ..sh("trap '' TERM; while true; do echo ready; sleep 0.1; done", RestartPolicy::Always(backoff()))
+        };
+        let process = SystemProcess::new(spec);
+        // SIGTERM must not arrive before the shell ignores it. Output may start before the callback is set, so it repeats.
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let cb: ProcessOutputCB = Arc::new(move |v: String| {
+            let _ = tx.lock().unwrap().send(v);
+        });
+        process.set_output_cb(Some(cb));
+        assert_eq!(rx.recv().unwrap(), "ready");
+
+        let started = Instant::now();
+        let status = process.shutdown().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGKILL));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_shutdown_terminates() {
+        let process = SystemProcess::new(sh("while true; do sleep 1; done", RestartPolicy::Never));
+        let status = process.shutdown().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
 }
@@ -1,9 +1,17 @@
 extern crate std;
+extern crate treexml;
+extern crate treexml_util;
 
 use errors;
+use file_names;
+use util;
+
+use std::path::Path;
+
+use self::treexml_util::Unmarshaller;
 
 /// Represents the contents of project_init.xml, specifying an account to attach to initially
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct ProjectInit {
     pub url: String,
     pub name: String,
@@ -16,7 +24,110 @@ pub struct ProjectInit {
 }
 
 impl ProjectInit {
-    pub fn remove(&self) -> Result<(), errors::Error> {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<ProjectInit> {
+        if root.name != "project_init" {
+            bail!(errors::ErrorKind::XMLError(format!("unexpected project init root: {}", root.name).into()));
+        }
+
+        let mut v = ProjectInit::default();
+        for node in &root.children {
+            match &*node.name {
+                "url" => {
+                    let _ = v.url.unmarshal(&node);
+                }
+                "name" => {
+                    let _ = v.name.unmarshal(&node);
+                }
+                "team_name" => {
+                    let _ = v.team_name.unmarshal(&node);
+                }
+                "account_key" => {
+                    let _ = v.account_key.unmarshal(&node);
+                }
+                "setup_cookie" => {
+                    v.setup_cookie = node.text
+                        .as_ref()
+                        .map(|s| s.trim().as_bytes().to_vec())
+                        .unwrap_or_default();
+                }
+                "embedded" => {
+                    v.embedded = util::parse_flag(&node);
+                }
+                _ => {}
+            }
+        }
+        v.url = v.url.trim().to_string();
+        v.account_key = v.account_key.trim().to_string();
+        Ok(v)
+    }
+
+    /// Reads project_init.xml from the directory, if there is one.
+    pub fn read(dir: &Path) -> errors::Result<Option<ProjectInit>> {
+        let path = dir.join(file_names::PROJECT_INIT_FILE_NAME);
+        if !path.exists() {
+            return Ok(None);
+        }
+        match treexml::Document::parse(std::fs::File::open(path)?)?.root {
+            Some(root) => ProjectInit::try_from(&root).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Whether the file names an account to attach to without asking the user
+    pub fn has_account(&self) -> bool {
+        !self.url.is_empty() && !self.account_key.is_empty()
+    }
+
+    pub fn remove(dir: &Path) -> Result<(), errors::Error> {
+        let path = dir.join(file_names::PROJECT_INIT_FILE_NAME);
+        if path.exists() {
+            std::fs::remove_file(path)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use std::io::Write;
+
+    #[test]
+    fn test_read() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("project-init-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        assert!(ProjectInit::read(&dir).unwrap().is_none());
+
+        std::fs::File::create(dir.join(file_names::PROJECT_INIT_FILE_NAME))
+            .unwrap()
+            .write_all(
+                b"<project_init>\n\
+                  <url> http://example.com/proj/ </url>\n\
+                  <name>Example</name>\n\
+                  <team_name>Team</team_name>\n\
+                  <account_key>abc</account_key>\n\
+                  <setup_cookie>cookie</setup_cookie>\n\
+                  <embedded/>\n\
+                  </project_init>",
+            )
+            .unwrap();
+
+        let v = ProjectInit::read(&dir).unwrap().unwrap();
+        assert_eq!(v.url, "http://example.com/proj/");
+        assert_eq!(v.name, "Example");
+        assert_eq!(v.team_name, "Team");
+        assert_eq!(v.setup_cookie, b"cookie".to_vec());
+        assert!(v.embedded);
+        assert!(v.has_account());
+
+        ProjectInit::remove(&dir).unwrap();
+        assert!(ProjectInit::read(&dir).unwrap().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -13,13 +13,43 @@ use coproc;
 use errors;
 use file_names;
 use messages;
+use util;
 
 use common::ProjAm;
 
 use self::std::collections::{HashMap, HashSet};
 use self::std::hash::{Hash, Hasher};
+use self::std::io::Write;
+use self::std::path::Path;
 use self::std::sync::{Arc, Mutex};
-use self::treexml_util::{make_text_element, make_tree_element};
+use self::treexml_util::{make_text_element, make_tree_element, Unmarshaller};
+
+/// Resource share of projects whose preferences don't set one
+pub const DEFAULT_RESOURCE_SHARE: f64 = 100.0;
+
+/// Disk share given to projects with zero resource share
+const MIN_DISK_RESOURCE_SHARE: f64 = 1e-3;
+
+/// The part of the project preferences that applies to the venue: the matching
+/// `<venue>` if there is one, otherwise the preferences outside of any venue.
+pub fn venue_prefs(prefs: &treexml::Element, venue: &str) -> treexml::Element {
+    if !venue.is_empty() {
+        if let Some(v) = prefs.find_child(|e| {
+            e.name == "venue" && e.attributes.get("name").map(|s| s.as_str()) == Some(venue)
+        }) {
+            return make_tree_element("project_preferences", v.children.clone());
+        }
+    }
+    make_tree_element(
+        "project_preferences",
+        prefs
+            .children
+            .iter()
+            .filter(|e| e.name != "venue")
+            .cloned()
+            .collect(),
+    )
+}
 
 #[derive(Clone, Default)]
 pub struct DailyStats {
@@ -30,6 +60,15 @@ pub struct DailyStats {
     pub day: i64,
 }
 
+/// Work fetch backoff of a project for one resource
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RscBackoff {
+    /// don't ask for work for this resource until then
+    pub backoff_time: Option<common::Time>,
+    /// seconds, doubled after each request that brings no jobs
+    pub backoff_interval: f64,
+}
+
 // Describes a project to which this client is attached
 #[derive(Default)]
 pub struct ProjectData {
@@ -69,6 +108,9 @@ pub struct ProjectData {
     /// the following are from the account manager, if any
     pub no_rsc_ams: [bool; coproc::MAX_RSC],
 
+    /// work fetch backoff per resource
+    pub rsc_backoff: [RscBackoff; coproc::MAX_RSC],
+
     pub host_venue: String,
     pub scheduler_urls: Vec<String>,
     pub user_name: String,
@@ -90,6 +132,8 @@ pub struct ProjectData {
     pub cpu_time: f64,
     pub gpu_ec: f64,
     pub gpu_time: f64,
+    /// ratio of actual to estimated job runtimes
+    pub duration_correction_factor: f64,
 
     pub rpc_seqno: usize,
     pub nrpc_failures: usize,
@@ -106,13 +150,20 @@ pub struct ProjectData {
 
     pub suspended_via_gui: bool,
     pub dont_request_more_work: bool,
+    /// detach once the project's remaining work is done
+    pub detach_when_done: bool,
 
     pub statistics: Vec<DailyStats>,
 }
 
 impl ProjectData {
+    /// Whether the project uses more disk than its share of the allowed space
+    pub fn over_disk_quota(&self) -> bool {
+        self.disk_usage > self.disk_quota
+    }
+
     pub fn can_request_work(&self, now: &common::Time) -> bool {
-        !(self.suspended_via_gui || self.master_url_fetch_pending || {
+        !(self.suspended_via_gui || self.master_url_fetch_pending || self.over_disk_quota() || {
             if let Some(ref v) = self.min_rpc_time {
                 v > now
             } else {
@@ -121,13 +172,141 @@ impl ProjectData {
         } || self.dont_request_more_work)
     }
 
-    pub fn parse_account(&mut self, _: &treexml::Element) -> errors::Result<()> {
+    /// Decays recent estimated credit and adds processing done over the last `dt` seconds at the given FLOPS rates.
+    pub fn update_rec(&mut self, dt: f64, cpu_flops: f64, gpu_flops: f64, half_life: f64) {
+        let weight = (-dt * std::f64::consts::LN_2 / half_life).exp();
+        self.cpu_ec = self.cpu_ec * weight + (1.0 - weight) * cpu_flops;
+        self.gpu_ec = self.gpu_ec * weight + (1.0 - weight) * gpu_flops;
+        if cpu_flops > 0.0 {
+            self.cpu_time += dt;
+        }
+        if gpu_flops > 0.0 {
+            self.gpu_time += dt;
+        }
+    }
+
+    /// Reads the fields kept in client_state.xml.
+    pub fn parse_state(&mut self, root: &treexml::Element) -> errors::Result<()> {
+        for node in &root.children {
+            match &*node.name {
+                "duration_correction_factor" => {
+                    let _ = self.duration_correction_factor.unmarshal(&node);
+                }
+                "host_venue" => {
+                    let _ = self.host_venue.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+        self.apply_project_prefs();
         Ok(())
     }
 
-    pub fn write_account_file(&self) -> errors::Result<()> {
+    /// Derives the resource share, the resources the user has turned off and the
+    /// project-specific preferences from the project preferences for the host's venue.
+    pub fn apply_project_prefs(&mut self) {
+        let prefs = match self.project_prefs {
+            Some(ref v) => venue_prefs(v, &self.host_venue),
+            None => {
+                return;
+            }
+        };
+
+        self.resource_share = DEFAULT_RESOURCE_SHARE;
+        self.no_rsc_pref = Default::default();
+        self.project_specific_prefs = None;
+        for node in &prefs.children {
+            match &*node.name {
+                "resource_share" => {
+                    let mut n = 0.0f64;
+                    if n.unmarshal(&node).is_ok() && n >= 0.0 {
+                        self.resource_share = n;
+                    }
+                }
+                "no_rsc" => {
+                    if let Some(rsc) = node.text
+                        .as_ref()
+                        .and_then(|s| coproc::ProcType::from_rsc_name(s.trim()))
+                    {
+                        self.no_rsc_pref[rsc.rsc_index()] = true;
+                    }
+                }
+                "no_cpu" => {
+                    self.no_rsc_pref[coproc::ProcType::CPU.rsc_index()] = util::parse_flag(node);
+                }
+                "no_cuda" => {
+                    self.no_rsc_pref[coproc::ProcType::NVIDIAGraphics.rsc_index()] = util::parse_flag(node);
+                }
+                "no_ati" => {
+                    self.no_rsc_pref[coproc::ProcType::AMDGraphics.rsc_index()] = util::parse_flag(node);
+                }
+                "no_intel_gpu" => {
+                    self.no_rsc_pref[coproc::ProcType::IntelGraphics.rsc_index()] = util::parse_flag(node);
+                }
+                "project_specific" => {
+                    self.project_specific_prefs = Some(node.clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the account details kept in the project's account file.
+    pub fn parse_account(&mut self, root: &treexml::Element) -> errors::Result<()> {
+        if root.name != "account" {
+            bail!(errors::ErrorKind::XMLError(format!("unexpected account file root: {}", root.name).into()));
+        }
+
+        for node in &root.children {
+            match &*node.name {
+                "authenticator" => {
+                    let _ = self.authenticator.unmarshal(&node);
+                }
+                "project_name" => {
+                    let mut v = String::new();
+                    if v.unmarshal(&node).is_ok() && !v.is_empty() {
+                        self.project_name = Some(v);
+                    }
+                }
+                "project_preferences" => {
+                    self.project_prefs = Some(node.clone());
+                }
+                "gui_urls" => {
+                    self.gui_urls = node.children
+                        .iter()
+                        .filter(|e| e.name == "gui_url")
+                        .filter_map(|e| e.find_child(|e| e.name == "url"))
+                        .filter_map(|e| e.text.as_ref().map(|v| v.trim().to_string()))
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        self.apply_project_prefs();
         Ok(())
     }
+
+    fn account_element(&self, master_url: &str) -> treexml::Element {
+        let mut children = vec![
+            make_text_element("master_url", master_url),
+            make_text_element("authenticator", &self.authenticator),
+            make_text_element(
+                "project_name",
+                self.project_name.clone().unwrap_or_default(),
+            ),
+        ];
+        if let Some(ref v) = self.project_prefs {
+            children.push(v.clone());
+        }
+        children.push(make_tree_element(
+            "gui_urls",
+            self.gui_urls
+                .iter()
+                .map(|v| make_tree_element("gui_url", vec![make_text_element("url", v)]))
+                .collect(),
+        ));
+        make_tree_element("account", children)
+    }
 }
 
 #[derive(Default)]
@@ -160,11 +339,16 @@ impl common::ProjAm for Project {
 
 impl<'a> From<&'a Project> for treexml::Element {
     fn from(v: &Project) -> treexml::Element {
+        // get_project_name takes the lock itself
+        let project_name = v.get_project_name();
+        let data = v.data.lock().unwrap();
         make_tree_element(
             "project",
             vec![
                 make_text_element("master_url", v.master_url()),
-                make_text_element("project_name", v.get_project_name()),
+                make_text_element("project_name", project_name),
+                make_text_element("duration_correction_factor", data.duration_correction_factor),
+                make_text_element("host_venue", &data.host_venue),
                 // TODO: serialize more fields
             ],
         )
@@ -175,18 +359,50 @@ impl Project {
     pub fn new(master_url: String) -> Project {
         Project {
             _master_url: master_url,
-            ..Default::default()
+            data: Arc::new(Mutex::new(ProjectData {
+                duration_correction_factor: 1.0,
+                resource_share: DEFAULT_RESOURCE_SHARE,
+                ..Default::default()
+            })),
         }
     }
 
     pub fn project_dir(&self) -> std::path::PathBuf {
         let mut v = std::path::PathBuf::new();
         v.push(file_names::PROJECTS_DIR);
-        v.push(self.master_url());
+        v.push(util::escape_project_url(&self.master_url()));
         v
     }
 
+    /// Writes `account_<url>.xml` into the directory.
+    pub fn write_account_file(&self, dir: &Path) -> errors::Result<()> {
+        let root = self.data.lock().unwrap().account_element(&self.master_url());
+        std::fs::File::create(dir.join(file_names::account_filename(&self.master_url())))?
+            .write_fmt(format_args!("{}", root))?;
+        Ok(())
+    }
+
+    /// Restores a project from an account file written by `write_account_file`.
+    pub fn read_account_file(path: &Path) -> errors::Result<Project> {
+        let root = match treexml::Document::parse(std::fs::File::open(path)?)?.root {
+            Some(v) => v,
+            None => bail!(errors::ErrorKind::XMLError("empty account file".into())),
+        };
+        let master_url = match root.find_child(|e| e.name == "master_url")
+            .and_then(|e| e.text.as_ref())
+        {
+            Some(v) => util::canonicalize_url(v.trim()),
+            None => bail!(errors::ErrorKind::XMLError("account file has no master URL".into())),
+        };
+
+        let proj = Project::new(master_url);
+        proj.data.lock().unwrap().parse_account(&root)?;
+        Ok(proj)
+    }
+
+    /// Creates the directory the project's files are kept in.
     pub fn make_project_dir(&self) -> errors::Result<()> {
+        std::fs::create_dir_all(self.project_dir())?;
         Ok(())
     }
 }
@@ -208,4 +424,195 @@ impl Projects {
             ..Default::default()
         })
     }
+
+    /// Finds the project even if its URL differs in scheme or case
+    pub fn find_matching(&self, url: &str) -> Option<&Project> {
+        self.data.iter().find(|p| util::urls_match(&p._master_url, url))
+    }
+
+    /// Divides the allowed bytes of disk among projects by resource share. Projects that
+    /// need less than their share get what they need, and the rest is divided among the others.
+    pub fn allocate_disk_shares(&self, allowed: f64) {
+        let mut data = self.data
+            .iter()
+            .map(|p| p.data.lock().unwrap())
+            .collect::<Vec<_>>();
+
+        let mut allowed = allowed;
+        let mut total_share = 0.0;
+        for d in &mut data {
+            d.ddu = d.disk_usage.max(d.desired_disk_usage);
+            // Projects with zero resource share still get a sliver of space
+            d.disk_resource_share = d.resource_share.max(MIN_DISK_RESOURCE_SHARE);
+            d.disk_quota = 0.0;
+            total_share += d.disk_resource_share;
+        }
+
+        loop {
+            let mut found = false;
+            for d in &mut data {
+                if d.disk_resource_share > 0.0 && d.ddu < allowed * d.disk_resource_share / total_share {
+                    allowed -= d.ddu;
+                    total_share -= d.disk_resource_share;
+                    d.disk_quota = d.ddu;
+                    d.disk_resource_share = 0.0;
+                    found = true;
+                }
+            }
+            if !found {
+                break;
+            }
+        }
+
+        for d in &mut data {
+            if d.disk_resource_share > 0.0 {
+                d.disk_quota = allowed * d.disk_resource_share / total_share;
+            }
+        }
+    }
+
+    pub fn remove(&mut self, url: &str) -> Option<Project> {
+        self.data.take(&Project {
+            _master_url: url.to_string(),
+            ..Default::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_account_file() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("account-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let proj = Project::new("http://example.com/".into());
+        {
+            let mut data = proj.data.lock().unwrap();
+            data.authenticator = "abc".into();
+            data.project_name = Some("Example".into());
+            data.project_prefs = Some(make_tree_element(
+                "project_preferences",
+                vec![
+                    make_text_element("resource_share", 50),
+                    make_tree_element("project_specific", vec![make_text_element("color", "red")]),
+                ],
+            ));
+            data.gui_urls = vec!["http://example.com/forum".into()];
+        }
+        proj.write_account_file(&dir).unwrap();
+
+        let path = dir.join(file_names::account_filename(&proj.master_url()));
+        assert!(file_names::is_account_filename(&path.file_name().unwrap().to_string_lossy()));
+
+        let v = Project::read_account_file(&path).unwrap();
+        assert_eq!(v.master_url(), proj.master_url());
+        let data = v.data.lock().unwrap();
+        assert_eq!(data.authenticator, "abc");
+        assert_eq!(data.project_name, Some("Example".into()));
+        assert_eq!(data.gui_urls, vec!["http://example.com/forum".to_string()]);
+        assert!(data.project_prefs.is_some());
+        assert_eq!(data.project_specific_prefs.as_ref().unwrap().children[0].name, "color");
+        assert_eq!(data.resource_share, 50.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_state_element() {
+        let proj = Project::new("http://example.com/".into());
+        {
+            let mut data = proj.data.lock().unwrap();
+            data.duration_correction_factor = 1.5;
+            data.host_venue = "home".into();
+        }
+
+        let root = treexml::Element::from(&proj);
+        assert_eq!(root.name, "project");
+
+        let mut v = ProjectData::default();
+        v.parse_state(&root).unwrap();
+        assert_eq!(v.duration_correction_factor, 1.5);
+        assert_eq!(v.host_venue, "home");
+    }
+
+    #[test]
+    fn test_allocate_disk_shares() {
+        let mut projects = Projects {
+            data: Default::default(),
+        };
+        for &(url, rs, usage, desired) in &[
+            ("http://a/", 100.0, 1e9, 0.0),
+            ("http://b/", 100.0, 8e9, 0.0),
+            ("http://c/", 200.0, 0.0, 2e9),
+        ] {
+            let p = Project::new(url.into());
+            {
+                let mut d = p.data.lock().unwrap();
+                d.resource_share = rs;
+                d.disk_usage = usage;
+                d.desired_disk_usage = desired;
+            }
+            projects.data.insert(p);
+        }
+
+        let quota = |projects: &Projects, url: &str| projects.find_by_url(url).unwrap().data.lock().unwrap().disk_quota;
+
+        // A and C need less than their shares of 10 GB, B gets the rest
+        projects.allocate_disk_shares(10e9);
+        assert_eq!(quota(&projects, "http://a/"), 1e9);
+        assert_eq!(quota(&projects, "http://c/"), 2e9);
+        assert_eq!(quota(&projects, "http://b/"), 7e9);
+
+        projects.allocate_disk_shares(1e9);
+        assert_eq!(quota(&projects, "http://a/"), 0.25e9);
+        assert!(projects.find_by_url("http://a/").unwrap().data.lock().unwrap().over_disk_quota());
+        assert!(!projects.find_by_url("http://c/").unwrap().data.lock().unwrap().over_disk_quota());
+    }
+
+    #[test]
+    fn test_venue_prefs() {
+        let mut data = ProjectData::default();
+        data.project_prefs = Some(make_tree_element(
+            "project_preferences",
+            vec![
+                make_text_element("resource_share", 50),
+                make_text_element("no_cuda", 1),
+                make_tree_element(
+                    "venue",
+                    vec![
+                        make_text_element("resource_share", 200),
+                        make_text_element("no_rsc", "CPU"),
+                        make_tree_element("project_specific", vec![make_text_element("color", "blue")]),
+                    ],
+                ),
+            ],
+        ));
+        data.project_prefs
+            .as_mut()
+            .unwrap()
+            .children[2]
+            .attributes
+            .insert("name".into(), "home".into());
+
+        data.apply_project_prefs();
+        assert_eq!(data.resource_share, 50.0);
+        assert!(data.no_rsc_pref[coproc::ProcType::NVIDIAGraphics.rsc_index()]);
+        assert!(!data.no_rsc_pref[coproc::ProcType::CPU.rsc_index()]);
+        assert!(data.project_specific_prefs.is_none());
+
+        data.host_venue = "home".into();
+        data.apply_project_prefs();
+        assert_eq!(data.resource_share, 200.0);
+        assert!(!data.no_rsc_pref[coproc::ProcType::NVIDIAGraphics.rsc_index()]);
+        assert!(data.no_rsc_pref[coproc::ProcType::CPU.rsc_index()]);
+        assert_eq!(data.project_specific_prefs.as_ref().unwrap().children[0].name, "color");
+
+        data.host_venue = "work".into();
+        data.apply_project_prefs();
+        assert_eq!(data.resource_share, 50.0);
+    }
 }
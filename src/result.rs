@@ -0,0 +1,460 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+extern crate uuid;
+
+use app;
+use cc_config;
+use common;
+use errors;
+use file_info;
+use tasks;
+
+use self::treexml_util::{make_cdata_element, make_text_element, make_tree_element, Unmarshaller};
+use self::uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+
+/// Exit status of results whose input files could not be downloaded
+pub const ERR_RESULT_DOWNLOAD: i32 = -186;
+/// Exit status of results whose output files could not be uploaded
+pub const ERR_RESULT_UPLOAD: i32 = -187;
+
+/// Report finished results at the latest this long before their deadline, in seconds
+pub const REPORT_DEADLINE_CUSHION: f64 = 86400.0;
+/// Don't hold finished results back for longer than this, in seconds
+pub const MAX_REPORT_DELAY: f64 = 86400.0;
+
+/// Where a result is in its lifecycle. Numbering matches BOINC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResultState {
+    New = 0,
+    FilesDownloading = 1,
+    FilesDownloaded = 2,
+    ComputeError = 3,
+    FilesUploading = 4,
+    FilesUploaded = 5,
+    Aborted = 6,
+    UploadFailed = 7,
+}
+
+impl Default for ResultState {
+    fn default() -> Self {
+        ResultState::New
+    }
+}
+
+impl ResultState {
+    /// Whether the result has reached a state that gets reported to the project
+    pub fn is_final(&self) -> bool {
+        match *self {
+            ResultState::ComputeError
+            | ResultState::FilesUploaded
+            | ResultState::Aborted
+            | ResultState::UploadFailed => true,
+            _ => false,
+        }
+    }
+}
+
+/// An instance of a workunit to be computed with a particular app version
+#[derive(Clone, Debug)]
+pub struct Result {
+    pub name: String,
+    pub wu_name: String,
+    pub project_url: String,
+    pub app_version: app::AppVersion,
+    pub report_deadline: common::Time,
+
+    /// file infos of the workunit's input files
+    pub input_files: Vec<Uuid>,
+    /// file infos of the files produced by the task
+    pub output_files: Vec<Uuid>,
+    /// references to the output files, naming them as the application opens them
+    pub output_refs: Vec<file_info::FileRef>,
+    /// task computing the result, once files have been downloaded
+    pub task: Option<Uuid>,
+
+    pub state: ResultState,
+    pub exit_status: i32,
+    pub final_cpu_time: f64,
+    pub final_elapsed_time: f64,
+    pub stderr_out: Option<String>,
+
+    pub ready_to_report: bool,
+    pub completed_time: Option<common::Time>,
+    /// the project has acknowledged the report
+    pub got_server_ack: bool,
+}
+
+impl Result {
+    pub fn new(name: String, wu_name: String, project_url: String, report_deadline: common::Time) -> Self {
+        Self {
+            name: name,
+            wu_name: wu_name,
+            project_url: project_url,
+            app_version: Default::default(),
+            report_deadline: report_deadline,
+            input_files: Vec::new(),
+            output_files: Vec::new(),
+            output_refs: Vec::new(),
+            task: None,
+            state: ResultState::New,
+            exit_status: 0,
+            final_cpu_time: 0.0,
+            final_elapsed_time: 0.0,
+            stderr_out: None,
+            ready_to_report: false,
+            completed_time: None,
+            got_server_ack: false,
+        }
+    }
+
+    /// Reads a `<result>` sent by the project's scheduler. The app version only names the version
+    /// number, platform and plan class; the files are left for the caller to resolve.
+    pub fn try_from(root: &treexml::Element, project_url: &str) -> errors::Result<Self> {
+        let mut name = String::new();
+        let mut wu_name = String::new();
+        let mut report_deadline = 0.0f64;
+        let mut app_version = app::AppVersion::default();
+        for node in &root.children {
+            match &*node.name {
+                "name" => {
+                    let _ = name.unmarshal(&node);
+                }
+                "wu_name" => {
+                    let _ = wu_name.unmarshal(&node);
+                }
+                "report_deadline" => {
+                    let _ = report_deadline.unmarshal(&node);
+                }
+                "version_num" => {
+                    let mut n = 0i64;
+                    if n.unmarshal(&node).is_ok() {
+                        app_version.version_num = n as i32;
+                    }
+                }
+                "platform" => {
+                    let _ = app_version.platform.unmarshal(&node);
+                }
+                "plan_class" => {
+                    let _ = app_version.plan_class.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+        if name.is_empty() || wu_name.is_empty() {
+            bail!(errors::ErrorKind::XMLError("result has no name or workunit".into()));
+        }
+
+        let mut v = Self::new(
+            name,
+            wu_name,
+            project_url.into(),
+            common::Time::from(std::time::SystemTime::UNIX_EPOCH)
+                + common::Duration::seconds(report_deadline as i64),
+        );
+        v.app_version = app_version;
+        Ok(v)
+    }
+
+    fn finish(&mut self, state: ResultState, exit_status: i32, now: &common::Time) {
+        self.state = state;
+        self.exit_status = exit_status;
+        if state.is_final() {
+            self.ready_to_report = true;
+            self.completed_time = Some(*now);
+        }
+    }
+
+    /// Advances the result through downloading, computing and uploading according to its files and task.
+    pub fn update(
+        &mut self,
+        file_infos: &HashMap<Uuid, file_info::FileInfo>,
+        task: Option<&tasks::TaskStatus>,
+        now: &common::Time,
+    ) {
+        let files = |ids: &[Uuid]| {
+            ids.iter()
+                .filter_map(|id| file_infos.get(id))
+                .collect::<Vec<_>>()
+        };
+
+        match self.state {
+            ResultState::New | ResultState::FilesDownloading => {
+                let inputs = files(&self.input_files);
+                if inputs.iter().any(|f| f.status < 0) {
+                    self.finish(ResultState::ComputeError, ERR_RESULT_DOWNLOAD, now);
+                } else if inputs.iter().all(|f| f.status == file_info::FILE_PRESENT) {
+                    self.state = ResultState::FilesDownloaded;
+                } else {
+                    self.state = ResultState::FilesDownloading;
+                }
+            }
+            ResultState::FilesDownloaded => {
+                if let Some(status) = task {
+                    self.on_task_finished(status, now);
+                }
+            }
+            ResultState::FilesUploading => {
+                let outputs = files(&self.output_files);
+                if outputs.iter().any(|f| f.status < 0) {
+                    self.finish(ResultState::UploadFailed, ERR_RESULT_UPLOAD, now);
+                } else if outputs.iter().all(|f| f.uploaded) {
+                    let exit_status = self.exit_status;
+                    self.finish(ResultState::FilesUploaded, exit_status, now);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn on_task_finished(&mut self, status: &tasks::TaskStatus, now: &common::Time) {
+        let state = match status.status {
+            tasks::RunStatus::Done => if self.output_files.is_empty() {
+                ResultState::FilesUploaded
+            } else {
+                ResultState::FilesUploading
+            },
+            tasks::RunStatus::Error => ResultState::ComputeError,
+            tasks::RunStatus::Aborted => if status.limit_exceeded.is_some() {
+                ResultState::ComputeError
+            } else {
+                ResultState::Aborted
+            },
+            _ => {
+                return;
+            }
+        };
+
+        self.final_cpu_time = status.current_cpu_time;
+        self.final_elapsed_time = status.elapsed_time;
+        self.stderr_out = status.stderr_out.clone();
+        self.finish(state, status.exit_status.unwrap_or(0), now);
+    }
+
+    /// Aborts the result on user request. Its task, if any, must be aborted separately.
+    pub fn abort(&mut self, now: &common::Time) {
+        if !self.state.is_final() {
+            self.finish(ResultState::Aborted, tasks::EXIT_ABORTED_BY_CLIENT, now);
+        }
+    }
+
+    /// Whether the report should be sent now rather than with a later scheduler request.
+    pub fn report_due(&self, now: &common::Time, cc_config: &cc_config::CCConfig) -> bool {
+        if !self.ready_to_report || self.got_server_ack {
+            return false;
+        }
+        if cc_config.report_results_immediately {
+            return true;
+        }
+
+        let near_deadline = *now + common::Duration::seconds(REPORT_DEADLINE_CUSHION as i64) >= self.report_deadline;
+        let held_too_long = self.completed_time
+            .map(|t| *now - t >= common::Duration::seconds(MAX_REPORT_DELAY as i64))
+            .unwrap_or(false);
+        near_deadline || held_too_long
+    }
+}
+
+impl<'a> From<&'a Result> for treexml::Element {
+    fn from(v: &Result) -> treexml::Element {
+        let mut children = vec![
+            make_text_element("name", &v.name),
+            make_text_element("final_cpu_time", v.final_cpu_time),
+            make_text_element("final_elapsed_time", v.final_elapsed_time),
+            make_text_element("exit_status", v.exit_status),
+            make_text_element("state", v.state as i32),
+            make_text_element("app_version_num", v.app_version.version_num),
+            make_text_element("platform", &v.app_version.platform),
+            make_text_element("plan_class", &v.app_version.plan_class),
+        ];
+        if let Some(ref s) = v.stderr_out {
+            children.push(make_cdata_element("stderr_out", s));
+        }
+        make_tree_element("result", children)
+    }
+}
+
+/// Results of the project to include in the next scheduler request, oldest first.
+///
+/// Returns nothing unless at least one of them is due, in which case all ready results go along,
+/// up to `max_tasks_reported` if set.
+pub fn results_to_report<'a, I: IntoIterator<Item = &'a Result>>(
+    results: I,
+    project_url: &str,
+    now: &common::Time,
+    cc_config: &cc_config::CCConfig,
+) -> Vec<&'a Result> {
+    let mut v = results
+        .into_iter()
+        .filter(|r| r.project_url == project_url && r.ready_to_report && !r.got_server_ack)
+        .collect::<Vec<_>>();
+    if !v.iter().any(|r| r.report_due(now, cc_config)) {
+        return Vec::new();
+    }
+
+    v.sort_by(|a, b| a.completed_time.cmp(&b.completed_time).then(a.name.cmp(&b.name)));
+    if cc_config.max_tasks_reported > 0 {
+        v.truncate(cc_config.max_tasks_reported as usize);
+    }
+    v
+}
+
+/// Names of the results in the scheduler reply's `<result_ack>` elements
+pub fn acked_results(reply: &treexml::Element) -> HashSet<String> {
+    reply
+        .children
+        .iter()
+        .filter(|node| node.name == "result_ack")
+        .filter_map(|node| node.find_child(|v| v.name == "name"))
+        .filter_map(|node| node.text.as_ref().map(|s| s.trim().to_string()))
+        .collect()
+}
+
+/// Marks the acknowledged results as reported.
+pub fn handle_acks<'a, I: IntoIterator<Item = &'a mut Result>>(results: I, acked: &HashSet<String>) {
+    for r in results {
+        if acked.contains(&r.name) {
+            r.got_server_ack = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> common::Time {
+        std::time::SystemTime::UNIX_EPOCH.into()
+    }
+
+    fn file(status: i64, uploaded: bool) -> file_info::FileInfo {
+        file_info::FileInfo {
+            status: status,
+            uploaded: uploaded,
+            ..Default::default()
+        }
+    }
+
+    fn result(name: &str, deadline_days: i64) -> Result {
+        Result::new(
+            name.into(),
+            "wu".into(),
+            "http://a/".into(),
+            now() + common::Duration::days(deadline_days),
+        )
+    }
+
+    #[test]
+    fn test_lifecycle() {
+        let mut files = HashMap::new();
+        let input = Uuid::new(uuid::UuidVersion::Random).unwrap();
+        let output = Uuid::new(uuid::UuidVersion::Random).unwrap();
+        files.insert(input, file(file_info::FILE_NOT_PRESENT, false));
+        files.insert(output, file(file_info::FILE_NOT_PRESENT, false));
+
+        let mut r = result("r", 10);
+        r.input_files.push(input);
+        r.output_files.push(output);
+
+        r.update(&files, None, &now());
+        assert_eq!(r.state, ResultState::FilesDownloading);
+
+        files.get_mut(&input).unwrap().status = file_info::FILE_PRESENT;
+        r.update(&files, None, &now());
+        assert_eq!(r.state, ResultState::FilesDownloaded);
+
+        let status = tasks::TaskStatus {
+            status: tasks::RunStatus::Done,
+            current_cpu_time: 100.0,
+            exit_status: Some(0),
+            ..Default::default()
+        };
+        r.update(&files, Some(&status), &now());
+        assert_eq!(r.state, ResultState::FilesUploading);
+        assert!(!r.ready_to_report);
+
+        files.get_mut(&output).unwrap().uploaded = true;
+        r.update(&files, None, &now());
+        assert_eq!(r.state, ResultState::FilesUploaded);
+        assert_eq!(r.final_cpu_time, 100.0);
+        assert!(r.ready_to_report);
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut files = HashMap::new();
+        let input = Uuid::new(uuid::UuidVersion::Random).unwrap();
+        files.insert(input, file(-161, false));
+        let mut r = result("r", 10);
+        r.input_files.push(input);
+        r.update(&files, None, &now());
+        assert_eq!(r.state, ResultState::ComputeError);
+        assert_eq!(r.exit_status, ERR_RESULT_DOWNLOAD);
+
+        let mut r = result("r", 10);
+        r.state = ResultState::FilesDownloaded;
+        let status = tasks::TaskStatus {
+            status: tasks::RunStatus::Aborted,
+            exit_status: Some(tasks::EXIT_ABORTED_BY_CLIENT),
+            ..Default::default()
+        };
+        r.update(&files, Some(&status), &now());
+        assert_eq!(r.state, ResultState::Aborted);
+        assert!(r.ready_to_report);
+    }
+
+    #[test]
+    fn test_parse() {
+        let root = treexml::Element {
+            name: "result".into(),
+            children: vec![
+                make_text_element("name", "r_0"),
+                make_text_element("wu_name", "wu"),
+                make_text_element("report_deadline", 86400),
+                make_text_element("version_num", 712),
+                make_text_element("platform", "x86_64-pc-linux-gnu"),
+            ],
+            ..Default::default()
+        };
+        let v = Result::try_from(&root, "http://a/").unwrap();
+        assert_eq!(v.name, "r_0");
+        assert_eq!(v.wu_name, "wu");
+        assert_eq!(v.project_url, "http://a/");
+        assert_eq!(v.report_deadline, now() + common::Duration::days(1));
+        assert_eq!(v.app_version.version_num, 712);
+        assert_eq!(v.state, ResultState::New);
+
+        assert!(Result::try_from(&treexml::Element::new("result"), "http://a/").is_err());
+    }
+
+    #[test]
+    fn test_reporting() {
+        let mut cc_config = cc_config::CCConfig::default();
+        let mut results = vec![result("a", 10), result("b", 10), result("c", 10)];
+        for r in &mut results {
+            r.abort(&now());
+        }
+
+        assert!(results_to_report(&results, "http://a/", &now(), &cc_config).is_empty());
+
+        let later = now() + common::Duration::seconds(MAX_REPORT_DELAY as i64);
+        assert_eq!(results_to_report(&results, "http://a/", &later, &cc_config).len(), 3);
+
+        cc_config.report_results_immediately = true;
+        cc_config.max_tasks_reported = 2;
+        let v = results_to_report(&results, "http://a/", &now(), &cc_config);
+        assert_eq!(v.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(), vec!["a", "b"]);
+
+        let reply = treexml::Element {
+            name: "scheduler_reply".into(),
+            children: vec![
+                make_tree_element("result_ack", vec![make_text_element("name", "a")]),
+            ],
+            ..Default::default()
+        };
+        handle_acks(&mut results, &acked_results(&reply));
+        let v = results_to_report(&results, "http://a/", &now(), &cc_config);
+        assert_eq!(v.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(), vec!["b", "c"]);
+    }
+}
@@ -143,14 +143,23 @@ impl RpcService {
 
         (match &*v.name {
             "acct_mgr_info" => H::acct_mgr_info,
+            "acct_mgr_rpc" => H::acct_mgr_rpc,
+            "acct_mgr_rpc_poll" => H::acct_mgr_rpc_poll,
+            "create_account" => H::create_account,
+            "create_account_poll" => H::create_account_poll,
             "get_cc_status" => H::get_cc_status,
             "get_message_count" => H::get_message_count,
             "get_messages" => H::get_messages,
             "get_notices" => H::get_notices,
+            "get_project_config" => H::get_project_config,
+            "get_project_config_poll" => H::get_project_config_poll,
+            "get_results" => H::get_results,
             "get_state" => H::get_state,
             "get_statistics" => H::get_statistics,
             "get_all_projects_list" => H::get_all_projects_list,
             "get_disk_usage" => H::get_disk_usage,
+            "lookup_account" => H::lookup_account,
+            "lookup_account_poll" => H::lookup_account_poll,
             "project_attach" => H::project_attach,
             "project_attach_poll" => H::project_attach_poll,
             _ => {
@@ -4,11 +4,13 @@ extern crate treexml;
 extern crate treexml_util;
 
 use acct_mgr;
-use common;
+use acct_setup;
 use constants;
 use context;
+use errors;
 use projects;
 use state;
+use util;
 
 use self::std::io::Read;
 use self::futures::Future;
@@ -65,6 +67,16 @@ impl<'a, 'b> H<'a, 'b> {
         )
     }
 
+    pub fn get_results(&self) -> Option<treexml::Element> {
+        Some(make_tree_element(
+            "results",
+            self.context
+                .run_force(move |state| state.result_elements())
+                .wait()
+                .unwrap(),
+        ))
+    }
+
     pub fn get_all_projects_list(&self) -> Option<treexml::Element> {
         match std::fs::File::open(constants::ALL_PROJECTS_LIST_FILENAME) {
             Err(_) => None,
@@ -85,6 +97,8 @@ impl<'a, 'b> H<'a, 'b> {
     }
 
     pub fn get_disk_usage(&self) -> Option<treexml::Element> {
+        state::update_disk_usage(self.context.raw());
+
         Some({
             make_tree_element(
                 "disk_usage_summary",
@@ -146,59 +160,136 @@ impl<'a, 'b> H<'a, 'b> {
             }
         }
 
-        self.context
+        let started = self.context
             .run_mut_force(move |state| {
-                let project_init = state.project_init.take();
-                let (url, authenticator) = match project_init.as_ref() {
-                    Some(project_init) => {
-                        if project_init.url.is_empty() {
-                            return Some(make_error("Missing URL"));
-                        }
+                if state.project_attach.in_progress {
+                    return Err(make_error("Attach already in progress"));
+                }
 
-                        if project_init.account_key.is_empty() {
-                            return Some(make_error("Missing authenticator"));
+                let (url, authenticator, project_name) = if use_config_file {
+                    match state.project_init {
+                        Some(ref project_init) => {
+                            if project_init.url.is_empty() {
+                                return Err(make_error("Missing URL"));
+                            }
+
+                            if project_init.account_key.is_empty() {
+                                return Err(make_error("Missing authenticator"));
+                            }
+
+                            (
+                                project_init.url.clone(),
+                                project_init.account_key.clone(),
+                                project_init.name.clone(),
+                            )
                         }
-
-                        (project_init.url.clone(), project_init.account_key.clone())
-                    }
-                    None => {
-                        if url.is_empty() {
-                            return Some(make_error("Missing URL"));
+                        None => {
+                            return Err(make_error("Missing project_init.xml"));
                         }
+                    }
+                } else {
+                    if url.is_empty() {
+                        return Err(make_error("Missing URL"));
+                    }
 
-                        if authenticator.is_empty() {
-                            return Some(make_error("Missing authenticator"));
-                        }
-                        (url.clone(), authenticator.clone())
+                    if authenticator.is_empty() {
+                        return Err(make_error("Missing authenticator"));
                     }
+                    (url.clone(), authenticator.clone(), project_name.clone())
                 };
 
-                for proj in &state.projects.data {
-                    if proj.master_url() == url {
-                        return Some(make_error("Already attached to project"));
+                if state.projects.find_matching(&url).is_some() {
+                    return Err(make_error("Already attached to project"));
+                }
+
+                if let (true, Some(config)) = (
+                    util::urls_match(&state.project_config.url, &url),
+                    state.project_config.config.as_ref(),
+                ) {
+                    let mut platforms = vec![constants::PRIMARY_PLATFORM];
+                    platforms.extend(state.cc_config.alt_platforms.iter().map(|s| s.as_str()));
+                    if !config.supports_platform(&platforms) {
+                        return Err(make_error("Project has no applications for this platform"));
+                    }
+                    if !config.account_creation_allowed() {
+                        return Err(make_error("Project has disabled account creation"));
                     }
                 }
 
-                state.project_attach = Default::default();
-                *state.project_attach.error.lock().unwrap() = state
-                    .add_project(&url, &authenticator, &project_name, false)
-                    .err();
-
-                project_init.map(|project_init| {
-                    let _ = project_init.remove().map_err(|err| {
-                        state.messages.insert(
-                            None,
-                            common::MessagePriority::InternalError,
-                            state.clock_source.now(),
-                            &format!("Can't delete project init file: {}", err),
-                        );
-                    });
-                });
+                state.project_attach = acct_setup::ProjectAttach {
+                    in_progress: true,
+                    from_config_file: use_config_file,
+                    ..Default::default()
+                };
 
+                Ok((url, authenticator, project_name))
+            })
+            .wait()
+            .unwrap();
+
+        match started {
+            Ok((url, authenticator, project_name)) => {
+                let attach = self.context
+                    .compose()
+                    .bind_rwlock(move |lock, _| state::attach_project(lock, &url, &authenticator, &project_name))
+                    .assemble();
+                std::thread::spawn(move || attach());
                 Some(treexml::Element::new("success"))
+            }
+            Err(e) => Some(e),
+        }
+    }
+
+    pub fn get_project_config(&self) -> Option<treexml::Element> {
+        let url = match treexml_util::find_value::<String>("url", self.incoming) {
+            Ok(Some(v)) => v.trim().to_string(),
+            _ => String::new(),
+        };
+        if url.is_empty() {
+            return Some(make_error("Missing URL"));
+        }
+
+        self.context
+            .run_mut_force({
+                let url = url.clone();
+                move |state| {
+                    state.project_config = acct_setup::ProjectConfigOp {
+                        in_progress: true,
+                        url: url.clone(),
+                        config: None,
+                    };
+                }
             })
             .wait()
-            .unwrap()
+            .unwrap();
+
+        let fetch = self.context
+            .compose()
+            .bind_rwlock(move |lock, _| state::fetch_project_config(lock, &url))
+            .assemble();
+        std::thread::spawn(move || fetch());
+
+        Some(treexml::Element::new("success"))
+    }
+
+    pub fn get_project_config_poll(&self) -> Option<treexml::Element> {
+        Some(
+            self.context
+                .run_force(|state| match state.project_config.config {
+                    Some(ref config) if !state.project_config.in_progress => {
+                        treexml::Element::from(config)
+                    }
+                    None if !state.project_config.in_progress => {
+                        make_error("No project config request in progress")
+                    }
+                    _ => make_tree_element(
+                        "project_config",
+                        vec![make_text_element("error_num", errors::ERR_IN_PROGRESS)],
+                    ),
+                })
+                .wait()
+                .unwrap(),
+        )
     }
 
     pub fn project_attach_poll(&self) -> Option<treexml::Element> {
@@ -215,14 +306,11 @@ impl<'a, 'b> H<'a, 'b> {
                         .collect());
                     children.push(make_text_element(
                         "error_num",
-                        state
-                            .project_attach
-                            .error
-                            .lock()
-                            .unwrap()
-                            .as_ref()
-                            .map(i64::from)
-                            .unwrap_or(0),
+                        if state.project_attach.in_progress {
+                            errors::ERR_IN_PROGRESS
+                        } else {
+                            state.project_attach.error.as_ref().map(i64::from).unwrap_or(0)
+                        },
                     ));
 
                     children
@@ -232,6 +320,84 @@ impl<'a, 'b> H<'a, 'b> {
         ))
     }
 
+    fn start_account_rpc(&self, rpc: acct_setup::AccountRpc) -> Option<treexml::Element> {
+        let v = match acct_setup::AccountIn::try_from(self.incoming) {
+            Ok(v) => v,
+            Err(_) => {
+                return Some(make_error("Invalid request"));
+            }
+        };
+        if v.url.is_empty() {
+            return Some(make_error("Missing URL"));
+        }
+        if v.email_addr.is_empty() {
+            return Some(make_error("Missing email address"));
+        }
+
+        self.context
+            .run_mut_force(move |state| {
+                state.account_ops.insert(
+                    rpc,
+                    acct_setup::AccountOp {
+                        in_progress: true,
+                        reply: None,
+                    },
+                );
+            })
+            .wait()
+            .unwrap();
+
+        let call = self.context
+            .compose()
+            .bind_rwlock(move |lock, _| state::run_account_rpc(lock, rpc, &v))
+            .assemble();
+        std::thread::spawn(move || call());
+
+        Some(treexml::Element::new("success"))
+    }
+
+    fn poll_account_rpc(&self, rpc: acct_setup::AccountRpc) -> Option<treexml::Element> {
+        let op = self.context
+            .run_force(move |state| state.account_ops.get(&rpc).cloned())
+            .wait()
+            .unwrap();
+        match op {
+            None => Some(make_error("No account operation in progress")),
+            Some(op) => Some(make_tree_element(
+                "account_out",
+                match op.reply {
+                    Some(ref reply) if !op.in_progress => {
+                        let mut v = vec![make_text_element("error_num", reply.error_num)];
+                        if !reply.error_msg.is_empty() {
+                            v.push(make_text_element("error_msg", &reply.error_msg));
+                        }
+                        if !reply.authenticator.is_empty() {
+                            v.push(make_text_element("authenticator", &reply.authenticator));
+                        }
+                        v
+                    }
+                    _ => vec![make_text_element("error_num", errors::ERR_IN_PROGRESS)],
+                },
+            )),
+        }
+    }
+
+    pub fn lookup_account(&self) -> Option<treexml::Element> {
+        self.start_account_rpc(acct_setup::AccountRpc::Lookup)
+    }
+
+    pub fn lookup_account_poll(&self) -> Option<treexml::Element> {
+        self.poll_account_rpc(acct_setup::AccountRpc::Lookup)
+    }
+
+    pub fn create_account(&self) -> Option<treexml::Element> {
+        self.start_account_rpc(acct_setup::AccountRpc::Create)
+    }
+
+    pub fn create_account_poll(&self) -> Option<treexml::Element> {
+        self.poll_account_rpc(acct_setup::AccountRpc::Create)
+    }
+
     pub fn acct_mgr_info(&self) -> Option<treexml::Element> {
         Some(make_tree_element(
             "acct_mgr_info",
@@ -260,6 +426,103 @@ impl<'a, 'b> H<'a, 'b> {
         ))
     }
 
+    pub fn acct_mgr_rpc(&self) -> Option<treexml::Element> {
+        let mut url = String::new();
+        let mut name = String::new();
+        let mut password = String::new();
+        let mut use_config_file = false;
+        let mut detach_projects = false;
+
+        for child in &self.incoming.children {
+            match &*child.name {
+                "url" => {
+                    let _ = url.unmarshal(child);
+                }
+                "detach_projects" => {
+                    detach_projects = true;
+                }
+                "name" => {
+                    let _ = name.unmarshal(child);
+                }
+                "password" => {
+                    let _ = password.unmarshal(child);
+                }
+                "use_config_file" => {
+                    use_config_file = true;
+                }
+                _ => {}
+            }
+        }
+
+        let started = self.context
+            .run_mut_force(move |state| {
+                if !use_config_file {
+                    if url.is_empty() {
+                        state.leave_acct_mgr(detach_projects);
+                        return false;
+                    }
+                    if state.acct_mgr_info.master_url != url {
+                        state.acct_mgr_info = Default::default();
+                        state.acct_mgr_info.master_url = url.clone();
+                    }
+                    state.acct_mgr_info.login_name = Some(name.clone());
+                    state.acct_mgr_info.password_hash = acct_mgr::password_hash(&password, &name);
+                    state.acct_mgr_info.password_error = false;
+                }
+                if !state.acct_mgr_info.is_set() {
+                    return false;
+                }
+
+                state.acct_mgr_op = acct_mgr::AcctMgrOp {
+                    in_progress: true,
+                    ..Default::default()
+                };
+                true
+            })
+            .wait()
+            .unwrap();
+
+        if started {
+            let sync = self.context
+                .compose()
+                .bind_rwlock(|lock, _| state::sync_acct_mgr(lock))
+                .assemble();
+            std::thread::spawn(move || sync());
+        }
+
+        Some(treexml::Element::new("success"))
+    }
+
+    pub fn acct_mgr_rpc_poll(&self) -> Option<treexml::Element> {
+        Some(make_tree_element(
+            "acct_mgr_rpc_reply",
+            self.context
+                .run_force(|state| {
+                    let op = &state.acct_mgr_op;
+                    let mut children = vec![
+                        make_text_element(
+                            "error_num",
+                            if op.in_progress {
+                                errors::ERR_IN_PROGRESS
+                            } else {
+                                op.error.as_ref().map(i64::from).unwrap_or(0)
+                            },
+                        ),
+                    ];
+                    if let (false, Some(e)) = (op.in_progress, op.error.as_ref()) {
+                        children.push(make_text_element("error_msg", e.to_string()));
+                    }
+                    children.append(&mut op.messages
+                        .iter()
+                        .map(|m| make_text_element("message", m))
+                        .collect());
+                    children
+                })
+                .wait()
+                .unwrap(),
+        ))
+    }
+
     pub fn get_cc_status(&self) -> Option<treexml::Element> {
         Some(make_tree_element(
             "cc_status",
@@ -270,14 +533,14 @@ impl<'a, 'b> H<'a, 'b> {
                         make_text_element("ams_password_error", state.acct_mgr_info.password_error),
                         make_text_element(
                             "task_suspend_reason",
-                            state.suspend_reason.map(|v| v.into()).unwrap_or(0),
+                            state.suspend_reason.map(i32::from).unwrap_or(0),
                         ),
                         make_text_element("task_mode", u8::from(state.run_mode.get_current())),
                         make_text_element("task_mode_perm", u8::from(state.run_mode.get_perm())),
                         make_text_element("task_mode_delay", state.run_mode.delay().num_seconds()),
                         make_text_element(
                             "gpu_suspend_reason",
-                            state.gpu_suspend_reason.map(|v| u8::from(v)).unwrap_or(0),
+                            state.gpu_suspend_reason.map(i32::from).unwrap_or(0),
                         ),
                         make_text_element("gpu_mode", u8::from(state.gpu_run_mode.get_current())),
                         make_text_element("gpu_mode_perm", u8::from(state.gpu_run_mode.get_perm())),
@@ -286,6 +549,8 @@ impl<'a, 'b> H<'a, 'b> {
                             state.gpu_run_mode.delay().num_seconds(),
                         ),
                         make_text_element("network_mode", 0),
+                        make_text_element("user_active", state.user_active() as u8),
+                        make_text_element("idle_time", state.idle_time),
                         make_text_element("disallow_attach", state.cc_config.disallow_attach as u8),
                         make_text_element("simple_gui_only", state.cc_config.simple_gui_only as u8),
                         make_text_element(
@@ -350,3 +615,102 @@ impl<'a, 'b> H<'a, 'b> {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    use app;
+    use common;
+    use result;
+    use workunit;
+
+    use self::uuid::Uuid;
+
+    fn new_id() -> Uuid {
+        Uuid::new(uuid::UuidVersion::Random).unwrap()
+    }
+
+    #[test]
+    fn test_get_results_projection() {
+        let mut state = state::ClientState::default();
+        state.host_info.p_ncpus = 1;
+        state.host_info.p_fpops = 1e9;
+
+        let project = projects::Project::new("http://a/".into());
+        {
+            let mut app = app::App {
+                name: "app".into(),
+                user_friendly_name: "App".into(),
+                work_units: HashMap::new(),
+                versions: HashMap::new(),
+                active_tasks: HashSet::new(),
+            };
+            // Takes 10000 s, much longer than the deadline allows
+            app.work_units.insert(
+                new_id(),
+                workunit::Workunit {
+                    name: "wu".into(),
+                    app_name: "app".into(),
+                    rsc_fpops_est: 1e13,
+                    ..Default::default()
+                },
+            );
+            project.data.lock().unwrap().apps.insert(new_id(), app);
+        }
+        state.projects.data.insert(project);
+
+        let deadline = state.clock_source.now() + common::Duration::hours(1);
+        state.results.insert(
+            new_id(),
+            result::Result::new("r".into(), "wu".into(), "http://a/".into(), deadline),
+        );
+        state.poll_cpu_sched();
+
+        let context = context::Context::new(state);
+        let incoming = treexml::Element::new("get_results");
+        let root = H {
+            context: &context,
+            incoming: &incoming,
+        }.get_results()
+            .unwrap();
+
+        let r = root.find_child(|e| e.name == "result").unwrap();
+        let completion = r.find_child(|e| e.name == "projected_completion_time")
+            .and_then(|e| e.text.clone())
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap();
+        assert!(completion > deadline.timestamp());
+        assert!(r.find_child(|e| e.name == "deadline_at_risk").is_some());
+    }
+
+    #[test]
+    fn test_get_project_config_poll() {
+        let context = context::Context::new(state::ClientState::default());
+        let incoming = treexml::Element::new("get_project_config_poll");
+        let h = H {
+            context: &context,
+            incoming: &incoming,
+        };
+        assert_eq!(h.get_project_config_poll().unwrap().name, "error");
+
+        context
+            .run_mut_force(|state| {
+                state.project_config = acct_setup::ProjectConfigOp {
+                    in_progress: true,
+                    url: "http://a/".into(),
+                    config: None,
+                };
+            })
+            .wait()
+            .unwrap();
+        let v = h.get_project_config_poll().unwrap();
+        assert_eq!(v.name, "project_config");
+        assert_eq!(
+            v.find_child(|e| e.name == "error_num").and_then(|e| e.text.clone()),
+            Some(errors::ERR_IN_PROGRESS.to_string())
+        );
+    }
+}
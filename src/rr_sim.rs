@@ -0,0 +1,391 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+extern crate uuid;
+
+use common;
+use coproc;
+use cpu_sched;
+
+use self::treexml_util::make_text_element;
+use self::uuid::Uuid;
+use std::collections::{HashMap, HashSet};
+
+use cpu_sched::{Resources, RunnableJob};
+
+/// Simulated time stops advancing after this many seconds
+const MAX_SIM_DURATION: f64 = 365.0 * 86400.0;
+
+pub fn resource_of(job: &RunnableJob) -> (coproc::ProcType, f64) {
+    job.coproc
+        .unwrap_or((coproc::ProcType::CPU, job.avg_ncpus))
+}
+
+/// Projected fate of a single job
+#[derive(Clone, Debug)]
+pub struct ProjectedJob {
+    pub task: Uuid,
+    pub name: String,
+    pub project_url: String,
+    pub report_deadline: common::Time,
+    pub completion: common::Time,
+    pub deadline_at_risk: bool,
+}
+
+impl ProjectedJob {
+    /// Fields added to the job's `<result>` element in RPC output.
+    pub fn xml_fields(&self) -> Vec<treexml::Element> {
+        let mut v = vec![
+            make_text_element("projected_completion_time", self.completion.timestamp()),
+        ];
+        if self.deadline_at_risk {
+            v.push(treexml::Element::new("deadline_at_risk"));
+        }
+        v
+    }
+}
+
+/// Results of a simulation run
+#[derive(Clone, Debug, Default)]
+pub struct RRSimOutput {
+    pub jobs: HashMap<Uuid, ProjectedJob>,
+    /// idle instance-seconds of each resource within the buffer horizon
+    pub shortfall: HashMap<coproc::ProcType, f64>,
+    /// seconds until some instance of each resource becomes idle
+    pub saturated_time: HashMap<coproc::ProcType, f64>,
+}
+
+impl RRSimOutput {
+    pub fn deadline_at_risk(&self) -> HashSet<Uuid> {
+        self.jobs
+            .values()
+            .filter(|v| v.deadline_at_risk)
+            .map(|v| v.task)
+            .collect()
+    }
+}
+
+/// Splits `total` instances between projects in proportion to their shares.
+/// Projects that need less than their share get what they need and the rest goes to the others.
+fn allocate(total: f64, demand: &HashMap<String, f64>, shares: &HashMap<String, f64>) -> HashMap<String, f64> {
+    let mut out: HashMap<String, f64> = HashMap::new();
+    let mut left = total;
+    let mut pending = demand.keys().cloned().collect::<HashSet<_>>();
+
+    while left > 1e-9 && !pending.is_empty() {
+        let share_sum = pending
+            .iter()
+            .map(|url| shares.get(url).cloned().unwrap_or(0.0))
+            .sum::<f64>();
+        let fair = pending
+            .iter()
+            .map(|url| {
+                let v = if share_sum > 0.0 {
+                    left * shares.get(url).cloned().unwrap_or(0.0) / share_sum
+                } else {
+                    left / pending.len() as f64
+                };
+                (url.clone(), v)
+            })
+            .collect::<HashMap<_, _>>();
+        let satisfied = {
+            let need = |url: &String| demand[url] - out.get(url).cloned().unwrap_or(0.0);
+            fair.iter()
+                .filter(|&(url, v)| need(url) <= *v)
+                .map(|(url, _)| (url.clone(), need(url)))
+                .collect::<Vec<_>>()
+        };
+
+        if satisfied.is_empty() {
+            for (url, v) in fair {
+                *out.entry(url).or_insert(0.0) += v;
+            }
+            break;
+        }
+
+        for (url, v) in satisfied {
+            left -= v;
+            pending.remove(&url);
+            *out.entry(url).or_insert(0.0) += v;
+        }
+    }
+
+    out
+}
+
+/// Simulates round-robin processing of the jobs, each project getting its resource share of every resource.
+///
+/// `horizon` is the length of the work buffer in seconds over which shortfall is measured.
+pub fn simulate(
+    now: &common::Time,
+    jobs: &[RunnableJob],
+    shares: &HashMap<String, f64>,
+    resources: &Resources,
+    horizon: f64,
+) -> RRSimOutput {
+    let mut out = RRSimOutput::default();
+
+    let instances = |rsc: &coproc::ProcType| match *rsc {
+        coproc::ProcType::CPU => resources.ncpus,
+        ref other => resources.coprocs.get(other).cloned().unwrap_or(0.0),
+    };
+
+    let mut all_rsc = resources.coprocs.keys().cloned().collect::<HashSet<_>>();
+    all_rsc.insert(coproc::ProcType::CPU);
+    for rsc in &all_rsc {
+        out.shortfall.insert(*rsc, 0.0);
+        out.saturated_time.insert(*rsc, 0.0);
+    }
+    let mut saturated = all_rsc.clone();
+
+    let mut remaining: HashMap<Uuid, f64> = HashMap::new();
+    let mut ordered = jobs.iter().collect::<Vec<_>>();
+    ordered.sort_by(|a, b| {
+        a.report_deadline
+            .cmp(&b.report_deadline)
+            .then(a.task.cmp(&b.task))
+    });
+    for job in &ordered {
+        let (rsc, _) = resource_of(job);
+        if job.runtime_remaining <= 0.0 {
+            // Nothing is left to compute, so the job is as good as finished
+            out.jobs.insert(
+                job.task,
+                ProjectedJob {
+                    task: job.task,
+                    name: job.name.clone(),
+                    project_url: job.project_url.clone(),
+                    report_deadline: job.report_deadline,
+                    completion: *now,
+                    deadline_at_risk: false,
+                },
+            );
+        } else if instances(&rsc) > 0.0 {
+            remaining.insert(job.task, job.runtime_remaining);
+        }
+    }
+
+    let mut t = 0.0;
+    loop {
+        // Fraction of full speed each unfinished job runs at in this step
+        let mut rates: HashMap<Uuid, f64> = HashMap::new();
+        let mut busy: HashMap<coproc::ProcType, f64> = HashMap::new();
+        for rsc in &all_rsc {
+            let mut demand: HashMap<String, f64> = HashMap::new();
+            for job in &ordered {
+                let (job_rsc, usage) = resource_of(job);
+                if job_rsc == *rsc && remaining.get(&job.task).map(|v| *v > 0.0).unwrap_or(false) {
+                    *demand.entry(job.project_url.clone()).or_insert(0.0) += usage;
+                }
+            }
+
+            let alloc = allocate(instances(rsc), &demand, shares);
+            for (url, mut left) in alloc {
+                *busy.entry(*rsc).or_insert(0.0) += left;
+                // Within a project, jobs run in deadline order
+                for job in ordered.iter().filter(|job| job.project_url == url) {
+                    let (job_rsc, usage) = resource_of(job);
+                    if job_rsc != *rsc || !remaining.get(&job.task).map(|v| *v > 0.0).unwrap_or(false) {
+                        continue;
+                    }
+                    if left <= 1e-9 {
+                        break;
+                    }
+                    let granted = usage.min(left);
+                    left -= granted;
+                    rates.insert(job.task, granted / usage.max(1e-9));
+                }
+            }
+        }
+
+        // Record when resources first run short of work
+        for rsc in &all_rsc {
+            if saturated.contains(rsc) && busy.get(rsc).cloned().unwrap_or(0.0) < instances(rsc) - 1e-9 {
+                saturated.remove(rsc);
+                out.saturated_time.insert(*rsc, t);
+            }
+        }
+
+        let dt = rates
+            .iter()
+            .filter(|&(_, rate)| *rate > 0.0)
+            .map(|(id, rate)| remaining[id] / rate)
+            .fold(std::f64::INFINITY, f64::min);
+        if !dt.is_finite() || t >= MAX_SIM_DURATION {
+            break;
+        }
+
+        // Idle instances within the horizon count towards shortfall
+        let horizon_dt = (horizon - t).max(0.0).min(dt);
+        for rsc in &all_rsc {
+            let idle = (instances(rsc) - busy.get(rsc).cloned().unwrap_or(0.0)).max(0.0);
+            *out.shortfall.get_mut(rsc).unwrap() += idle * horizon_dt;
+        }
+
+        t += dt;
+        for (id, rate) in &rates {
+            let v = remaining.get_mut(id).unwrap();
+            *v -= rate * dt;
+            if *v <= 1e-6 {
+                *v = 0.0;
+            }
+        }
+        for job in &ordered {
+            if remaining.get(&job.task) == Some(&0.0) && !out.jobs.contains_key(&job.task) {
+                let completion = *now + common::Duration::milliseconds((t * 1000.0) as i64);
+                out.jobs.insert(
+                    job.task,
+                    ProjectedJob {
+                        task: job.task,
+                        name: job.name.clone(),
+                        project_url: job.project_url.clone(),
+                        report_deadline: job.report_deadline,
+                        completion: completion,
+                        deadline_at_risk: completion > job.report_deadline,
+                    },
+                );
+            }
+        }
+    }
+
+    // Whatever remains of the horizon after the last job is idle
+    for rsc in &all_rsc {
+        *out.shortfall.get_mut(rsc).unwrap() += instances(rsc) * (horizon - t).max(0.0);
+        if saturated.contains(rsc) {
+            out.saturated_time.insert(*rsc, t);
+        }
+    }
+
+    // Jobs that can never complete, e.g. for lack of a suitable coprocessor
+    for job in &ordered {
+        if !out.jobs.contains_key(&job.task) {
+            out.jobs.insert(
+                job.task,
+                ProjectedJob {
+                    task: job.task,
+                    name: job.name.clone(),
+                    project_url: job.project_url.clone(),
+                    report_deadline: job.report_deadline,
+                    completion: *now + common::Duration::seconds(MAX_SIM_DURATION as i64),
+                    deadline_at_risk: true,
+                },
+            );
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn epoch() -> common::Time {
+        std::time::SystemTime::UNIX_EPOCH.into()
+    }
+
+    fn job(project_url: &str, deadline_secs: i64, runtime_remaining: f64) -> RunnableJob {
+        RunnableJob {
+            task: uuid::Uuid::new(uuid::UuidVersion::Random).unwrap(),
+            name: "job".into(),
+            project_url: project_url.into(),
+            app_name: "app".into(),
+            report_deadline: epoch() + common::Duration::seconds(deadline_secs),
+            avg_ncpus: 1.0,
+            coproc: None,
+            runtime_remaining: runtime_remaining,
+        }
+    }
+
+    fn shares(v: &[(&str, f64)]) -> HashMap<String, f64> {
+        v.iter().map(|&(k, v)| (k.to_string(), v)).collect()
+    }
+
+    #[test]
+    fn test_shared_cpu() {
+        let a = job("http://a/", 150, 100.0);
+        let b = job("http://b/", 1000, 100.0);
+        let resources = Resources {
+            ncpus: 1.0,
+            ..Default::default()
+        };
+
+        let v = simulate(
+            &epoch(),
+            &[a.clone(), b.clone()],
+            &shares(&[("http://a/", 100.0), ("http://b/", 100.0)]),
+            &resources,
+            400.0,
+        );
+
+        // Both run at half speed until they finish together
+        assert_eq!(v.jobs[&a.task].completion, epoch() + common::Duration::seconds(200));
+        assert!(v.jobs[&a.task].deadline_at_risk);
+        assert!(!v.jobs[&b.task].deadline_at_risk);
+        assert_eq!(v.deadline_at_risk(), vec![a.task].into_iter().collect());
+        assert_eq!(v.saturated_time[&coproc::ProcType::CPU], 200.0);
+        assert_eq!(v.shortfall[&coproc::ProcType::CPU], 200.0);
+    }
+
+    #[test]
+    fn test_unused_share_is_redistributed() {
+        let a = job("http://a/", 1000, 100.0);
+        let b1 = job("http://b/", 1000, 100.0);
+        let b2 = job("http://b/", 1000, 100.0);
+        let resources = Resources {
+            ncpus: 4.0,
+            ..Default::default()
+        };
+
+        let v = simulate(
+            &epoch(),
+            &[a.clone(), b1.clone(), b2.clone()],
+            &shares(&[("http://a/", 300.0), ("http://b/", 100.0)]),
+            &resources,
+            100.0,
+        );
+
+        for job in &[a, b1, b2] {
+            assert_eq!(v.jobs[&job.task].completion, epoch() + common::Duration::seconds(100));
+        }
+        assert_eq!(v.saturated_time[&coproc::ProcType::CPU], 0.0);
+        assert_eq!(v.shortfall[&coproc::ProcType::CPU], 100.0);
+    }
+
+    #[test]
+    fn test_missing_coproc() {
+        let mut a = job("http://a/", 1000, 100.0);
+        a.coproc = Some((coproc::ProcType::AMDGraphics, 1.0));
+        let v = simulate(
+            &epoch(),
+            &[a.clone()],
+            &shares(&[("http://a/", 100.0)]),
+            &Default::default(),
+            100.0,
+        );
+        assert!(v.jobs[&a.task].deadline_at_risk);
+    }
+
+    #[test]
+    fn test_finished_job() {
+        let a = job("http://a/", 1000, 0.0);
+        let b = job("http://a/", 1000, 100.0);
+        let resources = Resources {
+            ncpus: 1.0,
+            ..Default::default()
+        };
+
+        let v = simulate(
+            &epoch(),
+            &[a.clone(), b.clone()],
+            &shares(&[("http://a/", 100.0)]),
+            &resources,
+            100.0,
+        );
+
+        assert_eq!(v.jobs[&a.task].completion, epoch());
+        assert!(!v.jobs[&a.task].deadline_at_risk);
+        assert_eq!(v.jobs[&b.task].completion, epoch() + common::Duration::seconds(100));
+        assert!(v.deadline_at_risk().is_empty());
+    }
+}
@@ -0,0 +1,316 @@
+extern crate libc;
+extern crate std;
+
+use cc_config;
+use errors;
+use workunit;
+
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+
+/// BOINC exit codes reported for tasks killed by the client
+pub const EXIT_DISK_LIMIT_EXCEEDED: i32 = 196;
+pub const EXIT_TIME_LIMIT_EXCEEDED: i32 = 197;
+pub const EXIT_MEM_LIMIT_EXCEEDED: i32 = 198;
+
+/// Which of the workunit's bounds a task went over
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Memory,
+    Disk,
+    CpuTime,
+}
+
+impl Limit {
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Limit::Memory => EXIT_MEM_LIMIT_EXCEEDED,
+            Limit::Disk => EXIT_DISK_LIMIT_EXCEEDED,
+            Limit::CpuTime => EXIT_TIME_LIMIT_EXCEEDED,
+        }
+    }
+}
+
+impl From<Limit> for errors::Error {
+    fn from(v: Limit) -> errors::Error {
+        errors::ErrorKind::ResourceLimitExceededError(
+            match v {
+                Limit::Memory => "memory usage",
+                Limit::Disk => "disk usage",
+                Limit::CpuTime => "CPU time",
+            }.into(),
+        ).into()
+    }
+}
+
+/// Bounds a task must stay within. Zero means unbounded.
+#[derive(Clone, Debug, Default)]
+pub struct ResourceLimits {
+    /// bytes of resident memory
+    pub memory_bound: f64,
+    /// bytes used in the slot directory
+    pub disk_bound: f64,
+    /// seconds of CPU time
+    pub cpu_time_bound: f64,
+}
+
+impl ResourceLimits {
+    /// Derives limits from the workunit. The FLOP bound is converted into CPU time using the host's measured speed.
+    pub fn new(wu: &workunit::Workunit, p_fpops: f64) -> Self {
+        Self {
+            memory_bound: wu.rsc_memory_bound,
+            disk_bound: wu.rsc_disk_bound,
+            cpu_time_bound: if p_fpops > 0.0 {
+                wu.rsc_fpops_bound / p_fpops
+            } else {
+                0.0
+            },
+        }
+    }
+
+    pub fn check(&self, usage: &Usage) -> Option<Limit> {
+        if self.memory_bound > 0.0 && usage.memory > self.memory_bound {
+            Some(Limit::Memory)
+        } else if self.disk_bound > 0.0 && usage.disk > self.disk_bound {
+            Some(Limit::Disk)
+        } else if self.cpu_time_bound > 0.0 && usage.cpu_time > self.cpu_time_bound {
+            Some(Limit::CpuTime)
+        } else {
+            None
+        }
+    }
+}
+
+/// Resources consumed by a running task
+#[derive(Clone, Debug, Default)]
+pub struct Usage {
+    pub memory: f64,
+    pub disk: f64,
+    pub cpu_time: f64,
+}
+
+/// Maps `process_priority` from cc_config.xml onto a nice value. `None` leaves the priority untouched.
+pub fn niceness(cc_config: &cc_config::CCConfig) -> Option<i32> {
+    if cc_config.no_priority_change {
+        return None;
+    }
+
+    let v = match cc_config.process_priority {
+        1 => 10,
+        2...4 => 0,
+        _ => 19,
+    };
+
+    if cc_config.lower_client_priority {
+        Some(std::cmp::max(v, 10))
+    } else {
+        Some(v)
+    }
+}
+
+fn setrlimit(resource: libc::c_int, v: f64) -> std::io::Result<()> {
+    if v <= 0.0 {
+        return Ok(());
+    }
+
+    let lim = libc::rlimit {
+        rlim_cur: v.ceil() as libc::rlim_t,
+        rlim_max: v.ceil() as libc::rlim_t,
+    };
+    if unsafe { libc::setrlimit(resource, &lim) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes the command start in its own process group with the task's niceness applied.
+///
+/// The kernel only enforces the CPU time bound, reduced by the time the task used in earlier runs.
+/// Memory is not limited here since address space says little about actual usage - `ResourceLimits::check` covers it.
+pub fn confine(
+    cmd: &mut std::process::Command,
+    limits: &ResourceLimits,
+    cpu_time_used: f64,
+    niceness: Option<i32>,
+) {
+    let cpu_time_limit = if limits.cpu_time_bound > 0.0 {
+        (limits.cpu_time_bound - cpu_time_used).max(1.0)
+    } else {
+        0.0
+    };
+    cmd.before_exec(move || {
+        if unsafe { libc::setpgid(0, 0) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        setrlimit(libc::RLIMIT_CPU, cpu_time_limit)?;
+
+        // Priority is only ever lowered, and failing to lower it is no reason not to run the task
+        if let Some(v) = niceness {
+            unsafe {
+                let current = libc::getpriority(libc::PRIO_PROCESS, 0);
+                libc::setpriority(libc::PRIO_PROCESS, 0, std::cmp::max(current, v));
+            }
+        }
+
+        Ok(())
+    });
+}
+
+/// Sends a signal to every process in the group.
+pub fn signal_group(pgid: i32, signal: libc::c_int) -> errors::Result<()> {
+    if unsafe { libc::killpg(pgid, signal) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+fn read_proc(pid: i32, name: &str) -> errors::Result<String> {
+    let mut s = String::new();
+    std::fs::File::open(format!("/proc/{}/{}", pid, name))?.read_to_string(&mut s)?;
+    Ok(s)
+}
+
+/// Process group and CPU clock ticks used by the process and its reaped children, from the contents of /proc/<pid>/stat.
+fn parse_stat(s: &str) -> errors::Result<(i32, f64)> {
+    // Command name may contain spaces, skip past it
+    let fields = s.rsplitn(2, ')')
+        .next()
+        .unwrap_or("")
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let malformed = || errors::Error::from(errors::ErrorKind::InternalError("Malformed stat".into()));
+    // pgrp is field 5, utime, stime, cutime and cstime are fields 14-17 counting from the pid
+    let pgrp = fields
+        .get(2)
+        .and_then(|v| v.parse::<i32>().ok())
+        .ok_or_else(&malformed)?;
+    let ticks = fields
+        .get(11..15)
+        .ok_or_else(&malformed)?
+        .iter()
+        .map(|v| v.parse::<f64>().unwrap_or(0.0))
+        .sum::<f64>();
+    Ok((pgrp, ticks))
+}
+
+/// Live processes in the group.
+fn group_pids(pgid: i32) -> errors::Result<Vec<i32>> {
+    let mut v = Vec::new();
+    for entry in std::fs::read_dir("/proc")? {
+        let pid = match entry?.file_name().to_str().and_then(|s| s.parse::<i32>().ok()) {
+            Some(pid) => pid,
+            None => {
+                continue;
+            }
+        };
+        // The process may exit while we look
+        if let Ok(Ok((pgrp, _))) = read_proc(pid, "stat").map(|s| parse_stat(&s)) {
+            if pgrp == pgid {
+                v.push(pid);
+            }
+        }
+    }
+    Ok(v)
+}
+
+/// CPU time used by every process in the group and their reaped children, in seconds.
+pub fn cpu_time(pgid: i32) -> errors::Result<f64> {
+    let mut ticks = 0.0;
+    for pid in group_pids(pgid)? {
+        ticks += read_proc(pid, "stat")
+            .and_then(|s| parse_stat(&s))
+            .map(|v| v.1)
+            .unwrap_or(0.0);
+    }
+    let clk_tck = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
+    Ok(ticks / clk_tck)
+}
+
+/// Resident memory of every process in the group, in bytes.
+pub fn resident_memory(pgid: i32) -> errors::Result<f64> {
+    let mut pages = 0.0;
+    for pid in group_pids(pgid)? {
+        pages += read_proc(pid, "statm")
+            .ok()
+            .and_then(|s| s.split_whitespace().nth(1).and_then(|v| v.parse::<f64>().ok()))
+            .unwrap_or(0.0);
+    }
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as f64;
+    Ok(pages * page_size)
+}
+
+/// Total size of all files under the directory.
+pub fn dir_usage(path: &std::path::Path) -> errors::Result<f64> {
+    let mut total = 0.0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let md = entry.metadata()?;
+        if md.is_dir() {
+            total += dir_usage(&entry.path())?;
+        } else {
+            total += md.len() as f64;
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        let limits = ResourceLimits {
+            memory_bound: 100.0,
+            disk_bound: 200.0,
+            cpu_time_bound: 0.0,
+        };
+        let usage = |memory: f64, disk: f64, cpu_time: f64| Usage {
+            memory: memory,
+            disk: disk,
+            cpu_time: cpu_time,
+        };
+        assert_eq!(limits.check(&usage(100.0, 200.0, 1e9)), None);
+        assert_eq!(limits.check(&usage(101.0, 0.0, 0.0)), Some(Limit::Memory));
+        assert_eq!(limits.check(&usage(0.0, 201.0, 0.0)), Some(Limit::Disk));
+
+        let limits = ResourceLimits {
+            cpu_time_bound: 10.0,
+            ..Default::default()
+        };
+        assert_eq!(limits.check(&usage(1e9, 1e9, 10.0)), None);
+        assert_eq!(limits.check(&usage(0.0, 0.0, 10.5)), Some(Limit::CpuTime));
+    }
+
+    #[test]
+    fn test_niceness() {
+        let mut config = cc_config::CCConfig::default();
+        assert_eq!(niceness(&config), Some(19));
+
+        config.process_priority = 1;
+        assert_eq!(niceness(&config), Some(10));
+        config.process_priority = 3;
+        assert_eq!(niceness(&config), Some(0));
+
+        config.lower_client_priority = true;
+        assert_eq!(niceness(&config), Some(10));
+
+        config.no_priority_change = true;
+        assert_eq!(niceness(&config), None);
+    }
+
+    #[test]
+    fn test_parse_stat() {
+        let s = "4242 (my app (v2)) R 1 4240 4240 0 -1 4194304 100 0 0 0 150 50 30 20 20 0 1 0 100 0 0";
+        assert_eq!(parse_stat(s).unwrap(), (4240, 250.0));
+        assert!(parse_stat("4242 (app) R 1").is_err());
+    }
+
+    #[test]
+    fn test_cpu_time() {
+        let pgid = unsafe { libc::getpgrp() };
+        assert!(cpu_time(pgid).unwrap() >= 0.0);
+        assert!(resident_memory(pgid).unwrap() > 0.0);
+    }
+}
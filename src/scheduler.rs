@@ -0,0 +1,418 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+
+use app;
+use constants;
+use coproc;
+use errors;
+use file_info;
+use hostinfo;
+use http;
+use projects;
+use result;
+use work_fetch;
+use workunit;
+
+use self::treexml_util::{make_text_element, make_tree_element, Unmarshaller};
+
+/// Version reported to schedulers, which use it to decide what work the client can handle
+pub const CORE_CLIENT_MAJOR_VERSION: u32 = 7;
+pub const CORE_CLIENT_MINOR_VERSION: u32 = 16;
+pub const CORE_CLIENT_RELEASE: u32 = 0;
+
+fn attr_value<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let pos = match tag.to_ascii_lowercase().find(&format!("{}=", name)) {
+        Some(v) => v,
+        None => {
+            return None;
+        }
+    };
+    let rest = &tag[pos + name.len() + 1..];
+    match rest.chars().next() {
+        Some(quote) if quote == '"' || quote == '\'' => rest[1..].find(quote).map(|end| &rest[1..end + 1]),
+        Some(_) => Some(
+            rest.find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                .map(|end| &rest[..end])
+                .unwrap_or(rest),
+        ),
+        None => None,
+    }
+}
+
+/// Scheduler URLs announced by a project's master page, in `<scheduler>` elements
+/// or `<link rel="boinc_scheduler">` tags.
+pub fn find_scheduler_urls(page: &str) -> Vec<String> {
+    let mut out = Vec::new();
+    let lower = page.to_ascii_lowercase();
+
+    let mut pos = 0;
+    while let Some(start) = lower[pos..].find("<scheduler>") {
+        let start = pos + start + "<scheduler>".len();
+        match lower[start..].find("</scheduler>") {
+            Some(end) => {
+                out.push(page[start..start + end].trim().to_string());
+                pos = start + end;
+            }
+            None => {
+                break;
+            }
+        }
+    }
+
+    pos = 0;
+    while let Some(start) = lower[pos..].find("<link") {
+        let start = pos + start;
+        let end = lower[start..].find('>').map(|v| start + v).unwrap_or(page.len());
+        let tag = &page[start..end];
+        if attr_value(tag, "rel").map(|v| v.eq_ignore_ascii_case("boinc_scheduler")) == Some(true) {
+            if let Some(href) = attr_value(tag, "href") {
+                out.push(href.trim().to_string());
+            }
+        }
+        pos = end;
+    }
+
+    let mut seen = std::collections::HashSet::new();
+    out.into_iter()
+        .filter(|v| !v.is_empty() && seen.insert(v.clone()))
+        .collect()
+}
+
+/// `<scheduler_request>` describing the host, asking for the work in `work_req` if any
+/// and reporting the finished results
+pub fn make_request(
+    project: &projects::ProjectData,
+    host_info: &hostinfo::HostInfo,
+    coprocs: &coproc::Coprocs,
+    alt_platforms: &[String],
+    work_req: Option<&work_fetch::WorkRequest>,
+    results: Vec<treexml::Element>,
+) -> treexml::Element {
+    let mut children = vec![
+        make_text_element("authenticator", &project.authenticator),
+        make_text_element("hostid", project.hostid),
+        make_text_element("rpc_seqno", project.rpc_seqno),
+        make_text_element("core_client_major_version", CORE_CLIENT_MAJOR_VERSION),
+        make_text_element("core_client_minor_version", CORE_CLIENT_MINOR_VERSION),
+        make_text_element("core_client_release", CORE_CLIENT_RELEASE),
+        make_text_element("platform_name", constants::PRIMARY_PLATFORM),
+    ];
+    for p in alt_platforms {
+        children.push(make_tree_element("alt_platform", vec![make_text_element("name", p)]));
+    }
+    match work_req {
+        Some(v) => {
+            children.append(&mut v.xml_fields());
+        }
+        None => {
+            children.push(make_text_element("work_req_seconds", 0));
+        }
+    }
+    children.push(host_info.into());
+    children.push(coprocs_element(coprocs, work_req));
+    children.extend(results);
+    make_tree_element("scheduler_request", children)
+}
+
+/// The host's usable coprocessors, each with the work requested for it
+fn coprocs_element(coprocs: &coproc::Coprocs, work_req: Option<&work_fetch::WorkRequest>) -> treexml::Element {
+    let mut v = coprocs
+        .data
+        .iter()
+        .filter(|&(rsc, c)| *rsc != coproc::ProcType::CPU && c.count() > 0)
+        .collect::<Vec<_>>();
+    v.sort_by_key(|&(rsc, _)| rsc.rsc_index());
+    make_tree_element(
+        "coprocs",
+        v.into_iter()
+            .map(|(rsc, c)| {
+                let req = work_req.map(|w| w.get(rsc)).unwrap_or_default();
+                make_tree_element(
+                    "coproc",
+                    vec![
+                        make_text_element("type", rsc.rsc_name()),
+                        make_text_element("count", c.count()),
+                        make_text_element("model", &c.model),
+                        make_text_element("req_secs", req.secs),
+                        make_text_element("req_instances", req.instances),
+                    ],
+                )
+            })
+            .collect(),
+    )
+}
+
+/// Parts of `<scheduler_reply>` that concern the account, the host and the jobs sent
+#[derive(Debug, Default)]
+pub struct SchedulerReply {
+    pub project_name: String,
+    pub user_name: String,
+    pub team_name: String,
+    pub userid: u64,
+    pub hostid: u64,
+    /// seconds to wait before the next request
+    pub request_delay: f64,
+    pub project_is_down: bool,
+    /// bytes of disk the project would like to use
+    pub desired_disk_usage: Option<f64>,
+    pub host_venue: Option<String>,
+    pub project_prefs: Option<treexml::Element>,
+    /// messages from the project along with their priority
+    pub messages: Vec<(String, String)>,
+    pub apps: Vec<app::App>,
+    pub app_versions: Vec<app::AppVersion>,
+    pub workunits: Vec<workunit::Workunit>,
+    pub file_infos: Vec<file_info::FileInfo>,
+    /// `<result>` elements of the jobs sent
+    pub results: Vec<treexml::Element>,
+    /// names of the reported results the project has received
+    pub result_acks: std::collections::HashSet<String>,
+}
+
+impl SchedulerReply {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<SchedulerReply> {
+        if root.name != "scheduler_reply" {
+            bail!(errors::ErrorKind::XMLError(format!("unexpected reply: {}", root.name).into()));
+        }
+
+        let mut v = SchedulerReply::default();
+        for node in &root.children {
+            match &*node.name {
+                "project_name" => {
+                    let _ = v.project_name.unmarshal(&node);
+                }
+                "user_name" => {
+                    let _ = v.user_name.unmarshal(&node);
+                }
+                "team_name" => {
+                    let _ = v.team_name.unmarshal(&node);
+                }
+                "userid" => {
+                    let _ = v.userid.unmarshal(&node);
+                }
+                "hostid" => {
+                    let _ = v.hostid.unmarshal(&node);
+                }
+                "request_delay" => {
+                    let _ = v.request_delay.unmarshal(&node);
+                }
+                "project_is_down" => {
+                    v.project_is_down = true;
+                }
+                "desired_disk_usage" => {
+                    let mut n = 0.0f64;
+                    if n.unmarshal(&node).is_ok() {
+                        v.desired_disk_usage = Some(n);
+                    }
+                }
+                "host_venue" => {
+                    let mut venue = String::new();
+                    let _ = venue.unmarshal(&node);
+                    v.host_venue = Some(venue);
+                }
+                "project_preferences" => {
+                    v.project_prefs = Some(node.clone());
+                }
+                "app" => {
+                    let mut name = String::new();
+                    let mut user_friendly_name = String::new();
+                    for child in &node.children {
+                        match &*child.name {
+                            "name" => {
+                                let _ = name.unmarshal(&child);
+                            }
+                            "user_friendly_name" => {
+                                let _ = user_friendly_name.unmarshal(&child);
+                            }
+                            _ => {}
+                        }
+                    }
+                    if !name.is_empty() {
+                        v.apps.push(app::App::new(name, user_friendly_name));
+                    }
+                }
+                "app_version" => {
+                    if let Ok(av) = app::AppVersion::try_from(&node) {
+                        v.app_versions.push(av);
+                    }
+                }
+                "workunit" => {
+                    if let Ok(wu) = workunit::Workunit::try_from(&node) {
+                        v.workunits.push(wu);
+                    }
+                }
+                "file_info" => {
+                    v.file_infos.push(file_info::FileInfo::from(node));
+                }
+                "result" => {
+                    v.results.push(node.clone());
+                }
+                "message" => {
+                    v.messages.push((
+                        node.attributes
+                            .get("priority")
+                            .cloned()
+                            .unwrap_or_else(|| "low".into()),
+                        node.text.clone().unwrap_or_default().trim().to_string(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        v.result_acks = result::acked_results(root);
+        Ok(v)
+    }
+
+    /// The scheduler recognized the account
+    pub fn accepted(&self) -> bool {
+        self.userid > 0
+    }
+
+    /// Resources the jobs sent run on, going by the app versions in the reply. Jobs of other app versions count as CPU jobs.
+    pub fn resources_sent(&self) -> std::collections::HashSet<coproc::ProcType> {
+        self.results
+            .iter()
+            .filter_map(|node| result::Result::try_from(node, "").ok())
+            .map(|r| {
+                let app_name = self.workunits
+                    .iter()
+                    .find(|wu| wu.name == r.wu_name)
+                    .map(|wu| wu.app_name.clone())
+                    .unwrap_or_default();
+                self.app_versions
+                    .iter()
+                    .find(|av| {
+                        av.app_name == app_name
+                            && (r.app_version.version_num == 0 || av.version_num == r.app_version.version_num)
+                            && av.plan_class == r.app_version.plan_class
+                    })
+                    .and_then(|av| av.coproc)
+                    .map(|(rsc, _)| rsc)
+                    .unwrap_or(coproc::ProcType::CPU)
+            })
+            .collect()
+    }
+}
+
+pub fn rpc(scheduler_url: &str, request: &treexml::Element) -> errors::Result<SchedulerReply> {
+    let body = http::post(scheduler_url, format!("{}", request).as_bytes())?;
+    let mut reply = SchedulerReply::try_from(&http::parse_xml(&body)?)?;
+    // Parsing loses the exact text of the file descriptions the project signed
+    let texts = file_info::signed_texts(&String::from_utf8_lossy(&body));
+    for (fi, text) in reply.file_infos.iter_mut().zip(texts.into_iter()) {
+        fi.signed_xml = Some(text);
+    }
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_scheduler_urls() {
+        let page = "<html><head>\n\
+                    <link rel=\"boinc_scheduler\" href=\"http://a/cgi-bin/cgi\">\n\
+                    <LINK rel='stylesheet' href='style.css'>\n\
+                    </head><body>\n\
+                    <!-- <scheduler>http://a/cgi-bin/cgi</scheduler> -->\n\
+                    <scheduler> http://b/cgi </scheduler>\n\
+                    </body></html>";
+        assert_eq!(
+            find_scheduler_urls(page),
+            vec!["http://a/cgi-bin/cgi".to_string(), "http://b/cgi".to_string()]
+        );
+        assert!(find_scheduler_urls("<html></html>").is_empty());
+    }
+
+    #[test]
+    fn test_rpc() {
+        let (url, server) = http::serve_once(
+            200,
+            "<scheduler_reply>\n\
+             <project_name>Test Project</project_name>\n\
+             <user_name>user</user_name>\n\
+             <userid>12</userid>\n\
+             <hostid>34</hostid>\n\
+             <host_venue>home</host_venue>\n\
+             <project_preferences><resource_share>50</resource_share></project_preferences>\n\
+             <message priority=\"low\">Welcome</message>\n\
+             <app><name>app</name><user_friendly_name>App</user_friendly_name></app>\n\
+             <file_info><name>app_1</name><url>http://a/app_1</url><executable/></file_info>\n\
+             <app_version><app_name>app</app_name><version_num>1</version_num>\n\
+             <file_ref><file_name>app_1</file_name><main_program/></file_ref></app_version>\n\
+             <app_version><app_name>app</app_name><version_num>1</version_num><plan_class>cuda</plan_class>\n\
+             <coproc><type>NVIDIA</type><count>1</count></coproc>\n\
+             <file_ref><file_name>app_1_cuda</file_name><main_program/></file_ref></app_version>\n\
+             <workunit><name>wu</name><app_name>app</app_name></workunit>\n\
+             <result><name>r1</name><wu_name>wu</wu_name><plan_class>cuda</plan_class></result>\n\
+             <result_ack><name>r0</name></result_ack>\n\
+             </scheduler_reply>",
+        );
+
+        let mut project = projects::ProjectData::default();
+        project.authenticator = "abc".into();
+        let mut work_req = work_fetch::WorkRequest::default();
+        work_req.rsc.insert(
+            coproc::ProcType::CPU,
+            work_fetch::RscRequest {
+                secs: 3600.0,
+                instances: 2.0,
+            },
+        );
+        work_req.rsc.insert(
+            coproc::ProcType::NVIDIAGraphics,
+            work_fetch::RscRequest {
+                secs: 600.0,
+                instances: 1.0,
+            },
+        );
+        let mut coprocs = coproc::Coprocs::default();
+        coprocs.data.insert(
+            coproc::ProcType::NVIDIAGraphics,
+            coproc::Coproc {
+                devices: vec![0],
+                ..Default::default()
+            },
+        );
+        let v = rpc(
+            &format!("{}cgi", url),
+            &make_request(
+                &project,
+                &hostinfo::HostInfo::default(),
+                &coprocs,
+                &["i686-pc-linux-gnu".into()],
+                Some(&work_req),
+                vec![make_tree_element("result", vec![make_text_element("name", "r0")])],
+            ),
+        ).unwrap();
+
+        let (_, body) = server.join().unwrap();
+        assert!(body.contains("<authenticator>abc</authenticator>"));
+        assert!(body.contains("<name>i686-pc-linux-gnu</name>"));
+        assert!(body.contains("<work_req_seconds>3600</work_req_seconds>"));
+        assert!(body.contains("<cpu_req_instances>2</cpu_req_instances>"));
+        assert!(body.contains("<coprocs><coproc><type>NVIDIA</type><count>1</count>"));
+        assert!(body.contains("<req_secs>600</req_secs><req_instances>1</req_instances></coproc></coprocs>"));
+        assert!(body.contains("<result><name>r0</name></result>"));
+        assert_eq!(v.apps.len(), 1);
+        assert_eq!(v.app_versions[0].file_name, "app_1");
+        assert_eq!(v.workunits[0].name, "wu");
+        assert!(v.file_infos[0].executable);
+        assert_eq!(
+            v.file_infos[0].signed_xml,
+            Some("<name>app_1</name><url>http://a/app_1</url><executable/>".into())
+        );
+        assert_eq!(v.results.len(), 1);
+        assert_eq!(v.resources_sent(), vec![coproc::ProcType::NVIDIAGraphics].into_iter().collect());
+        assert!(v.result_acks.contains("r0"));
+
+        assert!(v.accepted());
+        assert_eq!(v.project_name, "Test Project");
+        assert_eq!(v.hostid, 34);
+        assert_eq!(v.host_venue, Some("home".into()));
+        assert!(v.project_prefs.is_some());
+        assert_eq!(v.messages, vec![("low".to_string(), "Welcome".to_string())]);
+    }
+}
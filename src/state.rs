@@ -1,31 +1,55 @@
 extern crate chan;
+extern crate futures;
 extern crate std;
 extern crate treexml;
+extern crate treexml_util;
 extern crate uuid;
 
 use acct_mgr;
 use acct_setup;
+use app;
 use cc_config;
 use common;
 use constants;
+use coproc;
+use coproc_detect;
+use cpu_sched;
+use crypt;
 use errors;
+use estimate;
+use exclusive_apps;
 use file_info;
 use file_names;
+use file_xfer;
 use hostinfo;
+use http;
+use idle;
 use messages;
+use power;
+use prefs;
 use project_init;
 use projects;
+use result;
+use rr_sim;
+use sandbox;
+use scheduler;
 use tasks;
+use throttle;
 use util;
+use work_fetch;
+use workunit;
 
 use std::io::Write;
 use std::ops::Deref;
 
-use std::collections::HashMap;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
 
 use common::*;
 
+use self::futures::Future;
+use self::treexml_util::make_text_element;
+
 #[derive(Clone)]
 pub struct RunSettings {
     clock_source: Arc<ClockSource>,
@@ -77,24 +101,42 @@ pub struct ClientState {
     pub clock_source: Box<ClockSource>,
 
     pub cc_config: cc_config::CCConfig,
+    pub global_prefs: prefs::GlobalPrefs,
     pub messages: messages::SafeLogger,
 
     pub host_info: hostinfo::HostInfo,
+    pub coprocs: coproc::Coprocs,
     pub projects: projects::Projects,
     pub file_infos: HashMap<uuid::Uuid, file_info::FileInfo>,
+    pub results: HashMap<uuid::Uuid, result::Result>,
 
     pub project_attach: acct_setup::ProjectAttach,
+    /// account lookups and creations started through the GUI RPC
+    pub account_ops: HashMap<acct_setup::AccountRpc, acct_setup::AccountOp>,
+    /// configuration of the project about to be attached
+    pub project_config: acct_setup::ProjectConfigOp,
     pub project_init: Option<project_init::ProjectInit>,
 
     pub acct_mgr_info: acct_mgr::AcctMgrInfo,
+    pub acct_mgr_op: acct_mgr::AcctMgrOp,
 
-    pub tasks: Box<tasks::TaskServer + Send + Sync + 'static>,
+    pub tasks: Arc<tasks::TaskServer + Send + Sync + 'static>,
+    pub cpu_sched: cpu_sched::CpuScheduler,
+    pub throttler: throttle::Throttler,
+    /// outcome of the last round-robin simulation
+    pub rr_sim: rr_sim::RRSimOutput,
 
     pub run_mode: RunSettings,
     pub gpu_run_mode: RunSettings,
 
-    pub suspend_reason: Option<RpcReason>,
-    pub gpu_suspend_reason: Option<RpcReason>,
+    pub suspend_reason: Option<SuspendReason>,
+    pub gpu_suspend_reason: Option<SuspendReason>,
+    pub exclusive_apps: exclusive_apps::ExclusiveAppMonitor,
+    pub idle_detector: idle::IdleDetector,
+    /// seconds since the last user input
+    pub idle_time: f64,
+    pub power_monitor: power::PowerMonitor,
+    pub power_status: power::PowerStatus,
 }
 
 impl<'a> From<&'a ClientState> for treexml::Element {
@@ -105,57 +147,474 @@ impl<'a> From<&'a ClientState> for treexml::Element {
         treexml::Element {
             name: "client_state".into(),
             children: {
-                let mut v = Vec::new();
-                v.push(host_info.deref().into());
-                v.append(&mut projects.deref().data.iter().map(|v| v.into()).collect());
-                v
+                let mut children = Vec::new();
+                children.push(host_info.deref().into());
+                children.append(&mut projects.deref().data.iter().map(|v| v.into()).collect());
+                children.append(&mut v.result_elements());
+                children
             },
             ..Default::default()
         }
     }
 }
 
+#[cfg(test)]
 impl Default for ClientState {
     fn default() -> Self {
-        let messages: messages::SafeLogger = Arc::new(messages::DummyLogger::default());
+        Self::new(
+            Arc::new(messages::DummyLogger::default()),
+            Arc::new(tasks::MockTaskServer::default()),
+        )
+    }
+}
+
+/// Reads cc_config.xml, logging failures. Options take effect for what starts after loading,
+/// so this comes before anything else at startup.
+pub fn read_cc_config(messages: &messages::SafeLogger) -> cc_config::CCConfig {
+    match cc_config::CCConfig::read(std::path::Path::new(".")) {
+        Ok(v) => v,
+        Err(e) => {
+            messages.insert(
+                None,
+                MessagePriority::InternalError,
+                std::time::SystemTime::now().into(),
+                &format!("Can't read config file: {}", e),
+            );
+            Default::default()
+        }
+    }
+}
+
+/// Syncs with the account manager. The state is only locked while building the request
+/// and applying the reply, not while waiting for the account manager.
+pub fn sync_acct_mgr(lock: &RwLock<Option<ClientState>>) {
+    let (url, request) = match lock.write().unwrap().as_mut() {
+        Some(state) => {
+            state.acct_mgr_op.in_progress = true;
+            (state.acct_mgr_info.master_url.clone(), state.acct_mgr_request())
+        }
+        None => {
+            return;
+        }
+    };
+
+    let reply = acct_mgr::rpc(&url, &request);
+
+    if let Some(state) = lock.write().unwrap().as_mut() {
+        state.handle_acct_mgr_reply(reply);
+    }
+}
+
+/// Looks up or creates an account on the project without holding the state lock while waiting for it.
+pub fn run_account_rpc(lock: &RwLock<Option<ClientState>>, rpc: acct_setup::AccountRpc, v: &acct_setup::AccountIn) {
+    let reply = acct_setup::call(rpc, v);
+    if let Some(state) = lock.write().unwrap().as_mut() {
+        state.finish_account_rpc(rpc, reply);
+    }
+}
+
+/// Fetches the project's configuration without holding the state lock while waiting for it.
+pub fn fetch_project_config(lock: &RwLock<Option<ClientState>>, url: &str) {
+    let config = acct_setup::get_project_config(url);
+    if let Some(state) = lock.write().unwrap().as_mut() {
+        // A request for another project may have been made in the meantime
+        if state.project_config.url == url {
+            state.project_config.in_progress = false;
+            state.project_config.config = Some(config);
+        }
+    }
+}
+
+fn attach_error(code: i64, msg: String) -> errors::Error {
+    errors::ErrorKind::AttachError(code, msg).into()
+}
+
+/// Fetches the master page and makes the first scheduler request for a freshly added project.
+fn contact_new_project(lock: &RwLock<Option<ClientState>>, url: &str, key: &str) -> errors::Result<()> {
+    let page = http::get(url).map_err(|e| {
+        attach_error(
+            acct_setup::ERR_ATTACH_FAIL_DOWNLOAD,
+            format!("Can't fetch master page: {}", e),
+        )
+    })?;
+    let scheduler_urls = scheduler::find_scheduler_urls(&String::from_utf8_lossy(&page));
+    if scheduler_urls.is_empty() {
+        bail!(errors::ErrorKind::AttachError(
+            acct_setup::ERR_ATTACH_FAIL_PARSE,
+            "Master page does not name a scheduler".into(),
+        ));
+    }
+
+    let request = match lock.write().unwrap().as_mut() {
+        Some(state) => {
+            state.project_attach.messages.push("Fetched master page".into());
+            let project = match state.projects.find_by_url(key) {
+                Some(v) => v,
+                None => bail!(errors::ErrorKind::InternalError("project was detached".into())),
+            };
+            let mut data = project.data.lock().unwrap();
+            data.scheduler_urls = scheduler_urls.clone();
+            data.master_url_fetch_pending = false;
+            scheduler::make_request(
+                &data,
+                &state.host_info,
+                &state.coprocs,
+                &state.cc_config.alt_platforms,
+                None,
+                Vec::new(),
+            )
+        }
+        None => bail!(errors::ErrorKind::InternalError("client is shutting down".into())),
+    };
+
+    let mut reply = Err(errors::ErrorKind::InternalError("no scheduler".into()).into());
+    for scheduler_url in &scheduler_urls {
+        reply = scheduler::rpc(scheduler_url, &request);
+        if reply.is_ok() {
+            break;
+        }
+    }
+    let reply = reply.map_err(|e| {
+        attach_error(
+            acct_setup::ERR_ATTACH_FAIL_SERVER_ERROR,
+            format!("Scheduler request failed: {}", e),
+        )
+    })?;
+
+    let mut guard = lock.write().unwrap();
+    let state = match guard.as_mut() {
+        Some(v) => v,
+        None => bail!(errors::ErrorKind::InternalError("client is shutting down".into())),
+    };
+    for &(_, ref msg) in &reply.messages {
+        state.project_attach.messages.push(msg.clone());
+    }
+    if !reply.accepted() {
+        bail!(errors::ErrorKind::AttachError(
+            acct_setup::ERR_ATTACH_FAIL_BAD_KEY,
+            "Project did not accept the account key".into(),
+        ));
+    }
+
+    let now = state.clock_source.now();
+    let project = match state.projects.find_by_url(key) {
+        Some(v) => v,
+        None => bail!(errors::ErrorKind::InternalError("project was detached".into())),
+    };
+    apply_scheduler_reply(&mut project.data.lock().unwrap(), &reply, &now);
+    // The account file keeps the project name and preferences from the reply
+    project.write_account_file(std::path::Path::new("."))
+}
+
+/// Takes over the account and host details from a successful scheduler reply.
+fn apply_scheduler_reply(data: &mut projects::ProjectData, reply: &scheduler::SchedulerReply, now: &Time) {
+    if !reply.project_name.is_empty() {
+        data.project_name = Some(reply.project_name.clone());
+    }
+    data.user_name = reply.user_name.clone();
+    data.team_name = reply.team_name.clone();
+    data.userid = reply.userid;
+    if reply.hostid > 0 {
+        data.hostid = reply.hostid;
+    }
+    if let Some(v) = reply.desired_disk_usage {
+        data.desired_disk_usage = v;
+    }
+    if let Some(ref venue) = reply.host_venue {
+        data.host_venue = venue.clone();
+    }
+    if let Some(ref prefs) = reply.project_prefs {
+        data.project_prefs = Some(prefs.clone());
+    }
+    data.apply_project_prefs();
+    data.rpc_seqno += 1;
+    data.nrpc_failures = 0;
+    data.last_rpc_time = Some(*now);
+    data.min_rpc_time = Some(*now + Duration::seconds(reply.request_delay as i64));
+    data.sched_rpc_pending = None;
+}
+
+/// Scheduler request chosen by `ClientState::scheduler_request`
+pub struct SchedulerRpc {
+    pub project_url: String,
+    pub scheduler_urls: Vec<String>,
+    pub request: treexml::Element,
+    pub work_req: Option<work_fetch::WorkRequest>,
+}
+
+/// Contacts the scheduler of the project that needs it most, if any. The state is not locked while
+/// waiting for the scheduler. The master page is fetched first if the scheduler URLs are not known.
+pub fn contact_scheduler(lock: &RwLock<Option<ClientState>>) {
+    let rpc = match lock.read().unwrap().as_ref() {
+        Some(state) => state.scheduler_request(),
+        None => None,
+    };
+    let rpc = match rpc {
+        Some(v) => v,
+        None => {
+            return;
+        }
+    };
+
+    let scheduler_urls = if rpc.scheduler_urls.is_empty() {
+        http::get(&rpc.project_url)
+            .map(|page| scheduler::find_scheduler_urls(&String::from_utf8_lossy(&page)))
+            .unwrap_or_default()
+    } else {
+        rpc.scheduler_urls.clone()
+    };
+    let mut reply = Err(errors::ErrorKind::InternalError("Project has no scheduler".into()).into());
+    for url in &scheduler_urls {
+        reply = scheduler::rpc(url, &rpc.request);
+        if reply.is_ok() {
+            break;
+        }
+    }
+
+    if let Some(state) = lock.write().unwrap().as_mut() {
+        state.finish_scheduler_rpc(&rpc, scheduler_urls, reply);
+    }
+}
+
+/// Downloads and uploads the files that are due, one after another. The state is not locked while transferring.
+pub fn transfer_files(lock: &RwLock<Option<ClientState>>) {
+    let xfers = match lock.read().unwrap().as_ref() {
+        Some(state) => {
+            let xfers = state.file_transfers();
+            // Downloads are written into the project directory
+            for &(_, ref xfer) in xfers.iter().filter(|&&(_, ref v)| !v.upload) {
+                if let Some(project) = state.projects.find_by_url(&xfer.info.project_url) {
+                    let _ = project.make_project_dir();
+                }
+            }
+            xfers
+        }
+        None => {
+            return;
+        }
+    };
+
+    for (id, xfer) in xfers {
+        let outcome = xfer.run();
+        match lock.write().unwrap().as_mut() {
+            Some(state) => state.finish_file_transfer(&id, xfer.upload, outcome),
+            None => {
+                return;
+            }
+        }
+    }
+}
+
+/// Disk use found by scanning the client's directories
+#[derive(Debug)]
+pub struct DiskUsage {
+    /// total and free space of the filesystem
+    pub space: errors::Result<(f64, f64)>,
+    pub client: f64,
+    /// usage of each project directory by master URL
+    pub projects: HashMap<String, f64>,
+}
+
+impl DiskUsage {
+    /// Scans the client directory and the given project directories.
+    pub fn measure(project_dirs: &[(String, std::path::PathBuf)]) -> Self {
+        let dir = std::path::Path::new(".");
+        Self {
+            space: hostinfo::disk_space(dir),
+            client: sandbox::dir_usage(dir).unwrap_or(0.0),
+            projects: project_dirs
+                .iter()
+                .map(|&(ref url, ref path)| (url.clone(), sandbox::dir_usage(path).unwrap_or(0.0)))
+                .collect(),
+        }
+    }
+}
+
+/// Updates disk usage without holding the lock while the directories are scanned.
+pub fn update_disk_usage(lock: &RwLock<Option<ClientState>>) {
+    let project_dirs = match lock.read().unwrap().as_ref() {
+        Some(state) => state.project_dirs(),
+        None => {
+            return;
+        }
+    };
+
+    let usage = DiskUsage::measure(&project_dirs);
+
+    if let Some(state) = lock.write().unwrap().as_mut() {
+        state.apply_disk_usage(usage);
+    }
+}
+
+/// Attaches to a project in the background: adds it, fetches its master page and makes
+/// the first scheduler request. Progress goes to `project_attach`, and a failed attach
+/// leaves no project behind.
+pub fn attach_project(lock: &RwLock<Option<ClientState>>, url: &str, authenticator: &str, project_name: &str) {
+    let key = util::canonicalize_url(url);
+    let added = match lock.write().unwrap().as_mut() {
+        Some(state) => {
+            let v = state.add_project(url, authenticator, project_name, false);
+            if v.is_ok() {
+                state.project_attach.messages.push("Added project".into());
+            }
+            v
+        }
+        None => {
+            return;
+        }
+    };
+
+    let result = added.and_then(|_| contact_new_project(lock, url, &key));
+
+    if let Some(state) = lock.write().unwrap().as_mut() {
+        state.finish_attach(&key, result);
+    }
+}
+
+/// Attaches to the account named in project_init.xml, if the file has one.
+pub fn attach_from_project_init(lock: &RwLock<Option<ClientState>>) {
+    let account = match lock.write().unwrap().as_mut() {
+        Some(state) => state.start_project_init_attach(),
+        None => None,
+    };
+    if let Some((url, authenticator, project_name)) = account {
+        attach_project(lock, &url, &authenticator, &project_name);
+    }
+}
+
+/// Whether the run mode and the suspend reason keep tasks from running.
+fn computing_suspended(mode: RunMode, reason: Option<SuspendReason>) -> bool {
+    match mode {
+        RunMode::Never => true,
+        // Preferences don't apply, but exclusive apps still do
+        RunMode::Always => reason == Some(SuspendReason::ExclusiveAppRunning),
+        _ => reason.is_some(),
+    }
+}
+
+/// Sets or clears the suspend reasons owned by one monitor without overriding reasons set by others.
+fn update_suspend_reason(current: &mut Option<SuspendReason>, owned: &[SuspendReason], v: Option<SuspendReason>) {
+    match *current {
+        None => {
+            *current = v;
+        }
+        Some(r) if owned.contains(&r) => {
+            *current = v;
+        }
+        _ => {}
+    }
+}
+
+/// The app with the given name, added if the project has not sent it before.
+fn find_app<'a>(apps: &'a mut HashMap<uuid::Uuid, app::App>, name: &str) -> &'a mut app::App {
+    let id = apps.iter()
+        .find(|&(_, v)| v.name == name)
+        .map(|(id, _)| *id);
+    match id {
+        Some(id) => apps.get_mut(&id).unwrap(),
+        None => util::insert_unique(apps, app::App::new(name.into(), name.into())).1,
+    }
+}
+
+impl ClientState {
+    pub fn new(messages: messages::SafeLogger, tasks: Arc<tasks::TaskServer + Send + Sync>) -> Self {
         let clock_source = Arc::new(SystemClockSource);
+        let cpu_sched = cpu_sched::CpuScheduler::new_with_clock(clock_source.clone());
         Self {
             clock_source: Box::new(*clock_source.clone()),
             messages: Arc::clone(&messages),
             projects: projects::Projects::new(Arc::clone(&messages)),
-            tasks: Box::new(tasks::MockTaskServer::default()),
+            throttler: throttle::Throttler::new(Arc::clone(&tasks), cpu_sched.throttled.clone()),
+            tasks: tasks,
+            cpu_sched: cpu_sched,
+            rr_sim: Default::default(),
 
             cc_config: Default::default(),
+            global_prefs: Default::default(),
             host_info: Default::default(),
+            coprocs: Default::default(),
             file_infos: Default::default(),
+            results: Default::default(),
             project_attach: Default::default(),
+            account_ops: Default::default(),
+            project_config: Default::default(),
             project_init: Default::default(),
 
             acct_mgr_info: Default::default(),
+            acct_mgr_op: Default::default(),
 
             gpu_run_mode: ClockInitializable::new_with_clock(clock_source.clone()),
             run_mode: ClockInitializable::new_with_clock(clock_source.clone()),
 
             suspend_reason: Default::default(),
             gpu_suspend_reason: Default::default(),
+            exclusive_apps: ClockInitializable::new_with_clock(clock_source.clone()),
+            idle_detector: ClockInitializable::new_with_clock(clock_source.clone()),
+            idle_time: 0.0,
+            power_monitor: Default::default(),
+            power_status: Default::default(),
         }
     }
-}
 
-impl ClientState {
-    pub fn new(messages: messages::SafeLogger) -> Self {
-        Self {
-            messages: Arc::clone(&messages),
-            ..Default::default()
+    pub fn write_state_file(&self, dir: &std::path::Path) -> errors::Result<()> {
+        let next = dir.join(constants::STATE_FILE_NEXT_NAME);
+        std::fs::File::create(&next)?
+            .write_fmt(format_args!("{}", treexml::Element::from(self)))?;
+        std::fs::rename(next, dir.join(constants::STATE_FILE_NAME))?;
+        Ok(())
+    }
+
+    /// Saves the state, logging failures. Called periodically.
+    pub fn save_state(&self) {
+        if let Err(e) = self.write_state_file(std::path::Path::new(".")) {
+            self.messages.insert(
+                None,
+                MessagePriority::InternalError,
+                self.clock_source.now(),
+                &format!("Can't write state file: {}", e),
+            );
         }
     }
 
-    pub fn write_state_file(&self) -> errors::Result<()> {
-        std::fs::File::create(constants::STATE_FILE_NAME)?
-            .write_fmt(format_args!("{}", treexml::Element::from(self)))?;
+    /// Restores what client_state.xml keeps about the attached projects. Projects must be loaded first.
+    pub fn read_state_file(&mut self, dir: &std::path::Path) -> errors::Result<()> {
+        let path = dir.join(constants::STATE_FILE_NAME);
+        if !path.exists() {
+            return Ok(());
+        }
+        let root = match treexml::Document::parse(std::fs::File::open(path)?)?.root {
+            Some(v) => v,
+            None => {
+                return Ok(());
+            }
+        };
+
+        for node in root.children.iter().filter(|v| v.name == "project") {
+            let url = match node.find_child(|e| e.name == "master_url").and_then(|e| e.text.as_ref()) {
+                Some(v) => util::canonicalize_url(v.trim()),
+                None => {
+                    continue;
+                }
+            };
+            if let Some(project) = self.projects.find_by_url(&url) {
+                project.data.lock().unwrap().parse_state(node)?;
+            }
+        }
         Ok(())
     }
 
+    /// Loads client_state.xml left by the last run, logging failures.
+    pub fn load_state(&mut self) {
+        if let Err(e) = self.read_state_file(std::path::Path::new(".")) {
+            self.messages.insert(
+                None,
+                MessagePriority::InternalError,
+                self.clock_source.now(),
+                &format!("Can't read state file: {}", e),
+            );
+        }
+    }
+
     pub fn parse_app_info(
         &mut self,
         project: &projects::Project,
@@ -164,12 +623,12 @@ impl ClientState {
         for node in &root.children {
             match node.name.as_str() {
                 "file" | "file_info" => {
-                    let fi = file_info::FileInfo::from(node);
+                    let mut fi = file_info::FileInfo::from(node);
                     if !fi.download_urls.is_empty() || !fi.upload_urls.is_empty() {
                         bail!(errors::ErrorKind::XMLError("".into()));
                     }
 
-                    let _ = project;
+                    fi.project_url = project.master_url();
 
                     util::insert_unique(&mut self.file_infos, fi);
                 }
@@ -180,6 +639,1264 @@ impl ClientState {
         Ok(())
     }
 
+    /// Takes the task off the CPU, keeping it in memory if preferences allow.
+    pub fn preempt_task(&self, id: &uuid::Uuid) -> errors::FResult<tasks::StopMethod> {
+        self.tasks
+            .preempt_task(id, self.global_prefs.leave_apps_in_memory)
+    }
+
+    /// Processing resources usable by tasks
+    pub fn resources(&self) -> cpu_sched::Resources {
+        let ncpus = if self.cc_config.ncpus > 0 {
+            self.cc_config.ncpus
+        } else {
+            self.host_info.p_ncpus
+        };
+
+        let mut v = cpu_sched::Resources::default();
+        v.ncpus = ncpus as f64;
+        v.flops.insert(coproc::ProcType::CPU, self.host_info.p_fpops);
+        v.coprocs = self.coprocs.counts();
+        v.devices = self.coprocs
+            .data
+            .iter()
+            .map(|(rsc, c)| (*rsc, c.devices.clone()))
+            .collect();
+        v.exclude_gpus = self.cc_config.exclude_gpus.clone();
+        v
+    }
+
+    /// Bars projects from requesting work for GPU types they are excluded from entirely,
+    /// and logs every exclusion.
+    pub fn apply_gpu_exclusions(&mut self) {
+        let now = self.clock_source.now();
+        for project in &self.projects.data {
+            let url = project.master_url();
+            for ex in self.cc_config
+                .exclude_gpus
+                .iter()
+                .filter(|v| util::urls_match(&v.url, &url))
+            {
+                self.messages.insert(
+                    Some(project as &ProjAm),
+                    MessagePriority::Info,
+                    now,
+                    &format!(
+                        "Config: excluded GPU.  Type: {}.  App: {}.  Device: {}",
+                        ex.rsc_type.map(|v| v.rsc_name()).unwrap_or("all"),
+                        ex.appname.as_ref().map(|v| v.as_str()).unwrap_or("all"),
+                        ex.device_num
+                            .map(|v| v.to_string())
+                            .unwrap_or_else(|| "all".into())
+                    ),
+                );
+            }
+
+            let mut data = project.data.lock().unwrap();
+            for (rsc, c) in &self.coprocs.data {
+                let excluded = self.cc_config.excluded_devices(&url, None, rsc, &c.devices);
+                data.no_rsc_config[rsc.rsc_index()] = !c.devices.is_empty() && excluded.len() == c.devices.len();
+            }
+        }
+    }
+
+    /// Looks for GPUs and applies the coprocessor settings from cc_config.xml.
+    pub fn detect_coprocs(&mut self, detector: &coproc_detect::Detector) {
+        let mut v = detector.detect();
+        v.apply_config(
+            &self.cc_config.config_coprocs,
+            &self.cc_config.ignore_gpu_instance,
+            self.cc_config.no_gpus,
+        );
+        self.coprocs = v;
+        self.apply_gpu_exclusions();
+    }
+
+    /// Simulates round-robin processing of the jobs, for deadline checks, work fetch and RPC output.
+    pub fn update_rr_sim(&mut self, jobs: &[cpu_sched::RunnableJob]) {
+        self.rr_sim = rr_sim::simulate(
+            &self.clock_source.now(),
+            jobs,
+            &cpu_sched::resource_shares(&self.projects),
+            &self.resources(),
+            self.global_prefs.work_buf_total(),
+        );
+    }
+
+    /// Runs the CPU scheduler over the given jobs if it is due, using the last round-robin simulation.
+    pub fn schedule_cpus(
+        &mut self,
+        jobs: &[cpu_sched::RunnableJob],
+    ) -> errors::Result<Option<cpu_sched::Schedule>> {
+        let resources = self.resources();
+        self.cpu_sched.period = Duration::seconds(
+            (self.global_prefs.cpu_scheduling_period_minutes * 60.0) as i64,
+        );
+        self.cpu_sched.rec_half_life = cpu_sched::rec_half_life(self.cc_config.rec_half_life);
+        self.cpu_sched.run(
+            jobs,
+            &self.projects,
+            &self.rr_sim,
+            &resources,
+            &*self.tasks,
+            self.global_prefs.leave_apps_in_memory,
+        )
+    }
+
+    /// Creates tasks for results whose input files are all present.
+    fn create_tasks(&mut self) -> errors::Result<()> {
+        let pending = self.results
+            .iter()
+            .filter(|&(_, r)| r.state == result::ResultState::FilesDownloaded && r.task.is_none())
+            .map(|(id, r)| {
+                (
+                    *id,
+                    r.project_url.clone(),
+                    r.wu_name.clone(),
+                    r.app_version.clone(),
+                    r.output_refs.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        for (id, project_url, wu_name, mut app_version, output_refs) in pending {
+            let wu = match self.find_workunit(&project_url, &wu_name) {
+                Some(v) => v,
+                None => {
+                    continue;
+                }
+            };
+            let (init_data, project_dir) = match self.projects.find_by_url(&project_url) {
+                Some(v) => (tasks::InitData::from(v), v.project_dir()),
+                None => {
+                    continue;
+                }
+            };
+            // The task runs in its slot directory, so its files are named by absolute path
+            let project_dir = std::env::current_dir()?.join(project_dir);
+            app_version.file_name = project_dir
+                .join(&app_version.file_name)
+                .to_string_lossy()
+                .into_owned();
+            let slot_file = |f: &file_info::FileRef| tasks::SlotFile {
+                path: project_dir.join(&f.file_name),
+                open_name: f.open_name.clone(),
+                copy: f.copy_file,
+            };
+            let files = tasks::TaskFiles {
+                inputs: app_version
+                    .files
+                    .iter()
+                    .chain(wu.input_files.iter())
+                    .map(&slot_file)
+                    .collect(),
+                outputs: output_refs.iter().map(&slot_file).collect(),
+            };
+            let task = self.tasks
+                .create_task(&app_version, &wu, &init_data, &files)
+                .wait()?;
+            if let Some(r) = self.results.get_mut(&id) {
+                r.task = Some(task);
+            }
+        }
+        Ok(())
+    }
+
+    /// Jobs the CPU scheduler may run. There are none while computing is suspended.
+    pub fn runnable_jobs(&self) -> errors::Result<Vec<cpu_sched::RunnableJob>> {
+        if computing_suspended(self.run_mode.get_current(), self.suspend_reason) {
+            return Ok(Vec::new());
+        }
+        self.queued_jobs()
+    }
+
+    /// Jobs whose tasks have yet to finish, whether or not computing is suspended.
+    pub fn queued_jobs(&self) -> errors::Result<Vec<cpu_sched::RunnableJob>> {
+        let tasks = self.tasks.tasks().wait()?;
+        Ok(self.results
+            .values()
+            .filter(|r| r.state == result::ResultState::FilesDownloaded)
+            .filter_map(|r| {
+                let (id, status) = match r.task.and_then(|id| tasks.get(&id).map(|v| (id, v))) {
+                    Some(v) => v,
+                    None => {
+                        return None;
+                    }
+                };
+                match status.status {
+                    tasks::RunStatus::Done | tasks::RunStatus::Error | tasks::RunStatus::Aborted => {
+                        return None;
+                    }
+                    _ => {}
+                }
+                Some(cpu_sched::RunnableJob {
+                    task: id,
+                    name: r.name.clone(),
+                    project_url: r.project_url.clone(),
+                    app_name: r.app_version.app_name.clone(),
+                    report_deadline: r.report_deadline,
+                    avg_ncpus: if r.app_version.avg_ncpus > 0.0 {
+                        r.app_version.avg_ncpus
+                    } else {
+                        1.0
+                    },
+                    coproc: r.app_version.coproc,
+                    runtime_remaining: self.runtime_remaining(r, status),
+                })
+            })
+            .collect())
+    }
+
+    fn run_cpu_sched(&mut self) -> errors::Result<()> {
+        self.update_results()?;
+        self.create_tasks()?;
+        let queued = self.queued_jobs()?;
+        self.update_rr_sim(&queued);
+        let jobs = self.runnable_jobs()?;
+        self.schedule_cpus(&jobs)?;
+        Ok(())
+    }
+
+    /// Moves results along as their tasks progress and runs the CPU scheduler. Called periodically.
+    pub fn poll_cpu_sched(&mut self) {
+        if let Err(e) = self.run_cpu_sched() {
+            self.messages.insert(
+                None,
+                MessagePriority::InternalError,
+                self.clock_source.now(),
+                &format!("CPU scheduling failed: {}", e),
+            );
+        }
+    }
+
+    /// Picks the project to ask for work and how much, based on the last round-robin simulation.
+    pub fn work_fetch(&self, jobs: &[cpu_sched::RunnableJob]) -> Option<work_fetch::WorkRequest> {
+        work_fetch::choose_project(
+            &self.clock_source.now(),
+            &self.projects,
+            jobs,
+            &self.rr_sim,
+            &self.resources(),
+            &self.global_prefs,
+            &self.cc_config,
+        )
+    }
+
+    /// Picks the project to contact and builds its scheduler request, reporting the project's finished
+    /// results. Work is asked for when the buffer runs low; otherwise a project is only contacted
+    /// if an RPC is pending for it or it has results due.
+    pub fn scheduler_request(&self) -> Option<SchedulerRpc> {
+        let now = self.clock_source.now();
+        let jobs = self.queued_jobs().unwrap_or_default();
+        let work_req = self.work_fetch(&jobs);
+        let project = match work_req {
+            Some(ref req) => self.projects.find_by_url(&req.project_url),
+            None => self.projects.data.iter().find(|p| {
+                let results_due = !self.results_to_report(&p.master_url()).is_empty();
+                let data = p.data.lock().unwrap();
+                let due = (data.sched_rpc_pending.is_some() || results_due)
+                    && data.min_rpc_time.map(|t| t <= now).unwrap_or(true);
+                due
+            }),
+        };
+        let project = match project {
+            Some(v) => v,
+            None => {
+                return None;
+            }
+        };
+
+        let data = project.data.lock().unwrap();
+        let v = Some(SchedulerRpc {
+            project_url: project.master_url(),
+            scheduler_urls: data.scheduler_urls.clone(),
+            request: scheduler::make_request(
+                &data,
+                &self.host_info,
+                &self.coprocs,
+                &self.cc_config.alt_platforms,
+                work_req.as_ref(),
+                self.results_to_report(&project.master_url()),
+            ),
+            work_req: work_req.clone(),
+        });
+        v
+    }
+
+    /// Applies the scheduler reply, backing off work requests that brought no jobs, forgetting
+    /// the acknowledged results and taking in the new jobs.
+    /// Failed requests are retried later, waiting longer after each failure.
+    pub fn finish_scheduler_rpc(
+        &mut self,
+        rpc: &SchedulerRpc,
+        scheduler_urls: Vec<String>,
+        reply: errors::Result<scheduler::SchedulerReply>,
+    ) {
+        let now = self.clock_source.now();
+        let reply = {
+            let project = match self.projects.find_by_url(&rpc.project_url) {
+                Some(v) => v,
+                None => {
+                    return;
+                }
+            };
+
+            match reply {
+                Ok(reply) => {
+                    for &(_, ref msg) in &reply.messages {
+                        self.messages.insert(Some(project as &ProjAm), MessagePriority::Info, now, msg);
+                    }
+                    let mut data = project.data.lock().unwrap();
+                    data.scheduler_urls = scheduler_urls;
+                    apply_scheduler_reply(&mut data, &reply, &now);
+                    if let Some(ref req) = rpc.work_req {
+                        work_fetch::handle_reply(&mut data, req, &reply.resources_sent(), &now);
+                    }
+                    reply
+                }
+                Err(e) => {
+                    self.messages.insert(
+                        Some(project as &ProjAm),
+                        MessagePriority::Info,
+                        now,
+                        &format!("Scheduler request failed: {}", e),
+                    );
+                    let mut data = project.data.lock().unwrap();
+                    data.nrpc_failures += 1;
+                    let delay = (60.0 * 2f64.powi(data.nrpc_failures as i32)).min(86400.0);
+                    data.min_rpc_time = Some(now + Duration::seconds(delay as i64));
+                    if let Some(ref req) = rpc.work_req {
+                        work_fetch::handle_reply(&mut data, req, &HashSet::new(), &now);
+                    }
+                    return;
+                }
+            }
+        };
+
+        self.handle_result_acks(&rpc.project_url, &reply.result_acks);
+        self.add_jobs(&rpc.project_url, reply);
+    }
+
+    /// Takes in the apps, files and jobs sent by the project's scheduler.
+    /// Input files left on disk by earlier runs are not downloaded again.
+    fn add_jobs(&mut self, project_url: &str, reply: scheduler::SchedulerReply) {
+        let now = self.clock_source.now();
+        let project_dir = match self.projects.find_by_url(project_url) {
+            Some(v) => v.project_dir(),
+            None => {
+                return;
+            }
+        };
+
+        for mut fi in reply.file_infos {
+            if self.find_file(project_url, &fi.name).is_some() {
+                continue;
+            }
+            let present = fi.upload_urls.is_empty()
+                && std::fs::metadata(project_dir.join(&fi.name))
+                    .map(|m| fi.nbytes <= 0.0 || m.len() as f64 == fi.nbytes)
+                    .unwrap_or(false);
+            fi.status = if present {
+                file_info::FILE_PRESENT
+            } else {
+                file_info::FILE_NOT_PRESENT
+            };
+            fi.project_url = project_url.into();
+            util::insert_unique(&mut self.file_infos, fi);
+        }
+
+        if let Some(project) = self.projects.find_by_url(project_url) {
+            let mut data = project.data.lock().unwrap();
+            for app in reply.apps {
+                if !data.apps.values().any(|v| v.name == app.name) {
+                    util::insert_unique(&mut data.apps, app);
+                }
+            }
+            for av in reply.app_versions {
+                let app = find_app(&mut data.apps, &av.app_name);
+                if !app.versions.values().any(|v| {
+                    v.version_num == av.version_num && v.platform == av.platform && v.plan_class == av.plan_class
+                }) {
+                    util::insert_unique(&mut app.versions, av);
+                }
+            }
+            for wu in reply.workunits {
+                let app = find_app(&mut data.apps, &wu.app_name);
+                if !app.work_units.values().any(|v| v.name == wu.name) {
+                    util::insert_unique(&mut app.work_units, wu);
+                }
+            }
+        }
+
+        let mut errors = Vec::new();
+        for node in &reply.results {
+            let mut r = match result::Result::try_from(node, project_url) {
+                Ok(v) => v,
+                Err(e) => {
+                    errors.push(format!("Can't parse result: {}", e));
+                    continue;
+                }
+            };
+            if self.results
+                .values()
+                .any(|v| v.project_url == project_url && v.name == r.name)
+            {
+                continue;
+            }
+            let wu = match self.find_workunit(project_url, &r.wu_name) {
+                Some(v) => v,
+                None => {
+                    errors.push(format!("No workunit {} for result {}", r.wu_name, r.name));
+                    continue;
+                }
+            };
+            let av = match self.find_app_version(project_url, &wu.app_name, &r.app_version) {
+                Some(v) => v,
+                None => {
+                    errors.push(format!("No app version for result {}", r.name));
+                    continue;
+                }
+            };
+
+            let inputs = av.files
+                .iter()
+                .chain(wu.input_files.iter())
+                .map(|f| f.file_name.clone())
+                .collect::<Vec<_>>();
+            let output_refs = file_info::file_refs(node);
+            let outputs = output_refs.iter().map(|f| f.file_name.clone()).collect::<Vec<_>>();
+            match (self.file_ids(project_url, &inputs), self.file_ids(project_url, &outputs)) {
+                (Some(inputs), Some(outputs)) => {
+                    r.input_files = inputs;
+                    r.output_files = outputs;
+                    r.output_refs = output_refs;
+                }
+                _ => {
+                    errors.push(format!("Files of result {} are missing from the reply", r.name));
+                    continue;
+                }
+            }
+            r.app_version = av;
+            util::insert_unique(&mut self.results, r);
+        }
+
+        if let Some(project) = self.projects.find_by_url(project_url) {
+            for e in &errors {
+                self.messages.insert(Some(project as &ProjAm), MessagePriority::InternalError, now, e);
+            }
+        }
+    }
+
+    /// File of the project with the given name
+    pub fn find_file(&self, project_url: &str, name: &str) -> Option<uuid::Uuid> {
+        self.file_infos
+            .iter()
+            .find(|&(_, fi)| fi.project_url == project_url && fi.name == name)
+            .map(|(id, _)| *id)
+    }
+
+    /// Ids of the project's files with the given names, or None if any of them is unknown
+    fn file_ids(&self, project_url: &str, names: &[String]) -> Option<Vec<uuid::Uuid>> {
+        names.iter().map(|name| self.find_file(project_url, name)).collect()
+    }
+
+    /// App version the result is to be computed with. Results that don't name
+    /// a version number get the newest version of the app.
+    pub fn find_app_version(
+        &self,
+        project_url: &str,
+        app_name: &str,
+        wanted: &app::AppVersion,
+    ) -> Option<app::AppVersion> {
+        let project = match self.projects.find_by_url(project_url) {
+            Some(v) => v,
+            None => {
+                return None;
+            }
+        };
+        let data = project.data.lock().unwrap();
+        let v = data.apps
+            .values()
+            .filter(|app| app.name == app_name)
+            .flat_map(|app| app.versions.values())
+            .filter(|av| {
+                (wanted.version_num == 0 || av.version_num == wanted.version_num)
+                    && (wanted.platform.is_empty() || av.platform == wanted.platform)
+                    && av.plan_class == wanted.plan_class
+            })
+            .max_by_key(|av| av.version_num)
+            .cloned();
+        v
+    }
+
+    /// Transfers due: input files yet to be downloaded and output files of finished results yet to be uploaded
+    pub fn file_transfers(&self) -> Vec<(uuid::Uuid, file_xfer::FileXfer)> {
+        let uploads = self.results
+            .values()
+            .filter(|r| r.state == result::ResultState::FilesUploading)
+            .flat_map(|r| r.output_files.iter().cloned())
+            .collect::<HashSet<_>>();
+
+        self.file_infos
+            .iter()
+            .filter(|&(_, fi)| fi.status == file_info::FILE_NOT_PRESENT || fi.status == file_info::FILE_PRESENT)
+            .filter_map(|(id, fi)| {
+                let project = match self.projects.find_by_url(&fi.project_url) {
+                    Some(v) => v,
+                    None => {
+                        return None;
+                    }
+                };
+                let upload = if uploads.contains(id) && !fi.uploaded {
+                    true
+                } else if fi.status == file_info::FILE_NOT_PRESENT && !fi.download_urls.is_empty()
+                    && !project.data.lock().unwrap().over_disk_quota()
+                {
+                    false
+                } else {
+                    return None;
+                };
+                let project_dir = project.project_dir();
+                Some((
+                    *id,
+                    file_xfer::FileXfer {
+                        upload: upload,
+                        info: fi.clone(),
+                        path: project_dir.join(&fi.name),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    /// Records the outcome of a file transfer. Transient failures are retried on the next pass,
+    /// other failures mark the file as failed, failing its result.
+    pub fn finish_file_transfer(&mut self, id: &uuid::Uuid, upload: bool, outcome: errors::Result<()>) {
+        let now = self.clock_source.now();
+        let (project_url, msg) = match self.file_infos.get_mut(id) {
+            Some(fi) => match outcome {
+                Ok(()) => {
+                    if upload {
+                        fi.uploaded = true;
+                    } else {
+                        fi.status = file_info::FILE_PRESENT;
+                    }
+                    return;
+                }
+                Err(e) => {
+                    let transient = match *e.kind() {
+                        errors::ErrorKind::HttpTransientError(_) => true,
+                        _ => false,
+                    };
+                    if !transient {
+                        fi.status = i64::from(&e);
+                        fi.error_msg = Some(e.to_string());
+                    }
+                    (
+                        fi.project_url.clone(),
+                        format!(
+                            "{} of {} failed: {}",
+                            if upload { "Upload" } else { "Download" },
+                            fi.name,
+                            e
+                        ),
+                    )
+                }
+            },
+            None => {
+                return;
+            }
+        };
+
+        if let Some(project) = self.projects.find_by_url(&project_url) {
+            self.messages.insert(Some(project as &ProjAm), MessagePriority::Info, now, &msg);
+        }
+    }
+
+    pub fn find_workunit(&self, project_url: &str, wu_name: &str) -> Option<workunit::Workunit> {
+        let project = match self.projects.find_by_url(project_url) {
+            Some(v) => v,
+            None => {
+                return None;
+            }
+        };
+        let data = project.data.lock().unwrap();
+        let v = data.apps
+            .values()
+            .flat_map(|app| app.work_units.values())
+            .find(|wu| wu.name == wu_name)
+            .cloned();
+        v
+    }
+
+    /// Moves results along as their files transfer and their tasks finish.
+    /// Successful runs feed their elapsed time into the project's duration correction factor.
+    pub fn update_results(&mut self) -> errors::Result<()> {
+        let tasks = self.tasks.tasks().wait()?;
+        let now = self.clock_source.now();
+        let mut completed = Vec::new();
+        let mut collected = Vec::new();
+        for r in self.results.values_mut() {
+            let prev_state = r.state;
+            let task = r.task.as_ref().and_then(|id| tasks.get(id));
+            r.update(&self.file_infos, task, &now);
+
+            // The result has everything it needs from its task once it moves on
+            if prev_state == result::ResultState::FilesDownloaded && r.state != prev_state {
+                if let Some(id) = r.task.take() {
+                    collected.push(id);
+                }
+            }
+
+            let succeeded = match r.state {
+                result::ResultState::FilesUploading | result::ResultState::FilesUploaded => {
+                    r.exit_status == 0
+                }
+                _ => false,
+            };
+            if prev_state == result::ResultState::FilesDownloaded && succeeded {
+                completed.push((
+                    r.project_url.clone(),
+                    r.wu_name.clone(),
+                    r.app_version.clone(),
+                    r.final_elapsed_time,
+                ));
+            }
+        }
+
+        for (project_url, wu_name, avp, elapsed_time) in completed {
+            if let Some(wu) = self.find_workunit(&project_url, &wu_name) {
+                let flops = estimate::app_version_flops(&avp, self.host_info.p_fpops);
+                let raw_estimate = estimate::estimated_runtime(&wu, flops, 1.0);
+                if let Some(project) = self.projects.find_by_url(&project_url) {
+                    let mut data = project.data.lock().unwrap();
+                    data.duration_correction_factor =
+                        estimate::update_dcf(data.duration_correction_factor, raw_estimate, elapsed_time);
+                }
+            }
+        }
+
+        for id in collected {
+            self.tasks.remove_task(&id).wait()?;
+        }
+        Ok(())
+    }
+
+    /// Seconds of work left for the result's task, estimated from its workunit and progress.
+    fn runtime_remaining(&self, r: &result::Result, status: &tasks::TaskStatus) -> f64 {
+        if r.state.is_final() {
+            return 0.0;
+        }
+        let dcf = self.projects
+            .find_by_url(&r.project_url)
+            .map(|p| p.data.lock().unwrap().duration_correction_factor)
+            .unwrap_or(1.0);
+        self.find_workunit(&r.project_url, &r.wu_name)
+            .map(|wu| {
+                estimate::estimated_runtime_remaining(
+                    &wu,
+                    estimate::app_version_flops(&r.app_version, self.host_info.p_fpops),
+                    dcf,
+                    status.pct_complete,
+                    status.elapsed_time,
+                )
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// `<result>` elements for RPC output, with progress and remaining time estimates of active tasks.
+    pub fn result_elements(&self) -> Vec<treexml::Element> {
+        let tasks = self.tasks.tasks().wait().unwrap_or_default();
+        self.results
+            .values()
+            .map(|r| {
+                let mut e = treexml::Element::from(r);
+                e.children.push(make_text_element("project_url", &r.project_url));
+                e.children.push(make_text_element("report_deadline", r.report_deadline.timestamp()));
+
+                if let Some((id, status)) = r.task.and_then(|id| tasks.get(&id).map(|v| (id, v))) {
+                    let remaining = self.runtime_remaining(r, status);
+                    e.children.append(&mut vec![
+                        make_text_element("fraction_done", status.pct_complete),
+                        make_text_element("current_cpu_time", status.current_cpu_time),
+                        make_text_element("elapsed_time", status.elapsed_time),
+                        make_text_element("estimated_cpu_time_remaining", remaining),
+                    ]);
+
+                    if let Some(job) = self.rr_sim.jobs.get(&id) {
+                        e.children.append(&mut job.xml_fields());
+                    }
+                }
+                e
+            })
+            .collect()
+    }
+
+    /// `<result>` elements to send in the project's next scheduler request.
+    pub fn results_to_report(&self, project_url: &str) -> Vec<treexml::Element> {
+        result::results_to_report(
+            self.results.values(),
+            project_url,
+            &self.clock_source.now(),
+            &self.cc_config,
+        ).into_iter()
+            .map(|r| r.into())
+            .collect()
+    }
+
+    /// Forgets results the project has acknowledged, along with their files.
+    pub fn handle_result_acks(&mut self, project_url: &str, acked: &HashSet<String>) {
+        result::handle_acks(
+            self.results
+                .values_mut()
+                .filter(|r| r.project_url == project_url),
+            acked,
+        );
+        let done = self.results
+            .values()
+            .filter(|r| r.got_server_ack)
+            .flat_map(|r| r.output_files.iter().cloned())
+            .collect::<HashSet<_>>();
+        self.file_infos.retain(|id, _| !done.contains(id));
+        self.results.retain(|_, r| !r.got_server_ack);
+    }
+
+    /// Suspends computation while an exclusive app from cc_config.xml is running.
+    pub fn check_exclusive_apps(&mut self) {
+        let (all, gpu) = self.exclusive_apps.poll(&self.cc_config);
+        let owned = [SuspendReason::ExclusiveAppRunning];
+        update_suspend_reason(&mut self.suspend_reason, &owned, all);
+        update_suspend_reason(&mut self.gpu_suspend_reason, &owned, gpu);
+    }
+
+    /// Suspends computation according to the user activity preferences.
+    pub fn check_user_activity(&mut self) {
+        self.idle_time = self.idle_detector.idle_time();
+        let (all, gpu) = self.global_prefs.activity_suspend_reasons(self.idle_time);
+        let owned = [SuspendReason::UserActive, SuspendReason::NoRecentInput];
+        update_suspend_reason(&mut self.suspend_reason, &owned, all);
+        update_suspend_reason(&mut self.gpu_suspend_reason, &owned, gpu);
+    }
+
+    /// Suspends computation on batteries or when the host runs hot, as preferences say.
+    pub fn check_power(&mut self) {
+        self.power_status = self.power_monitor.read();
+        let reason = self.power_status.suspend_reason(&self.global_prefs);
+        update_suspend_reason(
+            &mut self.suspend_reason,
+            &[
+                SuspendReason::Batteries,
+                SuspendReason::BatteryCharging,
+                SuspendReason::BatteryOverheated,
+            ],
+            reason,
+        );
+    }
+
+    /// Passes the CPU usage limit preference on to the throttler.
+    pub fn apply_cpu_usage_limit(&self) {
+        self.throttler.set_limit(self.global_prefs.cpu_usage_limit);
+    }
+
+    /// Directories of the attached projects by master URL
+    pub fn project_dirs(&self) -> Vec<(String, std::path::PathBuf)> {
+        self.projects
+            .data
+            .iter()
+            .map(|p| (p.master_url(), p.project_dir()))
+            .collect()
+    }
+
+    /// Measures disk use of the client and of each project in place. Used before the
+    /// state is shared; afterwards `update_disk_usage` scans without holding the lock.
+    pub fn update_disk_usage(&mut self) {
+        let usage = DiskUsage::measure(&self.project_dirs());
+        self.apply_disk_usage(usage);
+    }
+
+    /// Records measured disk use, adding the slots of each project's tasks, and divides
+    /// the space the preferences allow among projects.
+    pub fn apply_disk_usage(&mut self, usage: DiskUsage) {
+        match usage.space {
+            Ok((total, free)) => {
+                self.host_info.d_total = total;
+                self.host_info.d_free = free;
+            }
+            Err(e) => {
+                self.messages.insert(
+                    None,
+                    MessagePriority::InternalError,
+                    self.clock_source.now(),
+                    &format!("Can't get disk space: {}", e),
+                );
+            }
+        }
+        self.host_info.d_boinc = usage.client;
+        self.host_info.d_allowed = self.global_prefs.allowed_disk_usage(
+            self.host_info.d_total,
+            self.host_info.d_free,
+            self.host_info.d_boinc,
+        );
+
+        let task_usage = self.tasks.tasks().wait().unwrap_or_default();
+        let mut slot_usage: HashMap<String, f64> = HashMap::new();
+        for r in self.results.values() {
+            if let Some(status) = r.task.as_ref().and_then(|id| task_usage.get(id)) {
+                *slot_usage.entry(r.project_url.clone()).or_insert(0.0) += status.disk_usage;
+            }
+        }
+
+        for project in &self.projects.data {
+            let url = project.master_url();
+            let v = usage.projects.get(&url).cloned().unwrap_or(0.0)
+                + slot_usage.get(&url).cloned().unwrap_or(0.0);
+            project.data.lock().unwrap().disk_usage = v;
+        }
+        self.projects.allocate_disk_shares(self.host_info.d_allowed);
+    }
+
+    /// Whether the user has given input within the idle time preference
+    pub fn user_active(&self) -> bool {
+        self.idle_time < self.global_prefs.idle_time_to_run * 60.0
+    }
+
+    /// Records the outcome of a background attach, detaching the project if it failed.
+    pub fn finish_attach(&mut self, key: &str, result: errors::Result<()>) {
+        let now = self.clock_source.now();
+        self.project_attach.in_progress = false;
+        match result {
+            Ok(()) => {
+                if let Some(project) = self.projects.find_by_url(key) {
+                    self.messages.insert(
+                        Some(project as &ProjAm),
+                        MessagePriority::Info,
+                        now,
+                        "Attached to project",
+                    );
+                }
+                self.project_attach.messages.push("Attached to project".into());
+                self.project_attach.error = None;
+                if self.project_attach.from_config_file {
+                    self.project_init = None;
+                    if let Err(e) = project_init::ProjectInit::remove(std::path::Path::new(".")) {
+                        self.messages.insert(
+                            None,
+                            MessagePriority::InternalError,
+                            now,
+                            &format!("Can't delete project init file: {}", e),
+                        );
+                    }
+                }
+            }
+            Err(e) => {
+                self.messages.insert(
+                    None,
+                    MessagePriority::UserAlert,
+                    now,
+                    &format!("Attach to {} failed: {}", key, e),
+                );
+                // Leave alone a project that was attached before this request
+                match *e.kind() {
+                    errors::ErrorKind::AlreadyAttachedError(_) => {}
+                    _ => {
+                        self.detach_project(key);
+                    }
+                }
+                self.project_attach.messages.push(e.to_string());
+                self.project_attach.error = Some(e);
+            }
+        }
+    }
+
+    /// Records the outcome of an account web RPC, passing project errors on to the attach messages.
+    pub fn finish_account_rpc(&mut self, rpc: acct_setup::AccountRpc, reply: acct_setup::AccountOut) {
+        if !reply.error_msg.is_empty() {
+            self.project_attach.messages.push(reply.error_msg.clone());
+        }
+        self.account_ops.insert(
+            rpc,
+            acct_setup::AccountOp {
+                in_progress: false,
+                reply: Some(reply),
+            },
+        );
+    }
+
+    /// `<acct_mgr_request>` describing this host and its projects
+    pub fn acct_mgr_request(&self) -> treexml::Element {
+        acct_mgr::make_request(
+            &self.acct_mgr_info,
+            &self.host_info,
+            &self.projects,
+            env!("CARGO_PKG_VERSION"),
+        )
+    }
+
+    /// Whether the periodic account manager sync is due and not already running
+    pub fn acct_mgr_rpc_due(&self) -> bool {
+        !self.acct_mgr_op.in_progress && self.acct_mgr_info.rpc_due(&self.clock_source.now())
+    }
+
+    /// Restores the projects attached in earlier runs from their account files.
+    pub fn load_projects(&mut self) {
+        let now = self.clock_source.now();
+        let entries = match std::fs::read_dir(".") {
+            Ok(v) => v,
+            Err(e) => {
+                self.messages.insert(
+                    None,
+                    MessagePriority::InternalError,
+                    now,
+                    &format!("Can't list account files: {}", e),
+                );
+                return;
+            }
+        };
+
+        for entry in entries.filter_map(|v| v.ok()) {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !file_names::is_account_filename(&name) {
+                continue;
+            }
+            match projects::Project::read_account_file(&entry.path()) {
+                Ok(proj) => {
+                    if self.projects.find_matching(&proj.master_url()).is_some() {
+                        continue;
+                    }
+                    let _ = proj.make_project_dir();
+                    self.projects.data.insert(proj);
+                }
+                Err(e) => {
+                    self.messages.insert(
+                        None,
+                        MessagePriority::InternalError,
+                        now,
+                        &format!("Can't read account file {}: {}", name, e),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Reads project_init.xml left by an installer. A file for a project that is
+    /// already attached has served its purpose and is removed.
+    pub fn load_project_init(&mut self) {
+        let dir = std::path::Path::new(".");
+        let now = self.clock_source.now();
+        match project_init::ProjectInit::read(dir) {
+            Ok(Some(v)) => {
+                if self.projects.find_matching(&v.url).is_some() {
+                    let _ = project_init::ProjectInit::remove(dir);
+                } else {
+                    self.messages.insert(
+                        None,
+                        MessagePriority::Info,
+                        now,
+                        &format!("Found project_init.xml for {}", v.url),
+                    );
+                    self.project_init = Some(v);
+                }
+            }
+            Ok(None) => {}
+            Err(e) => {
+                self.messages.insert(
+                    None,
+                    MessagePriority::InternalError,
+                    now,
+                    &format!("Can't read project init file: {}", e),
+                );
+            }
+        }
+    }
+
+    /// Starts attaching to the account from project_init.xml. Returns the URL, account key
+    /// and project name to attach with, or None if there is nothing to attach to.
+    pub fn start_project_init_attach(&mut self) -> Option<(String, String, String)> {
+        if self.project_attach.in_progress {
+            return None;
+        }
+        let account = match self.project_init {
+            Some(ref v) if v.has_account() => (v.url.clone(), v.account_key.clone(), v.name.clone()),
+            _ => {
+                return None;
+            }
+        };
+        self.project_attach = acct_setup::ProjectAttach {
+            in_progress: true,
+            from_config_file: true,
+            ..Default::default()
+        };
+        Some(account)
+    }
+
+    /// Reads the global preferences and the local overrides, and applies them.
+    pub fn load_global_prefs(&mut self) {
+        match prefs::GlobalPrefs::read(std::path::Path::new(".")) {
+            Ok(v) => {
+                self.global_prefs = v;
+                self.apply_cpu_usage_limit();
+            }
+            Err(e) => {
+                self.messages.insert(
+                    None,
+                    MessagePriority::InternalError,
+                    self.clock_source.now(),
+                    &format!("Can't read global preferences: {}", e),
+                );
+            }
+        }
+    }
+
+    /// Loads the account manager and credentials saved by earlier runs.
+    pub fn load_acct_mgr_info(&mut self) {
+        match acct_mgr::AcctMgrInfo::read_files(std::path::Path::new(".")) {
+            Ok(v) => {
+                self.acct_mgr_info = v;
+            }
+            Err(e) => {
+                self.messages.insert(
+                    None,
+                    MessagePriority::InternalError,
+                    self.clock_source.now(),
+                    &format!("Can't read account manager files: {}", e),
+                );
+            }
+        }
+    }
+
+    fn write_acct_mgr_info(&self) {
+        if let Err(e) = self.acct_mgr_info.write_files(std::path::Path::new(".")) {
+            self.messages.insert(
+                Some(&self.acct_mgr_info as &ProjAm),
+                MessagePriority::InternalError,
+                self.clock_source.now(),
+                &format!("Can't write account manager files: {}", e),
+            );
+        }
+    }
+
+    /// Stops using the account manager and forgets its credentials. Its projects are either
+    /// detached or stay attached as the user's to manage.
+    pub fn leave_acct_mgr(&mut self, detach_projects: bool) {
+        let urls = self.projects
+            .data
+            .iter()
+            .filter(|p| p.data.lock().unwrap().attached_via_acct_mgr)
+            .map(|p| p.master_url())
+            .collect::<Vec<_>>();
+        for url in urls {
+            if detach_projects {
+                self.detach_project(&url);
+            } else if let Some(project) = self.projects.find_by_url(&url) {
+                let mut data = project.data.lock().unwrap();
+                data.attached_via_acct_mgr = false;
+                data.no_rsc_ams = Default::default();
+            }
+        }
+
+        self.acct_mgr_info = Default::default();
+        if let Err(e) = acct_mgr::AcctMgrInfo::remove_files(std::path::Path::new(".")) {
+            self.messages.insert(
+                None,
+                MessagePriority::InternalError,
+                self.clock_source.now(),
+                &format!("Can't remove account manager files: {}", e),
+            );
+        }
+    }
+
+    /// Attaches, detaches and updates projects as the account manager says.
+    pub fn handle_acct_mgr_reply(&mut self, reply: errors::Result<acct_mgr::AcctMgrReply>) {
+        let now = self.clock_source.now();
+        self.acct_mgr_op.in_progress = false;
+
+        let reply = match reply {
+            Ok(v) => v,
+            Err(e) => {
+                self.messages.insert(
+                    Some(&self.acct_mgr_info as &ProjAm),
+                    MessagePriority::UserAlert,
+                    now,
+                    &format!("Account manager request failed: {}", e),
+                );
+                self.acct_mgr_info.next_rpc_time = Some(now + Duration::hours(1));
+                self.acct_mgr_op.error = Some(e);
+                return;
+            }
+        };
+
+        for m in &reply.messages {
+            self.messages.insert(
+                Some(&self.acct_mgr_info as &ProjAm),
+                MessagePriority::Info,
+                now,
+                m,
+            );
+        }
+        self.acct_mgr_op.messages = reply.messages.clone();
+
+        self.acct_mgr_info.password_error = reply.error_num == acct_mgr::ERR_BAD_PASSWD;
+        if let Some(e) = reply.error() {
+            self.messages.insert(
+                Some(&self.acct_mgr_info as &ProjAm),
+                MessagePriority::UserAlert,
+                now,
+                &format!("Account manager error: {}", e),
+            );
+            self.acct_mgr_op.error = Some(e);
+            if self.acct_mgr_info.password_error {
+                self.write_acct_mgr_info();
+            }
+            return;
+        }
+        self.acct_mgr_op.error = None;
+
+        // A key, once known, must not change: a different one means the reply is not from our account manager
+        if !reply.signing_key.is_empty() {
+            if self.acct_mgr_info.signing_key.is_empty() {
+                self.acct_mgr_info.signing_key = reply.signing_key.clone();
+            } else if reply.signing_key != self.acct_mgr_info.signing_key {
+                self.messages.insert(
+                    Some(&self.acct_mgr_info as &ProjAm),
+                    MessagePriority::UserAlert,
+                    now,
+                    "Account manager reply has a different signing key; ignoring it",
+                );
+                self.acct_mgr_op.error = Some(
+                    errors::ErrorKind::AcctMgrError(
+                        acct_mgr::ERR_RSA_FAILED,
+                        "Signing key changed".into(),
+                    ).into(),
+                );
+                return;
+            }
+        }
+        let signing_key = crypt::PublicKey::parse(&self.acct_mgr_info.signing_key).ok();
+
+        if !reply.name.is_empty() {
+            self.acct_mgr_info.project_name = reply.name.clone();
+        }
+        if reply.opaque.is_some() {
+            self.acct_mgr_info.opaque = reply.opaque.clone();
+        }
+        self.acct_mgr_info.next_rpc_time = Some(now + Duration::seconds(reply.repeat_sec as i64));
+        self.write_acct_mgr_info();
+
+        let mut managed = HashSet::new();
+        for acct in &reply.accounts {
+            let verified = match signing_key {
+                Some(ref key) => crypt::verify_string(&acct.url, &acct.url_signature, key),
+                None => false,
+            };
+            if !verified {
+                self.messages.insert(
+                    Some(&self.acct_mgr_info as &ProjAm),
+                    MessagePriority::UserAlert,
+                    now,
+                    &format!("Bad signature for URL {}", &acct.url),
+                );
+                continue;
+            }
+
+            let existing = self.projects.find_matching(&acct.url).map(|p| p.master_url());
+            if acct.detach {
+                if let Some(url) = existing {
+                    self.detach_project(&url);
+                    self.messages.insert(
+                        Some(&self.acct_mgr_info as &ProjAm),
+                        MessagePriority::Info,
+                        now,
+                        &format!("Detached from {} at the account manager's request", &acct.url),
+                    );
+                }
+                continue;
+            }
+
+            let url = match existing {
+                Some(v) => v,
+                None => {
+                    if let Err(e) = self.add_project(&acct.url, &acct.authenticator, "", true) {
+                        self.messages.insert(
+                            Some(&self.acct_mgr_info as &ProjAm),
+                            MessagePriority::UserAlert,
+                            now,
+                            &format!("Failed to attach to {}: {}", &acct.url, e),
+                        );
+                        continue;
+                    }
+                    util::canonicalize_url(&acct.url)
+                }
+            };
+
+            let project = self.projects.find_by_url(&url).unwrap();
+            let mut data = project.data.lock().unwrap();
+            data.attached_via_acct_mgr = true;
+            if let Some(v) = acct.suspend {
+                data.suspended_via_gui = v;
+            }
+            if let Some(v) = acct.dont_request_more_work {
+                data.dont_request_more_work = v;
+            }
+            if let Some(v) = acct.resource_share {
+                data.resource_share = v;
+            }
+            data.no_rsc_ams = acct.no_rsc;
+            data.detach_when_done = acct.detach_when_done;
+            if acct.update {
+                data.sched_rpc_pending = Some(RpcReason::AccountManagerRequest);
+            }
+            managed.insert(url);
+        }
+
+        // Projects the account manager no longer lists become the user's to manage
+        for project in &self.projects.data {
+            if !managed.contains(&project.master_url()) {
+                project.data.lock().unwrap().attached_via_acct_mgr = false;
+            }
+        }
+
+        // Saved for later runs and applied under the local overrides
+        if let Some(ref e) = reply.global_preferences {
+            let saved = std::fs::File::create(file_names::GLOBAL_PREFS_FILE_NAME)
+                .and_then(|mut f| f.write_fmt(format_args!("{}", e)));
+            match saved {
+                Ok(()) => {
+                    self.load_global_prefs();
+                }
+                Err(e) => {
+                    self.messages.insert(
+                        Some(&self.acct_mgr_info as &ProjAm),
+                        MessagePriority::InternalError,
+                        now,
+                        &format!("Can't save global preferences from account manager: {}", e),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Removes the project along with its results, aborting their tasks.
+    pub fn detach_project(&mut self, url: &str) {
+        let project = match self.projects.remove(url) {
+            Some(v) => v,
+            None => {
+                return;
+            }
+        };
+
+        for r in self.results.values().filter(|r| r.project_url == url) {
+            if let Some(ref id) = r.task {
+                let _ = self.tasks.abort_task(id).wait();
+                let _ = self.tasks.remove_task(id).wait();
+            }
+        }
+        self.results.retain(|_, r| r.project_url != url);
+        let _ = std::fs::remove_dir_all(project.project_dir());
+        let _ = std::fs::remove_file(file_names::account_filename(url));
+
+        self.set_client_state_dirty("Detach project");
+    }
+
     pub fn sort_projects_by_name(&mut self) {}
 
     pub fn set_client_state_dirty(&mut self, _: &str) {}
@@ -197,23 +1914,24 @@ impl ClientState {
             ));
         }
 
-        let canonical_master_url = util::canonicalize_url(url);
-
-        if false {
-            bail!(errors::ErrorKind::InvalidURLError(
-                "Invalid master URL".into(),
-            ));
+        if !util::is_valid_master_url(url) {
+            bail!(errors::ErrorKind::InvalidURLError(format!(
+                "Invalid master URL: {}",
+                url
+            )));
         }
 
+        let canonical_master_url = util::canonicalize_url(url);
+
         let auth = _auth.trim().to_string();
         if auth.is_empty() {
             bail!(errors::ErrorKind::AuthError("Missing account key".into()));
         }
 
-        if self.projects.find_by_url(&canonical_master_url).is_some() {
+        if let Some(project) = self.projects.find_matching(&canonical_master_url) {
             bail!(errors::ErrorKind::AlreadyAttachedError(format!(
                 "Already attached to project {}",
-                &canonical_master_url
+                project.master_url()
             )));
         }
 
@@ -226,15 +1944,10 @@ impl ClientState {
             p.project_name = Some(project_name.into());
             p.authenticator = auth.clone();
             p.attached_via_acct_mgr = attached_via_acct_mgr;
-
-            p.write_account_file()?;
-
-            p.parse_account(&treexml::Document::parse(std::fs::File::open(
-                file_names::account_filename(&canonical_master_url),
-            )?)?.root
-                .unwrap())?;
         }
 
+        proj.write_account_file(std::path::Path::new("."))?;
+
         let path = std::path::PathBuf::from(&format!(
             "{}/{}",
             project_dir.display(),
@@ -264,3 +1977,129 @@ impl ClientState {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern crate uuid;
+
+    fn rpc(url: &str) -> SchedulerRpc {
+        SchedulerRpc {
+            project_url: url.into(),
+            scheduler_urls: Vec::new(),
+            request: treexml::Element::new("scheduler_request"),
+            work_req: None,
+        }
+    }
+
+    fn reply(s: &str) -> errors::Result<scheduler::SchedulerReply> {
+        let root = treexml::Document::parse(std::io::Cursor::new(s)).unwrap().root.unwrap();
+        scheduler::SchedulerReply::try_from(&root)
+    }
+
+    #[test]
+    fn test_scheduler_jobs() {
+        let url = "http://a/";
+        let mut state = ClientState::default();
+        state.projects.data.insert(projects::Project::new(url.into()));
+
+        state.finish_scheduler_rpc(
+            &rpc(url),
+            vec!["http://a/cgi".into()],
+            reply(
+                "<scheduler_reply>\n\
+                 <userid>1</userid>\n\
+                 <app><name>app</name><user_friendly_name>App</user_friendly_name></app>\n\
+                 <file_info><name>app_1</name><url>http://a/dl/app_1</url><executable/></file_info>\n\
+                 <file_info><name>in_1</name><url>http://a/dl/in_1</url></file_info>\n\
+                 <file_info><name>out_1</name><generated_locally/><url>http://a/ul</url></file_info>\n\
+                 <app_version><app_name>app</app_name><version_num>1</version_num>\n\
+                 <file_ref><file_name>app_1</file_name><main_program/></file_ref></app_version>\n\
+                 <workunit><name>wu</name><app_name>app</app_name>\n\
+                 <file_ref><file_name>in_1</file_name></file_ref></workunit>\n\
+                 <result><name>r</name><wu_name>wu</wu_name><report_deadline>1e10</report_deadline>\n\
+                 <file_ref><file_name>out_1</file_name><open_name>out</open_name></file_ref></result>\n\
+                 <result><name>orphan</name><wu_name>none</wu_name></result>\n\
+                 </scheduler_reply>",
+            ),
+        );
+
+        assert_eq!(state.results.len(), 1);
+        let r = state.results.values().next().unwrap().clone();
+        assert_eq!(r.app_version.file_name, "app_1");
+        assert_eq!(r.input_files.len(), 2);
+        assert_eq!(r.output_files, vec![state.find_file(url, "out_1").unwrap()]);
+        assert_eq!(r.output_refs[0].open_name, "out");
+
+        // Nothing is downloaded while the project is over its disk quota
+        state.projects.find_by_url(url).unwrap().data.lock().unwrap().disk_usage = 1e9;
+        assert!(state.file_transfers().is_empty());
+        state.projects.find_by_url(url).unwrap().data.lock().unwrap().disk_usage = 0.0;
+
+        let xfers = state.file_transfers();
+        assert_eq!(xfers.len(), 2);
+        assert!(xfers.iter().all(|&(_, ref v)| !v.upload));
+
+        for (id, _) in xfers {
+            state.finish_file_transfer(&id, false, Ok(()));
+        }
+        state.update_results().unwrap();
+        assert_eq!(
+            state.results.values().next().unwrap().state,
+            result::ResultState::FilesDownloaded
+        );
+
+        // The task is dropped once its result has been collected
+        state.create_tasks().unwrap();
+        let task = state.results.values().next().unwrap().task.unwrap();
+        state.tasks.abort_task(&task).wait().unwrap();
+        state.update_results().unwrap();
+        assert_eq!(state.results.values().next().unwrap().state, result::ResultState::Aborted);
+        assert!(state.results.values().next().unwrap().task.is_none());
+        assert!(state.tasks.tasks().wait().unwrap().is_empty());
+
+        state.cc_config.report_results_immediately = true;
+        for r in state.results.values_mut() {
+            r.abort(&state.clock_source.now());
+        }
+        let rpc = state.scheduler_request().unwrap();
+        assert!(format!("{}", rpc.request).contains("<name>r</name>"));
+
+        state.finish_scheduler_rpc(
+            &rpc,
+            Vec::new(),
+            reply("<scheduler_reply><userid>1</userid><result_ack><name>r</name></result_ack></scheduler_reply>"),
+        );
+        assert!(state.results.is_empty());
+        assert!(state.find_file(url, "out_1").is_none());
+    }
+
+    #[test]
+    fn test_state_file() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("state-file-{}", uuid::Uuid::new(uuid::UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let url = "http://a/";
+        let mut state = ClientState::default();
+        state.read_state_file(&dir).unwrap();
+        state.projects.data.insert(projects::Project::new(url.into()));
+        {
+            let mut data = state.projects.find_by_url(url).unwrap().data.lock().unwrap();
+            data.duration_correction_factor = 2.5;
+            data.host_venue = "work".into();
+        }
+        state.write_state_file(&dir).unwrap();
+        assert!(!dir.join(constants::STATE_FILE_NEXT_NAME).exists());
+
+        let mut state = ClientState::default();
+        state.projects.data.insert(projects::Project::new(url.into()));
+        state.read_state_file(&dir).unwrap();
+        let data = state.projects.find_by_url(url).unwrap().data.lock().unwrap();
+        assert_eq!(data.duration_correction_factor, 2.5);
+        assert_eq!(data.host_venue, "work");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
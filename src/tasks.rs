@@ -2,46 +2,81 @@
 
 extern crate boinc_app_api as api;
 extern crate futures_await as futures;
-extern crate futures_cpupool;
 extern crate futures_spawn;
+extern crate libc;
 extern crate std;
+extern crate treexml;
+extern crate treexml_util;
 extern crate uuid;
 
+use cc_config;
+#[cfg(test)]
+use common;
+use coproc;
 use errors;
+use file_names;
+use hostinfo;
+use projects;
+use sandbox;
+#[cfg(test)]
+use test_util;
 use util;
 
 use self::futures::*;
 use self::futures::prelude::*;
 use self::futures::future::{ok, FutureResult, PollFn};
-use self::futures_cpupool::*;
 use self::futures_spawn::*;
+use self::treexml_util::Unmarshaller;
+use self::treexml_util::{make_text_element, make_tree_element};
 use self::uuid::*;
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, RwLock, TryLockError};
 use std::sync::atomic::{AtomicBool, Ordering};
 
 use app::*;
+use common::ProjAm;
+#[cfg(test)]
+use common::{ClockInitializable, ClockSource};
 use workunit::*;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RunStatus {
     Running,
     StopRequested,
+    Suspended,
     Stopped,
     Aborted,
     Error,
     Done,
 }
 
+/// Exit code reported for tasks aborted through `abort_task`
+pub const EXIT_ABORTED_BY_CLIENT: i32 = 194;
+
+/// Time given to a task to exit after being asked to quit
+const QUIT_TIMEOUT_SECS: u64 = 15;
+
 struct ProcessData {
-    pub pid: i64,
-    pub conn: api::app_connection::AppConnection,
+    /// Process ID of the task, which is also its process group ID
+    pub pid: i32,
+    pub child: std::process::Child,
+    pub kill_deadline: Option<std::time::Instant>,
+}
+
+impl ProcessData {
+    fn signal(&self, signal: i32) -> errors::Result<()> {
+        sandbox::signal_group(self.pid, signal)
+    }
 }
 
 enum FullRunStatus {
     Running(ProcessData),
     StopRequested(ProcessData),
+    Suspended(ProcessData),
     Stopped,
     Aborted,
     Error,
@@ -53,6 +88,7 @@ impl<'a> From<&'a FullRunStatus> for RunStatus {
         match *v {
             FullRunStatus::Running(_) => RunStatus::Running,
             FullRunStatus::StopRequested(_) => RunStatus::StopRequested,
+            FullRunStatus::Suspended(_) => RunStatus::Suspended,
             FullRunStatus::Stopped => RunStatus::Stopped,
             FullRunStatus::Aborted => RunStatus::Aborted,
             FullRunStatus::Error => RunStatus::Error,
@@ -61,23 +97,441 @@ impl<'a> From<&'a FullRunStatus> for RunStatus {
     }
 }
 
+/// How a running task is taken off the CPU.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopMethod {
+    /// The process is kept in memory and resumes exactly where it left off.
+    Suspend,
+    /// The process exits and restarts from its last checkpoint later.
+    Quit,
+}
+
 #[derive(Clone, Debug)]
 pub struct TaskStatus {
     pub status: RunStatus,
     pub pct_complete: f64,
+    /// CPU time used by the current run
+    pub current_cpu_time: f64,
+    /// wall time spent running
+    pub elapsed_time: f64,
+    /// CPU time at the last checkpoint, zero if the task has never checkpointed
+    pub checkpoint_cpu_time: f64,
+    /// elapsed time at the last checkpoint
+    pub checkpoint_elapsed_time: f64,
+    /// exit code of the finished process
+    pub exit_status: Option<i32>,
+    /// set if the task was aborted for exceeding its workunit's bounds
+    pub limit_exceeded: Option<sandbox::Limit>,
+    /// contents of the slot's stderr.txt once the task has finished
+    pub stderr_out: Option<String>,
+    /// bytes used by the slot directory as of the last scan
+    pub disk_usage: f64,
+}
+
+impl Default for TaskStatus {
+    fn default() -> Self {
+        Self {
+            status: RunStatus::Stopped,
+            pct_complete: 0.0,
+            current_cpu_time: 0.0,
+            elapsed_time: 0.0,
+            checkpoint_cpu_time: 0.0,
+            checkpoint_elapsed_time: 0.0,
+            exit_status: None,
+            limit_exceeded: None,
+            stderr_out: None,
+            disk_usage: 0.0,
+        }
+    }
+}
+
+impl TaskStatus {
+    pub fn has_checkpointed(&self) -> bool {
+        self.checkpoint_cpu_time > 0.0
+    }
+
+    /// Wall time that would be lost if the task were to restart from its last checkpoint now.
+    pub fn time_since_checkpoint(&self) -> f64 {
+        (self.elapsed_time - self.checkpoint_elapsed_time).max(0.0)
+    }
+
+    /// Rolls the task back to its last checkpoint, as happens after it is quit.
+    fn restart_from_checkpoint(&mut self) {
+        self.current_cpu_time = self.checkpoint_cpu_time;
+        self.elapsed_time = self.checkpoint_elapsed_time;
+    }
+}
+
+/// Decides how to preempt a task.
+///
+/// Tasks are kept in memory if the user allows it. Otherwise they are quit and restarted from their checkpoint,
+/// unless they have never checkpointed - quitting those would throw away all work done so far.
+pub fn preempt_method(status: &TaskStatus, leave_apps_in_memory: bool) -> StopMethod {
+    if leave_apps_in_memory || !status.has_checkpointed() {
+        StopMethod::Suspend
+    } else {
+        StopMethod::Quit
+    }
+}
+
+/// Status report received from the application over its status channel
+#[derive(Clone, Debug, Default)]
+pub struct AppStatus {
+    pub current_cpu_time: f64,
+    pub checkpoint_cpu_time: f64,
+    pub fraction_done: f64,
+}
+
+impl AppStatus {
+    pub fn parse(msg: &str) -> errors::Result<AppStatus> {
+        let doc = treexml::Document::parse(std::io::Cursor::new(format!("<root>{}</root>", msg)))?;
+        let mut v = AppStatus::default();
+        for node in &doc.root.unwrap().children {
+            match &*node.name {
+                "current_cpu_time" => {
+                    let _ = v.current_cpu_time.unmarshal(&node);
+                }
+                "checkpoint_cpu_time" => {
+                    let _ = v.checkpoint_cpu_time.unmarshal(&node);
+                }
+                "fraction_done" => {
+                    let _ = v.fraction_done.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(v)
+    }
+}
+
+/// Size of each message channel in the shared memory file. The first byte is set while a message is pending.
+const MSG_CHANNEL_SIZE: u64 = 1024;
+/// Number of channels in the shared memory file
+const NUM_CHANNELS: u64 = 8;
+/// Position of the app status channel in the shared memory file
+const APP_STATUS_CHANNEL: u64 = 5;
+
+/// Creates the file the application maps to exchange messages with the client.
+fn create_shmem_file(slot_dir: &Path) -> errors::Result<()> {
+    std::fs::File::create(slot_dir.join(file_names::MMAPPED_FILE_NAME))?
+        .set_len(NUM_CHANNELS * MSG_CHANNEL_SIZE)?;
+    Ok(())
+}
+
+/// Takes the pending message off the app status channel, if there is one.
+fn receive_app_status(slot_dir: &Path) -> errors::Result<Option<String>> {
+    let f = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(slot_dir.join(file_names::MMAPPED_FILE_NAME))?;
+    let offset = APP_STATUS_CHANNEL * MSG_CHANNEL_SIZE;
+    let mut buf = vec![0u8; MSG_CHANNEL_SIZE as usize];
+    let n = f.read_at(&mut buf, offset)?;
+    if n == 0 || buf[0] == 0 {
+        return Ok(None);
+    }
+
+    let end = buf[1..n].iter().position(|b| *b == 0).map(|v| v + 1).unwrap_or(n);
+    let msg = String::from_utf8_lossy(&buf[1..end]).into_owned();
+    f.write_at(&[0], offset)?;
+    Ok(Some(msg))
 }
 
 struct Task {
+    pub exec_path: PathBuf,
     pub cmdline: String,
+    pub slot_dir: PathBuf,
+    pub limits: sandbox::ResourceLimits,
+    pub output_limits: OutputLimits,
     pub status: FullRunStatus,
     pub pct_complete: f64,
+    pub current_cpu_time: f64,
+    pub elapsed_time: f64,
+    pub checkpoint_cpu_time: f64,
+    pub checkpoint_elapsed_time: f64,
+    /// CPU time carried over from earlier runs, which the current process's usage adds to
+    pub cpu_time_base: f64,
+    pub exit_status: Option<i32>,
+    pub limit_exceeded: Option<sandbox::Limit>,
+    pub stderr_out: Option<String>,
+    pub disk_usage: f64,
+    /// files the application writes in its slot, moved out once it finishes
+    pub output_files: Vec<SlotFile>,
+    pub init_data: InitData,
 }
 
 impl Task {
+    pub fn new(
+        exec_path: PathBuf,
+        cmdline: String,
+        slot_dir: PathBuf,
+        limits: sandbox::ResourceLimits,
+        output_limits: OutputLimits,
+    ) -> Self {
+        Self {
+            exec_path: exec_path,
+            cmdline: cmdline,
+            slot_dir: slot_dir,
+            limits: limits,
+            output_limits: output_limits,
+            status: FullRunStatus::Stopped,
+            pct_complete: 0.0,
+            current_cpu_time: 0.0,
+            elapsed_time: 0.0,
+            checkpoint_cpu_time: 0.0,
+            checkpoint_elapsed_time: 0.0,
+            cpu_time_base: 0.0,
+            exit_status: None,
+            limit_exceeded: None,
+            stderr_out: None,
+            disk_usage: 0.0,
+            output_files: Vec::new(),
+            init_data: Default::default(),
+        }
+    }
+
     pub fn get_status(&self) -> TaskStatus {
         TaskStatus {
             status: (&self.status).into(),
             pct_complete: self.pct_complete,
+            current_cpu_time: self.current_cpu_time,
+            elapsed_time: self.elapsed_time,
+            checkpoint_cpu_time: self.checkpoint_cpu_time,
+            checkpoint_elapsed_time: self.checkpoint_elapsed_time,
+            exit_status: self.exit_status,
+            limit_exceeded: self.limit_exceeded,
+            stderr_out: self.stderr_out.clone(),
+            disk_usage: self.disk_usage,
+        }
+    }
+
+    /// Applies a status report from the application. Its times count from the start of the current process.
+    pub fn apply_app_status(&mut self, v: &AppStatus) {
+        self.current_cpu_time = self.cpu_time_base + v.current_cpu_time;
+        self.pct_complete = v.fraction_done;
+        let checkpoint_cpu_time = self.cpu_time_base + v.checkpoint_cpu_time;
+        if v.checkpoint_cpu_time > 0.0 && checkpoint_cpu_time != self.checkpoint_cpu_time {
+            self.checkpoint_cpu_time = checkpoint_cpu_time;
+            self.checkpoint_elapsed_time = self.elapsed_time;
+        }
+    }
+
+    fn open_output(&self, name: &str) -> errors::Result<std::process::Stdio> {
+        Ok(std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.slot_dir.join(name))?
+            .into())
+    }
+
+    /// Keeps output files within their configured sizes.
+    fn truncate_output(&self) -> errors::Result<()> {
+        let v = &self.output_limits;
+        util::truncate_file(
+            &self.slot_dir.join(file_names::STDERR_FILE_NAME),
+            v.max_stderr_size,
+            v.stderr_head,
+        )?;
+        util::truncate_file(
+            &self.slot_dir.join(file_names::STDOUT_FILE_NAME),
+            v.max_stdout_size,
+            false,
+        )?;
+        Ok(())
+    }
+
+    fn read_stderr(&self) -> errors::Result<String> {
+        let buf = util::read_truncated(
+            &self.slot_dir.join(file_names::STDERR_FILE_NAME),
+            self.output_limits.max_stderr_size,
+            self.output_limits.stderr_head,
+        )?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn spawn(&self, niceness: Option<i32>) -> errors::Result<ProcessData> {
+        create_shmem_file(&self.slot_dir)?;
+
+        let mut cmd = std::process::Command::new(&self.exec_path);
+        cmd.args(self.cmdline.split_whitespace())
+            .current_dir(&self.slot_dir)
+            .stdin(std::process::Stdio::null())
+            .stdout(self.open_output(file_names::STDOUT_FILE_NAME)?)
+            .stderr(self.open_output(file_names::STDERR_FILE_NAME)?);
+        sandbox::confine(&mut cmd, &self.limits, self.checkpoint_cpu_time, niceness);
+
+        let child = cmd.spawn()?;
+        Ok(ProcessData {
+            pid: child.id() as i32,
+            child: child,
+            kill_deadline: None,
+        })
+    }
+
+    fn start(&mut self, niceness: Option<i32>) -> errors::Result<()> {
+        self.status = match std::mem::replace(&mut self.status, FullRunStatus::Stopped) {
+            FullRunStatus::Stopped => {
+                let p = self.spawn(niceness)?;
+                self.cpu_time_base = self.checkpoint_cpu_time;
+                FullRunStatus::Running(p)
+            }
+            FullRunStatus::Suspended(p) => {
+                p.signal(libc::SIGCONT)?;
+                FullRunStatus::Running(p)
+            }
+            other @ FullRunStatus::Running(_) => other,
+            other => {
+                self.status = other;
+                bail!(errors::ErrorKind::InternalError(
+                    "Task cannot be started in its current state".into(),
+                ));
+            }
+        };
+        Ok(())
+    }
+
+    fn suspend(&mut self) -> errors::Result<()> {
+        self.status = match std::mem::replace(&mut self.status, FullRunStatus::Stopped) {
+            FullRunStatus::Running(p) => {
+                p.signal(libc::SIGSTOP)?;
+                FullRunStatus::Suspended(p)
+            }
+            other => other,
+        };
+        Ok(())
+    }
+
+    fn quit(&mut self) -> errors::Result<()> {
+        self.status = match std::mem::replace(&mut self.status, FullRunStatus::Stopped) {
+            FullRunStatus::Running(mut p) | FullRunStatus::Suspended(mut p) => {
+                p.signal(libc::SIGTERM)?;
+                p.signal(libc::SIGCONT)?;
+                p.kill_deadline = Some(
+                    std::time::Instant::now() + std::time::Duration::from_secs(QUIT_TIMEOUT_SECS),
+                );
+                FullRunStatus::StopRequested(p)
+            }
+            other => other,
+        };
+        Ok(())
+    }
+
+    /// Kills the process right away. Further status is determined when it is reaped.
+    fn kill(&mut self) -> errors::Result<()> {
+        match self.status {
+            FullRunStatus::Running(ref p)
+            | FullRunStatus::Suspended(ref p)
+            | FullRunStatus::StopRequested(ref p) => {
+                p.signal(libc::SIGKILL)?;
+                p.signal(libc::SIGCONT)?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self) -> errors::Result<()> {
+        self.kill()?;
+        self.status = match std::mem::replace(&mut self.status, FullRunStatus::Aborted) {
+            FullRunStatus::Running(mut p)
+            | FullRunStatus::Suspended(mut p)
+            | FullRunStatus::StopRequested(mut p) => {
+                let _ = p.child.wait();
+                FullRunStatus::Aborted
+            }
+            _ => FullRunStatus::Aborted,
+        };
+        self.exit_status = Some(EXIT_ABORTED_BY_CLIENT);
+        Ok(())
+    }
+
+    fn on_exit(&mut self, v: std::process::ExitStatus) {
+        let quit_requested = match self.status {
+            FullRunStatus::StopRequested(_) => true,
+            _ => false,
+        };
+
+        if !quit_requested {
+            self.stderr_out = self.read_stderr().ok();
+        }
+
+        if v.signal() == Some(libc::SIGXCPU) {
+            self.limit_exceeded = Some(sandbox::Limit::CpuTime);
+        }
+
+        self.status = if let Some(limit) = self.limit_exceeded {
+            self.exit_status = Some(limit.exit_code());
+            FullRunStatus::Aborted
+        } else if quit_requested {
+            self.current_cpu_time = self.checkpoint_cpu_time;
+            self.elapsed_time = self.checkpoint_elapsed_time;
+            FullRunStatus::Stopped
+        } else {
+            self.exit_status = v.code();
+            if v.success() && move_output_files(&self.slot_dir, &self.output_files).is_ok() {
+                FullRunStatus::Done
+            } else {
+                FullRunStatus::Error
+            }
+        };
+    }
+
+    /// Polls the process, accounts for the time it ran and enforces the workunit's bounds.
+    fn supervise(&mut self, dt: f64, scan_disk: bool) {
+        let exited = match self.status {
+            FullRunStatus::Running(ref mut p)
+            | FullRunStatus::Suspended(ref mut p)
+            | FullRunStatus::StopRequested(ref mut p) => {
+                if let Some(deadline) = p.kill_deadline {
+                    if std::time::Instant::now() > deadline {
+                        let _ = p.signal(libc::SIGKILL);
+                    }
+                }
+                p.child.try_wait().ok().and_then(|v| v)
+            }
+            _ => {
+                return;
+            }
+        };
+
+        if let Some(v) = exited {
+            self.on_exit(v);
+            return;
+        }
+
+        let pid = match self.status {
+            FullRunStatus::Running(ref p) => Some(p.pid),
+            _ => None,
+        };
+        if let Some(pid) = pid {
+            self.elapsed_time += dt;
+
+            if let Ok(Some(msg)) = receive_app_status(&self.slot_dir) {
+                if let Ok(v) = AppStatus::parse(&msg) {
+                    self.apply_app_status(&v);
+                }
+            }
+
+            // Measured usage takes precedence over what the application reports
+            if let Ok(v) = sandbox::cpu_time(pid) {
+                self.current_cpu_time = self.cpu_time_base + v;
+            }
+
+            let mut usage = sandbox::Usage::default();
+            usage.cpu_time = self.current_cpu_time;
+            usage.memory = sandbox::resident_memory(pid).unwrap_or(0.0);
+            if scan_disk {
+                let _ = self.truncate_output();
+                usage.disk = sandbox::dir_usage(&self.slot_dir).unwrap_or(0.0);
+                self.disk_usage = usage.disk;
+            }
+
+            self.limit_exceeded = self.limits.check(&usage);
+        }
+
+        if self.limit_exceeded.is_some() {
+            let _ = self.kill();
         }
     }
 }
@@ -95,54 +549,279 @@ impl Future for Task {
 }
 */
 
+/// What the application learns about its project and account from init_data.xml in its slot
+#[derive(Clone, Debug, Default)]
+pub struct InitData {
+    pub master_url: String,
+    pub project_name: String,
+    pub user_name: String,
+    pub team_name: String,
+    pub userid: u64,
+    pub teamid: u64,
+    pub hostid: u64,
+    pub host_venue: String,
+    pub wu_name: String,
+    /// `<project_specific>` preferences for the host's venue
+    pub project_preferences: Option<treexml::Element>,
+    /// coprocessor type and device number the task is to use
+    pub gpu: Option<(coproc::ProcType, i64)>,
+}
+
+impl<'a> From<&'a projects::Project> for InitData {
+    fn from(v: &projects::Project) -> InitData {
+        let data = v.data.lock().unwrap();
+        InitData {
+            master_url: v.master_url(),
+            project_name: data.project_name.clone().unwrap_or_default(),
+            user_name: data.user_name.clone(),
+            team_name: data.team_name.clone(),
+            userid: data.userid,
+            teamid: data.teamid,
+            hostid: data.hostid,
+            host_venue: data.host_venue.clone(),
+            wu_name: String::new(),
+            project_preferences: data.project_specific_prefs.clone(),
+            gpu: None,
+        }
+    }
+}
+
+impl<'a> From<&'a InitData> for treexml::Element {
+    fn from(v: &InitData) -> treexml::Element {
+        let mut children = vec![
+            make_text_element("master_url", &v.master_url),
+            make_text_element("project_name", &v.project_name),
+            make_text_element("user_name", &v.user_name),
+            make_text_element("team_name", &v.team_name),
+            make_text_element("userid", v.userid),
+            make_text_element("teamid", v.teamid),
+            make_text_element("hostid", v.hostid),
+            make_text_element("host_venue", &v.host_venue),
+            make_text_element("wu_name", &v.wu_name),
+            make_tree_element(
+                "project_preferences",
+                v.project_preferences
+                    .as_ref()
+                    .map(|e| e.children.clone())
+                    .unwrap_or_default(),
+            ),
+        ];
+        if let Some((rsc, n)) = v.gpu {
+            children.push(make_text_element("gpu_type", rsc.rsc_name()));
+            children.push(make_text_element("gpu_device_num", n));
+        }
+        make_tree_element("app_init_data", children)
+    }
+}
+
+fn write_init_data(slot_dir: &PathBuf, v: &InitData) -> errors::Result<()> {
+    std::fs::File::create(slot_dir.join(file_names::INIT_DATA_FILE_NAME))?
+        .write_fmt(format_args!("{}", treexml::Element::from(v)))?;
+    Ok(())
+}
+
+/// A file of the task and the name the application opens it by in its slot
+#[derive(Clone, Debug, Default)]
+pub struct SlotFile {
+    /// where the file is kept outside of the slot
+    pub path: PathBuf,
+    pub open_name: String,
+    /// the file is copied into the slot rather than linked
+    pub copy: bool,
+}
+
+/// Files the task reads and writes
+#[derive(Clone, Debug, Default)]
+pub struct TaskFiles {
+    pub inputs: Vec<SlotFile>,
+    pub outputs: Vec<SlotFile>,
+}
+
+/// Makes the input files available in the slot under their open names, as soft links the application resolves.
+fn link_input_files(slot_dir: &Path, files: &[SlotFile]) -> errors::Result<()> {
+    for f in files {
+        let dst = slot_dir.join(&f.open_name);
+        if f.copy {
+            std::fs::copy(&f.path, &dst)?;
+        } else {
+            std::fs::File::create(&dst)?
+                .write_fmt(format_args!("<soft_link>{}</soft_link>\n", f.path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Moves the output files the application wrote in its slot to where they are kept. Outputs it did not write are left missing.
+fn move_output_files(slot_dir: &Path, files: &[SlotFile]) -> errors::Result<()> {
+    for f in files {
+        let src = slot_dir.join(&f.open_name);
+        if !src.exists() {
+            continue;
+        }
+        if std::fs::rename(&src, &f.path).is_err() {
+            std::fs::copy(&src, &f.path)?;
+            std::fs::remove_file(&src)?;
+        }
+    }
+    Ok(())
+}
+
 /// Managing server that controls all tasks, running or otherwise.
 pub trait TaskServer {
     fn tasks(&self) -> errors::FResult<HashMap<Uuid, TaskStatus>>;
 
-    fn create_task(&self, &AppVersion, &Workunit) -> errors::FResult<Uuid>;
+    fn create_task(&self, &AppVersion, &Workunit, &InitData, &TaskFiles) -> errors::FResult<Uuid>;
 
     fn start_task(&self, &Uuid) -> errors::FResult<()>;
+    /// Names the coprocessor device the task is to use from its next start.
+    fn assign_gpu(&self, &Uuid, Option<(coproc::ProcType, i64)>) -> errors::FResult<()>;
+    /// Pauses the task while keeping it in memory.
+    fn suspend_task(&self, &Uuid) -> errors::FResult<()>;
+    /// Quits the task. It will restart from its last checkpoint.
     fn stop_task(&self, &Uuid) -> errors::FResult<()>;
     fn abort_task(&self, &Uuid) -> errors::FResult<()>;
+    /// Forgets a task that is no longer running, along with its slot.
+    fn remove_task(&self, &Uuid) -> errors::FResult<()>;
+
+    /// Takes the task off the CPU using the method chosen by `preempt_method`.
+    fn preempt_task(&self, &Uuid, leave_apps_in_memory: bool) -> errors::FResult<StopMethod>;
+}
+
+/// Size limit used for output files unless cc_config.xml sets one
+pub const DEFAULT_MAX_OUTPUT_SIZE: u64 = 64 * 1024;
+
+/// Maximum sizes of the files that task output is redirected to
+#[derive(Clone, Debug)]
+pub struct OutputLimits {
+    pub max_stderr_size: u64,
+    pub max_stdout_size: u64,
+    /// keep the beginning of stderr.txt rather than its end when truncating
+    pub stderr_head: bool,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        Self {
+            max_stderr_size: DEFAULT_MAX_OUTPUT_SIZE,
+            max_stdout_size: DEFAULT_MAX_OUTPUT_SIZE,
+            stderr_head: false,
+        }
+    }
+}
+
+impl<'a> From<&'a cc_config::CCConfig> for OutputLimits {
+    fn from(v: &cc_config::CCConfig) -> Self {
+        let size = |v: i64| if v > 0 {
+            v as u64
+        } else {
+            DEFAULT_MAX_OUTPUT_SIZE
+        };
+        Self {
+            max_stderr_size: size(v.max_stderr_file_size),
+            max_stdout_size: size(v.max_stdout_file_size),
+            stderr_head: v.stderr_head,
+        }
+    }
+}
+
+/// Process settings shared by all tasks
+#[derive(Clone, Debug, Default)]
+pub struct SpawnSettings {
+    /// measured floating point speed of one CPU, used to turn FLOP bounds into CPU time limits
+    pub p_fpops: f64,
+    pub niceness: Option<i32>,
+    pub output_limits: OutputLimits,
 }
 
+impl SpawnSettings {
+    pub fn new(cc_config: &cc_config::CCConfig, host_info: &hostinfo::HostInfo) -> Self {
+        Self {
+            p_fpops: host_info.p_fpops,
+            niceness: sandbox::niceness(cc_config),
+            output_limits: cc_config.into(),
+        }
+    }
+}
+
+/// How often running tasks are polled
+const SUPERVISE_PERIOD_MS: u64 = 1000;
+/// Slot directories are scanned once every this many polls
+const DISK_SCAN_INTERVAL: usize = 10;
+
 pub struct RealTaskServer {
-    // Makes sure that the task is alive
     root: std::path::PathBuf,
+    settings: SpawnSettings,
+    close_flag: Arc<AtomicBool>,
+    // Makes sure that the task is alive
     worker: Option<std::thread::JoinHandle<()>>,
     data: Arc<Mutex<HashMap<Uuid, Task>>>,
     reserved: Arc<Mutex<HashSet<Uuid>>>,
 }
 
+impl Drop for RealTaskServer {
+    fn drop(&mut self) {
+        self.close_flag.store(true, Ordering::Relaxed);
+        self.worker.take().unwrap().join().unwrap();
+    }
+}
+
 impl RealTaskServer {
     fn task_path(&self, id: &Uuid) -> PathBuf {
         util::task_path(&self.root, id)
     }
 
-    fn new(root: std::path::PathBuf) -> Self {
+    pub fn new(root: std::path::PathBuf, settings: SpawnSettings) -> Self {
+        let data = Arc::new(Mutex::new(HashMap::default()));
+        let close_flag = Arc::new(AtomicBool::default());
+
+        let worker = std::thread::spawn({
+            let data = Arc::clone(&data);
+            let close_flag = Arc::clone(&close_flag);
+
+            move || {
+                let mut tick = 0;
+                loop {
+                    if close_flag.load(Ordering::Relaxed) == true {
+                        return;
+                    }
+                    {
+                        let mut d: std::sync::MutexGuard<HashMap<Uuid, Task>> = data.lock().unwrap();
+                        for (_, task) in d.iter_mut() {
+                            task.supervise(
+                                SUPERVISE_PERIOD_MS as f64 / 1000.0,
+                                tick % DISK_SCAN_INTERVAL == 0,
+                            );
+                        }
+                    }
+                    tick += 1;
+                    std::thread::sleep(std::time::Duration::from_millis(SUPERVISE_PERIOD_MS));
+                }
+            }
+        });
+
         Self {
             root: root,
-            worker: Default::default(),
-            data: Default::default(),
+            settings: settings,
+            close_flag: close_flag,
+            worker: Some(worker),
+            data: data,
             reserved: Default::default(),
         }
     }
 
-    #[async(boxed)]
-    fn _create_task(
-        data: Arc<Mutex<HashMap<Uuid, Task>>>,
-        reserved: Arc<Mutex<HashSet<Uuid>>>,
-        root: std::path::PathBuf,
-        app_version: &AppVersion,
-        wu: &Workunit,
-    ) -> errors::Result<Uuid> {
-        let id = await!(util::mutex_critical(data, move |data| Ok(util::reserve_unique(data, &mut reserved.lock().unwrap()))))?;
-
-        // Create slot directory
-        std::fs::create_dir_all(util::task_path(&root, &id));
-
-        // Copy task files: appversion, wu, etc
-        Err(errors::ErrorKind::NotImplementedError(()).into())
+    fn with_task<T, F>(&self, id: &Uuid, f: F) -> errors::FResult<T>
+    where
+        T: 'static,
+        F: Fn(&mut Task) -> errors::Result<T> + 'static,
+    {
+        let id = id.clone();
+        Box::new(util::mutex_critical(
+            Arc::clone(&self.data),
+            move |d| match d.get_mut(&id) {
+                None => Err(errors::ErrorKind::NoSuchTaskError(id).into()),
+                Some(task) => f(task),
+            },
+        ))
     }
 }
 
@@ -155,59 +834,145 @@ impl TaskServer for RealTaskServer {
         }))
     }
 
-    fn create_task(&self, app_version: &AppVersion, wu: &Workunit) -> errors::FResult<Uuid> {
-        Self::_create_task(
-            self.data.clone(),
-            self.reserved.clone(),
-            self.root.clone(),
-            app_version,
-            wu,
-        )
+    fn create_task(
+        &self,
+        app_version: &AppVersion,
+        wu: &Workunit,
+        init_data: &InitData,
+        files: &TaskFiles,
+    ) -> errors::FResult<Uuid> {
+        let root = self.root.clone();
+        let init_data = InitData {
+            wu_name: wu.name.clone(),
+            ..init_data.clone()
+        };
+        let reserved = Arc::clone(&self.reserved);
+        let exec_path = PathBuf::from(&app_version.file_name);
+        let cmdline = wu.command_line.clone();
+        let limits = sandbox::ResourceLimits::new(wu, self.settings.p_fpops);
+        let output_limits = self.settings.output_limits.clone();
+        let files = files.clone();
+        Box::new(util::mutex_critical(Arc::clone(&self.data), move |data| {
+            let id = util::reserve_unique(data, &mut reserved.lock().unwrap());
+            let slot_dir = util::task_path(&root, &id);
+
+            let v = std::fs::create_dir_all(&slot_dir)
+                .map_err(errors::Error::from)
+                .and_then(|_| write_init_data(&slot_dir, &init_data))
+                .and_then(|_| link_input_files(&slot_dir, &files.inputs))
+                .map(|_| {
+                    data.insert(
+                        id,
+                        Task {
+                            output_files: files.outputs.clone(),
+                            init_data: init_data.clone(),
+                            ..Task::new(
+                                exec_path.clone(),
+                                cmdline.clone(),
+                                slot_dir.clone(),
+                                limits.clone(),
+                                output_limits.clone(),
+                            )
+                        },
+                    );
+                });
+            reserved.lock().unwrap().remove(&id);
+            v?;
+
+            Ok(id)
+        }))
     }
 
     fn start_task(&self, id: &Uuid) -> errors::FResult<()> {
-        let id = id.clone();
-        Box::new(util::mutex_critical(
-            Arc::clone(&self.data),
-            move |d| match d.get_mut(&id) {
-                None => Err(errors::ErrorKind::NotImplementedError(()).into()),
-                Some(e) => {
-                    //e.status = FullRunStatus::Running(ProcessData {});
-                    Err(errors::ErrorKind::NotImplementedError(()).into())
-                }
-            },
-        ))
+        let niceness = self.settings.niceness;
+        self.with_task(id, move |task| task.start(niceness))
+    }
+
+    fn assign_gpu(&self, id: &Uuid, gpu: Option<(coproc::ProcType, i64)>) -> errors::FResult<()> {
+        self.with_task(id, move |task| {
+            if task.init_data.gpu == gpu {
+                return Ok(());
+            }
+            task.init_data.gpu = gpu;
+            write_init_data(&task.slot_dir, &task.init_data)
+        })
+    }
+
+    fn suspend_task(&self, id: &Uuid) -> errors::FResult<()> {
+        self.with_task(id, |task| task.suspend())
+    }
+
+    fn stop_task(&self, id: &Uuid) -> errors::FResult<()> {
+        self.with_task(id, |task| task.quit())
+    }
+
+    fn abort_task(&self, id: &Uuid) -> errors::FResult<()> {
+        self.with_task(id, |task| task.abort())
     }
 
-    #[async(boxed)]
-    fn stop_task(&self, id: &Uuid) -> errors::Result<()> {
-        Err(errors::ErrorKind::NotImplementedError(()).into())
+    fn remove_task(&self, id: &Uuid) -> errors::FResult<()> {
+        let id = id.clone();
+        Box::new(util::mutex_critical(Arc::clone(&self.data), move |data| {
+            let slot_dir = match data.remove(&id) {
+                Some(task) => task.slot_dir,
+                None => {
+                    bail!(errors::ErrorKind::NoSuchTaskError(id));
+                }
+            };
+            std::fs::remove_dir_all(&slot_dir)?;
+            Ok(())
+        }))
     }
 
-    #[async(boxed)]
-    fn abort_task(&self, id: &Uuid) -> errors::Result<()> {
-        Err(errors::ErrorKind::NotImplementedError(()).into())
+    fn preempt_task(&self, id: &Uuid, leave_apps_in_memory: bool) -> errors::FResult<StopMethod> {
+        self.with_task(id, move |task| {
+            let method = preempt_method(&task.get_status(), leave_apps_in_memory);
+            match method {
+                StopMethod::Suspend => task.suspend(),
+                StopMethod::Quit => task.quit(),
+            }?;
+            Ok(method)
+        })
     }
 }
 
+#[cfg(test)]
 struct MockTask {
     pub status: Arc<RwLock<TaskStatus>>,
     close_chan: Option<std::sync::mpsc::Sender<()>>,
     refresher_thread: Arc<Option<std::thread::JoinHandle<()>>>,
 }
 
-/// Mock implementation of TaskServer. Useful for testing and development.
+/// Mock implementation of TaskServer for tests
+#[cfg(test)]
 pub struct MockTaskServer {
-    executor: CpuPool,
-    close_flag: Arc<AtomicBool>,
+    clock_source: Arc<ClockSource>,
+    /// time up to which the running tasks have progressed
+    progressed_to: Arc<Mutex<common::Time>>,
     data: Arc<Mutex<HashMap<Uuid, TaskStatus>>>,
-    worker: Option<std::thread::JoinHandle<()>>,
+    /// devices the tasks were told to use
+    gpus: Arc<Mutex<HashMap<Uuid, (coproc::ProcType, i64)>>>,
 }
 
-fn progress_mock_tasks(data: &mut HashMap<Uuid, TaskStatus>, pace: f64) {
+/// Mock tasks checkpoint after running for this many seconds.
+#[cfg(test)]
+const MOCK_CHECKPOINT_PERIOD: f64 = 60.0;
+/// and are done after running for this many seconds.
+#[cfg(test)]
+const MOCK_RUN_TIME: f64 = 86400.0;
+
+#[cfg(test)]
+fn progress_mock_tasks(data: &mut HashMap<Uuid, TaskStatus>, pace: f64, dt: f64) {
     for (_, v) in data.iter_mut() {
         if v.status == RunStatus::Running {
             v.pct_complete += pace;
+            v.current_cpu_time += dt;
+            v.elapsed_time += dt;
+
+            if v.elapsed_time - v.checkpoint_elapsed_time >= MOCK_CHECKPOINT_PERIOD {
+                v.checkpoint_cpu_time = v.current_cpu_time;
+                v.checkpoint_elapsed_time = v.elapsed_time;
+            }
 
             if v.pct_complete >= 1.0 {
                 v.pct_complete = 1.0;
@@ -217,47 +982,28 @@ fn progress_mock_tasks(data: &mut HashMap<Uuid, TaskStatus>, pace: f64) {
     }
 }
 
-impl Default for MockTaskServer {
-    fn default() -> Self {
-        let data = Arc::new(Mutex::new(HashMap::default()));
-        let executor = CpuPool::new(4);
-
-        let close_flag = Arc::new(AtomicBool::default());
-
-        let worker = std::thread::spawn({
-            let data = Arc::clone(&data);
-            let close_flag = Arc::clone(&close_flag);
-
-            move || loop {
-                if close_flag.load(Ordering::Relaxed) == true {
-                    return;
-                }
-                {
-                    let mut d: std::sync::MutexGuard<HashMap<Uuid, TaskStatus>> =
-                        data.lock().unwrap();
-
-                    progress_mock_tasks(&mut *d, 0.04);
-                }
-                std::thread::sleep(std::time::Duration::from_secs(3));
-            }
-        });
-
+/// Running tasks progress by the time the clock has moved whenever they are looked at.
+#[cfg(test)]
+impl ClockInitializable for MockTaskServer {
+    fn new_with_clock(clock_source: Arc<ClockSource>) -> Self {
         Self {
-            executor: executor,
-            data: data,
-            close_flag: close_flag,
-            worker: Some(worker),
+            progressed_to: Arc::new(Mutex::new(clock_source.now())),
+            clock_source: clock_source,
+            data: Default::default(),
+            gpus: Default::default(),
         }
     }
 }
 
-impl Drop for MockTaskServer {
-    fn drop(&mut self) {
-        self.close_flag.store(true, Ordering::Relaxed);
-        self.worker.take().unwrap().join().unwrap();
+/// Tasks of the default server stand still, its clock never moves.
+#[cfg(test)]
+impl Default for MockTaskServer {
+    fn default() -> Self {
+        Self::new_with_clock(Arc::new(test_util::TestClock::epoch()))
     }
 }
 
+#[cfg(test)]
 impl MockTaskServer {
     fn set_status(&self, id: &Uuid, v: RunStatus) -> errors::FResult<()> {
         let id = id.clone();
@@ -272,23 +1018,38 @@ impl MockTaskServer {
             },
         ))
     }
+
+    pub fn gpu(&self, id: &Uuid) -> Option<(coproc::ProcType, i64)> {
+        self.gpus.lock().unwrap().get(id).cloned()
+    }
+
+    fn quit(info: &mut TaskStatus) {
+        info.status = RunStatus::Stopped;
+        info.restart_from_checkpoint();
+    }
 }
 
+#[cfg(test)]
 impl TaskServer for MockTaskServer {
     fn tasks(&self) -> errors::FResult<HashMap<Uuid, TaskStatus>> {
-        Box::new(util::mutex_critical(Arc::clone(&self.data), |data| {
+        let now = self.clock_source.now();
+        let progressed_to = Arc::clone(&self.progressed_to);
+        Box::new(util::mutex_critical(Arc::clone(&self.data), move |data| {
+            let mut t = progressed_to.lock().unwrap();
+            let dt = (now - *t).num_milliseconds() as f64 / 1000.0;
+            if dt > 0.0 {
+                progress_mock_tasks(data, dt / MOCK_RUN_TIME, dt);
+                *t = now;
+            }
             Ok(data.clone())
         }))
     }
 
-    fn create_task(&self, _: &AppVersion, _: &Workunit) -> errors::FResult<Uuid> {
+    fn create_task(&self, _: &AppVersion, _: &Workunit, _: &InitData, _: &TaskFiles) -> errors::FResult<Uuid> {
         Box::new(util::mutex_critical(Arc::clone(&self.data), |data| {
             Ok(util::insert_unique(
                 data,
-                TaskStatus {
-                    status: RunStatus::Stopped,
-                    pct_complete: 0.0,
-                },
+                TaskStatus::default(),
             ).0)
         }))
     }
@@ -297,16 +1058,295 @@ impl TaskServer for MockTaskServer {
         self.set_status(id, RunStatus::Running)
     }
 
+    fn assign_gpu(&self, id: &Uuid, gpu: Option<(coproc::ProcType, i64)>) -> errors::FResult<()> {
+        let id = id.clone();
+        let gpus = Arc::clone(&self.gpus);
+        Box::new(util::mutex_critical(Arc::clone(&self.data), move |data| {
+            if !data.contains_key(&id) {
+                bail!(errors::ErrorKind::NoSuchTaskError(id));
+            }
+            let mut gpus = gpus.lock().unwrap();
+            match gpu {
+                Some(v) => {
+                    gpus.insert(id, v);
+                }
+                None => {
+                    gpus.remove(&id);
+                }
+            }
+            Ok(())
+        }))
+    }
+
+    fn suspend_task(&self, id: &Uuid) -> errors::FResult<()> {
+        self.set_status(id, RunStatus::Suspended)
+    }
+
     fn stop_task(&self, id: &Uuid) -> errors::FResult<()> {
-        self.set_status(id, RunStatus::Stopped)
+        let id = id.clone();
+        Box::new(util::mutex_critical(
+            Arc::clone(&self.data),
+            move |data| match data.get_mut(&id) {
+                None => Err(errors::ErrorKind::NoSuchTaskError(id).into()),
+                Some(info) => {
+                    Self::quit(info);
+                    Ok(())
+                }
+            },
+        ))
     }
 
     fn abort_task(&self, id: &Uuid) -> errors::FResult<()> {
         self.set_status(id, RunStatus::Aborted)
     }
+
+    fn remove_task(&self, id: &Uuid) -> errors::FResult<()> {
+        let id = id.clone();
+        let gpus = Arc::clone(&self.gpus);
+        Box::new(util::mutex_critical(Arc::clone(&self.data), move |data| {
+            if data.remove(&id).is_none() {
+                bail!(errors::ErrorKind::NoSuchTaskError(id));
+            }
+            gpus.lock().unwrap().remove(&id);
+            Ok(())
+        }))
+    }
+
+    fn preempt_task(&self, id: &Uuid, leave_apps_in_memory: bool) -> errors::FResult<StopMethod> {
+        let id = id.clone();
+        Box::new(util::mutex_critical(
+            Arc::clone(&self.data),
+            move |data| match data.get_mut(&id) {
+                None => Err(errors::ErrorKind::NoSuchTaskError(id).into()),
+                Some(info) => {
+                    let method = preempt_method(info, leave_apps_in_memory);
+                    match method {
+                        StopMethod::Suspend => {
+                            info.status = RunStatus::Suspended;
+                        }
+                        StopMethod::Quit => {
+                            Self::quit(info);
+                        }
+                    }
+                    Ok(method)
+                }
+            },
+        ))
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    fn running_task(server: &MockTaskServer, run_for: f64) -> Uuid {
+        let id = server
+            .create_task(&Default::default(), &Default::default(), &Default::default(), &Default::default())
+            .wait()
+            .unwrap();
+        server.start_task(&id).wait().unwrap();
+        let mut t = 0.0;
+        while t < run_for {
+            progress_mock_tasks(&mut *server.data.lock().unwrap(), 0.0, 30.0);
+            t += 30.0;
+        }
+        id
+    }
+
+    fn status(server: &MockTaskServer, id: &Uuid) -> TaskStatus {
+        server.tasks().wait().unwrap()[id].clone()
+    }
+
+    #[test]
+    fn test_mock_follows_clock() {
+        let clock = Arc::new(test_util::TestClock::epoch());
+        let server = MockTaskServer::new_with_clock(clock.clone());
+        let id = server
+            .create_task(&Default::default(), &Default::default(), &Default::default(), &Default::default())
+            .wait()
+            .unwrap();
+        server.start_task(&id).wait().unwrap();
+        assert_eq!(status(&server, &id).current_cpu_time, 0.0);
+
+        clock.advance(common::Duration::seconds(90));
+        let v = status(&server, &id);
+        assert_eq!(v.current_cpu_time, 90.0);
+        assert_eq!(v.checkpoint_cpu_time, 90.0);
+
+        server.remove_task(&id).wait().unwrap();
+        assert!(server.tasks().wait().unwrap().is_empty());
+        assert!(server.remove_task(&id).wait().is_err());
+    }
+
+    #[test]
+    fn test_init_data() {
+        let project = projects::Project::new("http://example.com/".into());
+        {
+            let mut data = project.data.lock().unwrap();
+            data.user_name = "user".into();
+            data.hostid = 7;
+            data.project_specific_prefs = Some(make_tree_element(
+                "project_specific",
+                vec![make_text_element("color", "red")],
+            ));
+        }
+
+        let root = treexml::Element::from(&InitData::from(&project));
+        let text = |name: &str| root.find_child(|e| e.name == name).and_then(|e| e.text.clone());
+        assert_eq!(text("master_url"), Some("http://example.com/".into()));
+        assert_eq!(text("user_name"), Some("user".into()));
+        assert_eq!(text("hostid"), Some("7".into()));
+        let prefs = root.find_child(|e| e.name == "project_preferences").unwrap();
+        assert_eq!(prefs.children[0].name, "color");
+        assert_eq!(text("gpu_device_num"), None);
+
+        let root = treexml::Element::from(&InitData {
+            gpu: Some((coproc::ProcType::NVIDIAGraphics, 1)),
+            ..Default::default()
+        });
+        let text = |name: &str| root.find_child(|e| e.name == name).and_then(|e| e.text.clone());
+        assert_eq!(text("gpu_type"), Some("NVIDIA".into()));
+        assert_eq!(text("gpu_device_num"), Some("1".into()));
+    }
+
+    #[test]
+    fn test_preempt_leaves_in_memory() {
+        let server = MockTaskServer::default();
+        let id = running_task(&server, 90.0);
+
+        assert_eq!(server.preempt_task(&id, true).wait().unwrap(), StopMethod::Suspend);
+
+        let v = status(&server, &id);
+        assert_eq!(v.status, RunStatus::Suspended);
+        assert_eq!(v.current_cpu_time, 90.0);
+    }
+
+    #[test]
+    fn test_preempt_quits_to_checkpoint() {
+        let server = MockTaskServer::default();
+        let id = running_task(&server, 90.0);
+
+        let before = status(&server, &id);
+        assert!(before.has_checkpointed());
+        assert_eq!(before.time_since_checkpoint(), 30.0);
+
+        assert_eq!(server.preempt_task(&id, false).wait().unwrap(), StopMethod::Quit);
+
+        let v = status(&server, &id);
+        assert_eq!(v.status, RunStatus::Stopped);
+        assert_eq!(v.current_cpu_time, 60.0);
+        assert_eq!(v.elapsed_time, 60.0);
+    }
+
+    #[test]
+    fn test_preempt_suspends_without_checkpoint() {
+        let server = MockTaskServer::default();
+        let id = running_task(&server, 30.0);
+
+        assert_eq!(server.preempt_task(&id, false).wait().unwrap(), StopMethod::Suspend);
+        assert_eq!(status(&server, &id).current_cpu_time, 30.0);
+    }
+
+    #[test]
+    fn test_app_status() {
+        let v = AppStatus::parse(
+            "<current_cpu_time>12.5</current_cpu_time><checkpoint_cpu_time>10</checkpoint_cpu_time><fraction_done>0.25</fraction_done>",
+        ).unwrap();
+        assert_eq!(v.current_cpu_time, 12.5);
+        assert_eq!(v.checkpoint_cpu_time, 10.0);
+        assert_eq!(v.fraction_done, 0.25);
+    }
+
+    #[test]
+    fn test_app_status_channel() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("app-status-{}", Uuid::new(UuidVersion::Random).unwrap()));
+        std::fs::create_dir_all(&dir).unwrap();
+        create_shmem_file(&dir).unwrap();
+        assert_eq!(receive_app_status(&dir).unwrap(), None);
+
+        let msg = b"<current_cpu_time>20</current_cpu_time><checkpoint_cpu_time>15</checkpoint_cpu_time>";
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(dir.join(file_names::MMAPPED_FILE_NAME))
+            .unwrap();
+        let mut buf = vec![1u8];
+        buf.extend_from_slice(msg);
+        buf.push(0);
+        f.write_at(&buf, APP_STATUS_CHANNEL * MSG_CHANNEL_SIZE).unwrap();
+
+        let v = AppStatus::parse(&receive_app_status(&dir).unwrap().unwrap()).unwrap();
+        assert_eq!(receive_app_status(&dir).unwrap(), None);
+
+        // The process was restarted from a checkpoint at 100 s
+        let mut task = Task::new(
+            PathBuf::new(),
+            String::new(),
+            dir.clone(),
+            Default::default(),
+            Default::default(),
+        );
+        task.checkpoint_cpu_time = 100.0;
+        task.cpu_time_base = 100.0;
+        task.elapsed_time = 130.0;
+        task.apply_app_status(&v);
+        assert_eq!(task.current_cpu_time, 120.0);
+        assert_eq!(task.checkpoint_cpu_time, 115.0);
+        assert_eq!(task.checkpoint_elapsed_time, 130.0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_slot_files() {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!("slot-files-{}", Uuid::new(UuidVersion::Random).unwrap()));
+        let slot_dir = dir.join("slot");
+        std::fs::create_dir_all(&slot_dir).unwrap();
+        std::fs::File::create(dir.join("in_1")).unwrap().write_all(b"input").unwrap();
+
+        let inputs = vec![
+            SlotFile {
+                path: dir.join("in_1"),
+                open_name: "in".into(),
+                copy: false,
+            },
+            SlotFile {
+                path: dir.join("in_1"),
+                open_name: "in_copy".into(),
+                copy: true,
+            },
+        ];
+        link_input_files(&slot_dir, &inputs).unwrap();
+        let read = |p: PathBuf| {
+            let mut v = String::new();
+            std::io::Read::read_to_string(&mut std::fs::File::open(p).unwrap(), &mut v).unwrap();
+            v
+        };
+        assert_eq!(
+            read(slot_dir.join("in")),
+            format!("<soft_link>{}</soft_link>\n", dir.join("in_1").display())
+        );
+        assert_eq!(read(slot_dir.join("in_copy")), "input");
+
+        std::fs::File::create(slot_dir.join("out")).unwrap().write_all(b"output").unwrap();
+        let outputs = vec![
+            SlotFile {
+                path: dir.join("out_1"),
+                open_name: "out".into(),
+                copy: false,
+            },
+            SlotFile {
+                path: dir.join("out_2"),
+                open_name: "missing".into(),
+                copy: false,
+            },
+        ];
+        move_output_files(&slot_dir, &outputs).unwrap();
+        assert_eq!(read(dir.join("out_1")), "output");
+        assert!(!slot_dir.join("out").exists());
+        assert!(!dir.join("out_2").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
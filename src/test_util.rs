@@ -0,0 +1,32 @@
+extern crate std;
+
+use common;
+
+use std::sync::Mutex;
+
+use common::ClockSource;
+
+/// Clock that only moves when a test moves it
+pub struct TestClock(Mutex<common::Time>);
+
+impl ClockSource for TestClock {
+    fn now(&self) -> common::Time {
+        *self.0.lock().unwrap()
+    }
+}
+
+impl TestClock {
+    pub fn new(t: common::Time) -> Self {
+        TestClock(Mutex::new(t))
+    }
+
+    /// Clock standing at the Unix epoch
+    pub fn epoch() -> Self {
+        Self::new(std::time::SystemTime::UNIX_EPOCH.into())
+    }
+
+    pub fn advance(&self, v: common::Duration) {
+        let mut t = self.0.lock().unwrap();
+        *t = *t + v;
+    }
+}
@@ -0,0 +1,228 @@
+extern crate futures;
+extern crate std;
+extern crate uuid;
+
+use tasks;
+
+use self::futures::Future;
+use self::uuid::Uuid;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Length of one run/suspend cycle used unless set otherwise, in milliseconds
+pub const DEFAULT_PERIOD_MS: u64 = 1000;
+
+/// How often the worker checks for shutdown or new settings while waiting, in milliseconds
+const POLL_PERIOD_MS: u64 = 10;
+
+/// Tasks that are suspended by the throttler rather than by the scheduler.
+/// The CPU scheduler treats them as running.
+pub type Throttled = Arc<Mutex<HashSet<Uuid>>>;
+
+/// Run and suspend durations of a cycle for the CPU usage limit in percent.
+/// None if there is no limit.
+pub fn duty_cycle(limit: f64, period: Duration) -> Option<(Duration, Duration)> {
+    if limit <= 0.0 || limit >= 100.0 {
+        return None;
+    }
+
+    let period_ms = period.as_secs() * 1000 + u64::from(period.subsec_nanos()) / 1_000_000;
+    let run_ms = (period_ms as f64 * limit / 100.0).round() as u64;
+    Some((
+        Duration::from_millis(run_ms),
+        Duration::from_millis(period_ms - run_ms),
+    ))
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Settings {
+    limit: f64,
+    period: Duration,
+}
+
+/// Sleeps for the given time unless shutdown is requested or settings change first. Returns false if interrupted.
+fn sleep_unless(close_flag: &AtomicBool, changed: &AtomicBool, duration: Duration) -> bool {
+    let deadline = Instant::now() + duration;
+    loop {
+        if close_flag.load(Ordering::Relaxed) || changed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return true;
+        }
+        std::thread::sleep(std::cmp::min(Duration::from_millis(POLL_PERIOD_MS), deadline - now));
+    }
+}
+
+fn suspend_running(tasks: &tasks::TaskServer, throttled: &Throttled) {
+    let mut throttled = throttled.lock().unwrap();
+    if let Ok(current) = tasks.tasks().wait() {
+        for (id, status) in current {
+            if status.status == tasks::RunStatus::Running && tasks.suspend_task(&id).wait().is_ok() {
+                throttled.insert(id);
+            }
+        }
+    }
+}
+
+fn resume_throttled(tasks: &tasks::TaskServer, throttled: &Throttled) {
+    let mut throttled = throttled.lock().unwrap();
+    for id in throttled.drain() {
+        let _ = tasks.start_task(&id).wait();
+    }
+}
+
+/// Keeps average CPU use within the preferences by alternately suspending and resuming running tasks.
+pub struct Throttler {
+    settings: Arc<Mutex<Settings>>,
+    changed: Arc<AtomicBool>,
+    throttled: Throttled,
+    close_flag: Arc<AtomicBool>,
+    worker: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for Throttler {
+    fn drop(&mut self) {
+        self.close_flag.store(true, Ordering::Relaxed);
+        self.worker.take().unwrap().join().unwrap();
+    }
+}
+
+impl Throttler {
+    pub fn new(tasks: Arc<tasks::TaskServer + Send + Sync>, throttled: Throttled) -> Self {
+        let settings = Arc::new(Mutex::new(Settings {
+            limit: 100.0,
+            period: Duration::from_millis(DEFAULT_PERIOD_MS),
+        }));
+        let changed = Arc::new(AtomicBool::default());
+        let close_flag = Arc::new(AtomicBool::default());
+
+        let worker = std::thread::spawn({
+            let settings = Arc::clone(&settings);
+            let changed = Arc::clone(&changed);
+            let throttled = Arc::clone(&throttled);
+            let close_flag = Arc::clone(&close_flag);
+
+            move || loop {
+                // New settings cut the current cycle short and start the next one right away
+                changed.store(false, Ordering::Relaxed);
+                let v = *settings.lock().unwrap();
+                match duty_cycle(v.limit, v.period) {
+                    None => {
+                        resume_throttled(&*tasks, &throttled);
+                        sleep_unless(&close_flag, &changed, v.period);
+                    }
+                    Some((run, suspend)) => {
+                        if sleep_unless(&close_flag, &changed, run) {
+                            suspend_running(&*tasks, &throttled);
+                            sleep_unless(&close_flag, &changed, suspend);
+                            resume_throttled(&*tasks, &throttled);
+                        }
+                    }
+                }
+                if close_flag.load(Ordering::Relaxed) {
+                    return;
+                }
+            }
+        });
+
+        Self {
+            settings: settings,
+            changed: changed,
+            throttled: throttled,
+            close_flag: close_flag,
+            worker: Some(worker),
+        }
+    }
+
+    /// Sets the share of time tasks may run, in percent. 100 or more turns throttling off.
+    pub fn set_limit(&self, limit: f64) {
+        self.settings.lock().unwrap().limit = limit;
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Sets the length of one run/suspend cycle.
+    pub fn set_period(&self, period: Duration) {
+        self.settings.lock().unwrap().period = period;
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    /// Tasks the throttler has currently suspended
+    pub fn throttled(&self) -> Throttled {
+        Arc::clone(&self.throttled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use app;
+    use workunit;
+
+    #[test]
+    fn test_duty_cycle() {
+        let period = Duration::from_millis(1000);
+        assert_eq!(duty_cycle(100.0, period), None);
+        assert_eq!(duty_cycle(0.0, period), None);
+        assert_eq!(
+            duty_cycle(25.0, period),
+            Some((Duration::from_millis(250), Duration::from_millis(750)))
+        );
+    }
+
+    #[test]
+    fn test_throttle() {
+        let server = Arc::new(tasks::MockTaskServer::default());
+        let id = server
+            .create_task(
+                &app::AppVersion::default(),
+                &workunit::Workunit::default(),
+                &tasks::InitData::default(),
+                &tasks::TaskFiles::default(),
+            )
+            .wait()
+            .unwrap();
+        server.start_task(&id).wait().unwrap();
+
+        let throttled = Throttled::default();
+        let throttler = Throttler::new(server.clone(), Arc::clone(&throttled));
+        throttler.set_period(Duration::from_millis(100));
+        throttler.set_limit(50.0);
+
+        let mut suspended = 0;
+        let samples = 100;
+        for _ in 0..samples {
+            std::thread::sleep(Duration::from_millis(10));
+            // The throttler changes task states while holding the lock
+            let guard = throttled.lock().unwrap();
+            let status = server.tasks().wait().unwrap()[&id].status;
+            let is_throttled = guard.contains(&id);
+            drop(guard);
+            match status {
+                tasks::RunStatus::Suspended => {
+                    assert!(is_throttled);
+                    suspended += 1;
+                }
+                _ => {
+                    assert_eq!(status, tasks::RunStatus::Running);
+                }
+            }
+        }
+        assert!(suspended > samples / 5 && suspended < samples * 4 / 5);
+
+        // Lifting the limit resumes the task without waiting for the cycle to end
+        throttler.set_period(Duration::from_secs(3600));
+        throttler.set_limit(100.0);
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(throttled.lock().unwrap().is_empty());
+        assert_eq!(server.tasks().wait().unwrap()[&id].status, tasks::RunStatus::Running);
+
+        drop(throttler);
+        assert!(throttled.lock().unwrap().is_empty());
+        assert_eq!(server.tasks().wait().unwrap()[&id].status, tasks::RunStatus::Running);
+    }
+}
@@ -1,10 +1,15 @@
 extern crate std;
 
 extern crate futures;
+extern crate libc;
+extern crate treexml;
 extern crate uuid;
 
 use std::collections::{HashMap, HashSet};
-use std::path::PathBuf;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
 use self::futures::prelude::*;
 use self::futures::future::poll_fn;
@@ -32,14 +37,160 @@ pub fn reserve_unique<T>(coll: &HashMap<Uuid, T>, reserve: &mut HashSet<Uuid>) -
     }
 }
 
+fn split_url(s: &str) -> (Option<&str>, &str, &str) {
+    let (scheme, rest) = match s.find("://") {
+        Some(pos) => (Some(&s[..pos]), &s[pos + 3..]),
+        None => (None, s),
+    };
+    match rest.find('/') {
+        Some(pos) => (scheme, &rest[..pos], &rest[pos..]),
+        None => (scheme, rest, ""),
+    }
+}
+
+/// Normalizes a master URL: lowercase scheme and host, and a trailing slash.
+/// URLs without a scheme are taken to be HTTP.
 pub fn canonicalize_url(s: &str) -> String {
-    String::from(s).replace("/", "_")
+    let (scheme, host, path) = split_url(s.trim());
+    let mut v = format!(
+        "{}://{}{}",
+        scheme.unwrap_or("http").to_ascii_lowercase(),
+        host.to_ascii_lowercase(),
+        path
+    );
+    if !v.ends_with('/') {
+        v.push('/');
+    }
+    v
+}
+
+/// Whether the string is an HTTP(S) URL usable as a master URL
+pub fn is_valid_master_url(s: &str) -> bool {
+    let (scheme, host, path) = split_url(s.trim());
+    let scheme_ok = match scheme {
+        Some(v) => v.eq_ignore_ascii_case("http") || v.eq_ignore_ascii_case("https"),
+        None => false,
+    };
+    scheme_ok && !host.is_empty()
+        && host.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == ':')
+        && !path.chars().any(|c| c.is_whitespace())
+}
+
+/// Whether both URLs name the same project. HTTP and HTTPS URLs are treated as equivalent.
+pub fn urls_match(a: &str, b: &str) -> bool {
+    let strip = |url: &str| {
+        let url = canonicalize_url(url);
+        let (_, host, path) = split_url(&url);
+        format!("{}{}", host, path)
+    };
+    strip(a) == strip(b)
+}
+
+/// File name safe form of a master URL, as produced by the reference client:
+/// the scheme is dropped, anything but alphanumerics, `.`, `-` and `_` becomes `_`,
+/// and a trailing `_` or `.` is removed.
+pub fn escape_project_url(s: &str) -> String {
+    let rest = match s.find("://") {
+        Some(pos) => &s[pos + 3..],
+        None => s,
+    };
+    let mut out: String = rest.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+            c
+        } else {
+            '_'
+        })
+        .collect();
+    if out.ends_with('_') || out.ends_with('.') {
+        out.pop();
+    }
+    out
+}
+
+/// Reads a boolean element, where an empty element like `<flag/>` means true.
+pub fn parse_flag(node: &treexml::Element) -> bool {
+    match node.text.as_ref().map(|s| s.trim()) {
+        None | Some("") => true,
+        Some(s) => s == "1" || s == "true",
+    }
+}
+
+/// Percent-encodes the string for use in a URL query.
+pub fn url_encode(s: &str) -> String {
+    let mut out = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => out.push(b as char),
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
 }
 
 pub fn task_path(root: &PathBuf, id: &Uuid) -> PathBuf {
     root.join("tasks").join(id.to_string())
 }
 
+/// Reads at most `max_len` bytes of the file, taking either its beginning or its end.
+pub fn read_truncated(path: &Path, max_len: u64, keep_head: bool) -> errors::Result<Vec<u8>> {
+    let mut f = std::fs::File::open(path)?;
+    let len = f.metadata()?.len();
+    if !keep_head && len > max_len {
+        f.seek(SeekFrom::Start(len - max_len))?;
+    }
+    let mut buf = Vec::new();
+    f.take(max_len).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Shrinks the file to about `max_len` bytes, keeping either its beginning or its end.
+///
+/// The file may be appended to by a running task at the same time. To keep the end, its beginning is cut off in place,
+/// which the kernel does atomically with respect to appends, in whole file system blocks - so up to a block more
+/// than `max_len` may remain. On file systems that cannot do this the end is copied to the beginning instead,
+/// and whatever is appended meanwhile may be lost.
+pub fn truncate_file(path: &Path, max_len: u64, keep_head: bool) -> errors::Result<()> {
+    let md = std::fs::metadata(path)?;
+    if md.len() <= max_len {
+        return Ok(());
+    }
+
+    let mut f = std::fs::OpenOptions::new().read(true).write(true).open(path)?;
+    if keep_head {
+        f.set_len(max_len)?;
+    } else {
+        let block = std::cmp::max(md.blksize(), 1);
+        // The range cut off must end before the end of the file
+        let excess = std::cmp::min(md.len() - max_len, md.len() - 1) / block * block;
+        if excess > 0
+            && unsafe { libc::fallocate(f.as_raw_fd(), libc::FALLOC_FL_COLLAPSE_RANGE, 0, excess as libc::off_t) } != 0
+        {
+            let e = std::io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) => {
+                    copy_tail(&mut f, max_len)?;
+                }
+                _ => {
+                    return Err(e.into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Moves the last `len` bytes of the file to its beginning and cuts off the rest.
+fn copy_tail(f: &mut std::fs::File, len: u64) -> errors::Result<()> {
+    let file_len = f.metadata()?.len();
+    let mut tail = Vec::new();
+    f.seek(SeekFrom::Start(file_len.saturating_sub(len)))?;
+    f.read_to_end(&mut tail)?;
+    f.seek(SeekFrom::Start(0))?;
+    f.write_all(&tail)?;
+    f.set_len(tail.len() as u64)?;
+    Ok(())
+}
+
 pub fn mutex_critical<T, U, F>(
     data: Arc<Mutex<T>>,
     f: F,
@@ -49,7 +200,11 @@ where
 {
     poll_fn(move || match data.try_lock() {
         Err(e) => match e {
-            TryLockError::WouldBlock => Ok(Async::NotReady),
+            TryLockError::WouldBlock => {
+                // Nothing else will wake us up, so ask to be polled again
+                futures::task::current().notify();
+                Ok(Async::NotReady)
+            }
             TryLockError::Poisoned(m) => {
                 Err(errors::ErrorKind::InternalError("Poisoned mutex".into()).into())
             }
@@ -57,3 +212,93 @@ where
         Ok(mut g) => f(&mut *g).map(|v| Async::Ready(v)),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_canonicalize_url() {
+        assert_eq!(canonicalize_url(" HTTP://Example.COM/Proj "), "http://example.com/Proj/");
+        assert_eq!(canonicalize_url("https://example.com"), "https://example.com/");
+        assert_eq!(canonicalize_url("example.com/proj/"), "http://example.com/proj/");
+        assert_eq!(canonicalize_url(&canonicalize_url("http://a/b")), "http://a/b/");
+    }
+
+    #[test]
+    fn test_is_valid_master_url() {
+        assert!(is_valid_master_url("http://example.com/proj/"));
+        assert!(is_valid_master_url("https://example.com:8080"));
+        assert!(!is_valid_master_url("example.com/proj/"));
+        assert!(!is_valid_master_url("ftp://example.com/"));
+        assert!(!is_valid_master_url("http:///proj/"));
+        assert!(!is_valid_master_url("http://exa mple.com/"));
+    }
+
+    #[test]
+    fn test_urls_match() {
+        assert!(urls_match("http://Example.com/proj", "https://example.com/proj/"));
+        assert!(!urls_match("http://example.com/proj/", "http://example.com/other/"));
+    }
+
+    #[test]
+    fn test_escape_project_url() {
+        assert_eq!(escape_project_url("http://www.example.com/proj/"), "www.example.com_proj");
+        assert_eq!(escape_project_url("https://example.com:8080/a~b/"), "example.com_8080_a_b");
+    }
+
+    fn temp_file(name: &str, data: &[u8]) -> PathBuf {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("{}-{}", name, Uuid::new(super::uuid::UuidVersion::Random).unwrap()));
+        std::fs::File::create(&path).unwrap().write_all(data).unwrap();
+        path
+    }
+
+    fn read_all(path: &Path) -> Vec<u8> {
+        let mut buf = Vec::new();
+        std::fs::File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn test_read_truncated() {
+        let path = temp_file("read-truncated", b"0123456789");
+        assert_eq!(read_truncated(&path, 4, true).unwrap(), b"0123".to_vec());
+        assert_eq!(read_truncated(&path, 4, false).unwrap(), b"6789".to_vec());
+        assert_eq!(read_truncated(&path, 20, false).unwrap(), b"0123456789".to_vec());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncate_file_head() {
+        let path = temp_file("truncate-head", b"0123456789");
+        truncate_file(&path, 20, true).unwrap();
+        assert_eq!(read_all(&path), b"0123456789".to_vec());
+        truncate_file(&path, 4, true).unwrap();
+        assert_eq!(read_all(&path), b"0123".to_vec());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_truncate_file_tail() {
+        let data = (0..3 * 65536).map(|v| (v % 251) as u8).collect::<Vec<_>>();
+        let path = temp_file("truncate-tail", &data);
+        let block = std::fs::metadata(&path).unwrap().blksize() as usize;
+
+        truncate_file(&path, 1000, false).unwrap();
+        let v = read_all(&path);
+        assert!(v.len() >= 1000 && v.len() < 1000 + block);
+        assert!(data.ends_with(&v));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_copy_tail() {
+        let path = temp_file("copy-tail", b"0123456789");
+        let mut f = std::fs::OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        copy_tail(&mut f, 4).unwrap();
+        assert_eq!(read_all(&path), b"6789".to_vec());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+extern crate std;
+extern crate treexml;
+extern crate treexml_util;
+extern crate uuid;
+
+use cc_config;
+use common;
+use cpu_sched;
+use prefs;
+use projects;
+use rr_sim;
+
+use self::treexml_util::make_text_element;
+use std::collections::{HashMap, HashSet};
+
+use common::ProjAm;
+use coproc::ProcType;
+use cpu_sched::{Resources, RunnableJob};
+
+/// Work fetch backoff starts at this many seconds
+pub const MIN_BACKOFF_INTERVAL: f64 = 60.0;
+/// and never grows longer than this
+pub const MAX_BACKOFF_INTERVAL: f64 = 86400.0;
+
+/// Resources work can be requested for
+pub const FETCHABLE_RSC: [ProcType; 4] = [
+    ProcType::CPU,
+    ProcType::NVIDIAGraphics,
+    ProcType::AMDGraphics,
+    ProcType::IntelGraphics,
+];
+
+/// Amount of work asked for one resource
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RscRequest {
+    /// instance-seconds of work
+    pub secs: f64,
+    /// instances that are idle right now
+    pub instances: f64,
+}
+
+/// Work to request from a project in the next scheduler RPC
+#[derive(Clone, Debug, Default)]
+pub struct WorkRequest {
+    pub project_url: String,
+    pub rsc: HashMap<ProcType, RscRequest>,
+}
+
+impl WorkRequest {
+    pub fn get(&self, rsc: &ProcType) -> RscRequest {
+        self.rsc.get(rsc).cloned().unwrap_or_default()
+    }
+
+    /// Total instance-seconds asked for, as sent to servers that do not understand per-resource requests.
+    pub fn work_req_seconds(&self) -> f64 {
+        self.rsc.values().map(|v| v.secs).fold(0.0, f64::max)
+    }
+
+    /// Fields added to the scheduler request. Coprocessor requests go with the host's coprocessors.
+    pub fn xml_fields(&self) -> Vec<treexml::Element> {
+        let cpu = self.get(&ProcType::CPU);
+        vec![
+            make_text_element("work_req_seconds", self.work_req_seconds()),
+            make_text_element("cpu_req_secs", cpu.secs),
+            make_text_element("cpu_req_instances", cpu.instances),
+        ]
+    }
+}
+
+/// Whether the project may be asked for work for the resource.
+pub fn rsc_allowed(p: &projects::ProjectData, rsc: &ProcType, now: &common::Time) -> bool {
+    let i = rsc.rsc_index();
+    if p.no_rsc_pref[i] || p.no_rsc_config[i] || p.no_rsc_apps[i] || p.no_rsc_ams[i] {
+        return false;
+    }
+    match p.rsc_backoff[i].backoff_time {
+        Some(ref v) => v <= now,
+        None => true,
+    }
+}
+
+/// Updates the project's backoff after a scheduler reply. `got` holds the resources that jobs were received for.
+pub fn handle_reply(
+    p: &mut projects::ProjectData,
+    req: &WorkRequest,
+    got: &HashSet<ProcType>,
+    now: &common::Time,
+) {
+    for rsc in req.rsc.keys() {
+        let backoff = &mut p.rsc_backoff[rsc.rsc_index()];
+        if got.contains(rsc) {
+            *backoff = Default::default();
+        } else {
+            backoff.backoff_interval = (backoff.backoff_interval * 2.0)
+                .max(MIN_BACKOFF_INTERVAL)
+                .min(MAX_BACKOFF_INTERVAL);
+            backoff.backoff_time = Some(
+                *now + common::Duration::seconds(backoff.backoff_interval as i64),
+            );
+        }
+    }
+}
+
+/// Instances of each resource with no job to run right now
+fn idle_instances(jobs: &[RunnableJob], resources: &Resources) -> HashMap<ProcType, f64> {
+    let mut busy: HashMap<ProcType, f64> = HashMap::new();
+    for job in jobs {
+        let (rsc, usage) = rr_sim::resource_of(job);
+        *busy.entry(rsc).or_insert(0.0) += usage;
+    }
+
+    FETCHABLE_RSC
+        .iter()
+        .map(|rsc| {
+            let total = match *rsc {
+                ProcType::CPU => resources.ncpus,
+                ref other => resources.coprocs.get(other).cloned().unwrap_or(0.0),
+            };
+            let idle = (total - busy.get(rsc).cloned().unwrap_or(0.0)).max(0.0);
+            (*rsc, if total > 0.0 { idle.floor() } else { -1.0 })
+        })
+        .filter(|&(_, v)| v >= 0.0)
+        .collect()
+}
+
+/// Decides how much work each resource needs and picks the project to ask for it.
+///
+/// A resource needs work once the simulation shows it running dry within the minimum buffer.
+/// The request then covers its shortfall over the whole buffer. With `fetch_minimal_work`
+/// only idle instances get work, one job each.
+pub fn choose_project(
+    now: &common::Time,
+    projects: &projects::Projects,
+    jobs: &[RunnableJob],
+    sim: &rr_sim::RRSimOutput,
+    resources: &Resources,
+    prefs: &prefs::GlobalPrefs,
+    cc_config: &cc_config::CCConfig,
+) -> Option<WorkRequest> {
+    let min_buf = prefs.work_buf_min_days * 86400.0;
+    let idle = idle_instances(jobs, resources);
+
+    let needed = idle.iter()
+        .filter_map(|(rsc, idle)| {
+            let req = if cc_config.fetch_minimal_work {
+                RscRequest {
+                    secs: 1.0,
+                    instances: *idle,
+                }
+            } else {
+                let saturated = sim.saturated_time.get(rsc).cloned().unwrap_or(0.0);
+                if saturated >= min_buf {
+                    return None;
+                }
+                RscRequest {
+                    secs: sim.shortfall.get(rsc).cloned().unwrap_or(0.0).max(1.0),
+                    instances: *idle,
+                }
+            };
+            if cc_config.fetch_minimal_work && req.instances <= 0.0 {
+                None
+            } else {
+                Some((*rsc, req))
+            }
+        })
+        .collect::<HashMap<_, _>>();
+    if needed.is_empty() {
+        return None;
+    }
+
+    let at_risk = sim.jobs
+        .values()
+        .filter(|v| v.deadline_at_risk)
+        .map(|v| v.project_url.clone())
+        .collect::<HashSet<_>>();
+    let priorities = cpu_sched::project_priorities(projects);
+
+    projects
+        .data
+        .iter()
+        .filter(|p| !at_risk.contains(&p.master_url()))
+        .filter_map(|p| {
+            let d = p.data.lock().unwrap();
+            if !d.can_request_work(now) {
+                return None;
+            }
+            let rsc = needed
+                .iter()
+                .filter(|&(rsc, _)| rsc_allowed(&d, rsc, now))
+                .map(|(rsc, req)| (*rsc, *req))
+                .collect::<HashMap<_, _>>();
+            if rsc.is_empty() {
+                return None;
+            }
+            let priority = priorities
+                .get(&p.master_url())
+                .cloned()
+                .unwrap_or(std::f64::MIN);
+            Some((
+                priority,
+                WorkRequest {
+                    project_url: p.master_url(),
+                    rsc: rsc,
+                },
+            ))
+        })
+        .fold(None, |best: Option<(f64, WorkRequest)>, (priority, req)| match best {
+            Some(ref v) if v.0 > priority || (v.0 == priority && v.1.project_url < req.project_url) => {
+                best.clone()
+            }
+            _ => Some((priority, req)),
+        })
+        .map(|(_, req)| req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn projects(urls: &[(&str, f64)]) -> projects::Projects {
+        let mut v = projects::Projects {
+            data: Default::default(),
+        };
+        for &(url, rs) in urls {
+            let p = projects::Project::new(url.into());
+            p.data.lock().unwrap().resource_share = rs;
+            v.data.insert(p);
+        }
+        v
+    }
+
+    fn now() -> common::Time {
+        std::time::SystemTime::UNIX_EPOCH.into()
+    }
+
+    fn resources() -> Resources {
+        let mut v = Resources {
+            ncpus: 2.0,
+            ..Default::default()
+        };
+        v.coprocs.insert(ProcType::NVIDIAGraphics, 1.0);
+        v
+    }
+
+    fn simulate(resources: &Resources, prefs: &prefs::GlobalPrefs) -> rr_sim::RRSimOutput {
+        rr_sim::simulate(&now(), &[], &HashMap::new(), resources, prefs.work_buf_total())
+    }
+
+    #[test]
+    fn test_empty_queue_requests_all_resources() {
+        let prefs = prefs::GlobalPrefs::default();
+        let resources = resources();
+        let projects = projects(&[("http://a/", 100.0)]);
+
+        let v = choose_project(
+            &now(),
+            &projects,
+            &[],
+            &simulate(&resources, &prefs),
+            &resources,
+            &prefs,
+            &Default::default(),
+        ).unwrap();
+        assert_eq!(v.project_url, "http://a/");
+        assert_eq!(v.get(&ProcType::CPU).instances, 2.0);
+        assert_eq!(v.get(&ProcType::CPU).secs, 2.0 * prefs.work_buf_total());
+        assert_eq!(v.get(&ProcType::NVIDIAGraphics).instances, 1.0);
+        assert!(!v.rsc.contains_key(&ProcType::AMDGraphics));
+    }
+
+    #[test]
+    fn test_rsc_exclusions_and_backoff() {
+        let prefs = prefs::GlobalPrefs::default();
+        let resources = resources();
+        let projects = projects(&[("http://a/", 100.0)]);
+        {
+            let mut d = projects.find_by_url("http://a/").unwrap().data.lock().unwrap();
+            d.no_rsc_apps[ProcType::NVIDIAGraphics.rsc_index()] = true;
+            let req = WorkRequest {
+                project_url: "http://a/".into(),
+                rsc: vec![(ProcType::CPU, Default::default())].into_iter().collect(),
+            };
+            handle_reply(&mut d, &req, &HashSet::new(), &now());
+            assert_eq!(d.rsc_backoff[0].backoff_interval, MIN_BACKOFF_INTERVAL);
+        }
+
+        let sim = simulate(&resources, &prefs);
+        let v = choose_project(&now(), &projects, &[], &sim, &resources, &prefs, &Default::default());
+        assert!(v.is_none());
+
+        let later = now() + common::Duration::seconds(MIN_BACKOFF_INTERVAL as i64);
+        let v = choose_project(&later, &projects, &[], &sim, &resources, &prefs, &Default::default()).unwrap();
+        assert_eq!(v.rsc.keys().collect::<Vec<_>>(), vec![&ProcType::CPU]);
+    }
+
+    #[test]
+    fn test_fetch_minimal_work() {
+        let prefs = prefs::GlobalPrefs::default();
+        let resources = resources();
+        let projects = projects(&[("http://a/", 100.0)]);
+        let mut cc_config = cc_config::CCConfig::default();
+        cc_config.fetch_minimal_work = true;
+        let jobs = [
+            RunnableJob {
+                task: uuid::Uuid::new(uuid::UuidVersion::Random).unwrap(),
+                name: "job".into(),
+                project_url: "http://a/".into(),
+                app_name: "app".into(),
+                report_deadline: now() + common::Duration::days(10),
+                avg_ncpus: 1.0,
+                coproc: Some((ProcType::NVIDIAGraphics, 1.0)),
+                runtime_remaining: 100.0,
+            },
+        ];
+
+        let sim = rr_sim::simulate(&now(), &jobs, &HashMap::new(), &resources, prefs.work_buf_total());
+        let v = choose_project(&now(), &projects, &jobs, &sim, &resources, &prefs, &cc_config).unwrap();
+        assert_eq!(v.get(&ProcType::CPU), RscRequest { secs: 1.0, instances: 2.0 });
+        assert!(!v.rsc.contains_key(&ProcType::NVIDIAGraphics));
+    }
+}
@@ -1,4 +1,12 @@
-#[derive(Debug)]
+extern crate treexml;
+extern crate treexml_util;
+
+use errors;
+use file_info;
+
+use self::treexml_util::Unmarshaller;
+
+#[derive(Clone, Debug, Default)]
 pub struct Workunit {
     pub name: String,
     pub app_name: String,
@@ -7,4 +15,47 @@ pub struct Workunit {
     pub rsc_fpops_bound: f64,
     pub rsc_memory_bound: f64,
     pub rsc_disk_bound: f64,
+    /// input files and the names the application opens them by
+    pub input_files: Vec<file_info::FileRef>,
+}
+
+impl Workunit {
+    pub fn try_from(root: &treexml::Element) -> errors::Result<Workunit> {
+        if root.name != "workunit" {
+            bail!(errors::ErrorKind::XMLError(format!("unexpected workunit root: {}", root.name).into()));
+        }
+
+        let mut v = Workunit::default();
+        for node in &root.children {
+            match &*node.name {
+                "name" => {
+                    let _ = v.name.unmarshal(&node);
+                }
+                "app_name" => {
+                    let _ = v.app_name.unmarshal(&node);
+                }
+                "command_line" => {
+                    let _ = v.command_line.unmarshal(&node);
+                }
+                "rsc_fpops_est" => {
+                    let _ = v.rsc_fpops_est.unmarshal(&node);
+                }
+                "rsc_fpops_bound" => {
+                    let _ = v.rsc_fpops_bound.unmarshal(&node);
+                }
+                "rsc_memory_bound" => {
+                    let _ = v.rsc_memory_bound.unmarshal(&node);
+                }
+                "rsc_disk_bound" => {
+                    let _ = v.rsc_disk_bound.unmarshal(&node);
+                }
+                _ => {}
+            }
+        }
+        v.input_files = file_info::file_refs(root);
+        if v.name.is_empty() || v.app_name.is_empty() {
+            bail!(errors::ErrorKind::XMLError("workunit has no name or app".into()));
+        }
+        Ok(v)
+    }
 }